@@ -0,0 +1,72 @@
+// common.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! The agent shared by the fuzz targets.
+
+use std::fs::read;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use ssh_agent_lib::proto::message::AddIdentity;
+use ssh_agent_lib::proto::message::Message;
+use ssh_agent_lib::proto::private_key::PrivateKey;
+use ssh_agent_lib::proto::to_bytes;
+
+use ssh_gpg_agent::handle_bytes;
+use ssh_gpg_agent::set_prompt_methods;
+use ssh_gpg_agent::Config;
+use ssh_gpg_agent::FromPem as _;
+use ssh_gpg_agent::GpgKeyAgent;
+use ssh_gpg_agent::OnAdd;
+
+
+/// Retrieve the agent to fuzz, creating it on first use.
+///
+/// The agent has no key directories and keeps identities added at
+/// runtime in memory. It starts out with the Ed25519 test key, so that
+/// sign requests have something to work with.
+pub fn agent() -> &'static GpgKeyAgent {
+  static AGENT: OnceLock<GpgKeyAgent> = OnceLock::new();
+
+  AGENT.get_or_init(|| {
+    // Asking the agent to shut down makes it raise `SIGTERM`, which
+    // would end the fuzzing session.
+    // SAFETY: Ignoring a signal is always safe.
+    let _ = unsafe { libc::signal(libc::SIGTERM, libc::SIG_IGN) };
+    // Never prompt the user, e.g., for confirming the usage of a key.
+    let () = set_prompt_methods(Vec::new());
+
+    let config = Config {
+      on_add: OnAdd::Memory,
+      ..Default::default()
+    };
+    let agent = GpgKeyAgent::new(Vec::new(), None, config);
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/valid_keys/ed25519");
+    let pem = read(path).unwrap();
+    let privkey = PrivateKey::from_pem(pem.into()).unwrap();
+    let request = Message::AddIdentity(AddIdentity {
+      privkey,
+      comment: "ed25519".to_string(),
+    });
+    let response = handle_bytes(&agent, &to_bytes(&request).unwrap());
+    assert_eq!(response, to_bytes(&Message::Success).unwrap());
+    agent
+  })
+}
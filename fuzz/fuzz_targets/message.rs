@@ -0,0 +1,33 @@
+// message.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Fuzz the agent with arbitrary (unframed) protocol messages.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use ssh_gpg_agent::handle_bytes;
+
+mod common;
+
+
+fuzz_target!(|data: &[u8]| {
+  let _response = handle_bytes(common::agent(), data);
+});
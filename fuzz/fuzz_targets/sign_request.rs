@@ -0,0 +1,57 @@
+// sign_request.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Fuzz the agent with sign requests for arbitrary public key blobs,
+//! as well as truncated versions thereof.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use ssh_agent_lib::proto::message::Message;
+use ssh_agent_lib::proto::message::SignRequest;
+use ssh_agent_lib::proto::to_bytes;
+
+use ssh_gpg_agent::handle_bytes;
+
+mod common;
+
+
+fuzz_target!(|data: &[u8]| {
+  // The first five bytes select the flags and where to truncate the
+  // encoded request; the remainder is split into the public key blob
+  // and the data to sign.
+  let (header, rest) = data.split_at(data.len().min(5));
+  let mut header = header.to_vec();
+  let () = header.resize(5, 0);
+  let flags = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+  let (pubkey_blob, data) = rest.split_at(rest.len() / 2);
+
+  let request = Message::SignRequest(SignRequest {
+    pubkey_blob: pubkey_blob.to_vec(),
+    data: data.to_vec(),
+    flags,
+  });
+  let request = to_bytes(&request).unwrap();
+  let agent = common::agent();
+  let _response = handle_bytes(agent, &request);
+
+  let len = request.len() * usize::from(header[4]) / 255;
+  let _response = handle_bytes(agent, &request[..len]);
+});
@@ -0,0 +1,1669 @@
+// agent.rs
+
+// *************************************************************************
+// * Copyright (C) 2019-2024 Daniel Mueller (deso@posteo.net)              *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! The SSH agent itself.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::iter::once;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::id;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::Weak;
+use std::thread::sleep;
+use std::thread::spawn;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use libc::geteuid;
+
+use signal_hook::consts::SIGTERM;
+use signal_hook::low_level::raise;
+
+use ssh_agent_lib::agent::Agent;
+use ssh_agent_lib::proto::message::AddIdentity;
+use ssh_agent_lib::proto::message::AddIdentityConstrained;
+use ssh_agent_lib::proto::message::Extension;
+use ssh_agent_lib::proto::message::Identity;
+use ssh_agent_lib::proto::message::Message;
+use ssh_agent_lib::proto::message::RemoveIdentity;
+use ssh_agent_lib::proto::message::SignatureBlob;
+use ssh_agent_lib::proto::message::SignRequest;
+use ssh_agent_lib::proto::public_key::PublicKey;
+use ssh_agent_lib::proto::signature::RSA_SHA2_256;
+use ssh_agent_lib::proto::signature::RSA_SHA2_512;
+
+use tracing::debug;
+use tracing::debug_span;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::batch::BatchSignRequest;
+use crate::batch::BatchSignResponse;
+use crate::batch::SignOutcome;
+use crate::batch::SIGN_BATCH_EXTENSION;
+use crate::cache::Cache;
+use crate::cancel::Cancellation;
+use crate::composite::sign_with;
+use crate::composite::CompositeStore;
+use crate::config::Config;
+use crate::config::HostRule;
+use crate::config::OnAdd;
+use crate::config::RsaSignatureAlgorithm;
+use crate::config::SocketPolicy;
+use crate::constraint::check_destinations;
+use crate::constraint::Constraint;
+use crate::constraint::DestinationConstraint;
+use crate::decrypt::Decryptor;
+use crate::error::ErrorKind;
+use crate::error::ErrorReport;
+use crate::error::FailureCount;
+use crate::error::KeyStoreError;
+use crate::error::RequestError;
+use crate::filestore::FileStore;
+use crate::files::PemPublicKey;
+use crate::fingerprint::Fingerprint;
+use crate::fingerprint::HashAlg;
+use crate::gpgagent::GpgAgentStore;
+use crate::hosts::host_keys;
+use crate::lock::Lock;
+use crate::logging::Redacted;
+use crate::logging::CLIENTS;
+use crate::logging::REQUESTS;
+use crate::memstore::MemoryStore;
+use crate::peer::Peer;
+#[cfg(feature = "pkcs11")]
+use crate::pkcs11::Pkcs11;
+use crate::policy::check_key_size;
+use crate::prompt;
+use crate::session::Binding;
+use crate::session::Session;
+use crate::session::UserAuthRequest;
+use crate::session::SESSION_BIND_EXTENSION;
+use crate::status::KeyStatus;
+use crate::status::Status;
+use crate::status::QUERY_EXTENSION;
+use crate::store::KeyFiles;
+use crate::store::KeyStore;
+use crate::store::StoreIdentity;
+use crate::transport::PeerAgent;
+use crate::transport::RemoteAgent;
+use crate::upstream::Upstream;
+use crate::usage::KeyUsage;
+use crate::usage::Usage;
+
+
+/// The number of attempts a user has for entering the passphrase of a
+/// passphrase protected private key.
+pub const PASSPHRASE_ATTEMPTS: usize = 3;
+
+/// The name of the agent protocol extension causing the agent to
+/// rescan its key directories.
+pub const RELOAD_EXTENSION: &str = "reload@ssh-gpg-agent";
+
+/// The name of the agent protocol extension asking the agent to shut
+/// down, as if it had received `SIGTERM`. Only processes of the user
+/// running the agent connected to its main socket may do so.
+pub const SHUTDOWN_EXTENSION: &str = "shutdown@ssh-gpg-agent";
+
+/// The name of the agent protocol extension for checking that the
+/// agent is alive and responsive, to which it merely responds with
+/// success.
+pub const PING_EXTENSION: &str = "ping@ssh-gpg-agent";
+
+
+/// A client issuing requests to the agent.
+#[derive(Clone, Copy, Debug, Default)]
+struct Client<'peer> {
+  /// The process the client is, if known.
+  peer: Option<&'peer Peer>,
+  /// The state of the client's connection, if tracked.
+  session: Option<&'peer Session>,
+  /// Whether the client is connected to the forwarding socket.
+  forwarded: bool,
+  /// Whether the client is connected via TCP, i.e., is not known to be
+  /// a local process.
+  remote: bool,
+}
+
+impl Client<'_> {
+  /// Retrieve the SSH sessions the client's connection is bound to.
+  fn bindings(&self) -> Result<Vec<Binding>> {
+    self
+      .session
+      .map(Session::bindings)
+      .unwrap_or_else(|| Ok(Vec::new()))
+  }
+
+  /// Describe the client, for the purpose of logging.
+  fn describe(&self) -> String {
+    match self.peer {
+      Some(peer) => peer.to_string(),
+      None if self.remote => "<TCP client>".to_string(),
+      None => "<unknown>".to_string(),
+    }
+  }
+}
+
+
+/// The constraints an identity got added with.
+#[derive(Clone, Debug, Default)]
+struct IdentityConstraints {
+  /// The point in time at which the identity expires, if any.
+  expiry: Option<Instant>,
+  /// Whether every usage of the identity has to be confirmed.
+  confirm: bool,
+  /// The destinations the identity is restricted to, if any.
+  destinations: Vec<DestinationConstraint>,
+}
+
+
+/// Describe the path of a request through the given SSH sessions, for
+/// the purpose of logging.
+fn describe_path(bindings: &[Binding]) -> String {
+  if bindings.is_empty() {
+    String::new()
+  } else {
+    let hosts = bindings
+      .iter()
+      .map(ToString::to_string)
+      .collect::<Vec<_>>()
+      .join(" -> ");
+    format!(" via {hosts}")
+  }
+}
+
+
+/// Check whether the given request is permitted via the forwarding
+/// socket.
+fn is_forwardable_request(request: &Message) -> bool {
+  match request {
+    Message::RequestIdentities | Message::SignRequest(..) => true,
+    Message::Extension(extension) => {
+      extension.extension_type == SESSION_BIND_EXTENSION
+        || extension.extension_type == PING_EXTENSION
+        || extension.extension_type == SIGN_BATCH_EXTENSION
+    },
+    _ => false,
+  }
+}
+
+
+/// Check whether the given request asks for one or more signatures.
+fn is_sign_request(request: &Message) -> bool {
+  match request {
+    Message::SignRequest(..) => true,
+    Message::Extension(extension) => extension.extension_type == SIGN_BATCH_EXTENSION,
+    _ => false,
+  }
+}
+
+
+/// Check whether the given request is for one of the agent's own
+/// protocol extensions, the clients of which understand error reports.
+fn is_own_extension(request: &Message) -> bool {
+  match request {
+    Message::Extension(extension) => [
+      RELOAD_EXTENSION,
+      SHUTDOWN_EXTENSION,
+      PING_EXTENSION,
+      QUERY_EXTENSION,
+      SIGN_BATCH_EXTENSION,
+    ]
+    .contains(&extension.extension_type.as_str()),
+    _ => false,
+  }
+}
+
+
+/// The SSH agent supporting GPG encrypted SSH keys.
+///
+/// Upon creation the agent will load public keys that have
+/// corresponding encrypted private keys inside its associated directory
+/// and keep those public keys in memory. By default it does not cache
+/// secret key material, but loads it on demand for each and every
+/// request. If enabled, decrypted keys are kept in a cache for a
+/// limited amount of time.
+///
+/// Besides these key files, the agent offers the keys of further
+/// [`KeyStore`]s, such as those of the GnuPG agent or of PKCS#11
+/// tokens, and forwards requests to an upstream agent.
+#[derive(Debug)]
+pub struct GpgKeyAgent {
+  /// The store of the key files in the agent's directories.
+  files: Arc<FileStore>,
+  /// The store of the key files kept in the `pass` password store, if
+  /// configured.
+  pass: Option<Arc<FileStore>>,
+  /// The store of the identities added at runtime that are kept in
+  /// memory only.
+  memory: Arc<MemoryStore>,
+  /// All key stores, including the one for the key files.
+  stores: CompositeStore,
+  /// The agent's configuration.
+  config: RwLock<Arc<Config>>,
+  /// Identities that got removed by a client and are no longer
+  /// advertised.
+  removed: Mutex<HashSet<PublicKey>>,
+  /// The constraints of identities added with any.
+  constraints: Mutex<HashMap<PublicKey, IdentityConstraints>>,
+  /// The agent's lock state.
+  lock: Lock,
+  /// The time the last request (not merely querying the status) came
+  /// in.
+  last_request: Mutex<Instant>,
+  /// The cache of decrypted private keys.
+  cache: Arc<Cache>,
+  /// The time the agent got created.
+  started: Instant,
+  /// The usage statistics of the agent's keys.
+  usage: Usage,
+  /// The number of requests that failed, by kind of failure.
+  failures: Mutex<BTreeMap<ErrorKind, u64>>,
+}
+
+impl GpgKeyAgent {
+  /// Create a new agent managing the key pairs in the given
+  /// directories, optionally forwarding requests for identities it does
+  /// not know about to the agent listening on `upstream`.
+  pub fn new(dirs: Vec<PathBuf>, upstream: Option<PathBuf>, config: Config) -> Self {
+    let cache = Arc::new(Cache::new(
+      Duration::from_secs(config.cache.ttl),
+      config.cache.max_entries,
+    ));
+    let config = Arc::new(config);
+    let files = Arc::new(FileStore::new(dirs, config.clone(), cache.clone()));
+    let pass = config
+      .pass
+      .directory()
+      .map(|dir| Arc::new(FileStore::pass(dir, config.clone(), cache.clone())));
+
+    let memory = Arc::new(MemoryStore::default());
+
+    let stores = CompositeStore::new(config.stores.clone());
+    let () = stores.insert_store(memory.clone());
+    let () = stores.insert_store(files.clone());
+    if let Some(pass) = &pass {
+      let () = stores.insert_store(pass.clone());
+    }
+    let () = stores.insert_store(Arc::new(GpgAgentStore::new(config.clone())));
+    #[cfg(feature = "pkcs11")]
+    if let Some(pkcs11) = Pkcs11::new(&config.pkcs11) {
+      let () = stores.insert_store(Arc::new(pkcs11));
+    }
+    #[cfg(not(feature = "pkcs11"))]
+    if config.pkcs11.module.is_some() {
+      warn!("PKCS#11 module configured, but support for PKCS#11 tokens is not compiled in");
+    }
+    if let Some(upstream) = upstream {
+      let upstream = Upstream::new(upstream, config.upstream_timeout());
+      let () = stores.insert_store(Arc::new(upstream));
+    }
+
+    Self {
+      files,
+      pass,
+      memory,
+      stores,
+      usage: Usage::new(config.usage_file.clone()),
+      cache,
+      config: RwLock::new(config),
+      removed: Mutex::new(HashSet::new()),
+      constraints: Mutex::new(HashMap::new()),
+      lock: Lock::default(),
+      last_request: Mutex::new(Instant::now()),
+      started: Instant::now(),
+      failures: Mutex::new(BTreeMap::new()),
+    }
+  }
+
+  /// Use the given key files instead of those found by indexing the
+  /// agent's directories.
+  pub fn with_key_files<F>(self, files: F) -> Self
+  where
+    F: KeyFiles + 'static,
+  {
+    let () = self.files.set_key_files(files);
+    self
+  }
+
+  /// Use the given decryptor instead of the one for the configured
+  /// backend.
+  pub fn with_decryptor<D>(self, decryptor: D) -> Self
+  where
+    D: Decryptor + 'static,
+  {
+    let () = self.files.set_decryptor(decryptor);
+    self
+  }
+
+  /// Offer the keys of the given store in addition to those of the
+  /// stores the agent sets up itself.
+  pub fn with_key_store<S>(self, store: S) -> Self
+  where
+    S: KeyStore + 'static,
+  {
+    let () = self.insert_key_store(store);
+    self
+  }
+
+  /// Add the given key store at runtime, replacing any store with the
+  /// same name.
+  ///
+  /// The store's priority is looked up in the configuration by name.
+  /// Stores without a configured priority are consulted after the ones
+  /// the agent sets up itself.
+  pub fn insert_key_store<S>(&self, store: S)
+  where
+    S: KeyStore + 'static,
+  {
+    self.stores.insert_store(Arc::new(store))
+  }
+
+  /// Remove the key store with the given name at runtime, returning
+  /// whether one was present.
+  pub fn remove_key_store(&self, name: &str) -> bool {
+    self.stores.remove_store(name).is_some()
+  }
+
+  /// Retrieve the agent's current configuration.
+  fn config(&self) -> Arc<Config> {
+    self.config.read().unwrap().clone()
+  }
+
+  /// Retrieve the stores of key files, i.e., the one for the agent's
+  /// directories followed by the one for the password store, if any.
+  fn file_stores(&self) -> impl Iterator<Item = &Arc<FileStore>> {
+    once(&self.files).chain(&self.pass)
+  }
+
+  /// Replace the agent's configuration, e.g., after it got changed on
+  /// disk.
+  ///
+  /// The decryptor is recreated for the configured backend, key stores
+  /// are reordered as per their configured priorities, the PKCS#11
+  /// module is reloaded if its configuration changed, and all cached
+  /// keys are wiped. Settings that only take effect when the agent is
+  /// started, such as the cache configuration, are not affected.
+  pub fn reconfigure(&self, config: Config) {
+    let config = Arc::new(config);
+    let () = self.stores.reconfigure(&config);
+
+    #[cfg(feature = "pkcs11")]
+    if config.pkcs11 != self.config().pkcs11 {
+      let _store = self.stores.remove_store("pkcs11");
+      if let Some(pkcs11) = Pkcs11::new(&config.pkcs11) {
+        let () = self.stores.insert_store(Arc::new(pkcs11));
+      }
+    }
+
+    *self.config.write().unwrap() = config;
+    let () = self.cache.clear();
+  }
+
+  /// Rescan the agent's key directories on next access.
+  ///
+  /// Changes to the directories are usually picked up automatically,
+  /// but watching them may not be possible everywhere (e.g., on
+  /// network file systems).
+  pub fn reload_keys(&self) {
+    info!("Reloading keys");
+    let () = self.stores.invalidate();
+  }
+
+  /// Wipe all cached private keys.
+  pub fn clear_cache(&self) {
+    let () = self.cache.clear();
+  }
+
+  /// Periodically wipe expired keys from the cache in the background,
+  /// instead of only when the next request comes in.
+  pub fn purge_cache_periodically(&self) {
+    if self.cache.is_enabled() {
+      let cache = self.cache.clone();
+      let interval = cache.ttl().min(Duration::from_secs(1));
+      let _handle = spawn(move || loop {
+        let () = sleep(interval);
+        let () = cache.purge();
+      });
+    }
+  }
+
+  /// Periodically remove identities whose lifetime elapsed and lock
+  /// the agent once it has been idle for too long in the background,
+  /// instead of only when the next request comes in.
+  ///
+  /// The background thread terminates once the agent is dropped.
+  pub fn housekeep_periodically(agent: &Arc<Self>) {
+    let agent = Arc::downgrade(agent);
+    let _handle = spawn(move || loop {
+      let () = sleep(Duration::from_secs(1));
+      match Weak::upgrade(&agent) {
+        Some(agent) => {
+          let () = agent.expire_identities();
+          let () = agent.lock_if_idle();
+        },
+        None => break,
+      }
+    });
+  }
+
+  /// Derive the public keys of the GPG encrypted private key files
+  /// lacking a public key file, by decrypting them (see
+  /// [`FileStore::derive_orphans`]).
+  pub fn derive_orphans(&self) {
+    for store in self.file_stores() {
+      let () = store.derive_orphans();
+    }
+  }
+
+  /// Derive the public keys of the GPG encrypted private key files
+  /// lacking a public key file in the background.
+  pub fn derive_orphans_in_background(agent: &Arc<Self>) {
+    let agent = agent.clone();
+    let _handle = spawn(move || agent.derive_orphans());
+  }
+
+  /// Lock the agent and wipe all cached private keys if no request came
+  /// in for longer than the configured idle time.
+  pub fn lock_if_idle(&self) {
+    let timeout = self.config().idle_lock;
+    if timeout == 0 {
+      return
+    }
+
+    let idle = self.last_request.lock().unwrap().elapsed();
+    if idle >= Duration::from_secs(timeout) && self.lock.lock_idle() {
+      info!("Locking agent after {}s without requests", idle.as_secs());
+      let () = self.cache.clear();
+      let () = self.wipe_ephemeral();
+    }
+  }
+
+  /// Wipe all identities kept in memory only, along with the
+  /// constraints they got added with.
+  pub fn wipe_ephemeral(&self) {
+    let pubkeys = self.memory.clear();
+    if !pubkeys.is_empty() {
+      info!("Wiped {} identity(ies) kept in memory", pubkeys.len());
+      let mut constraints = self.constraints.lock().unwrap();
+      for pubkey in pubkeys {
+        let _constraints = constraints.remove(&pubkey);
+      }
+    }
+  }
+
+  /// Remove all identities whose lifetime elapsed, along with any
+  /// cached private keys of theirs.
+  pub fn expire_identities(&self) {
+    let now = Instant::now();
+    let expired = {
+      let mut constraints = self.constraints.lock().unwrap();
+      let expired = constraints
+        .iter()
+        .filter(|(_, constraints)| constraints.expiry.is_some_and(|expiry| expiry <= now))
+        .map(|(pubkey, _)| pubkey.clone())
+        .collect::<Vec<_>>();
+      let () = expired.iter().for_each(|pubkey| {
+        let _constraints = constraints.remove(pubkey);
+      });
+      expired
+    };
+
+    for pubkey in expired {
+      let () = self.cache.remove(&pubkey);
+      if let Err(err) = self.expire_identity(&pubkey) {
+        warn!("Failed to remove expired identity: {err:#}");
+      }
+    }
+  }
+
+  /// Remove the identity with the given public key, as its lifetime
+  /// elapsed.
+  fn expire_identity(&self, pubkey: &PublicKey) -> Result<()> {
+    let identity = self
+      .store_identities()?
+      .into_iter()
+      .filter_map(Result::ok)
+      .find(|identity| identity.pubkey.as_ref() == Some(pubkey) && !self.is_removed(identity));
+
+    // The identity may have been removed by a client already.
+    if let Some(identity) = identity {
+      info!("Lifetime of identity {:?} elapsed", identity.comment);
+      let () = self.remove_key(identity)?;
+    }
+    Ok(())
+  }
+
+  /// Apply the label configured for the key of the given identity, if
+  /// any.
+  fn label(&self, mut identity: StoreIdentity) -> StoreIdentity {
+    match self.config().key_label(identity.pubkey.as_ref(), &identity.blob) {
+      Ok(Some(label)) => identity.comment = label.to_string(),
+      Ok(None) => (),
+      Err(err) => warn!("Failed to look up label of key {:?}: {err:#}", identity.comment),
+    }
+    identity
+  }
+
+  /// Retrieve the identities of all stores, with configured labels
+  /// applied.
+  fn store_identities(&self) -> Result<Vec<Result<StoreIdentity>>> {
+    let identities = self
+      .stores
+      .identities()?
+      .into_iter()
+      .map(|result| {
+        result
+          .map(|identity| self.label(identity))
+          .map_err(KeyStoreError::into_inner)
+      })
+      .collect();
+    Ok(identities)
+  }
+
+  /// Check whether the given identity got removed by a client.
+  fn is_removed(&self, identity: &StoreIdentity) -> bool {
+    identity
+      .pubkey
+      .as_ref()
+      .map(|pubkey| self.removed.lock().unwrap().contains(pubkey))
+      .unwrap_or(false)
+  }
+
+  /// Retrieve the public keys of the key files in the agent's
+  /// directories (and the password store), along with their comments
+  /// (or configured labels).
+  ///
+  /// Keys are reported in the order of the directories they are
+  /// contained in. A key present in multiple directories is only
+  /// reported for the first one.
+  pub fn public_keys(&self) -> impl Iterator<Item = Result<(PublicKey, String, PathBuf)>> + '_ {
+    let config = self.config();
+    self
+      .file_stores()
+      .flat_map(|store| store.public_keys())
+      .filter(move |x| match x {
+        Ok((key, _, _)) => !self.removed.lock().unwrap().contains(key),
+        Err(_) => true,
+      })
+      .map(move |x| {
+        let (key, comment, path) = x?;
+        let comment = match config.key_label(Some(&key), &[])? {
+          Some(label) => label.to_string(),
+          None => comment,
+        };
+        Ok((key, comment, path))
+      })
+  }
+
+  /// Check whether the GPG encrypted private key stored in the given
+  /// file could currently be decrypted, without decrypting it.
+  ///
+  /// `None` is returned if that cannot be told.
+  pub fn can_decrypt(&self, gpg_path: &Path) -> Result<Option<bool>> {
+    self.files.can_decrypt(gpg_path)
+  }
+
+  /// Check that the private key stored in the given GPG encrypted file
+  /// belongs to the provided public key, by decrypting it.
+  pub fn verify_key_pair(&self, pubkey: &PublicKey, gpg_path: &Path) -> Result<()> {
+    self.files.verify_key_pair(pubkey, gpg_path)
+  }
+
+  /// Retrieve the GPG encrypted private keys lacking a public key file,
+  /// in the order of the directories they are contained in.
+  ///
+  /// Such keys are not offered to clients, because telling their
+  /// public keys requires decryption.
+  pub fn orphaned_private_keys(&self) -> Vec<Result<PathBuf>> {
+    self
+      .file_stores()
+      .flat_map(|store| store.orphaned_private_keys())
+      .collect()
+  }
+
+  /// Derive the public keys belonging to the private keys stored in
+  /// the given GPG encrypted file, by decrypting it.
+  pub fn derive_public_key(&self, gpg_path: &Path) -> Result<PemPublicKey> {
+    self.files.derive_public_key(gpg_path)
+  }
+
+  /// Check that the decryption backend is operational.
+  pub fn check_decryptor(&self) -> Result<()> {
+    self.files.check_decryptor()
+  }
+
+  /// Check whether the key of the given identity may be used via the
+  /// forwarding socket.
+  fn is_forwardable(&self, identity: &StoreIdentity) -> Result<bool> {
+    self
+      .config()
+      .forward_key(identity.pubkey.as_ref(), &identity.blob)
+  }
+
+  /// Check that the key of the given identity may be used by a client
+  /// whose connection is bound to the given SSH sessions.
+  ///
+  /// A key restricted to certain destinations may only be used if all
+  /// hosts along the path of the request are among them. Just like
+  /// with OpenSSH's agent, connections not bound to any session are
+  /// considered local and not restricted, unless they are made via
+  /// TCP.
+  ///
+  /// Returns whether the key is restricted to destinations and the
+  /// connection is bound to one of them, i.e., whether the key is meant
+  /// specifically for the destination.
+  fn check_destinations(&self, identity: &StoreIdentity, client: Client<'_>) -> Result<bool> {
+    let config = self.config();
+    let hosts = match config.key_destinations(identity.pubkey.as_ref(), &identity.blob)? {
+      Some(hosts) => hosts,
+      None => return Ok(false),
+    };
+    let bindings = client.bindings()?;
+    let last = match bindings.last() {
+      Some(last) => last,
+      None if client.remote => bail!(RequestError::PolicyDenied(
+        "destination of request via TCP connection is unknown".to_string()
+      )),
+      None => return Ok(false),
+    };
+
+    // A connection that got forwarded but not bound by the remote end
+    // has an unknown destination.
+    ensure!(
+      !last.forwarding,
+      RequestError::PolicyDenied(
+        "destination of request via forwarded connection is unknown".to_string()
+      )
+    );
+    let host_keys = host_keys(hosts, &config.destinations.known_hosts)
+      .with_context(|| "failed to look up host keys of permitted destinations")?;
+    for binding in &bindings {
+      if !host_keys.contains(&binding.host_key) {
+        let fingerprint = binding.host_fingerprint();
+        bail!(RequestError::PolicyDenied(format!(
+          "host with key {fingerprint} is not a permitted destination"
+        )))
+      }
+    }
+    Ok(true)
+  }
+
+  /// Retrieve the constraints the given identity got added with.
+  fn added_constraints(&self, identity: &StoreIdentity) -> IdentityConstraints {
+    identity
+      .pubkey
+      .as_ref()
+      .and_then(|pubkey| self.constraints.lock().unwrap().get(pubkey).cloned())
+      .unwrap_or_default()
+  }
+
+  /// Check that the destination constraints the given identity got
+  /// added with permit its usage by the provided client, for signing
+  /// the given request, if any.
+  ///
+  /// Just like with OpenSSH's agent, such an identity may only sign
+  /// user authentication requests for the session the client's
+  /// connection is bound to.
+  fn check_added_destinations(
+    &self,
+    identity: &StoreIdentity,
+    client: Client<'_>,
+    request: Option<&SignRequest>,
+  ) -> Result<()> {
+    let destinations = self.added_constraints(identity).destinations;
+    if destinations.is_empty() {
+      return Ok(())
+    }
+
+    let bindings = client.bindings()?;
+    ensure!(
+      !client.remote || !bindings.is_empty(),
+      RequestError::PolicyDenied("destination of request via TCP connection is unknown".to_string())
+    );
+    let user = match request {
+      Some(request) => {
+        let last = bindings
+          .last()
+          .ok_or_else(|| anyhow!("connection is not bound to a session"))?;
+        let auth = UserAuthRequest::parse(&request.data, &request.pubkey_blob)
+          .context("data to sign is not a user authentication request")?;
+        ensure!(
+          auth.session_id == last.session_id,
+          "user authentication request is for another session"
+        );
+        match auth.host_key {
+          Some(host_key) => ensure!(
+            host_key == last.host_key,
+            "user authentication request is for another host"
+          ),
+          None => ensure!(
+            bindings.len() == 1,
+            "user authentication request via forwarded connection lacks host key"
+          ),
+        }
+        Some(auth.user)
+      },
+      None => None,
+    };
+    check_destinations(&destinations, &bindings, user)
+  }
+
+  /// Find the rule selecting the keys to offer to a client whose
+  /// connection is bound to a session for authenticating to a host, if
+  /// any.
+  ///
+  /// Host names are resolved to host keys using the `known_hosts` files
+  /// configured for destination restrictions.
+  fn host_rule<'config>(
+    &self,
+    config: &'config Config,
+    client: Client<'_>,
+  ) -> Result<Option<&'config HostRule>> {
+    if config.hosts.is_empty() {
+      return Ok(None)
+    }
+
+    let bindings = client.bindings()?;
+    let host_key = match bindings.last() {
+      Some(last) if !last.forwarding => &last.host_key,
+      _ => return Ok(None),
+    };
+    for rule in &config.hosts {
+      let host_keys = host_keys(&rule.hosts, &config.destinations.known_hosts)
+        .with_context(|| format!("failed to look up host keys of hosts {:?}", rule.hosts))?;
+      if host_keys.contains(host_key) {
+        return Ok(Some(rule))
+      }
+    }
+    Ok(None)
+  }
+
+  /// Check whether the given host rule selects the key of the provided
+  /// identity.
+  fn is_selected(&self, rule: &HostRule, identity: &StoreIdentity) -> Result<bool> {
+    let found = self
+      .file_stores()
+      .find_map(|store| store.find_private_key(&identity.blob))
+      .transpose()?;
+    let name = match found {
+      Some((_, _, path)) => path
+        .file_stem()
+        .map(|name| name.to_string_lossy().into_owned()),
+      None => None,
+    };
+    rule.selects(name.as_deref(), identity.pubkey.as_ref(), &identity.blob)
+  }
+
+  /// Handle a request for all known identities by the given client.
+  ///
+  /// RSA keys shorter than the configured minimum are not offered and
+  /// neither are keys not permitted for the destination the client's
+  /// connection is bound to. Keys meant specifically for that
+  /// destination are offered first and, if configured, only up to the
+  /// maximum number of identities is offered, so that servers limiting
+  /// authentication attempts get to see the relevant keys. For hosts
+  /// with a configured rule, only the keys it selects are offered.
+  fn identities(&self, client: Client<'_>) -> Result<Vec<Identity>> {
+    let config = self.config();
+    let rule = self.host_rule(&config, client).unwrap_or_else(|err| {
+      warn!("Failed to determine keys to offer for host: {err:#}");
+      None
+    });
+    if let Some(rule) = rule {
+      debug!("Offering keys selected for hosts {:?}", rule.hosts);
+    }
+
+    let mut preferred = Vec::new();
+    let mut idents = Vec::new();
+    for result in self.store_identities()? {
+      // A single broken key must not keep the others from being offered.
+      let identity = match result {
+        Ok(identity) => identity,
+        Err(err) => {
+          warn!("Not offering key: {err:#}");
+          continue
+        },
+      };
+      if self.is_removed(&identity) {
+        continue
+      }
+      if client.forwarded {
+        match self.is_forwardable(&identity) {
+          Ok(true) => (),
+          Ok(false) => continue,
+          Err(err) => {
+            warn!("Not offering key {:?}: {err:#}", identity.comment);
+            continue
+          },
+        }
+      }
+      if let Some(pubkey) = &identity.pubkey {
+        if let Err(err) = check_key_size(&config, pubkey) {
+          warn!("Not offering key {:?}: {err:#}", identity.comment);
+          continue
+        }
+      }
+      if let Some(rule) = rule {
+        match self.is_selected(rule, &identity) {
+          Ok(true) => (),
+          Ok(false) => continue,
+          Err(err) => {
+            warn!("Not offering key {:?}: {err:#}", identity.comment);
+            continue
+          },
+        }
+      }
+      if let Err(err) = self.check_added_destinations(&identity, client, None) {
+        debug!("Not offering key {:?}: {err:#}", identity.comment);
+        continue
+      }
+      let specific = match self.check_destinations(&identity, client) {
+        Ok(specific) => specific,
+        Err(err) => {
+          debug!("Not offering key {:?}: {err:#}", identity.comment);
+          continue
+        },
+      };
+
+      let ident = Identity {
+        pubkey_blob: identity.blob,
+        comment: identity.comment,
+      };
+      if specific {
+        preferred.push(ident);
+      } else {
+        idents.push(ident);
+      }
+    }
+
+    let mut idents = preferred.into_iter().chain(idents).collect::<Vec<_>>();
+    if config.max_identities > 0 && idents.len() > config.max_identities {
+      info!(
+        "Offering only {} of {} identities",
+        config.max_identities,
+        idents.len()
+      );
+      let () = idents.truncate(config.max_identities);
+    }
+    Ok(idents)
+  }
+
+  /// Find the identities corresponding to the given public key or
+  /// certificate blob that are eligible for signing on behalf of the
+  /// given client, along with the stores offering them.
+  fn candidates(
+    &self,
+    blob: &[u8],
+    client: Client<'_>,
+  ) -> Result<Vec<(Arc<dyn KeyStore>, StoreIdentity)>> {
+    let mut candidates = self
+      .stores
+      .candidates(blob)?
+      .into_iter()
+      .map(|(store, identity)| (store, self.label(identity)))
+      .collect::<Vec<_>>();
+    let () = candidates.retain(|(_, identity)| !self.is_removed(identity));
+    if client.forwarded {
+      let () = candidates.retain(|(_, identity)| {
+        self.is_forwardable(identity).unwrap_or_else(|err| {
+          warn!("Failed to check whether key {:?} is forwardable: {err:#}", identity.comment);
+          false
+        })
+      });
+    }
+    ensure!(!candidates.is_empty(), RequestError::KeyNotFound);
+
+    let mut denied = None;
+    let () = candidates.retain(|(store, identity)| match store.check_access(identity, client.peer) {
+      Ok(()) => true,
+      Err(err) => {
+        let _ = denied.get_or_insert(
+          err.into_inner().context(RequestError::PolicyDenied(format!(
+            "access to key {:?} denied",
+            identity.comment
+          ))),
+        );
+        false
+      },
+    });
+
+    match denied {
+      Some(err) if candidates.is_empty() => Err(err),
+      _ => Ok(candidates),
+    }
+  }
+
+  /// Determine the signature flags to sign with the given key with,
+  /// narrowing down the requested ones to the configured RSA signature
+  /// algorithms.
+  ///
+  /// Of the configured SHA-2 based algorithms, the first one the client
+  /// requested is used. Legacy SHA-1 based `ssh-rsa` signatures are
+  /// only created if explicitly allowed.
+  fn rsa_flags(&self, pubkey: &PublicKey, flags: u32) -> Result<u32> {
+    if !matches!(pubkey, PublicKey::Rsa(..)) {
+      return Ok(flags)
+    }
+
+    let config = self.config();
+    let not_permitted = |algorithm| {
+      RequestError::PolicyDenied(format!(
+        "refusing to create {algorithm} signature; algorithm is not permitted by configuration"
+      ))
+    };
+
+    if flags & (RSA_SHA2_512 | RSA_SHA2_256) == 0 {
+      if !config.allow_sha1 {
+        bail!(RequestError::PolicyDenied(
+          "refusing to create SHA-1 based ssh-rsa signature; use --allow-sha1 to permit it"
+            .to_string()
+        ))
+      }
+      ensure!(
+        config.rsa.algorithms.contains(&RsaSignatureAlgorithm::SshRsa),
+        not_permitted(RsaSignatureAlgorithm::SshRsa)
+      );
+      return Ok(flags)
+    }
+
+    // The configured order takes precedence over the one implied by
+    // the client's flags.
+    let flag = config
+      .rsa
+      .algorithms
+      .iter()
+      .map(|algorithm| match algorithm {
+        RsaSignatureAlgorithm::RsaSha2_512 => RSA_SHA2_512,
+        RsaSignatureAlgorithm::RsaSha2_256 => RSA_SHA2_256,
+        RsaSignatureAlgorithm::SshRsa => 0,
+      })
+      .find(|flag| flags & flag != 0);
+
+    match flag {
+      Some(flag) => Ok(flags & !(RSA_SHA2_512 | RSA_SHA2_256) | flag),
+      None if flags & RSA_SHA2_512 != 0 => {
+        bail!(not_permitted(RsaSignatureAlgorithm::RsaSha2_512))
+      },
+      None => bail!(not_permitted(RsaSignatureAlgorithm::RsaSha2_256)),
+    }
+  }
+
+  /// Ask the user for confirmation before using the key of the given
+  /// identity, if the key is configured or was added to require that or
+  /// the request arrived via the forwarding socket.
+  fn confirm_usage(&self, identity: &StoreIdentity, forwarded: bool) -> Result<()> {
+    // Identities merely relayed from elsewhere are subject to the
+    // policies in place there.
+    let confirm = match &identity.pubkey {
+      Some(pubkey) => {
+        identity.confirm
+          || self.config().confirm_key(pubkey)?
+          || self.added_constraints(identity).confirm
+      },
+      None => false,
+    };
+
+    if forwarded || confirm {
+      let description = if forwarded {
+        format!("Allow use of SSH key {} by a remote host?", identity.comment)
+      } else {
+        format!("Allow use of SSH key {}?", identity.comment)
+      };
+      if !prompt::confirm(&description)? {
+        bail!(RequestError::PolicyDenied(format!(
+          "usage of key {:?} denied by user",
+          identity.comment
+        )))
+      }
+    }
+    Ok(())
+  }
+
+  /// Record a usage of the given key by the provided client, if known.
+  fn record_usage(&self, peer: Option<&Peer>, pubkey: &PublicKey, comment: &str) {
+    let client = peer
+      .map(|peer| match peer.executable() {
+        Some(exe) => format!("{} ({peer})", exe.display()),
+        None => peer.to_string(),
+      })
+      .unwrap_or_else(|| "<unknown>".to_string());
+    if let Err(err) = self.usage.record(pubkey, client) {
+      warn!("Failed to record usage of key {comment:?}: {err:#}");
+    }
+  }
+
+  /// Handle a sign request from the given client.
+  fn sign(&self, client: Client<'_>, request: &SignRequest) -> Result<SignatureBlob> {
+    let candidates = debug_span!("lookup")
+      .in_scope(|| self.candidates(&request.pubkey_blob, client))
+      .with_context(|| "failed to create signature")?;
+    // All candidates share the same public key.
+    let (_, identity) = &candidates[0];
+
+    // Identities merely relayed from elsewhere are subject to the
+    // policies in place there.
+    let mut flags = request.flags;
+    if let Some(pubkey) = &identity.pubkey {
+      let () = check_key_size(&self.config(), pubkey).map_err(|err| {
+        err.context(RequestError::PolicyDenied(format!(
+          "refusing to sign with key {:?}",
+          identity.comment
+        )))
+      })?;
+      flags = self.rsa_flags(pubkey, request.flags)?;
+    }
+    let _ = self
+      .check_destinations(identity, client)
+      .with_context(|| format!("refusing to sign with key {:?}", identity.comment))?;
+    let () = self
+      .check_added_destinations(identity, client, Some(request))
+      .map_err(|err| {
+        err.context(RequestError::PolicyDenied(format!(
+          "refusing to sign with key {:?}",
+          identity.comment
+        )))
+      })?;
+    let () = debug_span!("confirm")
+      .in_scope(|| self.confirm_usage(identity, client.forwarded))
+      .with_context(|| "failed to create signature")?;
+
+    let request = SignRequest {
+      flags,
+      ..request.clone()
+    };
+    let blob = sign_with(&candidates, &request, self.config().verify_signatures)?;
+    if let Some(pubkey) = &identity.pubkey {
+      let () = self.record_usage(client.peer, pubkey, &identity.comment);
+    }
+    Ok(blob)
+  }
+
+  /// Handle a request to add an identity, subject to the given
+  /// constraints.
+  ///
+  /// Depending on the configuration, the private key is either GPG
+  /// encrypted and stored in the agent's directory, alongside the
+  /// corresponding public key, or kept in memory only. The constraints
+  /// are only kept in memory.
+  fn add_identity(&self, identity: &AddIdentity, constraints: IdentityConstraints) -> Result<()> {
+    let pubkey = PublicKey::from(&identity.privkey);
+    let () = match self.config().on_add {
+      OnAdd::Store => self.files.add(identity)?,
+      OnAdd::Memory => self.memory.add(identity)?,
+    };
+    // An identity that got removed earlier may just be hidden.
+    let _ = self.removed.lock().unwrap().remove(&pubkey);
+
+    // Adding an identity again replaces its constraints.
+    let mut added = self.constraints.lock().unwrap();
+    let _constraints = if constraints.expiry.is_some()
+      || constraints.confirm
+      || !constraints.destinations.is_empty()
+    {
+      added.insert(pubkey, constraints)
+    } else {
+      added.remove(&pubkey)
+    };
+    Ok(())
+  }
+
+  /// Handle a request to add an identity subject to constraints.
+  fn add_constrained_identity(&self, request: &AddIdentityConstrained) -> Result<()> {
+    let comment = &request.identity.comment;
+    let mut constraints = IdentityConstraints::default();
+    for constraint in &request.constraints {
+      match Constraint::try_from(constraint).context("failed to add identity")? {
+        Constraint::Lifetime(lifetime) => {
+          info!("Identity {comment:?} expires in {}s", lifetime.as_secs());
+          constraints.expiry = Some(Instant::now() + lifetime);
+        },
+        Constraint::Confirm => {
+          info!("Identity {comment:?} requires confirmation of each usage");
+          constraints.confirm = true;
+        },
+        Constraint::Destinations(destinations) => {
+          info!(
+            "Identity {comment:?} is restricted to {} destination(s)",
+            destinations.len()
+          );
+          let () = constraints.destinations.extend(destinations);
+        },
+      }
+    }
+    self.add_identity(&request.identity, constraints)
+  }
+
+  /// Handle a request to remove an identity.
+  ///
+  /// The identity is removed from all stores offering it and hidden
+  /// from then on.
+  fn remove_identity(&self, request: &RemoveIdentity) -> Result<()> {
+    let identity = self
+      .stores
+      .identities()?
+      .into_iter()
+      .filter_map(Result::ok)
+      .find(|identity| identity.blob == request.pubkey_blob && !self.is_removed(identity))
+      .ok_or(RequestError::KeyNotFound)
+      .with_context(|| "failed to remove identity")?;
+    self.remove_key(identity)
+  }
+
+  /// Remove the given identity from all stores, hiding it from then
+  /// on.
+  fn remove_key(&self, identity: StoreIdentity) -> Result<()> {
+    let () = self.stores.remove(&identity)?;
+    if let Some(pubkey) = identity.pubkey {
+      let () = self.cache.remove(&pubkey);
+      let _ = self.constraints.lock().unwrap().remove(&pubkey);
+      let _ = self.removed.lock().unwrap().insert(pubkey);
+    }
+    Ok(())
+  }
+
+  /// Handle a request to remove all identities.
+  ///
+  /// Identities merely relayed from elsewhere are left alone. A single
+  /// broken key does not keep the others from being removed, but
+  /// failing to remove a key still fails the request.
+  fn remove_all_identities(&self) -> Result<()> {
+    let mut failed = 0;
+    for result in self.store_identities()? {
+      let identity = match result {
+        Ok(identity) => identity,
+        Err(err) => {
+          warn!("Not removing key: {err:#}");
+          continue
+        },
+      };
+      if identity.pubkey.is_some() && !self.is_removed(&identity) {
+        let comment = identity.comment.clone();
+        if let Err(err) = self.remove_key(identity) {
+          warn!("Failed to remove key {comment:?}: {err:#}");
+          failed += 1;
+        }
+      }
+    }
+    ensure!(failed == 0, "failed to remove {failed} key(s)");
+    Ok(())
+  }
+
+  /// Retrieve the agent's current status.
+  pub fn status(&self) -> Result<Status> {
+    let mut keys = Vec::<(PublicKey, String)>::new();
+    // Keys that fail to load are not usable and hence not reported.
+    for identity in self.store_identities()?.into_iter().filter_map(Result::ok) {
+      if self.is_removed(&identity) {
+        continue
+      }
+      if let Some(pubkey) = identity.pubkey {
+        if !keys.iter().any(|(key, _)| *key == pubkey) {
+          let () = keys.push((pubkey, identity.comment));
+        }
+      }
+    }
+
+    let keys = keys
+      .into_iter()
+      .map(|(pubkey, comment)| {
+        let status = KeyStatus {
+          fingerprint: Fingerprint::from_key(&pubkey, HashAlg::Sha256)?.to_string(),
+          comment,
+          ephemeral: self.memory.contains(&pubkey),
+          usage: self.usage.get(&pubkey)?,
+        };
+        Ok(status)
+      })
+      .collect::<Result<_>>()?;
+
+    let status = Status {
+      version: env!("CARGO_PKG_VERSION").to_string(),
+      pid: id(),
+      uptime: self.started.elapsed().as_secs(),
+      locked: self.lock.is_locked(),
+      keys,
+      cache: self.cache.stats(),
+      stores: self.stores.status(),
+      failures: self
+        .failures
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(kind, count)| FailureCount {
+          kind: *kind,
+          count: *count,
+        })
+        .collect(),
+    };
+    Ok(status)
+  }
+
+  /// Retrieve the usage statistics of the given key.
+  pub fn key_usage(&self, pubkey: &PublicKey) -> Result<KeyUsage> {
+    self.usage.get(pubkey)
+  }
+
+  /// Handle a batch of sign requests from the given client.
+  ///
+  /// Each request is handled just like a regular one and failing to
+  /// sign with one key does not affect the remaining requests.
+  fn sign_batch(&self, client: Client<'_>, batch: &BatchSignRequest) -> BatchSignResponse {
+    let outcomes = batch
+      .requests
+      .iter()
+      .enumerate()
+      .map(|(i, request)| match self.sign(client, request) {
+        Ok(signature) => SignOutcome::Signature(signature),
+        Err(err) => {
+          let kind = ErrorKind::of(&err);
+          *self.failures.lock().unwrap().entry(kind).or_default() += 1;
+          error!("Error handling sign request #{i} of batch ({kind}): {:?}", err);
+          SignOutcome::Failure(ErrorReport::new(&err))
+        },
+      })
+      .collect();
+
+    BatchSignResponse { outcomes }
+  }
+
+  /// Handle an agent protocol extension request by the given client.
+  fn extension(&self, client: Client<'_>, extension: &Extension) -> Result<Message> {
+    match extension.extension_type.as_str() {
+      RELOAD_EXTENSION => {
+        let () = self.reload_keys();
+        Ok(Message::Success)
+      },
+      SHUTDOWN_EXTENSION => {
+        // Only processes of the user running the agent get to shut it
+        // down, as they could just as well signal it.
+        // SAFETY: `geteuid` is always safe to call.
+        let uid = unsafe { geteuid() };
+        ensure!(
+          !client.forwarded && !client.remote && client.peer.is_some_and(|peer| peer.uid == uid),
+          RequestError::PolicyDenied(format!(
+            "shutdown request from {} not permitted",
+            client.describe()
+          ))
+        );
+        info!("Shutdown requested by {}", client.describe());
+        // Shutting down is left to whoever handles the signal, just as
+        // when an administrator terminates the agent.
+        let () = raise(SIGTERM).with_context(|| "failed to request shutdown")?;
+        Ok(Message::Success)
+      },
+      SESSION_BIND_EXTENSION => {
+        let session = client
+          .session
+          .ok_or_else(|| anyhow!("connection does not support session bindings"))?;
+        let binding = session
+          .bind(&extension.extension_contents.0)
+          .with_context(|| format!("failed to bind connection from {}", client.describe()))?;
+        if let Some(binding) = binding {
+          let purpose = if binding.forwarding {
+            "forwarded"
+          } else {
+            "authenticating"
+          };
+          info!(
+            target: CLIENTS,
+            "Connection from {} {purpose} to host {}",
+            client.describe(),
+            binding.host_fingerprint()
+          );
+        }
+        Ok(Message::Success)
+      },
+      QUERY_EXTENSION => self.status()?.to_response(),
+      PING_EXTENSION => Ok(Message::Success),
+      SIGN_BATCH_EXTENSION => {
+        let batch = BatchSignRequest::from_contents(&extension.extension_contents)?;
+        self.sign_batch(client, &batch).to_response()
+      },
+      other => {
+        let err = Err(anyhow!(RequestError::Unsupported(format!(
+          "extension {other:?} is not supported"
+        ))));
+        err.with_context(|| "failed to handle extension request")
+      },
+    }
+  }
+
+  /// Handle a message to the agent from the given client.
+  ///
+  /// If a request timeout is configured, operations still pending once
+  /// it elapsed (such as a decryption waiting for the user to enter a
+  /// passphrase) get cancelled and the request fails.
+  fn handle_message(&self, client: Client<'_>, request: Message) -> Result<Message> {
+    let timeout = self.config().request_timeout;
+    if timeout == 0 {
+      return self.handle_request(client, request)
+    }
+
+    let cancellation = Cancellation::new();
+    let _watchdog = cancellation.cancel_after(Duration::from_secs(timeout));
+    let result = cancellation.run(|| self.handle_request(client, request));
+    match result {
+      Err(err) if cancellation.is_cancelled() => {
+        Err(err).context(RequestError::TimedOut(format!(
+          "request timed out after {timeout}s"
+        )))
+      },
+      result => result,
+    }
+  }
+
+  /// Handle a request from the given client.
+  ///
+  /// Clients connected to the forwarding socket or via TCP may only
+  /// list and use keys, as well as bind their connection to SSH
+  /// sessions.
+  fn handle_request(&self, client: Client<'_>, request: Message) -> Result<Message> {
+    debug!(target: REQUESTS, "Request: {}", Redacted(&request));
+    let () = self.expire_identities();
+    let () = self.lock_if_idle();
+    // Status queries and pings may be issued periodically, e.g., by
+    // status bars or health checks, and must not keep the agent from
+    // locking itself.
+    let is_query = matches!(
+      &request,
+      Message::Extension(extension)
+        if extension.extension_type == QUERY_EXTENSION
+          || extension.extension_type == PING_EXTENSION
+    );
+    if !is_query {
+      *self.last_request.lock().unwrap() = Instant::now();
+    }
+
+    let response = match request {
+      _ if client.forwarded && !is_forwardable_request(&request) => {
+        let err = Err(anyhow!(RequestError::PolicyDenied(
+          "request not permitted via forwarding socket".to_string()
+        )));
+        err.with_context(|| "failed to handle agent request")
+      },
+      _ if client.remote && !is_forwardable_request(&request) => {
+        let err = Err(anyhow!(RequestError::PolicyDenied(
+          "request not permitted via TCP".to_string()
+        )));
+        err.with_context(|| "failed to handle agent request")
+      },
+      Message::Lock(passphrase) => {
+        let () = self.lock.lock(&passphrase)?;
+        let () = self.cache.clear();
+        let () = self.wipe_ephemeral();
+        Ok(Message::Success)
+      },
+      Message::Unlock(passphrase) => {
+        let () = self.lock.unlock(&passphrase)?;
+        Ok(Message::Success)
+      },
+      // The status can be queried regardless of the lock state, as it
+      // includes it, and a locked agent is still alive. Connections have
+      // to be bindable at all times, lest they count as local.
+      Message::Extension(extension)
+        if extension.extension_type == QUERY_EXTENSION
+          || extension.extension_type == PING_EXTENSION
+          || extension.extension_type == SESSION_BIND_EXTENSION =>
+      {
+        self.extension(client, &extension)
+      },
+      // A locked agent does not advertise any identities.
+      Message::RequestIdentities if self.lock.is_locked() => {
+        Ok(Message::IdentitiesAnswer(Vec::new()))
+      },
+      _ if self.lock.is_locked() => {
+        let err = Err(anyhow!(RequestError::Locked));
+        err.with_context(|| "failed to handle agent request")
+      },
+      Message::RequestIdentities => {
+        Ok(Message::IdentitiesAnswer(self.identities(client)?))
+      },
+      Message::SignRequest(request) => {
+        Ok(Message::SignResponse(self.sign(client, &request)?))
+      },
+      Message::AddIdentity(identity) => {
+        let () = self.add_identity(&identity, IdentityConstraints::default())?;
+        Ok(Message::Success)
+      },
+      Message::AddIdConstrained(request) => {
+        let () = self.add_constrained_identity(&request)?;
+        Ok(Message::Success)
+      },
+      Message::RemoveIdentity(request) => {
+        let () = self.remove_identity(&request)?;
+        Ok(Message::Success)
+      },
+      Message::RemoveAllIdentities => {
+        let () = self.remove_all_identities()?;
+        Ok(Message::Success)
+      },
+      Message::Extension(extension) => self.extension(client, &extension),
+      _ => {
+        let err = Err(anyhow!(RequestError::Unsupported(format!(
+          "received unsupported message: {}",
+          Redacted(&request)
+        ))));
+        err.with_context(|| "failed to handle agent request")
+      },
+    };
+    if let Ok(response) = &response {
+      debug!(target: REQUESTS, "Response: {}", Redacted(response));
+    }
+    response
+  }
+
+  /// Respond to a message to the agent from the given client.
+  ///
+  /// Failures are counted by their kind. Clients of the agent's own
+  /// extensions receive an [`ErrorReport`], while all others merely
+  /// learn that their request failed, as the protocol does not provide
+  /// for any details.
+  fn respond(&self, client: Client<'_>, message: Message) -> Message {
+    let _span = debug_span!("request", kind = %Redacted(&message)).entered();
+    let report = is_own_extension(&message);
+    self.handle_message(client, message).unwrap_or_else(|err| {
+      let kind = ErrorKind::of(&err);
+      *self.failures.lock().unwrap().entry(kind).or_default() += 1;
+      error!("Error handling message ({kind}): {:?}", err);
+
+      if report {
+        ErrorReport::new(&err)
+          .to_response()
+          .unwrap_or(Message::Failure)
+      } else {
+        Message::Failure
+      }
+    })
+  }
+}
+
+impl Agent for GpgKeyAgent {
+  type Error = ();
+
+  fn handle(&self, message: Message) -> StdResult<Message, ()> {
+    Ok(self.respond(Client::default(), message))
+  }
+}
+
+impl PeerAgent for GpgKeyAgent {
+  fn accept(&self, peer: &Peer) -> Result<()> {
+    // SAFETY: `geteuid` is always safe to call.
+    let uid = unsafe { geteuid() };
+    // Just like OpenSSH's agent, we always trust the super user.
+    ensure!(
+      !self.config().reject_other_users || peer.uid == uid || peer.uid == 0,
+      "rejecting connection from {peer}: process belongs to another user"
+    );
+    Ok(())
+  }
+
+  fn handle_peer(
+    &self,
+    peer: &Peer,
+    session: &Session,
+    message: Message,
+  ) -> StdResult<Message, ()> {
+    if is_sign_request(&message) {
+      let exe = peer
+        .executable()
+        .map(|exe| exe.display().to_string())
+        .unwrap_or_else(|| "<unknown>".to_string());
+      let path = describe_path(&session.bindings().unwrap_or_default());
+      info!(target: CLIENTS, "Sign request from {peer} ({exe}){path}");
+    }
+    let client = Client {
+      peer: Some(peer),
+      session: Some(session),
+      forwarded: false,
+      remote: false,
+    };
+    Ok(self.respond(client, message))
+  }
+}
+
+impl RemoteAgent for GpgKeyAgent {
+  fn handle_remote(&self, session: &Session, message: Message) -> StdResult<Message, ()> {
+    if is_sign_request(&message) {
+      let path = describe_path(&session.bindings().unwrap_or_default());
+      info!(target: CLIENTS, "Sign request via TCP{path}");
+    }
+    let client = Client {
+      peer: None,
+      session: Some(session),
+      forwarded: false,
+      remote: true,
+    };
+    Ok(self.respond(client, message))
+  }
+}
+
+
+/// A view of the agent for serving the socket intended for being
+/// forwarded to remote hosts.
+///
+/// Clients connected to it only get to see the keys configured for
+/// forwarding and every usage of them has to be confirmed by the user.
+/// Managing identities or locking the agent is not possible.
+#[derive(Debug)]
+pub struct ForwardedAgent(pub Arc<GpgKeyAgent>);
+
+impl Agent for ForwardedAgent {
+  type Error = ();
+
+  fn handle(&self, message: Message) -> StdResult<Message, ()> {
+    let client = Client {
+      forwarded: true,
+      ..Default::default()
+    };
+    Ok(self.0.respond(client, message))
+  }
+}
+
+impl PeerAgent for ForwardedAgent {
+  fn accept(&self, peer: &Peer) -> Result<()> {
+    self.0.accept(peer)
+  }
+
+  fn handle_peer(
+    &self,
+    peer: &Peer,
+    session: &Session,
+    message: Message,
+  ) -> StdResult<Message, ()> {
+    if is_sign_request(&message) {
+      let path = describe_path(&session.bindings().unwrap_or_default());
+      info!(target: CLIENTS, "Sign request via forwarding socket from {peer}{path}");
+    }
+    let client = Client {
+      peer: Some(peer),
+      session: Some(session),
+      forwarded: true,
+      remote: false,
+    };
+    Ok(self.0.respond(client, message))
+  }
+}
+
+
+/// The agent as served on one of its sockets, subject to the socket's
+/// policy.
+#[derive(Debug)]
+pub enum SocketAgent {
+  /// The agent granting full access.
+  Full(Arc<GpgKeyAgent>),
+  /// The agent granting access as via the forwarding socket.
+  Forwarding(ForwardedAgent),
+}
+
+impl SocketAgent {
+  /// Create the agent to serve on a socket with the given policy.
+  pub fn new(agent: Arc<GpgKeyAgent>, policy: SocketPolicy) -> Self {
+    match policy {
+      SocketPolicy::Full => Self::Full(agent),
+      SocketPolicy::Forwarding => Self::Forwarding(ForwardedAgent(agent)),
+    }
+  }
+}
+
+impl Agent for SocketAgent {
+  type Error = ();
+
+  fn handle(&self, message: Message) -> StdResult<Message, ()> {
+    match self {
+      Self::Full(agent) => agent.handle(message),
+      Self::Forwarding(agent) => agent.handle(message),
+    }
+  }
+}
+
+impl PeerAgent for SocketAgent {
+  fn accept(&self, peer: &Peer) -> Result<()> {
+    match self {
+      Self::Full(agent) => agent.accept(peer),
+      Self::Forwarding(agent) => agent.accept(peer),
+    }
+  }
+
+  fn handle_peer(
+    &self,
+    peer: &Peer,
+    session: &Session,
+    message: Message,
+  ) -> StdResult<Message, ()> {
+    match self {
+      Self::Full(agent) => agent.handle_peer(peer, session, message),
+      Self::Forwarding(agent) => agent.handle_peer(peer, session, message),
+    }
+  }
+}
+
@@ -0,0 +1,339 @@
+// args.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::path::PathBuf;
+
+use clap::ArgAction;
+use clap::Parser;
+use clap::Subcommand;
+
+use ssh_gpg_agent::HashAlg;
+
+
+/// An SSH agent transparently supporting GPG encrypted private SSH
+/// keys.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Args {
+  /// The path to the configuration file to use.
+  ///
+  /// Defaults to `~/.config/ssh-gpg-agent/config.toml`.
+  #[arg(long)]
+  pub config: Option<PathBuf>,
+  /// A directory in which to look for SSH key pairs.
+  ///
+  /// May be provided multiple times. Overrides the directories from the
+  /// configuration file, if any.
+  #[arg(short, long = "directory")]
+  pub directories: Vec<PathBuf>,
+  /// The path of the Unix domain socket to listen on.
+  #[arg(short = 'a', long, visible_alias = "bind")]
+  pub socket: Option<PathBuf>,
+  /// Additionally serve the agent on the given TCP address
+  /// (`HOST:PORT`). Clients have to authenticate with the token from
+  /// `--tcp-token-file`.
+  #[arg(long, value_name = "HOST:PORT")]
+  pub listen_tcp: Option<String>,
+  /// The file containing the token TCP clients authenticate with.
+  #[arg(long)]
+  pub tcp_token_file: Option<PathBuf>,
+  /// The socket of another SSH agent to forward requests for identities
+  /// we don't manage to.
+  #[arg(long)]
+  pub upstream: Option<PathBuf>,
+  /// Allow the creation of legacy SHA-1 based `ssh-rsa` signatures for
+  /// clients not supporting SHA-2 based RSA signature algorithms.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub allow_sha1: bool,
+  /// Print Bourne shell commands setting up the environment for using
+  /// the agent on startup.
+  #[arg(short = 's', long = "sh", action = ArgAction::SetTrue, conflicts_with = "csh")]
+  pub sh: bool,
+  /// Print C shell commands setting up the environment for using the
+  /// agent on startup.
+  #[arg(short = 'c', long = "csh", action = ArgAction::SetTrue)]
+  pub csh: bool,
+  /// Print shell commands setting up the environment for using the
+  /// agent on startup, for the shell inferred from `SHELL`.
+  #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["sh", "csh"])]
+  pub print_env: bool,
+  /// Fork into the background once the agent's socket is bound.
+  ///
+  /// Shell commands for using the agent are printed as with
+  /// `--print-env`, unless a shell flavor is selected explicitly.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub daemonize: bool,
+  /// Replace an ssh-gpg-agent already serving the socket, asking it to
+  /// shut down, instead of reusing it.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub replace: bool,
+  /// Write the process ID of the running agent to the given file.
+  #[arg(long)]
+  pub pid_file: Option<PathBuf>,
+  /// The log level or, more generally, an `env_logger` style filter
+  /// directive.
+  #[arg(short, long)]
+  pub log_level: Option<String>,
+  #[command(subcommand)]
+  pub command: Option<Command>,
+}
+
+
+/// A shell flavor for which to print environment setup commands.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shell {
+  /// A Bourne style shell.
+  Sh,
+  /// A C style shell.
+  Csh,
+}
+
+impl Shell {
+  /// Infer the shell flavor from the given shell path, as reported by
+  /// the `SHELL` environment variable.
+  pub fn infer(shell: Option<&str>) -> Self {
+    match shell {
+      Some(shell) if shell.ends_with("csh") => Self::Csh,
+      _ => Self::Sh,
+    }
+  }
+}
+
+
+impl Args {
+  /// Retrieve the shell flavor for which to print environment setup
+  /// commands, if any.
+  pub fn shell(&self, shell_var: Option<&str>) -> Option<Shell> {
+    if self.sh {
+      Some(Shell::Sh)
+    } else if self.csh {
+      Some(Shell::Csh)
+    } else if self.print_env {
+      Some(Shell::infer(shell_var))
+    } else {
+      None
+    }
+  }
+}
+
+
+/// The sub-commands the program supports.
+#[derive(Debug, Default, Subcommand)]
+pub enum Command {
+  /// Run the agent (the default).
+  #[default]
+  Run,
+  /// List the keys managed by the agent, along with their
+  /// fingerprints and whether they can currently be decrypted.
+  #[command(visible_alias = "list-keys")]
+  List {
+    /// The hash algorithm to use for fingerprints (sha256 or md5).
+    #[arg(short = 'E', long, default_value = "sha256")]
+    fingerprint_hash: HashAlg,
+  },
+  /// Diagnose problems with the agent's setup.
+  ///
+  /// Checks that the agent's socket can be bound, that the decryption
+  /// backend is operational, and that all managed keys can be loaded
+  /// and decrypted.
+  Check {
+    /// Actually decrypt all keys (possibly prompting for passphrases),
+    /// checking that they belong to their public keys.
+    #[arg(long, action = ArgAction::SetTrue)]
+    decrypt: bool,
+  },
+  /// Report the status of the agent running on the configured socket.
+  ///
+  /// Prints the agent's uptime, its lock state, the keys it manages
+  /// along with when they were last used, and cache statistics.
+  Status,
+  /// Check that the agent running on the configured socket is alive
+  /// and responsive.
+  ///
+  /// Exits with an error if the agent does not respond in time, making
+  /// it suitable for health checks.
+  Ping {
+    /// The number of seconds to wait for the agent to respond.
+    #[arg(short, long, default_value = "5")]
+    timeout: u64,
+  },
+  /// Terminate the agent whose process ID is given by `SSH_AGENT_PID`
+  /// or, absent that, the PID file, and print shell commands tearing
+  /// down its environment.
+  ///
+  /// Like `ssh-agent -k`, this is also available as `-k`.
+  #[command(short_flag = 'k')]
+  Kill,
+  /// GPG encrypt an existing plain text private key for use with the
+  /// agent.
+  ///
+  /// The encrypted key is stored alongside the original one, e.g., as
+  /// `id_ed25519.gpg` for `id_ed25519`. A missing public key file
+  /// (`id_ed25519.pub`) is derived from the private key.
+  Encrypt {
+    /// The plain text private key file to encrypt.
+    key: PathBuf,
+    /// The GnuPG identity to encrypt the key to.
+    ///
+    /// Defaults to the `recipient` from the configuration file.
+    #[arg(short, long)]
+    recipient: Option<String>,
+    /// Overwrite and remove the plain text private key file once the
+    /// encrypted one got stored.
+    #[arg(long, action = ArgAction::SetTrue)]
+    shred: bool,
+  },
+  /// Derive missing public key files from their GPG encrypted private
+  /// keys.
+  ///
+  /// Key pairs are only picked up by the agent if a public key file
+  /// (e.g., `id_ed25519.pub` for `id_ed25519.gpg`) exists. Deriving it
+  /// requires decrypting the private key, for which confirmation is
+  /// asked for first.
+  Derive {
+    /// Don't ask for confirmation before decrypting a private key.
+    #[arg(short, long, action = ArgAction::SetTrue)]
+    yes: bool,
+    /// Print the public keys instead of storing them alongside the
+    /// private keys.
+    #[arg(long, action = ArgAction::SetTrue)]
+    print: bool,
+  },
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use clap::CommandFactory as _;
+
+
+  /// Verify the consistency of our argument definitions.
+  #[test]
+  fn verify_args() {
+    let () = Args::command().debug_assert();
+  }
+
+  /// Check that we can parse a command line with options and a
+  /// sub-command.
+  #[test]
+  fn parse_args() {
+    let args =
+      Args::try_parse_from(["ssh-gpg-agent", "-d", "/tmp", "-d", "/var", "list"]).unwrap();
+    assert_eq!(
+      args.directories,
+      vec![PathBuf::from("/tmp"), PathBuf::from("/var")]
+    );
+    assert!(matches!(
+      args.command,
+      Some(Command::List {
+        fingerprint_hash: HashAlg::Sha256
+      })
+    ));
+
+    let args = Args::try_parse_from(["ssh-gpg-agent", "list-keys", "-E", "md5"]).unwrap();
+    assert!(matches!(
+      args.command,
+      Some(Command::List {
+        fingerprint_hash: HashAlg::Md5
+      })
+    ));
+  }
+
+  /// Check that we can parse the arguments of the `encrypt` command.
+  #[test]
+  fn parse_encrypt_args() {
+    let args =
+      Args::try_parse_from(["ssh-gpg-agent", "encrypt", "-r", "me", "--shred", "id_rsa"]).unwrap();
+    match args.command {
+      Some(Command::Encrypt {
+        key,
+        recipient,
+        shred,
+      }) => {
+        assert_eq!(key, PathBuf::from("id_rsa"));
+        assert_eq!(recipient.as_deref(), Some("me"));
+        assert!(shred);
+      },
+      _ => panic!("unexpected command: {:?}", args.command),
+    }
+  }
+
+  /// Check that we can parse the `status` command along with a socket.
+  #[test]
+  fn parse_status_args() {
+    let args = Args::try_parse_from(["ssh-gpg-agent", "-a", "/tmp/sock", "status"]).unwrap();
+    assert_eq!(args.socket, Some(PathBuf::from("/tmp/sock")));
+    assert!(matches!(args.command, Some(Command::Status)));
+  }
+
+  /// Check that we can parse the `ping` command, with and without a
+  /// timeout.
+  #[test]
+  fn parse_ping_args() {
+    let args = Args::try_parse_from(["ssh-gpg-agent", "ping"]).unwrap();
+    assert!(matches!(args.command, Some(Command::Ping { timeout: 5 })));
+
+    let args = Args::try_parse_from(["ssh-gpg-agent", "ping", "--timeout", "1"]).unwrap();
+    assert!(matches!(args.command, Some(Command::Ping { timeout: 1 })));
+  }
+
+  /// Check that we can parse the `kill` command, also in its `ssh-agent`
+  /// compatible form.
+  #[test]
+  fn parse_kill_args() {
+    let args = Args::try_parse_from(["ssh-gpg-agent", "kill"]).unwrap();
+    assert!(matches!(args.command, Some(Command::Kill)));
+
+    let args = Args::try_parse_from(["ssh-gpg-agent", "-c", "-k"]).unwrap();
+    assert!(matches!(args.command, Some(Command::Kill)));
+    assert!(args.csh);
+  }
+
+  /// Check that we can parse the options for running as a daemon.
+  #[test]
+  fn parse_daemon_args() {
+    let args = Args::try_parse_from(["ssh-gpg-agent", "--daemonize", "--pid-file", "/tmp/pid"])
+      .unwrap();
+    assert!(args.daemonize);
+    assert_eq!(args.pid_file, Some(PathBuf::from("/tmp/pid")));
+
+    let args = Args::try_parse_from(["ssh-gpg-agent"]).unwrap();
+    assert!(!args.daemonize);
+    assert_eq!(args.pid_file, None);
+  }
+
+  /// Check that we infer the correct shell flavor.
+  #[test]
+  fn shell_inference() {
+    let args = Args::try_parse_from(["ssh-gpg-agent", "--print-env"]).unwrap();
+    assert_eq!(args.shell(Some("/bin/tcsh")), Some(Shell::Csh));
+    assert_eq!(args.shell(Some("/bin/bash")), Some(Shell::Sh));
+    assert_eq!(args.shell(None), Some(Shell::Sh));
+
+    let args = Args::try_parse_from(["ssh-gpg-agent", "-c"]).unwrap();
+    assert_eq!(args.shell(Some("/bin/bash")), Some(Shell::Csh));
+
+    let args = Args::try_parse_from(["ssh-gpg-agent"]).unwrap();
+    assert_eq!(args.shell(Some("/bin/bash")), None);
+
+    assert!(Args::try_parse_from(["ssh-gpg-agent", "-s", "-c"]).is_err());
+  }
+}
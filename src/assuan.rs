@@ -0,0 +1,377 @@
+// assuan.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Support for the Assuan protocol, as spoken by pinentry and
+//! `gpg-agent`.
+
+use std::error::Error as StdError;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::io::BufRead as _;
+use std::io::BufReader;
+use std::io::Write as _;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use crate::cancel::on_cancel;
+use crate::cancel::Registration;
+
+
+/// The maximum length of a line sent to an Assuan server, excluding
+/// the line terminator.
+const MAX_LINE_LEN: usize = 1000;
+
+
+/// Escape a string for usage as an Assuan command parameter.
+pub fn escape(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '%' => escaped.push_str("%25"),
+      '\n' => escaped.push_str("%0A"),
+      '\r' => escaped.push_str("%0D"),
+      _ => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+
+/// Undo the escaping of an Assuan data line.
+pub fn unescape(bytes: &[u8]) -> Vec<u8> {
+  let mut unescaped = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let hex = bytes.get(i + 1..i + 3).and_then(|hex| {
+      let hex = std::str::from_utf8(hex).ok()?;
+      u8::from_str_radix(hex, 16).ok()
+    });
+    match (bytes[i], hex) {
+      (b'%', Some(byte)) => {
+        unescaped.push(byte);
+        i += 3;
+      },
+      (byte, _) => {
+        unescaped.push(byte);
+        i += 1;
+      },
+    }
+  }
+  unescaped
+}
+
+
+/// Escape binary data for transmission in Assuan data lines, splitting
+/// it up into lines not exceeding the maximum line length.
+fn data_lines(data: &[u8]) -> Vec<Vec<u8>> {
+  let mut lines = Vec::new();
+  let mut line = b"D ".to_vec();
+  for byte in data {
+    // Make sure that an escaped byte always fits.
+    if line.len() + 3 > MAX_LINE_LEN {
+      let () = lines.push(line);
+      line = b"D ".to_vec();
+    }
+    match byte {
+      b'%' | b'\r' | b'\n' => line.extend_from_slice(format!("%{byte:02X}").as_bytes()),
+      _ => line.push(*byte),
+    }
+  }
+  if line.len() > 2 {
+    let () = lines.push(line);
+  }
+  lines
+}
+
+
+/// An error reported by an Assuan server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Error {
+  /// The (GnuPG) error code.
+  pub code: u32,
+  /// The description of the error.
+  pub description: String,
+}
+
+impl Error {
+  /// The error code, without the error source GnuPG encodes in the
+  /// upper bits.
+  pub fn code(&self) -> u32 {
+    self.code & 0xffff
+  }
+}
+
+impl Display for Error {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    write!(f, "{} (error code {})", self.description, self.code())
+  }
+}
+
+impl StdError for Error {}
+
+
+/// The response to an Assuan command.
+#[derive(Debug, Default)]
+pub struct Response {
+  /// The data sent along with the response.
+  pub data: Vec<u8>,
+  /// The status lines sent along with the response, without the
+  /// leading `S`.
+  pub status: Vec<String>,
+}
+
+
+/// A client connected to an Assuan server.
+#[derive(Debug)]
+pub struct Client {
+  /// The connection to the server.
+  stream: BufReader<UnixStream>,
+  /// The registration shutting down the connection when the request on
+  /// whose behalf it is used gets cancelled.
+  _cancel: Registration,
+}
+
+impl Client {
+  /// Connect to the Assuan server listening on the given socket.
+  pub fn connect(socket: &Path) -> Result<Self> {
+    let stream = UnixStream::connect(socket)
+      .with_context(|| format!("failed to connect to {}", socket.display()))?;
+    Self::new(stream)
+  }
+
+  /// Create a client using the given connection, waiting for the
+  /// server's greeting.
+  pub fn new(stream: UnixStream) -> Result<Self> {
+    let shutdown = stream
+      .try_clone()
+      .with_context(|| "failed to duplicate Assuan connection")?;
+    let cancel = on_cancel(move || {
+      let _ = shutdown.shutdown(Shutdown::Both);
+    });
+    let mut client = Self {
+      stream: BufReader::new(stream),
+      _cancel: cancel,
+    };
+
+    let _response = client
+      .read_response(&mut |_, _| Ok(None))
+      .with_context(|| "Assuan server sent unexpected greeting")?;
+    Ok(client)
+  }
+
+  /// Write a line to the server.
+  fn write_line(&mut self, line: &[u8]) -> Result<()> {
+    let stream = self.stream.get_mut();
+    stream
+      .write_all(line)
+      .and_then(|()| stream.write_all(b"\n"))
+      .with_context(|| "failed to write to Assuan server")
+  }
+
+  /// Read the response to a command, answering inquiries of the server
+  /// using the provided function.
+  fn read_response<F>(&mut self, inquire: &mut F) -> Result<Response>
+  where
+    F: FnMut(&str, &str) -> Result<Option<Vec<u8>>>,
+  {
+    let mut response = Response::default();
+    let mut line = Vec::new();
+    loop {
+      line.clear();
+      let count = self
+        .stream
+        .read_until(b'\n', &mut line)
+        .with_context(|| "failed to read from Assuan server")?;
+      if count == 0 {
+        bail!("Assuan server closed connection")
+      }
+      if line.ends_with(b"\n") {
+        let _ = line.pop();
+      }
+
+      if let Some(data) = line.strip_prefix(b"D ") {
+        response.data.extend(unescape(data));
+        continue
+      }
+
+      let line = String::from_utf8_lossy(&line);
+      let (keyword, params) = line.split_once(' ').unwrap_or((&line, ""));
+      match keyword {
+        "OK" => return Ok(response),
+        "ERR" => {
+          let (code, description) = params.split_once(' ').unwrap_or((params, ""));
+          let error = Error {
+            code: code.parse().unwrap_or_default(),
+            description: description.to_string(),
+          };
+          return Err(error.into())
+        },
+        "S" => response.status.push(params.to_string()),
+        "INQUIRE" => {
+          let (keyword, params) = params.split_once(' ').unwrap_or((params, ""));
+          match inquire(keyword, params)? {
+            Some(data) => {
+              for line in data_lines(&data) {
+                let () = self.write_line(&line)?;
+              }
+              let () = self.write_line(b"END")?;
+            },
+            None => {
+              let () = self.write_line(b"CAN")?;
+            },
+          }
+        },
+        // Comments and anything we don't know about.
+        _ => (),
+      }
+    }
+  }
+
+  /// Send a command to the server, returning its response.
+  pub fn transact(&mut self, command: &str) -> Result<Response> {
+    self.transact_inquire(command, |_, _| Ok(None))
+  }
+
+  /// Send a command to the server, returning its response and
+  /// answering inquiries of the server by means of the given function.
+  ///
+  /// The function receives the keyword and parameters of the inquiry
+  /// and returns the data to send or `None` to cancel it.
+  pub fn transact_inquire<F>(&mut self, command: &str, mut inquire: F) -> Result<Response>
+  where
+    F: FnMut(&str, &str) -> Result<Option<Vec<u8>>>,
+  {
+    if command.len() > MAX_LINE_LEN {
+      return Err(anyhow!("Assuan command exceeds maximum line length"))
+    }
+    let () = self.write_line(command.as_bytes())?;
+    let name = command.split(' ').next().unwrap_or_default();
+    self
+      .read_response(&mut inquire)
+      .with_context(|| format!("Assuan command {name} failed"))
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::io::Read as _;
+  use std::thread::spawn;
+
+
+  /// Check that we escape Assuan parameters correctly.
+  #[test]
+  fn escaping() {
+    assert_eq!(escape("foo"), "foo");
+    assert_eq!(escape("100%\nsure"), "100%25%0Asure");
+  }
+
+  /// Check that we unescape Assuan data correctly.
+  #[test]
+  fn unescaping() {
+    assert_eq!(unescape(b"foo"), b"foo");
+    assert_eq!(unescape(b"100%25%0Asure"), b"100%\nsure");
+    assert_eq!(unescape(b"50%"), b"50%");
+    assert_eq!(unescape(b"%zz"), b"%zz");
+  }
+
+  /// Check that we split up data into lines of bounded length.
+  #[test]
+  fn data_line_splitting() {
+    assert!(data_lines(b"").is_empty());
+    assert_eq!(data_lines(b"a%b\n"), vec![b"D a%25b%0A".to_vec()]);
+
+    let data = vec![b'%'; 1000];
+    let lines = data_lines(&data);
+    assert!(lines.iter().all(|line| line.len() <= MAX_LINE_LEN));
+    let unescaped = lines
+      .iter()
+      .flat_map(|line| unescape(&line[2..]))
+      .collect::<Vec<_>>();
+    assert_eq!(unescaped, data);
+  }
+
+  /// Check that we can talk to an Assuan server.
+  #[test]
+  fn transaction() -> Result<()> {
+    let (client, mut server) = UnixStream::pair()?;
+    let handle = spawn(move || {
+      let () = server.write_all(b"OK Pleased to meet you\n").unwrap();
+      let mut received = Vec::new();
+      let mut reader = BufReader::new(server.try_clone().unwrap());
+      let mut line = String::new();
+
+      let _ = reader.read_line(&mut line).unwrap();
+      received.push(line.clone());
+      let () = server
+        .write_all(b"# comment\nS STATUS one\nD a%25b\nD c\nOK\n")
+        .unwrap();
+
+      line.clear();
+      let _ = reader.read_line(&mut line).unwrap();
+      received.push(line.clone());
+      let () = server.write_all(b"INQUIRE DATA\n").unwrap();
+      for _ in 0..2 {
+        line.clear();
+        let _ = reader.read_line(&mut line).unwrap();
+        received.push(line.clone());
+      }
+      let () = server.write_all(b"INQUIRE OTHER\n").unwrap();
+      line.clear();
+      let _ = reader.read_line(&mut line).unwrap();
+      received.push(line.clone());
+      let () = server.write_all(b"ERR 83886179 Operation cancelled\n").unwrap();
+
+      let mut rest = String::new();
+      let _ = reader.read_to_string(&mut rest).unwrap();
+      received
+    });
+
+    let mut client = Client::new(client)?;
+    let response = client.transact("FIRST arg")?;
+    assert_eq!(response.data, b"a%bc");
+    assert_eq!(response.status, vec!["STATUS one".to_string()]);
+
+    let err = client
+      .transact_inquire("SECOND", |keyword, _params| {
+        Ok((keyword == "DATA").then(|| b"x\ny".to_vec()))
+      })
+      .unwrap_err();
+    let err = err.downcast_ref::<Error>().unwrap();
+    assert_eq!(err.code(), 99);
+    assert_eq!(err.description, "Operation cancelled");
+    drop(client);
+
+    let received = handle.join().unwrap();
+    assert_eq!(
+      received,
+      vec!["FIRST arg\n", "SECOND\n", "D x%0Ay\n", "END\n", "CAN\n"]
+    );
+    Ok(())
+  }
+}
@@ -0,0 +1,224 @@
+// batch.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Signing multiple pieces of data in a single round trip, via the
+//! `sign-batch@ssh-gpg-agent` agent protocol extension.
+//!
+//! Clients signing many artifacts (e.g., in CI pipelines) can save the
+//! overhead of issuing one sign request after another by sending a
+//! [`BatchSignRequest`] instead. The agent handles each of the contained
+//! requests just like a regular sign request and reports the outcome of
+//! each individually, as part of a [`BatchSignResponse`].
+
+use std::os::unix::net::UnixStream;
+use std::result::Result as StdResult;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use ssh_agent_lib::proto::from_bytes;
+use ssh_agent_lib::proto::message::Extension;
+use ssh_agent_lib::proto::message::ExtensionContents;
+use ssh_agent_lib::proto::message::Message;
+use ssh_agent_lib::proto::message::SignRequest;
+use ssh_agent_lib::proto::message::SignatureBlob;
+use ssh_agent_lib::proto::to_bytes;
+
+use crate::error::ErrorReport;
+use crate::error::ProtocolError;
+use crate::transport::read_message;
+use crate::transport::write_message;
+
+
+/// The name of the agent protocol extension for signing multiple pieces
+/// of data at once.
+///
+/// The request contains the serialized [`BatchSignRequest`] and the
+/// agent responds with an extension message of the same name,
+/// containing the serialized [`BatchSignResponse`].
+pub const SIGN_BATCH_EXTENSION: &str = "sign-batch@ssh-gpg-agent";
+
+
+/// A request for signing multiple pieces of data, each possibly with a
+/// different key.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BatchSignRequest {
+  /// The individual sign requests, in the order in which to handle them.
+  pub requests: Vec<SignRequest>,
+}
+
+impl BatchSignRequest {
+  /// Convert the batch into a request message.
+  pub fn to_request(&self) -> Result<Message> {
+    let contents = to_bytes(self).with_context(|| "failed to encode batch sign request")?;
+    let request = Message::Extension(Extension {
+      extension_type: SIGN_BATCH_EXTENSION.to_string(),
+      extension_contents: ExtensionContents(contents),
+    });
+    Ok(request)
+  }
+
+  /// Extract the batch from the contents of a batch sign request.
+  pub fn from_contents(contents: &ExtensionContents) -> Result<Self> {
+    from_bytes(&contents.0).with_context(|| "failed to decode batch sign request")
+  }
+}
+
+
+/// The outcome of one of the requests of a batch.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum SignOutcome {
+  /// The signature got created.
+  Signature(SignatureBlob),
+  /// Creating the signature failed, as reported.
+  Failure(ErrorReport),
+}
+
+impl From<SignOutcome> for StdResult<SignatureBlob, ErrorReport> {
+  fn from(outcome: SignOutcome) -> Self {
+    match outcome {
+      SignOutcome::Signature(signature) => Ok(signature),
+      SignOutcome::Failure(report) => Err(report),
+    }
+  }
+}
+
+
+/// The response to a [`BatchSignRequest`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BatchSignResponse {
+  /// The outcomes of the individual sign requests, in the order of the
+  /// requests.
+  pub outcomes: Vec<SignOutcome>,
+}
+
+impl BatchSignResponse {
+  /// Convert the outcomes into the response to a batch sign request.
+  pub fn to_response(&self) -> Result<Message> {
+    let contents = to_bytes(self).with_context(|| "failed to encode batch sign response")?;
+    let response = Message::Extension(Extension {
+      extension_type: SIGN_BATCH_EXTENSION.to_string(),
+      extension_contents: ExtensionContents(contents),
+    });
+    Ok(response)
+  }
+
+  /// Extract the outcomes from an agent's response to a batch sign
+  /// request.
+  pub fn from_response(response: &Message) -> Result<Self> {
+    if let Some(report) = ErrorReport::from_response(response) {
+      return Err(report).context("agent failed to handle batch sign request")
+    }
+
+    match response {
+      Message::Extension(extension) if extension.extension_type == SIGN_BATCH_EXTENSION => {
+        from_bytes(&extension.extension_contents.0)
+          .with_context(|| "failed to decode batch sign response")
+      },
+      Message::Failure | Message::ExtensionFailure => {
+        bail!("agent does not support {SIGN_BATCH_EXTENSION} extension")
+      },
+      _ => bail!("agent sent unexpected response to batch sign request"),
+    }
+  }
+}
+
+
+/// Have the agent at the other end of the given connection handle the
+/// provided sign requests in a single round trip.
+///
+/// The outcome of each request is reported individually, in the order
+/// of the requests.
+pub fn sign_batch(
+  stream: &mut UnixStream,
+  requests: Vec<SignRequest>,
+) -> StdResult<Vec<StdResult<SignatureBlob, ErrorReport>>, ProtocolError> {
+  let count = requests.len();
+  let request = BatchSignRequest { requests }.to_request()?;
+  let () = write_message(stream, &request)?;
+  let response = read_message(stream)?.ok_or_else(|| anyhow!("agent closed connection"))?;
+  let response = BatchSignResponse::from_response(&response)?;
+  if response.outcomes.len() != count {
+    return Err(
+      anyhow!(
+        "agent reported {} outcome(s) for {count} sign request(s)",
+        response.outcomes.len()
+      )
+      .into(),
+    )
+  }
+  Ok(response.outcomes.into_iter().map(StdResult::from).collect())
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use crate::error::ErrorKind;
+
+
+  /// Check that batch sign requests and responses survive a round trip
+  /// through the agent protocol.
+  #[test]
+  fn batch_round_trip() -> Result<()> {
+    let batch = BatchSignRequest {
+      requests: vec![
+        SignRequest {
+          pubkey_blob: vec![1, 2, 3],
+          data: b"first".to_vec(),
+          flags: 0,
+        },
+        SignRequest {
+          pubkey_blob: vec![4, 5],
+          data: b"second".to_vec(),
+          flags: 4,
+        },
+      ],
+    };
+    let request = batch.to_request()?;
+    let contents = match &request {
+      Message::Extension(extension) => {
+        assert_eq!(extension.extension_type, SIGN_BATCH_EXTENSION);
+        &extension.extension_contents
+      },
+      _ => panic!("unexpected request: {request:?}"),
+    };
+    assert_eq!(BatchSignRequest::from_contents(contents)?, batch);
+
+    let response = BatchSignResponse {
+      outcomes: vec![
+        SignOutcome::Signature(vec![6, 7, 8]),
+        SignOutcome::Failure(ErrorReport {
+          kind: ErrorKind::KeyNotFound,
+          message: "identity not found".to_string(),
+        }),
+      ],
+    };
+    let decoded = BatchSignResponse::from_response(&response.to_response()?)?;
+    assert_eq!(decoded, response);
+    assert!(BatchSignResponse::from_response(&Message::Failure).is_err());
+    Ok(())
+  }
+}
@@ -0,0 +1,375 @@
+// cache.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::result::Result as StdResult;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+
+use ring::signature::RsaKeyPair;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use ssh_agent_lib::proto::private_key::PrivateKey;
+use ssh_agent_lib::proto::private_key::RsaPrivateKey;
+use ssh_agent_lib::proto::public_key::PublicKey;
+use ssh_agent_lib::proto::signature::Signature;
+
+use crate::error::SignError;
+use crate::secret::wipe;
+use crate::sign::is_ring_rsa_modulus;
+use crate::sign::rsa_key_pair;
+use crate::sign::sign_key;
+use crate::sign::Signer;
+
+
+/// A decrypted private key that is wiped from memory once dropped.
+///
+/// The `ring` key pair of an RSA key is created on first use and kept
+/// alongside the key for as long as the latter is cached, sparing us
+/// the costly setup on each signature.
+#[derive(Debug)]
+pub struct CachedKey {
+  /// The private key.
+  key: PrivateKey,
+  /// The precomputed key pair of an RSA private key.
+  rsa: OnceLock<RsaKeyPair>,
+}
+
+impl CachedKey {
+  /// Retrieve the precomputed key pair of the given RSA private key,
+  /// creating it if necessary.
+  fn rsa_key_pair(&self, key: &RsaPrivateKey) -> Result<&RsaKeyPair> {
+    if let Some(key_pair) = self.rsa.get() {
+      return Ok(key_pair)
+    }
+    let key_pair = rsa_key_pair(key)?;
+    Ok(self.rsa.get_or_init(|| key_pair))
+  }
+}
+
+impl From<PrivateKey> for CachedKey {
+  fn from(key: PrivateKey) -> Self {
+    Self {
+      key,
+      rsa: OnceLock::new(),
+    }
+  }
+}
+
+impl Deref for CachedKey {
+  type Target = PrivateKey;
+
+  fn deref(&self) -> &Self::Target {
+    &self.key
+  }
+}
+
+impl Signer for CachedKey {
+  fn sign(&self, flags: u32, data: &[u8]) -> StdResult<Signature, SignError> {
+    let key_pair = match &self.key {
+      PrivateKey::Rsa(rsa) if is_ring_rsa_modulus(&rsa.n) => Some(self.rsa_key_pair(rsa)?),
+      _ => None,
+    };
+    let signature = sign_key(&self.key, key_pair, flags, data)?;
+    Ok(signature)
+  }
+}
+
+impl Drop for CachedKey {
+  fn drop(&mut self) {
+    wipe(&mut self.key)
+  }
+}
+
+
+/// An entry in the cache.
+#[derive(Debug)]
+struct Entry {
+  /// The time at which the entry was inserted.
+  inserted: Instant,
+  /// The cached key.
+  key: Arc<CachedKey>,
+}
+
+
+/// Statistics about the usage of a [`Cache`].
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CacheStats {
+  /// Whether the cache is enabled.
+  pub enabled: bool,
+  /// The number of keys currently cached, including expired ones not
+  /// yet purged.
+  pub entries: u32,
+  /// The number of lookups that found a key.
+  pub hits: u64,
+  /// The number of lookups that did not find a key.
+  pub misses: u64,
+}
+
+
+/// An in-memory cache of decrypted private keys.
+///
+/// Entries expire after a configurable time to live, at which point the
+/// key material is wiped. A cache with a time to live of zero is
+/// disabled and never stores anything.
+#[derive(Debug)]
+pub struct Cache {
+  /// The time after which entries expire.
+  ttl: Duration,
+  /// The maximum number of entries to keep.
+  max_entries: usize,
+  /// The cached keys, indexed by their public key.
+  entries: Mutex<HashMap<PublicKey, Entry>>,
+  /// The number of lookups that found a key.
+  hits: AtomicU64,
+  /// The number of lookups that did not find a key.
+  misses: AtomicU64,
+}
+
+impl Cache {
+  /// Create a new cache with the given time to live and maximum number
+  /// of entries.
+  pub fn new(ttl: Duration, max_entries: usize) -> Self {
+    Self {
+      ttl,
+      max_entries,
+      entries: Mutex::new(HashMap::new()),
+      hits: AtomicU64::new(0),
+      misses: AtomicU64::new(0),
+    }
+  }
+
+  /// Check whether the cache is enabled.
+  pub fn is_enabled(&self) -> bool {
+    !self.ttl.is_zero() && self.max_entries > 0
+  }
+
+  /// Retrieve the time to live of entries.
+  pub fn ttl(&self) -> Duration {
+    self.ttl
+  }
+
+  /// Look up the private key for the given public key.
+  pub fn get(&self, pubkey: &PublicKey) -> Option<Arc<CachedKey>> {
+    let key = self.lookup(pubkey);
+    if self.is_enabled() {
+      let counter = if key.is_some() {
+        &self.hits
+      } else {
+        &self.misses
+      };
+      let _ = counter.fetch_add(1, Ordering::Relaxed);
+    }
+    key
+  }
+
+  /// Look up the private key for the given public key, without
+  /// accounting for the lookup in the cache's statistics.
+  fn lookup(&self, pubkey: &PublicKey) -> Option<Arc<CachedKey>> {
+    let mut entries = self.entries.lock().unwrap();
+    match entries.get(pubkey) {
+      Some(entry) if entry.inserted.elapsed() < self.ttl => Some(entry.key.clone()),
+      Some(_) => {
+        let _ = entries.remove(pubkey);
+        None
+      },
+      None => None,
+    }
+  }
+
+  /// Insert a private key into the cache, evicting the oldest entry if
+  /// the cache is full.
+  pub fn insert(&self, pubkey: PublicKey, key: Arc<CachedKey>) {
+    if !self.is_enabled() {
+      return
+    }
+
+    let mut entries = self.entries.lock().unwrap();
+    if !entries.contains_key(&pubkey) && entries.len() >= self.max_entries {
+      let oldest = entries
+        .iter()
+        .min_by_key(|(_, entry)| entry.inserted)
+        .map(|(pubkey, _)| pubkey.clone());
+      if let Some(oldest) = oldest {
+        let _ = entries.remove(&oldest);
+      }
+    }
+
+    let entry = Entry {
+      inserted: Instant::now(),
+      key,
+    };
+    let _ = entries.insert(pubkey, entry);
+  }
+
+  /// Remove the entry for the given public key, if any.
+  pub fn remove(&self, pubkey: &PublicKey) {
+    let _ = self.entries.lock().unwrap().remove(pubkey);
+  }
+
+  /// Remove all expired entries.
+  pub fn purge(&self) {
+    let ttl = self.ttl;
+    let () = self
+      .entries
+      .lock()
+      .unwrap()
+      .retain(|_, entry| entry.inserted.elapsed() < ttl);
+  }
+
+  /// Remove all entries.
+  pub fn clear(&self) {
+    let () = self.entries.lock().unwrap().clear();
+  }
+
+  /// Retrieve statistics about the cache's usage.
+  pub fn stats(&self) -> CacheStats {
+    CacheStats {
+      enabled: self.is_enabled(),
+      entries: self.entries.lock().unwrap().len() as u32,
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::ptr;
+  use std::thread::sleep;
+
+  use ssh_agent_lib::proto::private_key::Ed25519PrivateKey;
+  use ssh_agent_lib::proto::signature::RSA_SHA2_256;
+
+  use crate::files::test::load_unencrypted_private_key;
+  use crate::keys::FromPem as _;
+  use crate::sign::verify;
+
+
+  /// Create a dummy Ed25519 key pair using the given byte for all key
+  /// material.
+  fn key_pair(byte: u8) -> (PublicKey, Arc<CachedKey>) {
+    let key = PrivateKey::Ed25519(Ed25519PrivateKey {
+      enc_a: vec![byte; 32],
+      k_enc_a: vec![byte; 32],
+    });
+    (PublicKey::from(&key), Arc::new(CachedKey::from(key)))
+  }
+
+
+  /// Check that entries expire.
+  #[test]
+  fn expiry() {
+    let cache = Cache::new(Duration::from_millis(10), 8);
+    let (pubkey, key) = key_pair(1);
+    let () = cache.insert(pubkey.clone(), key);
+    assert!(cache.get(&pubkey).is_some());
+
+    let () = sleep(Duration::from_millis(20));
+    assert!(cache.get(&pubkey).is_none());
+  }
+
+  /// Check that the oldest entry gets evicted once the cache is full.
+  #[test]
+  fn eviction() {
+    let cache = Cache::new(Duration::from_secs(60), 2);
+    let keys = (1..=3).map(key_pair).collect::<Vec<_>>();
+    for (pubkey, key) in &keys {
+      let () = cache.insert(pubkey.clone(), key.clone());
+    }
+
+    assert!(cache.get(&keys[0].0).is_none());
+    assert!(cache.get(&keys[1].0).is_some());
+    assert!(cache.get(&keys[2].0).is_some());
+  }
+
+  /// Check that we keep track of cache hits and misses.
+  #[test]
+  fn stats() {
+    let cache = Cache::new(Duration::from_secs(60), 8);
+    let (pubkey, key) = key_pair(1);
+    assert!(cache.get(&pubkey).is_none());
+    let () = cache.insert(pubkey.clone(), key);
+    assert!(cache.get(&pubkey).is_some());
+    assert!(cache.get(&pubkey).is_some());
+
+    let stats = cache.stats();
+    assert!(stats.enabled);
+    assert_eq!(stats.entries, 1);
+    assert_eq!(stats.hits, 2);
+    assert_eq!(stats.misses, 1);
+  }
+
+  /// Check that a disabled cache does not store anything.
+  #[test]
+  fn disabled() {
+    let cache = Cache::new(Duration::ZERO, 8);
+    let (pubkey, key) = key_pair(1);
+    let () = cache.insert(pubkey.clone(), key);
+    assert!(cache.get(&pubkey).is_none());
+  }
+
+  /// Check that we sign with the precomputed key pair of a cached RSA
+  /// key, producing the same signatures as without it.
+  #[test]
+  fn rsa_key_pair_reuse() -> Result<()> {
+    let privkey = load_unencrypted_private_key("tests/valid_keys/rsa2048")?;
+    let privkey = PrivateKey::from_pem(privkey)?;
+    let key = CachedKey::from(privkey.clone());
+    assert!(key.rsa.get().is_none());
+
+    let data = b"test-data";
+    let sig = key.sign(RSA_SHA2_256, data)?;
+    let key_pair = key.rsa.get().unwrap();
+    assert_eq!(sig, privkey.sign(RSA_SHA2_256, data)?);
+
+    // Legacy signatures are unaffected.
+    assert_eq!(key.sign(0, data)?, privkey.sign(0, data)?);
+    assert!(ptr::eq(key.rsa.get().unwrap(), key_pair));
+    Ok(())
+  }
+
+  /// Check that cached RSA keys too short for `ring` to handle can
+  /// still be used for signing.
+  #[test]
+  fn rsa_short_key() -> Result<()> {
+    let privkey = load_unencrypted_private_key("tests/valid_keys/rsa1024")?;
+    let privkey = PrivateKey::from_pem(privkey)?;
+    let key = CachedKey::from(privkey.clone());
+
+    let sig = key.sign(RSA_SHA2_256, b"test-data")?;
+    assert_eq!(sig.algorithm, "rsa-sha2-256");
+    assert!(key.rsa.get().is_none());
+    let () = verify(&PublicKey::from(&privkey), &sig, b"test-data")?;
+    Ok(())
+  }
+}
@@ -0,0 +1,365 @@
+// cancel.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Cancellation of long running operations, such as decryptions
+//! waiting for the user to enter a passphrase.
+//!
+//! A request is handled in the scope of a [`Cancellation`], which is
+//! made available to the code running on its behalf via a thread local.
+//! Blocking operations register a handler with [`on_cancel`] (or, for
+//! helper processes, [`kill_on_cancel`]) that makes them fail once the
+//! request gets cancelled.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::io::Error as IoError;
+use std::io::ErrorKind;
+use std::io::Read as _;
+use std::io::Result as IoResult;
+use std::mem::zeroed;
+use std::process::Child;
+use std::process::ExitStatus;
+use std::process::Output;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::spawn;
+use std::time::Duration;
+
+use libc::kill;
+use libc::pid_t;
+use libc::siginfo_t;
+use libc::waitid;
+use libc::P_PID;
+use libc::SIGTERM;
+use libc::WEXITED;
+use libc::WNOWAIT;
+
+
+/// A handler invoked upon cancellation.
+type Handler = Box<dyn Fn() + Send>;
+
+
+thread_local! {
+  /// The cancellation in whose scope the current thread runs, if any.
+  static CURRENT: RefCell<Option<Cancellation>> = const { RefCell::new(None) };
+}
+
+
+/// The state shared by all clones of a [`Cancellation`].
+#[derive(Default)]
+struct Inner {
+  /// Whether cancellation was requested.
+  cancelled: AtomicBool,
+  /// The handlers to invoke upon cancellation.
+  handlers: Mutex<HashMap<u64, Handler>>,
+  /// The ID to assign to the next handler.
+  next_id: AtomicU64,
+}
+
+
+/// A means for cancelling the operations performed on behalf of a
+/// request.
+#[derive(Clone, Default)]
+pub struct Cancellation(Arc<Inner>);
+
+impl Cancellation {
+  /// Create a new cancellation object.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Request cancellation, invoking all registered handlers.
+  pub fn cancel(&self) {
+    let () = self.0.cancelled.store(true, Ordering::SeqCst);
+    let handlers = self.0.handlers.lock().unwrap();
+    let () = handlers.values().for_each(|handler| handler());
+  }
+
+  /// Check whether cancellation was requested.
+  pub fn is_cancelled(&self) -> bool {
+    self.0.cancelled.load(Ordering::SeqCst)
+  }
+
+  /// Run the given function in the scope of this object, making it
+  /// subject to cancellation.
+  pub fn run<F, R>(&self, f: F) -> R
+  where
+    F: FnOnce() -> R,
+  {
+    let previous = CURRENT.with(|current| current.replace(Some(self.clone())));
+    let result = f();
+    let _ = CURRENT.with(|current| current.replace(previous));
+    result
+  }
+
+  /// Request cancellation once the given timeout elapsed, unless the
+  /// returned watchdog was dropped before.
+  pub fn cancel_after(&self, timeout: Duration) -> Watchdog {
+    let (sender, receiver) = channel::<()>();
+    let cancellation = self.clone();
+    let _handle = spawn(move || {
+      if let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(timeout) {
+        let () = cancellation.cancel();
+      }
+    });
+    Watchdog { _stop: sender }
+  }
+}
+
+impl Debug for Cancellation {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    f.debug_struct("Cancellation")
+      .field("cancelled", &self.is_cancelled())
+      .finish()
+  }
+}
+
+
+/// A guard requesting cancellation after a timeout, unless dropped
+/// before it elapsed.
+#[derive(Debug)]
+pub struct Watchdog {
+  /// The sender whose disconnection stops the watchdog.
+  _stop: Sender<()>,
+}
+
+
+/// A registered cancellation handler, which gets unregistered once the
+/// object is dropped.
+#[derive(Debug)]
+#[must_use = "the handler is unregistered once dropped"]
+pub struct Registration {
+  /// The cancellation the handler is registered with and its ID.
+  handler: Option<(Cancellation, u64)>,
+}
+
+impl Registration {
+  /// Wait for the given child process, whose termination upon
+  /// cancellation this object registered via [`kill_on_cancel`], to
+  /// exit.
+  ///
+  /// The handler is unregistered after the process exited, but before
+  /// it gets reaped. Afterwards its PID may get reused by an unrelated
+  /// process, which the handler must not send a signal to.
+  pub fn wait(self, child: &mut Child) -> IoResult<ExitStatus> {
+    let () = wait_exited(child)?;
+    drop(self);
+    child.wait()
+  }
+
+  /// Collect the output of the given child process and wait for it to
+  /// exit, as [`Child::wait_with_output`] does, while unregistering the
+  /// handler as [`Registration::wait`] does.
+  pub fn wait_with_output(self, mut child: Child) -> IoResult<Output> {
+    // Close the process' standard input, so that it does not wait for
+    // more.
+    drop(child.stdin.take());
+
+    // Read both streams concurrently, so that the process can never
+    // block on writing to one of them while we wait for the other.
+    let reader = child.stdout.take().map(|mut stdout| {
+      spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_count| output)
+      })
+    });
+    let mut stderr = Vec::new();
+    if let Some(mut err) = child.stderr.take() {
+      let _count = err.read_to_end(&mut stderr)?;
+    }
+    let stdout = match reader {
+      Some(reader) => reader
+        .join()
+        .map_err(|_| IoError::new(ErrorKind::Other, "failed to read standard output"))??,
+      None => Vec::new(),
+    };
+
+    let status = self.wait(&mut child)?;
+    Ok(Output {
+      status,
+      stdout,
+      stderr,
+    })
+  }
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    if let Some((cancellation, id)) = self.handler.take() {
+      let _ = cancellation.0.handlers.lock().unwrap().remove(&id);
+    }
+  }
+}
+
+
+/// Register a handler to invoke when the operations performed by the
+/// current thread get cancelled.
+///
+/// If cancellation was already requested, the handler is invoked right
+/// away. Outside of the scope of a [`Cancellation`] nothing happens.
+pub fn on_cancel<F>(handler: F) -> Registration
+where
+  F: Fn() + Send + 'static,
+{
+  let cancellation = CURRENT.with(|current| current.borrow().clone());
+  let handler = cancellation.and_then(|cancellation| {
+    let mut handlers = cancellation.0.handlers.lock().unwrap();
+    if cancellation.is_cancelled() {
+      drop(handlers);
+      let () = handler();
+      None
+    } else {
+      let id = cancellation.0.next_id.fetch_add(1, Ordering::Relaxed);
+      let _ = handlers.insert(id, Box::new(handler));
+      drop(handlers);
+      Some((cancellation, id))
+    }
+  });
+  Registration { handler }
+}
+
+
+/// Wait for the given child process to exit, without reaping it.
+fn wait_exited(child: &Child) -> IoResult<()> {
+  loop {
+    // SAFETY: `siginfo_t` is a plain C struct, for which all zeroes is
+    //         a valid value.
+    let mut info = unsafe { zeroed::<siginfo_t>() };
+    // SAFETY: `info` is a valid `siginfo_t` for `waitid` to fill in.
+    let rc = unsafe { waitid(P_PID, child.id(), &mut info, WEXITED | WNOWAIT) };
+    if rc == 0 {
+      return Ok(())
+    }
+
+    let err = IoError::last_os_error();
+    if err.kind() != ErrorKind::Interrupted {
+      return Err(err)
+    }
+  }
+}
+
+
+/// Terminate the given child process when the operations performed by
+/// the current thread get cancelled.
+///
+/// Once the process got reaped its PID may be reused, so the returned
+/// registration has to be dropped before, as [`Registration::wait`]
+/// and [`Registration::wait_with_output`] do.
+pub fn kill_on_cancel(child: &Child) -> Registration {
+  let pid = child.id() as pid_t;
+  on_cancel(move || {
+    // SAFETY: `kill` is always safe to call.
+    let _ = unsafe { kill(pid, SIGTERM) };
+  })
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::process::Command;
+  use std::process::Stdio;
+  use std::sync::atomic::AtomicUsize;
+  use std::time::Instant;
+
+
+  /// Check that handlers are invoked upon cancellation, but only while
+  /// registered.
+  #[test]
+  fn handlers() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let handler = |count: &Arc<AtomicUsize>| {
+      let count = count.clone();
+      move || {
+        let _ = count.fetch_add(1, Ordering::SeqCst);
+      }
+    };
+
+    // Outside of a cancellation scope nothing happens.
+    let _registration = on_cancel(handler(&count));
+
+    let cancellation = Cancellation::new();
+    let () = cancellation.run(|| {
+      let _registration = on_cancel(handler(&count));
+      let registration = on_cancel(handler(&count));
+      drop(registration);
+      let () = cancellation.cancel();
+    });
+    assert!(cancellation.is_cancelled());
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+
+    // Handlers registered after cancellation are invoked right away.
+    let () = cancellation.run(|| {
+      let _registration = on_cancel(handler(&count));
+    });
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+  }
+
+  /// Check that a watchdog kills a process once its timeout elapsed.
+  #[test]
+  fn watchdog() {
+    let cancellation = Cancellation::new();
+    let start = Instant::now();
+    let status = cancellation.run(|| {
+      let _watchdog = cancellation.cancel_after(Duration::from_millis(100));
+      let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+      kill_on_cancel(&child).wait(&mut child).unwrap()
+    });
+
+    assert!(!status.success());
+    assert!(cancellation.is_cancelled());
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // A dropped watchdog does not cancel anything.
+    let cancellation = Cancellation::new();
+    let watchdog = cancellation.cancel_after(Duration::from_millis(10));
+    drop(watchdog);
+    let () = std::thread::sleep(Duration::from_millis(50));
+    assert!(!cancellation.is_cancelled());
+  }
+
+  /// Check that the handler terminating a child process is unregistered
+  /// once the process exited.
+  #[test]
+  fn child_exit() {
+    let cancellation = Cancellation::new();
+    let output = cancellation.run(|| {
+      let child = Command::new("echo")
+        .arg("test")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+      kill_on_cancel(&child).wait_with_output(child).unwrap()
+    });
+
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"test\n");
+    assert!(cancellation.0.handlers.lock().unwrap().is_empty());
+  }
+}
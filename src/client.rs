@@ -0,0 +1,137 @@
+// client.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! A minimal client of the SSH agent protocol.
+//!
+//! The client covers the requests the agent itself cares about and is
+//! mostly meant for driving an agent in tests, without relying on
+//! `ssh-add` or similar being available.
+
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::result::Result as StdResult;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use anyhow::Error;
+
+use ssh_agent_lib::proto::from_bytes;
+use ssh_agent_lib::proto::message::Extension;
+use ssh_agent_lib::proto::message::ExtensionContents;
+use ssh_agent_lib::proto::message::Identity;
+use ssh_agent_lib::proto::message::Message;
+use ssh_agent_lib::proto::message::SignRequest;
+use ssh_agent_lib::proto::signature::Signature;
+
+use crate::error::ErrorReport;
+use crate::error::ProtocolError;
+use crate::status::Status;
+use crate::transport::connect_unix;
+use crate::transport::read_message;
+use crate::transport::write_message;
+
+
+/// A client of an SSH agent, connected via a Unix domain socket.
+#[derive(Debug)]
+pub struct AgentClient {
+  /// The connection to the agent.
+  stream: UnixStream,
+}
+
+impl AgentClient {
+  /// Connect to the agent listening on the given Unix domain socket.
+  pub fn connect(socket: &Path) -> StdResult<Self, ProtocolError> {
+    let stream = connect_unix(socket)
+      .with_context(|| format!("failed to connect to agent at {}", socket.display()))?;
+    Ok(Self { stream })
+  }
+
+  /// Send the given request to the agent and wait for its response.
+  ///
+  /// Error reports sent in response to requests for the agent's own
+  /// extensions are reported as errors.
+  pub fn request(&mut self, request: &Message) -> StdResult<Message, ProtocolError> {
+    let () = write_message(&mut self.stream, request)?;
+    let response =
+      read_message(&mut self.stream)?.ok_or_else(|| anyhow!("agent closed connection"))?;
+    match ErrorReport::from_response(&response) {
+      Some(report) => Err(Error::from(report).context("agent failed to handle request").into()),
+      None => Ok(response),
+    }
+  }
+
+  /// Retrieve the identities the agent offers.
+  pub fn identities(&mut self) -> StdResult<Vec<Identity>, ProtocolError> {
+    match self.request(&Message::RequestIdentities)? {
+      Message::IdentitiesAnswer(identities) => Ok(identities),
+      Message::Failure => Err(anyhow!("agent refused to list identities").into()),
+      _ => Err(anyhow!("agent sent unexpected response to identities request").into()),
+    }
+  }
+
+  /// Have the agent sign the given data with the key with the provided
+  /// public key blob, taking into account the given signature flags.
+  pub fn sign(
+    &mut self,
+    pubkey_blob: &[u8],
+    data: &[u8],
+    flags: u32,
+  ) -> StdResult<Signature, ProtocolError> {
+    let request = Message::SignRequest(SignRequest {
+      pubkey_blob: pubkey_blob.to_vec(),
+      data: data.to_vec(),
+      flags,
+    });
+    match self.request(&request)? {
+      Message::SignResponse(blob) => {
+        let signature = from_bytes(&blob).with_context(|| "agent sent malformed signature")?;
+        Ok(signature)
+      },
+      Message::Failure => Err(anyhow!("agent refused to create signature").into()),
+      _ => Err(anyhow!("agent sent unexpected response to sign request").into()),
+    }
+  }
+
+  /// Issue a request for the agent protocol extension with the given
+  /// name and contents, returning the agent's response.
+  pub fn extension(
+    &mut self,
+    name: &str,
+    contents: Vec<u8>,
+  ) -> StdResult<Message, ProtocolError> {
+    let request = Message::Extension(Extension {
+      extension_type: name.to_string(),
+      extension_contents: ExtensionContents(contents),
+    });
+    self.request(&request)
+  }
+
+  /// Query the agent's status.
+  pub fn status(&mut self) -> StdResult<Status, ProtocolError> {
+    let response = self.request(&Status::request())?;
+    let status = Status::from_response(&response)?;
+    Ok(status)
+  }
+}
+
+impl From<UnixStream> for AgentClient {
+  fn from(stream: UnixStream) -> Self {
+    Self { stream }
+  }
+}
@@ -0,0 +1,542 @@
+// composite.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! A key store merging the identities of several others.
+
+use std::cmp::Reverse;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use ssh_agent_lib::proto::message::SignatureBlob;
+use ssh_agent_lib::proto::message::SignRequest;
+
+use tracing::debug_span;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::config::OnConflict;
+use crate::config::StoresConfig;
+use crate::error::KeyStoreError;
+use crate::peer::Peer;
+use crate::sign::is_verifiable;
+use crate::sign::verify_blob;
+use crate::status::StoreStatus;
+use crate::store::KeyStore;
+use crate::store::StoreIdentity;
+
+
+/// The priorities of the stores the agent sets up itself, unless
+/// configured otherwise. Other stores have a priority of zero.
+const DEFAULT_PRIORITIES: [(&str, i32); 6] = [
+  ("memory", 50),
+  ("files", 40),
+  ("pass", 35),
+  ("gpg-agent", 30),
+  ("pkcs11", 20),
+  ("upstream", 10),
+];
+
+
+/// A store that is part of a composite one.
+#[derive(Clone, Debug)]
+struct Member {
+  /// The store itself.
+  store: Arc<dyn KeyStore>,
+  /// The store's priority.
+  priority: i32,
+}
+
+
+/// A store offering the identities of the stores it is composed of, in
+/// order of their priority.
+///
+/// Stores can be added and removed at any time. A store that fails to
+/// report its identities does not affect the others.
+#[derive(Debug, Default)]
+pub struct CompositeStore {
+  /// The stores, ordered by descending priority.
+  members: RwLock<Vec<Member>>,
+  /// The configuration of the composition.
+  config: RwLock<StoresConfig>,
+}
+
+impl CompositeStore {
+  /// Create an empty composite store using the given configuration.
+  pub fn new(config: StoresConfig) -> Self {
+    Self {
+      members: RwLock::new(Vec::new()),
+      config: RwLock::new(config),
+    }
+  }
+
+  /// Determine the priority of the store with the given name.
+  fn priority(config: &StoresConfig, name: &str) -> i32 {
+    config.priorities.get(name).copied().unwrap_or_else(|| {
+      DEFAULT_PRIORITIES
+        .iter()
+        .find_map(|(store, priority)| (*store == name).then_some(*priority))
+        .unwrap_or(0)
+    })
+  }
+
+  /// Add the given store, replacing any with the same name.
+  ///
+  /// Among stores of equal priority, the ones added first take
+  /// precedence.
+  pub fn insert_store(&self, store: Arc<dyn KeyStore>) {
+    let priority = Self::priority(&self.config.read().unwrap(), store.name());
+    let mut members = self.members.write().unwrap();
+    let () = members.retain(|member| member.store.name() != store.name());
+    let index = members
+      .iter()
+      .position(|member| member.priority < priority)
+      .unwrap_or(members.len());
+    let () = members.insert(index, Member { store, priority });
+  }
+
+  /// Remove the store with the given name, returning it.
+  pub fn remove_store(&self, name: &str) -> Option<Arc<dyn KeyStore>> {
+    let mut members = self.members.write().unwrap();
+    let index = members.iter().position(|member| member.store.name() == name)?;
+    Some(members.remove(index).store)
+  }
+
+  /// Retrieve a snapshot of the stores, so that no lock is held while
+  /// accessing them.
+  fn members(&self) -> Vec<Member> {
+    self.members.read().unwrap().clone()
+  }
+
+  /// Retrieve the identities of the given store, logging a failure to
+  /// do so.
+  fn member_identities(member: &Member) -> Vec<StdResult<StoreIdentity, KeyStoreError>> {
+    member.store.identities().unwrap_or_else(|err| {
+      warn!(
+        "Failed to retrieve identities of {} key store: {err:#}",
+        member.store.name()
+      );
+      Vec::new()
+    })
+  }
+
+  /// Find the identities corresponding to the given public key or
+  /// certificate blob, along with the stores offering them, in order
+  /// of priority.
+  fn find(&self, blob: &[u8], all: bool) -> Result<Vec<(Arc<dyn KeyStore>, StoreIdentity)>> {
+    let mut found = Vec::new();
+    let mut error = None;
+
+    for member in self.members() {
+      for result in Self::member_identities(&member) {
+        match result {
+          Ok(identity) if identity.blob == blob => {
+            let () = found.push((member.store.clone(), identity));
+          },
+          Ok(_) => (),
+          Err(err) => {
+            let _ = error.get_or_insert(err);
+          },
+        }
+      }
+
+      if !all && !found.is_empty() {
+        break
+      }
+    }
+
+    match error {
+      // An identity that failed to load may be the one looked for, so
+      // report the error if we didn't find it elsewhere.
+      Some(err) if found.is_empty() => Err(err.into_inner()),
+      _ => Ok(found),
+    }
+  }
+
+  /// Find the identities corresponding to the given public key or
+  /// certificate blob that may be used for signing, as per the
+  /// configured conflict resolution, along with the stores offering
+  /// them.
+  pub fn candidates(&self, blob: &[u8]) -> Result<Vec<(Arc<dyn KeyStore>, StoreIdentity)>> {
+    let all = self.config.read().unwrap().on_conflict == OnConflict::Fallback;
+    self.find(blob, all)
+  }
+
+  /// Retrieve the status of all stores, in order of priority.
+  pub fn status(&self) -> Vec<StoreStatus> {
+    self
+      .members()
+      .into_iter()
+      .map(|member| {
+        let (identities, error) = match member.store.identities() {
+          Ok(identities) => (identities.len() as u64, String::new()),
+          Err(err) => (0, format!("{err:#}")),
+        };
+        StoreStatus {
+          name: member.store.name().to_string(),
+          priority: member.priority,
+          identities,
+          error,
+        }
+      })
+      .collect()
+  }
+}
+
+impl KeyStore for CompositeStore {
+  fn name(&self) -> &str {
+    "composite"
+  }
+
+  /// Retrieve the identities of all stores, in order of priority.
+  /// Identities offered by multiple stores are only reported once.
+  fn identities(
+    &self,
+  ) -> StdResult<Vec<StdResult<StoreIdentity, KeyStoreError>>, KeyStoreError> {
+    let mut identities = Vec::<StdResult<StoreIdentity, KeyStoreError>>::new();
+    for member in self.members() {
+      for result in Self::member_identities(&member) {
+        let duplicate = match &result {
+          Ok(identity) => identities
+            .iter()
+            .any(|x| matches!(x, Ok(x) if x.blob == identity.blob)),
+          Err(_) => false,
+        };
+        if !duplicate {
+          let () = identities.push(result);
+        }
+      }
+    }
+    Ok(identities)
+  }
+
+  fn check_access(
+    &self,
+    identity: &StoreIdentity,
+    peer: Option<&Peer>,
+  ) -> StdResult<(), KeyStoreError> {
+    let candidates = self.candidates(&identity.blob)?;
+    let (store, identity) = candidates
+      .first()
+      .ok_or_else(|| anyhow!("identity {:?} no longer present", identity.comment))?;
+    store.check_access(identity, peer)
+  }
+
+  fn sign(
+    &self,
+    identity: &StoreIdentity,
+    request: &SignRequest,
+  ) -> StdResult<SignatureBlob, KeyStoreError> {
+    let candidates = self.candidates(&identity.blob)?;
+    // Verifying signatures is up to the agent, as configured.
+    let signature = sign_with(&candidates, request, false)?;
+    Ok(signature)
+  }
+
+  /// Remove the identity from all stores offering it.
+  fn remove(&self, identity: &StoreIdentity) -> StdResult<(), KeyStoreError> {
+    for (store, identity) in self.find(&identity.blob, true)? {
+      let () = store.remove(&identity)?;
+    }
+    Ok(())
+  }
+
+  fn invalidate(&self) {
+    for member in self.members() {
+      let () = member.store.invalidate();
+    }
+  }
+
+  /// Apply a changed configuration to all stores and reorder them as
+  /// per the configured priorities.
+  fn reconfigure(&self, config: &Arc<Config>) {
+    for member in self.members() {
+      let () = member.store.reconfigure(config);
+    }
+
+    *self.config.write().unwrap() = config.stores.clone();
+    let mut members = self.members.write().unwrap();
+    for member in members.iter_mut() {
+      member.priority = Self::priority(&config.stores, member.store.name());
+    }
+    let () = members.sort_by_key(|member| Reverse(member.priority));
+  }
+}
+
+
+/// Sign the data of the given request with the first of the provided
+/// candidate identities, falling back to the others in order if that
+/// fails.
+///
+/// If `verify` is set, each signature is verified against the public
+/// key of the identity before being returned, and one failing
+/// verification counts as a failure to sign.
+pub fn sign_with(
+  candidates: &[(Arc<dyn KeyStore>, StoreIdentity)],
+  request: &SignRequest,
+  verify: bool,
+) -> Result<SignatureBlob> {
+  let mut result = Err(anyhow!("identity not found"));
+  for (i, (store, identity)) in candidates.iter().enumerate() {
+    result = debug_span!("store", name = store.name()).in_scope(|| {
+      let blob = store.sign(identity, request)?;
+      match &identity.pubkey {
+        Some(pubkey) if verify && is_verifiable(pubkey) => {
+          let () = verify_blob(pubkey, &blob, &request.data).with_context(|| {
+            format!(
+              "{} key store created invalid signature with key {:?}",
+              store.name(),
+              identity.comment
+            )
+          })?;
+        },
+        _ => (),
+      }
+      Ok(blob)
+    });
+    match &result {
+      Err(err) if i + 1 < candidates.len() => warn!(
+        "Failed to sign with key {:?} of {} key store, falling back: {err:#}",
+        identity.comment,
+        store.name()
+      ),
+      _ => break,
+    }
+  }
+  result
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use ssh_agent_lib::proto::message::AddIdentity;
+  use ssh_agent_lib::proto::private_key::PrivateKey;
+
+  use crate::files::test::load_unencrypted_private_key;
+  use crate::keys::FromPem as _;
+  use crate::memstore::MemoryStore;
+
+
+  /// A key store offering fixed identities.
+  #[derive(Debug)]
+  struct Fixed {
+    /// The store's name.
+    name: &'static str,
+    /// The blobs of the identities the store offers, or `None` if it is
+    /// unavailable.
+    blobs: Option<Vec<u8>>,
+    /// Whether signing succeeds.
+    sign: bool,
+  }
+
+  impl Fixed {
+    fn new(name: &'static str, blobs: &[u8], sign: bool) -> Arc<Self> {
+      Arc::new(Self {
+        name,
+        blobs: Some(blobs.to_vec()),
+        sign,
+      })
+    }
+  }
+
+  impl KeyStore for Fixed {
+    fn name(&self) -> &str {
+      self.name
+    }
+
+    fn identities(
+    &self,
+  ) -> StdResult<Vec<StdResult<StoreIdentity, KeyStoreError>>, KeyStoreError> {
+      let blobs = self
+        .blobs
+        .as_ref()
+        .ok_or_else(|| anyhow!("{} is unavailable", self.name))?;
+      let identities = blobs
+        .iter()
+        .map(|blob| {
+          Ok(StoreIdentity {
+            blob: vec![*blob],
+            pubkey: None,
+            comment: self.name.to_string(),
+            confirm: false,
+          })
+        })
+        .collect();
+      Ok(identities)
+    }
+
+    fn sign(
+      &self,
+      _identity: &StoreIdentity,
+      _request: &SignRequest,
+    ) -> StdResult<SignatureBlob, KeyStoreError> {
+      if !self.sign {
+        return Err(anyhow!("{} failed to sign", self.name).into())
+      }
+      Ok(self.name.as_bytes().to_vec())
+    }
+  }
+
+
+  /// Create a sign request for the given blob.
+  fn request(blob: u8) -> SignRequest {
+    SignRequest {
+      pubkey_blob: vec![blob],
+      data: Vec::new(),
+      flags: 0,
+    }
+  }
+
+  /// Retrieve the comments of all identities of the given store.
+  fn comments(store: &CompositeStore) -> Vec<String> {
+    store
+      .identities()
+      .unwrap()
+      .into_iter()
+      .map(|identity| identity.unwrap().comment)
+      .collect()
+  }
+
+  /// Check that identities are reported in the order of the priorities
+  /// of their stores, without duplicates.
+  #[test]
+  fn identity_order() {
+    let mut config = StoresConfig::default();
+    let _ = config.priorities.insert("custom".to_string(), 100);
+    let store = CompositeStore::new(config);
+    let () = store.insert_store(Fixed::new("upstream", &[1, 3], true));
+    let () = store.insert_store(Fixed::new("files", &[1, 2], true));
+    let () = store.insert_store(Fixed::new("other", &[4], true));
+    let () = store.insert_store(Fixed::new("custom", &[2], true));
+
+    assert_eq!(comments(&store), vec!["custom", "files", "upstream", "other"]);
+    let blobs = store
+      .identities()
+      .unwrap()
+      .into_iter()
+      .map(|identity| identity.unwrap().blob)
+      .collect::<Vec<_>>();
+    assert_eq!(blobs, vec![vec![2], vec![1], vec![3], vec![4]]);
+  }
+
+  /// Check that stores can be replaced and removed.
+  #[test]
+  fn insertion_removal() {
+    let store = CompositeStore::default();
+    let () = store.insert_store(Fixed::new("files", &[1], true));
+    let () = store.insert_store(Fixed::new("pkcs11", &[2], true));
+    let () = store.insert_store(Fixed::new("pkcs11", &[3], true));
+    assert_eq!(store.members().len(), 2);
+    assert_eq!(store.candidates(&[3]).unwrap().len(), 1);
+    assert!(store.candidates(&[2]).unwrap().is_empty());
+
+    assert!(store.remove_store("pkcs11").is_some());
+    assert!(store.remove_store("pkcs11").is_none());
+    assert_eq!(comments(&store), vec!["files"]);
+  }
+
+  /// Check that an unavailable store does not affect others and gets
+  /// reported in the status.
+  #[test]
+  fn unavailable_store() {
+    let store = CompositeStore::default();
+    let () = store.insert_store(Fixed::new("files", &[1], true));
+    let () = store.insert_store(Arc::new(Fixed {
+      name: "gpg-agent",
+      blobs: None,
+      sign: true,
+    }));
+
+    assert_eq!(comments(&store), vec!["files"]);
+    let status = store.status();
+    assert_eq!(status.len(), 2);
+    assert_eq!(status[0].name, "files");
+    assert_eq!(status[0].identities, 1);
+    assert_eq!(status[0].error, "");
+    assert_eq!(status[1].name, "gpg-agent");
+    assert_eq!(status[1].priority, 30);
+    assert_eq!(status[1].error, "gpg-agent is unavailable");
+  }
+
+  /// Check that conflicting keys are resolved as configured.
+  #[test]
+  fn conflict_resolution() {
+    let store = CompositeStore::default();
+    let () = store.insert_store(Fixed::new("files", &[1], false));
+    let () = store.insert_store(Fixed::new("gpg-agent", &[1], true));
+
+    let identity = store.candidates(&[1]).unwrap().remove(0).1;
+    assert_eq!(store.candidates(&[1]).unwrap().len(), 1);
+    assert!(store.sign(&identity, &request(1)).is_err());
+
+    let mut config = Config::default();
+    config.stores.on_conflict = OnConflict::Fallback;
+    let () = store.reconfigure(&Arc::new(config));
+    assert_eq!(store.candidates(&[1]).unwrap().len(), 2);
+    assert_eq!(store.sign(&identity, &request(1)).unwrap(), b"gpg-agent");
+
+    let mut config = Config::default();
+    let _ = config.stores.priorities.insert("gpg-agent".to_string(), 50);
+    let () = store.reconfigure(&Arc::new(config));
+    assert_eq!(comments(&store), vec!["gpg-agent"]);
+    assert_eq!(store.sign(&identity, &request(1)).unwrap(), b"gpg-agent");
+  }
+
+  /// Check that signatures failing verification count as failures to
+  /// sign, if verification is requested.
+  #[test]
+  fn signature_verification() -> Result<()> {
+    let privkey = load_unencrypted_private_key("tests/valid_keys/ed25519")?;
+    let privkey = PrivateKey::from_pem(privkey)?;
+    let memory = MemoryStore::default();
+    let () = memory.add(&AddIdentity {
+      privkey,
+      comment: "ed25519".to_string(),
+    })?;
+    let identity = memory.identities()?.remove(0)?;
+    let request = SignRequest {
+      pubkey_blob: identity.blob.clone(),
+      data: b"test-data".to_vec(),
+      flags: 0,
+    };
+    // The "broken" store merely returns its name as signature.
+    let candidates: Vec<(Arc<dyn KeyStore>, _)> = vec![
+      (Fixed::new("broken", &[], true), identity.clone()),
+      (Arc::new(memory), identity.clone()),
+    ];
+
+    assert_eq!(sign_with(&candidates, &request, false)?, b"broken");
+    let blob = sign_with(&candidates, &request, true)?;
+    let pubkey = identity.pubkey.as_ref().unwrap();
+    let () = verify_blob(pubkey, &blob, &request.data)?;
+
+    let err = sign_with(&candidates[..1], &request, true).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "broken key store created invalid signature with key \"ed25519\""
+    );
+    Ok(())
+  }
+}
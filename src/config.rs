@@ -0,0 +1,1102 @@
+// config.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::collections::HashMap;
+use std::env::var_os;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::fs::read_to_string;
+use std::mem::take;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use dirs::config_dir;
+use dirs::home_dir;
+
+use serde::Deserialize;
+
+use ssh_agent_lib::proto::public_key::PublicKey;
+
+use crate::fingerprint::Fingerprint;
+
+
+/// The name of the program's directory inside the user's configuration
+/// directory.
+const CONFIG_DIR: &str = "ssh-gpg-agent";
+/// The name of the configuration file.
+const CONFIG_FILE: &str = "config.toml";
+/// The default number of seconds to wait for the upstream agent to
+/// respond.
+pub const UPSTREAM_TIMEOUT: u64 = 60;
+
+
+/// Expand a leading `~` in the given path to the user's home directory.
+fn expand_tilde(path: PathBuf) -> PathBuf {
+  match (path.strip_prefix("~"), home_dir()) {
+    (Ok(rest), Some(home)) => home.join(rest),
+    _ => path,
+  }
+}
+
+
+/// Where to keep identities added to the agent at runtime.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnAdd {
+  /// Store the identity GPG encrypted in the key directory.
+  #[default]
+  Store,
+  /// Keep the identity in memory only, until it is removed, the agent
+  /// gets locked, or it shuts down.
+  Memory,
+}
+
+
+/// What to do with the files of an identity that got removed from the
+/// agent.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnRemove {
+  /// Merely hide the identity from clients until the agent restarts.
+  #[default]
+  Hide,
+  /// Rename the key files so that they are no longer discovered.
+  Rename,
+  /// Delete the key files.
+  Delete,
+}
+
+
+/// Where log messages are sent to.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogSink {
+  /// Write log messages to standard error.
+  #[default]
+  Stderr,
+  /// Send log messages to the systemd journal.
+  Journald,
+  /// Send log messages to the system logger.
+  Syslog,
+}
+
+
+/// The means by which GPG encrypted private keys are decrypted.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+  /// Use the `gpgme` library.
+  #[cfg(feature = "gpgme")]
+  #[default]
+  Gpgme,
+  /// Invoke the `gpg` command line program.
+  #[cfg_attr(not(feature = "gpgme"), default)]
+  Gpg,
+  /// Use Sequoia-PGP, with secret keys read from `keyrings`.
+  #[cfg(feature = "sequoia")]
+  Sequoia,
+}
+
+
+/// The pinentry mode to use for GnuPG operations.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PinentryMode {
+  /// Use GnuPG's default.
+  #[default]
+  Default,
+  /// Always use a pinentry.
+  Ask,
+  /// Emulate cancellation of the pinentry.
+  Cancel,
+  /// Return an error instead of using a pinentry.
+  Error,
+  /// Have the agent prompt for passphrases itself.
+  Loopback,
+}
+
+
+/// A means of prompting the user, e.g., for a passphrase.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PromptMethod {
+  /// The system's default `pinentry` program.
+  Pinentry,
+  /// The first graphical pinentry program available, provided there
+  /// is a display to show it on.
+  PinentryGui,
+  /// `pinentry-curses` on the agent's controlling terminal.
+  PinentryCurses,
+  /// A `zenity` dialog.
+  Zenity,
+  /// A plain prompt on the agent's controlling terminal.
+  Tty,
+}
+
+
+/// Configuration of the prompting of the user.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PromptConfig {
+  /// The methods to try for prompting the user, in order. The first
+  /// one that is available is used.
+  pub methods: Vec<PromptMethod>,
+}
+
+impl Default for PromptConfig {
+  fn default() -> Self {
+    Self {
+      methods: vec![
+        PromptMethod::Pinentry,
+        PromptMethod::PinentryGui,
+        PromptMethod::Zenity,
+        PromptMethod::PinentryCurses,
+        PromptMethod::Tty,
+      ],
+    }
+  }
+}
+
+
+/// Options for the usage of GnuPG.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GpgOptions {
+  /// The GnuPG home directory to use instead of the default one.
+  pub home: Option<PathBuf>,
+  /// The pinentry mode to use.
+  pub pinentry_mode: PinentryMode,
+  /// Whether to try all available secret keys for decryption, instead
+  /// of only the ones files claim to be encrypted to.
+  pub try_all_secrets: bool,
+}
+
+
+/// Configuration of the PKCS#11 module providing token-resident keys.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pkcs11Config {
+  /// The path to the PKCS#11 module to load. Token keys are not used if
+  /// unset.
+  pub module: Option<PathBuf>,
+  /// The label of the token to use keys of. Keys of all tokens are used
+  /// if unset.
+  pub token: Option<String>,
+}
+
+
+/// Configuration of the usage of keys kept in the `pass` password
+/// store.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PassConfig {
+  /// The directory inside the password store containing the keys. Keys
+  /// from the password store are not used if unset.
+  pub prefix: Option<PathBuf>,
+  /// The password store's directory; defaults to the one set in the
+  /// `PASSWORD_STORE_DIR` environment variable or `~/.password-store`.
+  pub store: Option<PathBuf>,
+}
+
+impl PassConfig {
+  /// Retrieve the directory containing the keys, if any are to be used.
+  pub fn directory(&self) -> Option<PathBuf> {
+    let prefix = self.prefix.as_ref()?;
+    let store = self
+      .store
+      .clone()
+      .or_else(|| var_os("PASSWORD_STORE_DIR").map(PathBuf::from))
+      .or_else(|| home_dir().map(|home| home.join(".password-store")))?;
+    Some(store.join(prefix))
+  }
+}
+
+
+/// Configuration of the usage of the TPM for unsealing private keys.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TpmConfig {
+  /// The TCTI to connect to the TPM with (e.g., `device:/dev/tpmrm0`),
+  /// if not the one configured in the environment.
+  pub tcti: Option<String>,
+  /// The persistent handle of the storage key keys are sealed under.
+  pub parent: u32,
+}
+
+impl Default for TpmConfig {
+  fn default() -> Self {
+    Self {
+      tcti: None,
+      // The well-known handle of the storage root key.
+      parent: 0x81000001,
+    }
+  }
+}
+
+
+/// How to handle a key offered by multiple key stores.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+  /// Only use the key of the store with the highest priority.
+  #[default]
+  First,
+  /// Fall back to the stores with lower priority if signing with the
+  /// key of the one with the highest priority fails.
+  Fallback,
+}
+
+
+/// Configuration of the composition of the agent's key stores.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoresConfig {
+  /// The priorities of key stores by name, overriding the default ones.
+  /// Stores with a higher priority are consulted first.
+  pub priorities: HashMap<String, i32>,
+  /// How to handle keys offered by multiple stores.
+  pub on_conflict: OnConflict,
+}
+
+
+/// Configuration of the socket intended for being forwarded to remote
+/// hosts.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardingConfig {
+  /// The path of the Unix domain socket to additionally listen on, for
+  /// forwarding to remote hosts.
+  pub socket: Option<PathBuf>,
+  /// The fingerprints of the keys visible via the forwarding socket.
+  pub keys: Vec<Fingerprint>,
+}
+
+
+/// The access the agent grants clients connected to one of its sockets.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SocketPolicy {
+  /// Clients have full access to the agent.
+  #[default]
+  Full,
+  /// Clients are restricted just like those connected to the socket
+  /// intended for forwarding to remote hosts.
+  Forwarding,
+}
+
+
+/// Configuration of an additional Unix domain socket to serve the agent
+/// on.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketConfig {
+  /// The path of the socket.
+  pub path: PathBuf,
+  /// The access clients connected to the socket have.
+  pub policy: SocketPolicy,
+  /// The permissions to create the socket with; defaults to `0o600` or,
+  /// if `group` is set, to `0o660`.
+  pub mode: Option<u32>,
+  /// The group (name or ID) to share the socket with.
+  pub group: Option<String>,
+}
+
+
+/// A rule selecting the keys to offer when authenticating to certain
+/// hosts.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostRule {
+  /// The patterns of the hosts the rule applies to.
+  pub hosts: Vec<String>,
+  /// The keys to offer, as file names of key pairs or fingerprints.
+  pub keys: Vec<String>,
+}
+
+impl HostRule {
+  /// Check whether the rule selects the key with the given name (i.e.,
+  /// the file name of the key pair, if any) and public key or, absent
+  /// that, public key blob.
+  pub fn selects(&self, name: Option<&str>, key: Option<&PublicKey>, blob: &[u8]) -> Result<bool> {
+    for selected in &self.keys {
+      let matches = match selected.parse::<Fingerprint>() {
+        Ok(fingerprint) => matches_key(&fingerprint, key, blob)?,
+        Err(_) => name == Some(selected.as_str()),
+      };
+      if matches {
+        return Ok(true)
+      }
+    }
+    Ok(false)
+  }
+}
+
+
+/// How to treat symbolic links when looking for key pairs.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+  /// Follow symbolic links wherever they point to.
+  #[default]
+  Follow,
+  /// Follow symbolic links only if they point to a file inside the
+  /// directory containing them (or one of its sub-directories).
+  FollowWithinDirectory,
+  /// Ignore symbolic links.
+  Never,
+}
+
+
+/// What to do about key files that are writable by others or owned by
+/// another user.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionPolicy {
+  /// Use the key pair without complaint.
+  Ignore,
+  /// Use the key pair, but log a warning.
+  #[default]
+  Warn,
+  /// Refuse to use the key pair.
+  Refuse,
+}
+
+
+/// The rules selecting the public key files considered when looking
+/// for key pairs in the key directories.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+  /// The file name patterns of the public key files to consider. Empty
+  /// to consider all of them.
+  pub include: Vec<String>,
+  /// The file name patterns of the public key files to ignore, even if
+  /// included.
+  pub exclude: Vec<String>,
+  /// How to treat key files that are symbolic links.
+  pub symlinks: SymlinkPolicy,
+  /// What to do about key files with unsafe permissions.
+  pub permissions: PermissionPolicy,
+}
+
+
+/// Configuration of the destinations keys may be used for.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DestinationConfig {
+  /// The `known_hosts` files to look up the host keys of permitted
+  /// destinations in.
+  pub known_hosts: Vec<PathBuf>,
+  /// The host patterns keys may only be used for, keyed by the keys'
+  /// fingerprints.
+  pub keys: HashMap<Fingerprint, Vec<String>>,
+}
+
+impl Default for DestinationConfig {
+  fn default() -> Self {
+    let user = home_dir().map(|home| home.join(".ssh").join("known_hosts"));
+    let system = PathBuf::from("/etc/ssh/ssh_known_hosts");
+    Self {
+      known_hosts: user.into_iter().chain([system]).collect(),
+      keys: HashMap::new(),
+    }
+  }
+}
+
+
+/// A signature algorithm for RSA keys.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum RsaSignatureAlgorithm {
+  /// PKCS #1 v1.5 signatures using SHA-512.
+  #[serde(rename = "rsa-sha2-512")]
+  RsaSha2_512,
+  /// PKCS #1 v1.5 signatures using SHA-256.
+  #[serde(rename = "rsa-sha2-256")]
+  RsaSha2_256,
+  /// Legacy PKCS #1 v1.5 signatures using SHA-1.
+  #[serde(rename = "ssh-rsa")]
+  SshRsa,
+}
+
+impl Display for RsaSignatureAlgorithm {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    let s = match self {
+      Self::RsaSha2_512 => "rsa-sha2-512",
+      Self::RsaSha2_256 => "rsa-sha2-256",
+      Self::SshRsa => "ssh-rsa",
+    };
+    f.write_str(s)
+  }
+}
+
+
+/// The policy for RSA keys.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RsaConfig {
+  /// The minimum modulus length in bits of RSA keys we offer and sign
+  /// with.
+  pub min_bits: usize,
+  /// The fingerprints of RSA keys to use despite a modulus shorter
+  /// than the minimum.
+  pub allow_weak: Vec<Fingerprint>,
+  /// The signature algorithms we create signatures with, if requested
+  /// by the client, in order of preference. Of the algorithms a client
+  /// requests, the first one listed is used. Creating legacy `ssh-rsa`
+  /// signatures additionally requires `allow_sha1` to be set.
+  pub algorithms: Vec<RsaSignatureAlgorithm>,
+}
+
+impl Default for RsaConfig {
+  fn default() -> Self {
+    Self {
+      min_bits: 2048,
+      allow_weak: Vec::new(),
+      algorithms: vec![
+        RsaSignatureAlgorithm::RsaSha2_512,
+        RsaSignatureAlgorithm::RsaSha2_256,
+        RsaSignatureAlgorithm::SshRsa,
+      ],
+    }
+  }
+}
+
+
+/// The cryptographic protocol a private key file is encrypted with.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+  /// OpenPGP, as used by `gpg`.
+  OpenPgp,
+  /// CMS (S/MIME), as used by `gpgsm`.
+  Cms,
+}
+
+
+/// Options applying to a single key, identified by the file name of the
+/// key pair without extension.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyConfig {
+  /// Whether the key is served by the agent.
+  pub enabled: bool,
+  /// Whether to ask the user for confirmation before each usage of the
+  /// key.
+  pub confirm: bool,
+  /// The protocol the private key is encrypted with, if it should not
+  /// be inferred from the file extension.
+  pub protocol: Option<Protocol>,
+  /// The GPG keys (fingerprints or key IDs) one of which must have
+  /// been used to decrypt the private key. Empty to accept any key.
+  pub decryption_keys: Vec<String>,
+  /// The user IDs of the clients allowed to use the key. Empty to allow
+  /// all users.
+  pub allowed_users: Vec<u32>,
+  /// The programs (absolute paths or file names) allowed to use the
+  /// key. Empty to allow all programs.
+  pub allowed_programs: Vec<String>,
+  /// The PCRs (of the SHA-256 bank) the private key is sealed to, if
+  /// it is sealed to the TPM with a PCR policy.
+  pub pcrs: Vec<u8>,
+  /// The label to show for the key instead of the comment of its
+  /// public key file.
+  pub label: Option<String>,
+}
+
+impl KeyConfig {
+  /// Parse per-key options from the given TOML string.
+  pub fn parse(toml: &str) -> Result<Self> {
+    toml::from_str::<Self>(toml).with_context(|| "failed to parse key configuration")
+  }
+}
+
+impl Default for KeyConfig {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      confirm: false,
+      protocol: None,
+      decryption_keys: Vec::new(),
+      allowed_users: Vec::new(),
+      allowed_programs: Vec::new(),
+      pcrs: Vec::new(),
+      label: None,
+    }
+  }
+}
+
+
+/// Configuration of the cache of decrypted private keys.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+  /// The time in seconds for which to cache decrypted keys. Zero
+  /// disables caching.
+  pub ttl: u64,
+  /// The maximum number of keys to cache.
+  pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+  fn default() -> Self {
+    Self {
+      ttl: 0,
+      max_entries: 16,
+    }
+  }
+}
+
+
+/// Configuration of the hardening of the agent process.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HardeningConfig {
+  /// Whether to disable core dumps and prevent other processes from
+  /// attaching to the agent. Disabling this can be useful for
+  /// debugging.
+  pub enabled: bool,
+  /// Whether to lock all of the agent's memory, so that none of it
+  /// gets swapped out.
+  pub lock_memory: bool,
+}
+
+impl Default for HardeningConfig {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      lock_memory: false,
+    }
+  }
+}
+
+
+/// The agent's configuration, as read from its configuration file.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  /// The directories in which to look for SSH key pairs, in order of
+  /// precedence.
+  pub directories: Vec<PathBuf>,
+  /// The rules selecting the public key files in these directories.
+  pub discovery: DiscoveryConfig,
+  /// The path of the Unix domain socket to listen on.
+  pub socket: Option<PathBuf>,
+  /// The permissions to create the socket with; defaults to `0o600` or,
+  /// if `socket_group` is set, to `0o660`.
+  pub socket_mode: Option<u32>,
+  /// The group (name or ID) to share the socket with.
+  pub socket_group: Option<String>,
+  /// The TCP address (`HOST:PORT`) to additionally serve the agent on.
+  pub listen_tcp: Option<String>,
+  /// The file containing the token TCP clients authenticate with.
+  pub tcp_token_file: Option<PathBuf>,
+  /// The socket of another SSH agent to forward requests for identities
+  /// we don't manage to.
+  pub upstream: Option<PathBuf>,
+  /// The number of seconds to wait for the upstream agent to respond;
+  /// defaults to [`UPSTREAM_TIMEOUT`]. Zero disables the timeout.
+  pub upstream_timeout: Option<u64>,
+  /// The log level or, more generally, an `env_logger` style filter
+  /// directive.
+  pub log_level: Option<String>,
+  /// Where to send log messages.
+  pub log_sink: LogSink,
+  /// The GPG recipient to encrypt keys added at runtime to.
+  pub recipient: Option<String>,
+  /// Whether to create legacy SHA-1 based `ssh-rsa` signatures for
+  /// clients not requesting one of the SHA-2 based RSA signature
+  /// algorithms.
+  pub allow_sha1: bool,
+  /// Whether to verify each signature against the public key before
+  /// handing it out, to catch corrupted private keys or faulty
+  /// signing hardware.
+  pub verify_signatures: bool,
+  /// The backend to use for decrypting private keys.
+  pub backend: Backend,
+  /// Options for the usage of GnuPG.
+  pub gpg: GpgOptions,
+  /// Whether to additionally offer the authentication keys enabled in
+  /// the GnuPG agent's `sshcontrol` file, delegating signing to it.
+  pub gpg_agent_keys: bool,
+  /// Whether to additionally offer the authentication keys of the
+  /// OpenPGP smartcard available to the GnuPG agent.
+  pub card_keys: bool,
+  /// The configuration of the PKCS#11 module providing additional keys.
+  pub pkcs11: Pkcs11Config,
+  /// The configuration of the usage of keys kept in the `pass` password
+  /// store.
+  pub pass: PassConfig,
+  /// The configuration of the usage of the TPM.
+  pub tpm: TpmConfig,
+  /// The configuration of the composition of the key stores.
+  pub stores: StoresConfig,
+  /// The policy for RSA keys.
+  pub rsa: RsaConfig,
+  /// The configuration of the socket for forwarding to remote hosts.
+  pub forwarding: ForwardingConfig,
+  /// Additional sockets to serve the agent on.
+  pub sockets: Vec<SocketConfig>,
+  /// The configuration of the destinations keys may be used for.
+  pub destinations: DestinationConfig,
+  /// The rules selecting the keys to offer when authenticating to
+  /// certain hosts, in order of precedence.
+  pub hosts: Vec<HostRule>,
+  /// The keyring files or directories the Sequoia-PGP backend reads
+  /// secret keys from.
+  pub keyrings: Vec<PathBuf>,
+  /// Whether to reject connections from processes belonging to users
+  /// other than the agent's (and the super user).
+  pub reject_other_users: bool,
+  /// The fingerprints of keys to ask the user for confirmation before
+  /// each usage of, in addition to keys configured to do so by name.
+  pub confirm: Vec<Fingerprint>,
+  /// The labels to show for keys instead of their comments, keyed by
+  /// the keys' fingerprints.
+  pub labels: HashMap<Fingerprint, String>,
+  /// Where to keep identities added at runtime.
+  pub on_add: OnAdd,
+  /// What to do with the files of identities removed at runtime.
+  pub on_remove: OnRemove,
+  /// The file to persist per-key usage statistics in. Statistics are
+  /// only kept in memory if unset.
+  pub usage_file: Option<PathBuf>,
+  /// The OpenSSH client configuration file whose `IdentityFile`
+  /// directives refer to further key pairs to offer, if any.
+  pub ssh_config: Option<PathBuf>,
+  /// The file to persist the index of public key files in, so that
+  /// unchanged files need not be read again. The index is only kept in
+  /// memory if unset.
+  pub index_file: Option<PathBuf>,
+  /// The maximum number of identities to offer to clients. Zero
+  /// offers all of them.
+  pub max_identities: usize,
+  /// The number of seconds after which requests still being handled
+  /// (e.g., because of a passphrase prompt left unanswered) fail. Zero
+  /// disables the timeout.
+  pub request_timeout: u64,
+  /// The number of seconds without any request after which the agent
+  /// locks itself. Zero disables locking due to inactivity.
+  pub idle_lock: u64,
+  /// The configuration of the prompting of the user.
+  pub prompt: PromptConfig,
+  /// The configuration of the decrypted key cache.
+  pub cache: CacheConfig,
+  /// The configuration of the process hardening.
+  pub hardening: HardeningConfig,
+  /// Per-key options.
+  pub keys: HashMap<String, KeyConfig>,
+}
+
+impl Config {
+  /// Retrieve the path to the default configuration file.
+  pub fn default_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(CONFIG_DIR).join(CONFIG_FILE))
+  }
+
+  /// Parse a configuration from the given TOML string.
+  pub fn parse(toml: &str) -> Result<Self> {
+    let mut config =
+      toml::from_str::<Self>(toml).with_context(|| "failed to parse configuration")?;
+    config.destinations.known_hosts =
+      config.destinations.known_hosts.into_iter().map(expand_tilde).collect();
+    config.directories = config.directories.into_iter().map(expand_tilde).collect();
+    config.forwarding.socket = config.forwarding.socket.map(expand_tilde);
+    config.gpg.home = config.gpg.home.map(expand_tilde);
+    config.keyrings = config.keyrings.into_iter().map(expand_tilde).collect();
+    config.pass.store = config.pass.store.map(expand_tilde);
+    config.pkcs11.module = config.pkcs11.module.map(expand_tilde);
+    config.socket = config.socket.map(expand_tilde);
+    for socket in &mut config.sockets {
+      socket.path = expand_tilde(take(&mut socket.path));
+    }
+    config.tcp_token_file = config.tcp_token_file.map(expand_tilde);
+    config.upstream = config.upstream.map(expand_tilde);
+    config.usage_file = config.usage_file.map(expand_tilde);
+    config.index_file = config.index_file.map(expand_tilde);
+    config.ssh_config = config.ssh_config.map(expand_tilde);
+    Ok(config)
+  }
+
+  /// Load the configuration from the given file.
+  pub fn load(path: &Path) -> Result<Self> {
+    let toml = read_to_string(path)
+      .with_context(|| format!("failed to read configuration file {}", path.display()))?;
+    Self::parse(&toml).with_context(|| format!("invalid configuration in {}", path.display()))
+  }
+
+  /// Load the configuration from the default location, falling back to
+  /// the default configuration if no configuration file is present.
+  pub fn load_default() -> Result<Self> {
+    match Self::default_path() {
+      Some(path) if path.exists() => Self::load(&path),
+      _ => Ok(Self::default()),
+    }
+  }
+
+  /// Retrieve the options for the key pair with the given file name
+  /// (without extension).
+  pub fn key(&self, name: &str) -> KeyConfig {
+    self.keys.get(name).cloned().unwrap_or_default()
+  }
+
+  /// Check whether the given key is referenced by fingerprint as
+  /// requiring confirmation before each usage.
+  pub fn confirm_key(&self, key: &PublicKey) -> Result<bool> {
+    for fingerprint in &self.confirm {
+      if fingerprint.matches(key)? {
+        return Ok(true)
+      }
+    }
+    Ok(false)
+  }
+
+  /// Check whether the given key is referenced by fingerprint as
+  /// usable despite being shorter than the minimum RSA key size.
+  pub fn allow_weak_key(&self, key: &PublicKey) -> Result<bool> {
+    for fingerprint in &self.rsa.allow_weak {
+      if fingerprint.matches(key)? {
+        return Ok(true)
+      }
+    }
+    Ok(false)
+  }
+
+  /// Check whether the key with the given public key or, absent that,
+  /// the given public key blob may be used via the forwarding socket.
+  pub fn forward_key(&self, key: Option<&PublicKey>, blob: &[u8]) -> Result<bool> {
+    for fingerprint in &self.forwarding.keys {
+      if matches_key(fingerprint, key, blob)? {
+        return Ok(true)
+      }
+    }
+    Ok(false)
+  }
+
+  /// Retrieve the host patterns the key with the given public key or,
+  /// absent that, the given public key blob is restricted to, if any.
+  pub fn key_destinations(
+    &self,
+    key: Option<&PublicKey>,
+    blob: &[u8],
+  ) -> Result<Option<&[String]>> {
+    for (fingerprint, hosts) in &self.destinations.keys {
+      if matches_key(fingerprint, key, blob)? {
+        return Ok(Some(hosts))
+      }
+    }
+    Ok(None)
+  }
+
+  /// Retrieve the label configured for the key with the given public
+  /// key or, absent that, the given public key blob, if any.
+  pub fn key_label(&self, key: Option<&PublicKey>, blob: &[u8]) -> Result<Option<&str>> {
+    for (fingerprint, label) in &self.labels {
+      if matches_key(fingerprint, key, blob)? {
+        return Ok(Some(label))
+      }
+    }
+    Ok(None)
+  }
+
+  /// Retrieve the time to wait for the upstream agent to respond, if
+  /// it is limited.
+  pub fn upstream_timeout(&self) -> Option<Duration> {
+    match self.upstream_timeout.unwrap_or(UPSTREAM_TIMEOUT) {
+      0 => None,
+      timeout => Some(Duration::from_secs(timeout)),
+    }
+  }
+}
+
+
+/// Check whether the given fingerprint is that of the provided public
+/// key or, absent that, public key blob.
+fn matches_key(fingerprint: &Fingerprint, key: Option<&PublicKey>, blob: &[u8]) -> Result<bool> {
+  match key {
+    Some(key) => fingerprint.matches(key),
+    None => Ok(Fingerprint::from_blob(blob, fingerprint.alg())? == *fingerprint),
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+
+  /// Check that an empty configuration results in the defaults.
+  #[test]
+  fn parse_empty() -> Result<()> {
+    let config = Config::parse("")?;
+    assert_eq!(config, Config::default());
+    assert_eq!(
+      config.upstream_timeout(),
+      Some(Duration::from_secs(UPSTREAM_TIMEOUT))
+    );
+    Ok(())
+  }
+
+  /// Check that we can parse a configuration with all fields set.
+  #[test]
+  fn parse_full() -> Result<()> {
+    let toml = r#"
+      directories = ["/home/user/.ssh", "/home/user/work-keys"]
+      socket = "/run/user/1000/ssh-gpg-agent.sock"
+      socket_mode = 0o660
+      socket_group = "backup"
+      listen_tcp = "127.0.0.1:6666"
+      tcp_token_file = "/home/user/.config/ssh-gpg-agent/token"
+      log_level = "debug"
+      log_sink = "journald"
+      recipient = "deso@posteo.net"
+      on_add = "memory"
+      on_remove = "rename"
+      allow_sha1 = true
+      verify_signatures = true
+      reject_other_users = true
+      backend = "gpg"
+      gpg_agent_keys = true
+      card_keys = true
+      confirm = ["SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU"]
+      usage_file = "/home/user/.local/state/ssh-gpg-agent/usage.toml"
+      index_file = "/home/user/.cache/ssh-gpg-agent/index.toml"
+      ssh_config = "/home/user/.ssh/config"
+      request_timeout = 120
+      upstream_timeout = 30
+      max_identities = 5
+      idle_lock = 900
+
+      [discovery]
+      include = ["id_*"]
+      exclude = ["*-cert.pub", "*.bak.pub"]
+      symlinks = "follow-within-directory"
+      permissions = "refuse"
+
+      [labels]
+      "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU" = "Prod bastion"
+
+      [gpg]
+      home = "/home/user/.gnupg-ssh"
+      pinentry_mode = "loopback"
+
+      [pkcs11]
+      module = "/usr/lib/libykcs11.so"
+      token = "YubiKey PIV #12345678"
+
+      [pass]
+      prefix = "ssh"
+      store = "/home/user/.password-store"
+
+      [tpm]
+      tcti = "device:/dev/tpmrm0"
+      parent = 0x81000002
+
+      [stores]
+      priorities = { gpg-agent = 50, upstream = -1 }
+      on_conflict = "fallback"
+
+      [rsa]
+      min_bits = 3072
+      allow_weak = ["SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU"]
+      algorithms = ["rsa-sha2-512", "rsa-sha2-256"]
+
+      [forwarding]
+      socket = "/run/user/1000/ssh-gpg-agent-forward.sock"
+      keys = ["SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU"]
+
+      [destinations]
+      known_hosts = ["~/.ssh/known_hosts"]
+
+      [destinations.keys]
+      "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU" = [
+        "*.example.com",
+        "!untrusted.example.com",
+      ]
+
+      [prompt]
+      methods = ["pinentry-gui", "tty"]
+
+      [[sockets]]
+      path = "/srv/backup/ssh-gpg-agent.sock"
+      policy = "forwarding"
+      group = "backup"
+
+      [[hosts]]
+      hosts = ["github.com"]
+      keys = ["id_github", "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU"]
+
+      [cache]
+      ttl = 300
+
+      [hardening]
+      lock_memory = true
+
+      [keys.id_rsa]
+      enabled = false
+
+      [keys.id_ecdsa]
+      confirm = true
+      protocol = "cms"
+      decryption_keys = ["0x974F33B637ECF782"]
+      allowed_users = [1000]
+      allowed_programs = ["ssh", "/usr/bin/rsync"]
+
+      [keys.id_tpm]
+      pcrs = [0, 7]
+      label = "GitHub personal"
+    "#;
+    let config = Config::parse(toml)?;
+    assert_eq!(
+      config.directories,
+      vec![
+        PathBuf::from("/home/user/.ssh"),
+        PathBuf::from("/home/user/work-keys")
+      ]
+    );
+    assert_eq!(config.discovery.include, vec!["id_*".to_string()]);
+    assert_eq!(
+      config.discovery.exclude,
+      vec!["*-cert.pub".to_string(), "*.bak.pub".to_string()]
+    );
+    assert_eq!(config.discovery.symlinks, SymlinkPolicy::FollowWithinDirectory);
+    assert_eq!(config.discovery.permissions, PermissionPolicy::Refuse);
+    assert_eq!(config.socket_mode, Some(0o660));
+    assert_eq!(config.socket_group.as_deref(), Some("backup"));
+    assert_eq!(config.listen_tcp.as_deref(), Some("127.0.0.1:6666"));
+    assert_eq!(config.on_add, OnAdd::Memory);
+    assert_eq!(config.on_remove, OnRemove::Rename);
+    assert_eq!(config.log_sink, LogSink::Journald);
+    assert!(config.allow_sha1);
+    assert!(config.verify_signatures);
+    assert!(config.reject_other_users);
+    assert_eq!(config.backend, Backend::Gpg);
+    assert!(config.gpg_agent_keys);
+    assert!(config.card_keys);
+    assert_eq!(
+      config.pkcs11.module.as_deref(),
+      Some(Path::new("/usr/lib/libykcs11.so"))
+    );
+    assert_eq!(config.pkcs11.token.as_deref(), Some("YubiKey PIV #12345678"));
+    assert_eq!(
+      config.pass.directory(),
+      Some(PathBuf::from("/home/user/.password-store/ssh"))
+    );
+    assert_eq!(config.tpm.tcti.as_deref(), Some("device:/dev/tpmrm0"));
+    assert_eq!(config.tpm.parent, 0x81000002);
+    assert_eq!(config.stores.priorities.get("gpg-agent"), Some(&50));
+    assert_eq!(config.stores.priorities.get("upstream"), Some(&-1));
+    assert_eq!(config.stores.on_conflict, OnConflict::Fallback);
+    assert_eq!(config.rsa.min_bits, 3072);
+    assert_eq!(config.rsa.allow_weak, vec![Fingerprint::sha256(b"")]);
+    assert_eq!(
+      config.rsa.algorithms,
+      vec![
+        RsaSignatureAlgorithm::RsaSha2_512,
+        RsaSignatureAlgorithm::RsaSha2_256
+      ]
+    );
+    assert_eq!(
+      config.forwarding.socket,
+      Some(PathBuf::from("/run/user/1000/ssh-gpg-agent-forward.sock"))
+    );
+    assert_eq!(config.forwarding.keys, vec![Fingerprint::sha256(b"")]);
+    assert_eq!(
+      config.destinations.known_hosts,
+      vec![expand_tilde(PathBuf::from("~/.ssh/known_hosts"))]
+    );
+    assert_eq!(
+      config.destinations.keys.get(&Fingerprint::sha256(b"")),
+      Some(&vec!["*.example.com".to_string(), "!untrusted.example.com".to_string()])
+    );
+    assert_eq!(
+      config.sockets,
+      vec![SocketConfig {
+        path: PathBuf::from("/srv/backup/ssh-gpg-agent.sock"),
+        policy: SocketPolicy::Forwarding,
+        mode: None,
+        group: Some("backup".to_string()),
+      }]
+    );
+    assert_eq!(config.hosts.len(), 1);
+    assert_eq!(config.hosts[0].hosts, vec!["github.com".to_string()]);
+    assert!(config.hosts[0].selects(Some("id_github"), None, b"key")?);
+    assert!(config.hosts[0].selects(None, None, b"")?);
+    assert!(!config.hosts[0].selects(Some("id_rsa"), None, b"key")?);
+    assert_eq!(config.key("id_tpm").pcrs, vec![0, 7]);
+    assert_eq!(config.key("id_tpm").label.as_deref(), Some("GitHub personal"));
+    assert_eq!(config.key_label(None, b"")?, Some("Prod bastion"));
+    assert_eq!(config.confirm, vec![Fingerprint::sha256(b"")]);
+    assert_eq!(
+      config.usage_file,
+      Some(PathBuf::from("/home/user/.local/state/ssh-gpg-agent/usage.toml"))
+    );
+    assert_eq!(
+      config.index_file,
+      Some(PathBuf::from("/home/user/.cache/ssh-gpg-agent/index.toml"))
+    );
+    assert_eq!(config.ssh_config, Some(PathBuf::from("/home/user/.ssh/config")));
+    assert_eq!(config.request_timeout, 120);
+    assert_eq!(config.upstream_timeout(), Some(Duration::from_secs(30)));
+    assert_eq!(config.max_identities, 5);
+    assert_eq!(config.idle_lock, 900);
+    assert_eq!(config.gpg.home, Some(PathBuf::from("/home/user/.gnupg-ssh")));
+    assert_eq!(config.gpg.pinentry_mode, PinentryMode::Loopback);
+    assert!(!config.gpg.try_all_secrets);
+    assert_eq!(
+      config.prompt.methods,
+      vec![PromptMethod::PinentryGui, PromptMethod::Tty]
+    );
+    assert_eq!(config.cache.ttl, 300);
+    assert_eq!(config.cache.max_entries, 16);
+    assert!(config.hardening.enabled);
+    assert!(config.hardening.lock_memory);
+    assert!(!config.key("id_rsa").enabled);
+    assert!(config.key("id_ecdsa").enabled);
+    assert!(config.key("id_ecdsa").confirm);
+    assert_eq!(config.key("id_ecdsa").protocol, Some(Protocol::Cms));
+    assert_eq!(
+      config.key("id_ecdsa").decryption_keys,
+      vec!["0x974F33B637ECF782".to_string()]
+    );
+    assert_eq!(config.key("id_ecdsa").allowed_users, vec![1000]);
+    assert_eq!(
+      config.key("id_ecdsa").allowed_programs,
+      vec!["ssh".to_string(), "/usr/bin/rsync".to_string()]
+    );
+    assert_eq!(config.key("id_ed25519"), KeyConfig::default());
+    Ok(())
+  }
+
+  /// Check that unknown fields are rejected.
+  #[test]
+  fn parse_unknown_field() {
+    assert!(Config::parse("foo = 42").is_err());
+  }
+
+  /// Check that malformed fingerprints are rejected.
+  #[test]
+  fn parse_invalid_fingerprint() {
+    assert!(Config::parse(r#"confirm = ["SHA256:foo"]"#).is_err());
+  }
+}
@@ -0,0 +1,471 @@
+// constraint.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Support for constraints on identities added at runtime, such as the
+//! lifetime requested via `ssh-add -t`, the confirmation requested via
+//! `ssh-add -c`, and the destination restrictions requested via
+//! `ssh-add -h`.
+//!
+//! On the wire, the constraints simply follow the identity until the
+//! end of the message, without being preceded by their count. That is
+//! not what `ssh-agent-lib` expects, so we decode such messages
+//! ourselves.
+
+use std::str::from_utf8;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use serde::Deserialize as _;
+
+use ssh_agent_lib::proto::de::Deserializer;
+use ssh_agent_lib::proto::message::AddIdentity;
+use ssh_agent_lib::proto::message::AddIdentityConstrained;
+use ssh_agent_lib::proto::message::KeyConstraint;
+
+use crate::hosts::matches_user;
+use crate::openssh::read_string;
+use crate::openssh::read_u32;
+use crate::session::Binding;
+
+
+/// The type of the message adding an identity with constraints.
+pub const SSH_AGENTC_ADD_ID_CONSTRAINED: u8 = 25;
+
+/// The constraint limiting the lifetime of an identity.
+const SSH_AGENT_CONSTRAIN_LIFETIME: u8 = 1;
+/// The constraint requiring confirmation of each usage of an identity.
+const SSH_AGENT_CONSTRAIN_CONFIRM: u8 = 2;
+/// The constraint limiting the number of signatures made with an
+/// identity.
+const SSH_AGENT_CONSTRAIN_MAXSIGN: u8 = 3;
+/// A constraint defined by an extension.
+const SSH_AGENT_CONSTRAIN_EXTENSION: u8 = 255;
+
+/// The name of the constraint extension restricting the destinations
+/// an identity may be used for.
+const RESTRICT_DESTINATION_EXTENSION: &[u8] = b"restrict-destination-v00@openssh.com";
+
+
+/// Convert the given string, which is absent if empty, into a `String`.
+fn optional_string(string: &[u8]) -> Result<Option<String>> {
+  if string.is_empty() {
+    Ok(None)
+  } else {
+    let string = from_utf8(string).context("string is not valid UTF-8")?;
+    Ok(Some(string.to_string()))
+  }
+}
+
+
+/// A host along the path of a connection, as referred to by a
+/// destination constraint.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Hop {
+  /// The pattern the name of the user authenticating to the host has
+  /// to match, if any.
+  pub user: Option<String>,
+  /// The name of the host. A hop without one refers to the local
+  /// system.
+  pub host: Option<String>,
+  /// The keys of the host, along with whether each is that of a
+  /// certificate authority.
+  pub keys: Vec<(Vec<u8>, bool)>,
+}
+
+impl Hop {
+  /// Parse a hop in its wire format.
+  fn parse(mut data: &[u8]) -> Result<Self> {
+    let user = optional_string(read_string(&mut data)?)?;
+    let host = optional_string(read_string(&mut data)?)?;
+    let _reserved = read_string(&mut data)?;
+
+    let mut keys = Vec::new();
+    while !data.is_empty() {
+      let key = read_string(&mut data)?.to_vec();
+      let (&is_ca, rest) = data
+        .split_first()
+        .with_context(|| "host key lacks certificate authority flag")?;
+      let () = keys.push((key, is_ca != 0));
+      data = rest;
+    }
+
+    let hop = Self { user, host, keys };
+    Ok(hop)
+  }
+
+  /// Check whether the host with the given key is this hop.
+  ///
+  /// Host certificates are not supported, so certificate authority keys
+  /// never match.
+  fn matches(&self, host_key: &[u8]) -> bool {
+    self
+      .keys
+      .iter()
+      .any(|(key, is_ca)| !is_ca && key.as_slice() == host_key)
+  }
+}
+
+
+/// A constraint permitting usage of an identity on the way from one
+/// host to another.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DestinationConstraint {
+  /// The host the connection originates from.
+  pub from: Hop,
+  /// The host the connection is made to.
+  pub to: Hop,
+}
+
+impl DestinationConstraint {
+  /// Parse a constraint in its wire format.
+  fn parse(mut data: &[u8]) -> Result<Self> {
+    let from = Hop::parse(read_string(&mut data)?).context("source host is malformed")?;
+    let to = Hop::parse(read_string(&mut data)?).context("destination host is malformed")?;
+    let _reserved = read_string(&mut data)?;
+    ensure!(data.is_empty(), "constraint is followed by trailing data");
+    Ok(Self { from, to })
+  }
+
+  /// Check whether the constraint permits the hop from the host with
+  /// the given key (or the local system) to the one with the provided
+  /// key (or any host) as the given user, if known.
+  fn permits(&self, from: Option<&[u8]>, to: Option<&[u8]>, user: Option<&str>) -> bool {
+    let from = match from {
+      Some(from) => self.from.matches(from),
+      None => self.from.host.is_none() && self.from.keys.is_empty(),
+    };
+    let to = to.map(|to| self.to.matches(to)).unwrap_or(true);
+    let user = match (&self.to.user, user) {
+      (Some(pattern), Some(user)) => matches_user(pattern, user),
+      _ => true,
+    };
+    from && to && user
+  }
+}
+
+
+/// Check that the given destination constraints permit usage of an
+/// identity via a connection bound to the provided sessions, following
+/// the logic of OpenSSH's agent.
+///
+/// The user is only known when signing a user authentication request,
+/// in which case the connection has to be bound for authentication.
+/// Otherwise, a connection that got forwarded is only permitted if the
+/// identity may be used for onward connections from the last host.
+pub fn check_destinations(
+  constraints: &[DestinationConstraint],
+  bindings: &[Binding],
+  user: Option<&str>,
+) -> Result<()> {
+  for (idx, binding) in bindings.iter().enumerate() {
+    let last = idx + 1 == bindings.len();
+    if last && binding.forwarding && user.is_some() {
+      bail!("connection used for authentication got forwarded")
+    } else if !last && !binding.forwarding {
+      bail!("connection got forwarded via session bound for authentication")
+    }
+
+    let from = idx
+      .checked_sub(1)
+      .map(|prev| bindings[prev].host_key.as_slice());
+    let user = if last { user } else { None };
+    ensure!(
+      constraints
+        .iter()
+        .any(|constraint| constraint.permits(from, Some(&binding.host_key), user)),
+      "host with key {} is not a permitted destination",
+      binding.host_fingerprint()
+    );
+  }
+
+  if let Some(last) = bindings.last() {
+    if last.forwarding && user.is_none() {
+      ensure!(
+        constraints
+          .iter()
+          .any(|constraint| constraint.permits(Some(&last.host_key), None, None)),
+        "usage beyond host with key {} is not permitted",
+        last.host_fingerprint()
+      );
+    }
+  }
+  Ok(())
+}
+
+
+/// A constraint on an identity added at runtime that we support.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Constraint {
+  /// The identity is to be removed once the given time elapsed.
+  Lifetime(Duration),
+  /// Every usage of the identity has to be confirmed by the user.
+  Confirm,
+  /// The identity may only be used for the given destinations.
+  Destinations(Vec<DestinationConstraint>),
+}
+
+impl Constraint {
+  /// Parse the data of a constraint extension.
+  fn parse_extension(mut data: &[u8]) -> Result<Self> {
+    let name = read_string(&mut data)?;
+    match name {
+      RESTRICT_DESTINATION_EXTENSION => {
+        let mut constraints = read_string(&mut data)?;
+        ensure!(data.is_empty(), "constraint is followed by trailing data");
+
+        let mut destinations = Vec::new();
+        while !constraints.is_empty() {
+          let constraint = DestinationConstraint::parse(read_string(&mut constraints)?)?;
+          let () = destinations.push(constraint);
+        }
+        ensure!(!destinations.is_empty(), "no destinations specified");
+        Ok(Self::Destinations(destinations))
+      },
+      _ => bail!(
+        "constraint extension {} is not supported",
+        String::from_utf8_lossy(name)
+      ),
+    }
+  }
+}
+
+impl TryFrom<&KeyConstraint> for Constraint {
+  type Error = anyhow::Error;
+
+  fn try_from(constraint: &KeyConstraint) -> Result<Self> {
+    match constraint.constraint_type {
+      SSH_AGENT_CONSTRAIN_LIFETIME => {
+        let mut data = constraint.constraint_data.as_slice();
+        let secs = read_u32(&mut data).context("lifetime constraint is malformed")?;
+        Ok(Self::Lifetime(Duration::from_secs(secs.into())))
+      },
+      SSH_AGENT_CONSTRAIN_CONFIRM => Ok(Self::Confirm),
+      SSH_AGENT_CONSTRAIN_MAXSIGN => bail!("maximum signature constraint is not supported"),
+      SSH_AGENT_CONSTRAIN_EXTENSION => Self::parse_extension(&constraint.constraint_data)
+        .context("destination constraint is malformed"),
+      other => bail!("constraint of type {other} is not supported"),
+    }
+  }
+}
+
+
+/// Decode the body of an `SSH_AGENTC_ADD_ID_CONSTRAINED` message.
+///
+/// The data of each constraint is kept in its wire format. As the
+/// format of the data of constraint extensions depends on the
+/// extension, all data following one is attributed to it.
+pub fn decode_add_id_constrained(data: &[u8]) -> Result<AddIdentityConstrained> {
+  let mut deserializer = Deserializer::from_reader(data);
+  let identity =
+    AddIdentity::deserialize(&mut deserializer).context("failed to decode identity")?;
+  let mut data = deserializer.to_reader();
+
+  let mut constraints = Vec::new();
+  while let Some((&constraint_type, rest)) = data.split_first() {
+    let len = match constraint_type {
+      SSH_AGENT_CONSTRAIN_LIFETIME | SSH_AGENT_CONSTRAIN_MAXSIGN => 4,
+      SSH_AGENT_CONSTRAIN_CONFIRM => 0,
+      SSH_AGENT_CONSTRAIN_EXTENSION => rest.len(),
+      other => bail!("encountered unknown constraint of type {other}"),
+    };
+    ensure!(
+      rest.len() >= len,
+      "constraint of type {constraint_type} is truncated"
+    );
+    let (constraint_data, rest) = rest.split_at(len);
+    let constraint = KeyConstraint {
+      constraint_type,
+      constraint_data: constraint_data.to_vec(),
+    };
+    let () = constraints.push(constraint);
+    data = rest;
+  }
+
+  let constrained = AddIdentityConstrained {
+    identity,
+    constraints,
+  };
+  Ok(constrained)
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use ssh_agent_lib::proto::private_key::Ed25519PrivateKey;
+  use ssh_agent_lib::proto::private_key::PrivateKey;
+  use ssh_agent_lib::proto::to_bytes;
+
+
+  /// Create the wire representation of an identity to add.
+  fn identity() -> (AddIdentity, Vec<u8>) {
+    let identity = AddIdentity {
+      privkey: PrivateKey::Ed25519(Ed25519PrivateKey {
+        enc_a: vec![1; 32],
+        k_enc_a: vec![2; 64],
+      }),
+      comment: "test-key".to_string(),
+    };
+    let data = to_bytes(&identity).unwrap();
+    (identity, data)
+  }
+
+  /// Encode the given data as a string in the wire format.
+  fn string(data: &[u8]) -> Vec<u8> {
+    let len = u32::try_from(data.len()).unwrap();
+    [len.to_be_bytes().as_slice(), data].concat()
+  }
+
+  /// Encode a hop in the wire format.
+  fn hop(user: &str, host: &str, keys: &[&[u8]]) -> Vec<u8> {
+    let mut hop = [string(user.as_bytes()), string(host.as_bytes()), string(b"")].concat();
+    for key in keys {
+      let () = hop.extend(string(key));
+      let () = hop.push(0);
+    }
+    string(&hop)
+  }
+
+  /// Create a binding of a session with the host with the given key.
+  fn binding(host_key: &[u8], forwarding: bool) -> Binding {
+    Binding {
+      host_key: host_key.to_vec(),
+      session_id: b"session".to_vec(),
+      signature: Vec::new(),
+      forwarding,
+    }
+  }
+
+
+  /// Check that we can decode identities with constraints.
+  #[test]
+  fn constraint_decoding() -> Result<()> {
+    let (identity, data) = identity();
+    let constrained = decode_add_id_constrained(&data)?;
+    assert_eq!(constrained.identity, identity);
+    assert_eq!(constrained.constraints, Vec::new());
+
+    let data = [data.as_slice(), b"\x01\x00\x00\x0e\x10\x02"].concat();
+    let constrained = decode_add_id_constrained(&data)?;
+    assert_eq!(constrained.identity, identity);
+    assert_eq!(constrained.constraints.len(), 2);
+    assert_eq!(
+      Constraint::try_from(&constrained.constraints[0])?,
+      Constraint::Lifetime(Duration::from_secs(3600))
+    );
+    assert_eq!(constrained.constraints[1].constraint_type, SSH_AGENT_CONSTRAIN_CONFIRM);
+    assert_eq!(
+      Constraint::try_from(&constrained.constraints[1])?,
+      Constraint::Confirm
+    );
+
+    assert!(decode_add_id_constrained(&data[..data.len() - 3]).is_err());
+    assert!(decode_add_id_constrained(&[data.as_slice(), b"\x04"].concat()).is_err());
+    Ok(())
+  }
+
+  /// Check that we can decode destination constraints.
+  #[test]
+  fn destination_constraint_decoding() -> Result<()> {
+    let constraint = [hop("", "", &[]), hop("git", "github.com", &[b"key"]), string(b"")].concat();
+    let data = [
+      string(RESTRICT_DESTINATION_EXTENSION),
+      string(&string(&constraint)),
+    ]
+    .concat();
+    let constraint = KeyConstraint {
+      constraint_type: SSH_AGENT_CONSTRAIN_EXTENSION,
+      constraint_data: data.clone(),
+    };
+    let expected = DestinationConstraint {
+      from: Hop::default(),
+      to: Hop {
+        user: Some("git".to_string()),
+        host: Some("github.com".to_string()),
+        keys: vec![(b"key".to_vec(), false)],
+      },
+    };
+    assert_eq!(
+      Constraint::try_from(&constraint)?,
+      Constraint::Destinations(vec![expected])
+    );
+
+    let constraint = KeyConstraint {
+      constraint_type: SSH_AGENT_CONSTRAIN_EXTENSION,
+      constraint_data: [data.as_slice(), b"\x00"].concat(),
+    };
+    assert!(Constraint::try_from(&constraint).is_err());
+
+    let constraint = KeyConstraint {
+      constraint_type: SSH_AGENT_CONSTRAIN_EXTENSION,
+      constraint_data: [string(b"sk-provider@openssh.com"), string(b"")].concat(),
+    };
+    assert!(Constraint::try_from(&constraint).is_err());
+    Ok(())
+  }
+
+  /// Check that destination constraints are enforced along the path of
+  /// a connection.
+  #[test]
+  fn destination_checking() {
+    let hop = |user: Option<&str>, host: Option<&str>, key: Option<&[u8]>| Hop {
+      user: user.map(str::to_string),
+      host: host.map(str::to_string),
+      keys: key.into_iter().map(|key| (key.to_vec(), false)).collect(),
+    };
+    let constraints = [
+      DestinationConstraint {
+        from: Hop::default(),
+        to: hop(Some("git"), Some("jump"), Some(b"jump-key")),
+      },
+      DestinationConstraint {
+        from: hop(None, Some("jump"), Some(b"jump-key")),
+        to: hop(None, Some("target"), Some(b"target-key")),
+      },
+    ];
+
+    let jump = binding(b"jump-key", false);
+    let target = binding(b"target-key", false);
+    let forwarded = binding(b"jump-key", true);
+
+    let () = check_destinations(&constraints, &[], None).unwrap();
+    let bindings = [jump.clone()];
+    let () = check_destinations(&constraints, &bindings, Some("git")).unwrap();
+    assert!(check_destinations(&constraints, &bindings, Some("root")).is_err());
+    let bindings = [target.clone()];
+    assert!(check_destinations(&constraints, &bindings, Some("git")).is_err());
+
+    let bindings = [forwarded.clone()];
+    let () = check_destinations(&constraints, &bindings, None).unwrap();
+    assert!(check_destinations(&constraints, &bindings, Some("git")).is_err());
+    let bindings = [forwarded.clone(), target.clone()];
+    let () = check_destinations(&constraints, &bindings, Some("root")).unwrap();
+    // The connection has to be forwarded on all but the last hop.
+    let bindings = [jump, target];
+    assert!(check_destinations(&constraints, &bindings, Some("root")).is_err());
+    // Forwarding beyond the target is not permitted.
+    let bindings = [forwarded, binding(b"target-key", true)];
+    assert!(check_destinations(&constraints, &bindings, None).is_err());
+  }
+}
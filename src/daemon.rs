@@ -0,0 +1,272 @@
+// daemon.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Support for running the agent as a daemon in the background, for
+//! being started by systemd's socket activation, and for notifying
+//! systemd about the agent's state.
+
+use std::env::remove_var;
+use std::env::var;
+use std::fs::read_to_string;
+use std::fs::write;
+use std::io::Error as IoError;
+use std::os::unix::io::FromRawFd as _;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::SocketAddr;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::process::id;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use libc::chdir;
+use libc::close;
+use libc::dup2;
+use libc::fcntl;
+use libc::fork;
+use libc::kill;
+use libc::open;
+use libc::pid_t;
+use libc::setsid;
+use libc::FD_CLOEXEC;
+use libc::F_SETFD;
+use libc::O_RDWR;
+use libc::SIGTERM;
+use libc::STDERR_FILENO;
+use libc::STDIN_FILENO;
+use libc::STDOUT_FILENO;
+
+use crate::transport::abstract_addr;
+use crate::transport::abstract_name;
+
+
+/// The first file descriptor systemd passes in when activating a
+/// service via one of its sockets.
+const LISTEN_FDS_START: RawFd = 3;
+
+
+/// Convert the return value of a libc function into a `Result`.
+fn check(result: i32) -> Result<i32, IoError> {
+  if result >= 0 {
+    Ok(result)
+  } else {
+    Err(IoError::last_os_error())
+  }
+}
+
+
+/// Detach the process from its controlling terminal and continue in
+/// the background.
+///
+/// In the parent process the child's process ID is returned and the
+/// parent is expected to exit. In the child, which continues as the
+/// daemon, `None` is returned. The child runs in a new session, with
+/// the root directory as working directory and standard input and
+/// output streams connected to `/dev/null`.
+///
+/// This function has to be called before any threads are spawned, as
+/// only the calling thread continues to exist in the child.
+pub fn daemonize() -> Result<Option<u32>> {
+  // SAFETY: `fork` has no memory safety related preconditions. We are
+  //         still single threaded, so the child is in a consistent
+  //         state.
+  let pid = check(unsafe { fork() }).context("failed to fork process")?;
+  if pid > 0 {
+    return Ok(Some(pid as u32))
+  }
+
+  // SAFETY: `setsid` has no memory safety related preconditions.
+  let _sid = check(unsafe { setsid() }).context("failed to create new session")?;
+  // SAFETY: The provided path is a valid NUL terminated string.
+  let _ = check(unsafe { chdir(b"/\0".as_ptr().cast()) })
+    .context("failed to change working directory")?;
+  // SAFETY: The provided path is a valid NUL terminated string.
+  let null = check(unsafe { open(b"/dev/null\0".as_ptr().cast(), O_RDWR) })
+    .context("failed to open /dev/null")?;
+  for fd in [STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO] {
+    // SAFETY: Both file descriptors are valid.
+    let _ = check(unsafe { dup2(null, fd) })
+      .context("failed to redirect standard stream to /dev/null")?;
+  }
+  if null > STDERR_FILENO {
+    // SAFETY: `null` is a file descriptor we own and no longer use.
+    let _ = unsafe { close(null) };
+  }
+  Ok(None)
+}
+
+
+/// Write the ID of the current process to the file at the given path.
+pub fn write_pid_file(path: &Path) -> Result<()> {
+  write(path, format!("{}\n", id()))
+    .with_context(|| format!("failed to write PID file {}", path.display()))
+}
+
+
+/// Read the process ID from the given PID file.
+pub fn read_pid_file(path: &Path) -> Result<u32> {
+  let content =
+    read_to_string(path).with_context(|| format!("failed to read PID file {}", path.display()))?;
+  content
+    .trim()
+    .parse()
+    .with_context(|| format!("PID file {} does not contain a valid process ID", path.display()))
+}
+
+
+/// Ask the process with the given ID to terminate, by sending it
+/// `SIGTERM`.
+pub fn terminate(pid: u32) -> Result<()> {
+  // Zero and negative IDs refer to process groups.
+  let pid = pid_t::try_from(pid)
+    .ok()
+    .filter(|pid| *pid > 0)
+    .with_context(|| format!("invalid process ID {pid}"))?;
+  // SAFETY: `kill` has no memory safety related preconditions.
+  let _ = check(unsafe { kill(pid, SIGTERM) })
+    .with_context(|| format!("failed to terminate process {pid}"))?;
+  Ok(())
+}
+
+
+/// Retrieve the Unix domain sockets passed in by systemd's socket
+/// activation, along with their names (as set via
+/// `FileDescriptorName=`), if any.
+///
+/// The environment variables describing the sockets are cleared, so
+/// that they are not inherited by child processes.
+pub fn activated_sockets() -> Result<Vec<(String, UnixListener)>> {
+  let pid = var("LISTEN_PID").ok();
+  let fds = var("LISTEN_FDS").ok();
+  let names = var("LISTEN_FDNAMES").ok();
+  for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+    let () = remove_var(name);
+  }
+
+  // The sockets may be intended for a parent process of ours.
+  if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(id()) {
+    return Ok(Vec::new())
+  }
+
+  let count = fds
+    .as_deref()
+    .unwrap_or("0")
+    .parse::<RawFd>()
+    .context("LISTEN_FDS does not contain a valid file descriptor count")?;
+  let names = names.unwrap_or_default();
+  let mut names = names.split(':');
+
+  (LISTEN_FDS_START..LISTEN_FDS_START + count)
+    .map(|fd| {
+      let name = names.next().unwrap_or_default().to_string();
+      // Setting the flag also makes sure the descriptor is open.
+      // SAFETY: `fcntl` has no memory safety related preconditions.
+      let _ = check(unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC) })
+        .with_context(|| format!("failed to set up activated socket {name:?}"))?;
+      // SAFETY: `fd` is an open file descriptor systemd passed to us,
+      //         which nothing else owns.
+      let listener = unsafe { UnixListener::from_raw_fd(fd) };
+      let _addr = listener
+        .local_addr()
+        .with_context(|| format!("activated socket {name:?} is no Unix domain socket"))?;
+      Ok((name, listener))
+    })
+    .collect()
+}
+
+
+/// A means for notifying systemd about the agent's state, as set up by
+/// systemd for services of `Type=notify` or with `WatchdogSec=` set.
+#[derive(Debug)]
+pub struct Notifier {
+  /// The socket used for sending notifications.
+  socket: UnixDatagram,
+  /// The address of systemd's notification socket.
+  addr: SocketAddr,
+  /// The interval in which systemd expects to be told that the agent
+  /// is still alive, if its watchdog is enabled.
+  watchdog: Option<Duration>,
+}
+
+impl Notifier {
+  /// Set up notifications of systemd, if it asked for them.
+  ///
+  /// Like with socket activation, the environment variables describing
+  /// the notification socket and watchdog are cleared, so that they are
+  /// not inherited by child processes.
+  pub fn from_env() -> Result<Option<Self>> {
+    let path = var("NOTIFY_SOCKET").ok();
+    let usec = var("WATCHDOG_USEC").ok();
+    let pid = var("WATCHDOG_PID").ok();
+    for name in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+      let () = remove_var(name);
+    }
+
+    let path = match path {
+      Some(path) if !path.is_empty() => path,
+      _ => return Ok(None),
+    };
+    let path = Path::new(&path);
+    let addr = match abstract_name(path) {
+      Some(name) => abstract_addr(name),
+      None => SocketAddr::from_pathname(path),
+    }
+    .with_context(|| format!("invalid systemd notification socket {}", path.display()))?;
+    let socket = UnixDatagram::unbound().context("failed to create notification socket")?;
+
+    // The watchdog may be intended for a parent process of ours.
+    let watchdog = match pid {
+      Some(pid) if pid.parse::<u32>().ok() != Some(id()) => None,
+      _ => usec
+        .map(|usec| {
+          usec
+            .parse::<u64>()
+            .context("WATCHDOG_USEC does not contain a valid interval")
+        })
+        .transpose()?
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros),
+    };
+
+    let slf = Self {
+      socket,
+      addr,
+      watchdog,
+    };
+    Ok(Some(slf))
+  }
+
+  /// Send the given state (e.g., `READY=1`) to systemd.
+  pub fn notify(&self, state: &str) -> Result<()> {
+    let _count = self
+      .socket
+      .send_to_addr(state.as_bytes(), &self.addr)
+      .with_context(|| format!("failed to notify systemd of state {state:?}"))?;
+    Ok(())
+  }
+
+  /// Retrieve the interval in which systemd's watchdog expects to be
+  /// notified, if it is enabled.
+  pub fn watchdog(&self) -> Option<Duration> {
+    self.watchdog
+  }
+}
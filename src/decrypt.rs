@@ -0,0 +1,1013 @@
+// decrypt.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Decryption of the GPG encrypted private keys the agent manages.
+
+use std::fmt::Debug;
+use std::fs::read;
+use std::io::BufRead as _;
+use std::io::BufReader;
+use std::io::Read as _;
+use std::io::Write;
+use std::path::Path;
+use std::process::Child;
+use std::process::Command;
+use std::process::Output;
+use std::process::Stdio;
+use std::result::Result as StdResult;
+use std::thread::spawn;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Error;
+use anyhow::Result;
+
+#[cfg(feature = "gpgme")]
+use gpgme::Context;
+#[cfg(feature = "gpgme")]
+use gpgme::Error as GpgError;
+#[cfg(feature = "gpgme")]
+use gpgme::PassphraseRequest;
+#[cfg(feature = "gpgme")]
+use gpgme::PinentryMode as GpgmePinentryMode;
+#[cfg(feature = "gpgme")]
+use gpgme::Protocol as GpgmeProtocol;
+#[cfg(feature = "gpgme")]
+use gpgme_sys::gpgme_cancel_async;
+#[cfg(feature = "gpgme")]
+use gpgme_sys::gpgme_ctx_t;
+
+#[cfg(feature = "gpgme")]
+use tracing::warn;
+
+use crate::agent::PASSPHRASE_ATTEMPTS;
+use crate::cancel::kill_on_cancel;
+#[cfg(feature = "gpgme")]
+use crate::cancel::on_cancel;
+use crate::cancel::Registration;
+use crate::config::Backend;
+use crate::config::Config;
+use crate::config::GpgOptions;
+use crate::config::PinentryMode;
+use crate::config::Protocol;
+use crate::error::DecryptError;
+use crate::files::PemPrivateKey;
+use crate::prompt;
+#[cfg(feature = "sequoia")]
+use crate::sequoia::Sequoia;
+
+
+/// The name of the GnuPG command line program.
+pub const GPG: &str = "gpg";
+/// The name of the program for communicating with the GnuPG agent.
+const GPG_CONNECT_AGENT: &str = "gpg-connect-agent";
+/// The name of the GnuPG command line program for CMS (S/MIME).
+const GPGSM: &str = "gpgsm";
+/// The prefix of status lines emitted by `gpg`.
+const STATUS_PREFIX: &str = "[GNUPG:] ";
+/// The status `gpg` reports when given an incorrect passphrase.
+const BAD_PASSPHRASE_STATUS: &str = "BAD_PASSPHRASE";
+/// The OpenPGP packet tag of a symmetric-key encrypted session key.
+const SKESK_TAG: u8 = 3;
+/// The length of a (long) key ID in hexadecimal characters.
+const KEY_ID_LEN: usize = 16;
+
+
+/// Check whether the given OpenPGP message is symmetrically encrypted
+/// (as done by `gpg --symmetric`), i.e., whether it starts with a
+/// symmetric-key encrypted session key packet.
+///
+/// Only binary messages are recognized; ASCII armored ones are treated
+/// as not symmetrically encrypted.
+fn is_symmetric(data: &[u8]) -> bool {
+  match data.first() {
+    // New format packet header.
+    Some(byte) if byte & 0xc0 == 0xc0 => byte & 0x3f == SKESK_TAG,
+    // Old format packet header.
+    Some(byte) if byte & 0x80 == 0x80 => (byte >> 2) & 0x0f == SKESK_TAG,
+    _ => false,
+  }
+}
+
+
+/// Decrypt the symmetrically encrypted file `file`, prompting the user
+/// for the passphrase.
+///
+/// `decrypt` performs the actual decryption with a given passphrase,
+/// returning `None` if the passphrase is incorrect.
+pub fn decrypt_symmetric<F, T>(file: &Path, mut decrypt: F) -> Result<T>
+where
+  F: FnMut(&str) -> Result<Option<T>>,
+{
+  let mut description = format!("Enter passphrase to decrypt {}", file.display());
+
+  for _ in 0..PASSPHRASE_ATTEMPTS {
+    let passphrase = prompt::passphrase(&description)?
+      .ok_or_else(|| anyhow!("passphrase entry for {} canceled", file.display()))?;
+    if let Some(result) = decrypt(&passphrase)? {
+      return Ok(result)
+    }
+    description = format!(
+      "Incorrect passphrase. Enter passphrase to decrypt {}",
+      file.display()
+    );
+  }
+  Err(anyhow!(
+    "failed to decrypt {}: too many incorrect passphrases",
+    file.display()
+  ))
+}
+
+
+/// Normalize a GPG key identifier, i.e., a fingerprint or key ID, for
+/// comparison purposes.
+fn normalize_key_id(id: &str) -> String {
+  let id = id.trim();
+  let id = id
+    .strip_prefix("0x")
+    .or_else(|| id.strip_prefix("0X"))
+    .unwrap_or(id);
+  id.chars()
+    .filter(|c| !c.is_whitespace())
+    .collect::<String>()
+    .to_ascii_uppercase()
+}
+
+
+/// Check whether two GPG key identifiers refer to the same key.
+///
+/// A key ID matches the fingerprint it is a suffix of.
+fn key_id_matches(lhs: &str, rhs: &str) -> bool {
+  let lhs = normalize_key_id(lhs);
+  let rhs = normalize_key_id(rhs);
+  let (short, long) = if lhs.len() <= rhs.len() {
+    (lhs, rhs)
+  } else {
+    (rhs, lhs)
+  };
+  short.len() >= KEY_ID_LEN && long.ends_with(&short)
+}
+
+
+/// Reduce the given candidates for the key used for decryption to the
+/// one that was used, if that can be told.
+///
+/// If a file is encrypted to multiple keys we have secret keys for,
+/// we have no way of knowing which one got used. The same is true for
+/// hidden recipients, which are reported with an all zero key ID.
+fn unambiguous_key(mut candidates: Vec<String>) -> Vec<String> {
+  let () = candidates.retain(|id| id.chars().any(|c| c != '0'));
+  if candidates.len() == 1 {
+    candidates
+  } else {
+    Vec::new()
+  }
+}
+
+
+/// Parse the given `gpg` or `gpgsm` colon delimited key listing into
+/// the fingerprints of the keys listed, each with the primary key's
+/// first, followed by those of its subkeys.
+fn listed_keys(colons: &str) -> Vec<Vec<String>> {
+  let mut keys = Vec::<Vec<String>>::new();
+  for fields in colons.lines().map(|line| line.split(':').collect::<Vec<_>>()) {
+    match fields.first() {
+      Some(&"pub") | Some(&"sec") | Some(&"crt") | Some(&"crs") => keys.push(Vec::new()),
+      Some(&"fpr") => {
+        if let (Some(key), Some(fpr)) = (keys.last_mut(), fields.get(9)) {
+          let () = key.push(fpr.to_string());
+        }
+      },
+      _ => (),
+    }
+  }
+  keys
+}
+
+
+/// Determine the fingerprints of the (sub)key with the given ID and of
+/// its primary key, in that order, as `gpg` reports them for the key
+/// used for decryption.
+///
+/// `keys` contains the fingerprints of candidate keys, each with the
+/// primary key's first, followed by those of its subkeys.
+fn key_and_primary_key(id: &str, keys: &[Vec<String>]) -> Option<Vec<String>> {
+  keys.iter().find_map(|fingerprints| {
+    let primary = fingerprints.first()?;
+    let key = fingerprints.iter().find(|fpr| key_id_matches(fpr, id))?;
+    Some(vec![key.clone(), primary.clone()])
+  })
+}
+
+
+/// A private key as decrypted by a [`Decryptor`].
+#[derive(Debug)]
+pub struct Decrypted {
+  /// The decrypted private key.
+  pub key: PemPrivateKey,
+  /// Identifiers (fingerprints or key IDs) of the GPG key used for
+  /// decryption, e.g., of the subkey as well as its primary key.
+  ///
+  /// Empty if the file was symmetrically encrypted or if the key could
+  /// not be determined unambiguously.
+  pub keys: Vec<String>,
+}
+
+impl Decrypted {
+  /// Create a `Decrypted` object for a private key decrypted using the
+  /// GPG key with the given identifiers.
+  pub fn new(key: PemPrivateKey, keys: Vec<String>) -> Self {
+    Self { key, keys }
+  }
+
+  /// Check whether the private key was decrypted using one of the GPG
+  /// keys with the given fingerprints or key IDs.
+  pub fn decrypted_with(&self, keys: &[String]) -> bool {
+    self
+      .keys
+      .iter()
+      .any(|used| keys.iter().any(|key| key_id_matches(key, used)))
+  }
+}
+
+impl From<PemPrivateKey> for Decrypted {
+  fn from(key: PemPrivateKey) -> Self {
+    Self::new(key, Vec::new())
+  }
+}
+
+
+/// A trait for objects that can decrypt a GPG encrypted private key.
+pub trait Decryptor
+where
+  Self: Debug + Send + Sync,
+{
+  /// Decrypt the private key stored in the given file, which is
+  /// encrypted using the provided protocol.
+  fn decrypt(&self, file: &Path, protocol: Protocol) -> StdResult<Decrypted, DecryptError>;
+
+  /// Check whether the private key stored in the given file could be
+  /// decrypted, i.e., whether a secret key for one of the recipients is
+  /// available, without actually decrypting it.
+  ///
+  /// `None` is returned if that cannot be told.
+  fn can_decrypt(
+    &self,
+    file: &Path,
+    protocol: Protocol,
+  ) -> StdResult<Option<bool>, DecryptError> {
+    let _ = (file, protocol);
+    Ok(None)
+  }
+
+  /// Check that the decryptor is operational, e.g., that it can reach
+  /// the services it relies on.
+  fn check(&self) -> StdResult<(), DecryptError> {
+    Ok(())
+  }
+}
+
+
+/// Check that the GnuPG agent can be reached (and is started if
+/// necessary).
+fn check_gpg_agent(options: &GpgOptions) -> Result<()> {
+  let mut command = Command::new(GPG_CONNECT_AGENT);
+  if let Some(home) = &options.home {
+    let _ = command.arg("--homedir").arg(home);
+  }
+  let output = command
+    .arg("/bye")
+    .stdin(Stdio::null())
+    .output()
+    .with_context(|| format!("failed to run {GPG_CONNECT_AGENT}"))?;
+  ensure!(
+    output.status.success(),
+    "failed to connect to GnuPG agent: {}",
+    String::from_utf8_lossy(&output.stderr).trim()
+  );
+  Ok(())
+}
+
+
+/// Create a `gpgme` context for the given protocol, configured as per
+/// the provided options.
+#[cfg(feature = "gpgme")]
+pub fn context(protocol: Protocol, options: &GpgOptions) -> Result<Context> {
+  let protocol = match protocol {
+    Protocol::OpenPgp => GpgmeProtocol::OpenPgp,
+    Protocol::Cms => GpgmeProtocol::Cms,
+  };
+  let mut gpg = Context::from_protocol(protocol).with_context(|| "failed to connect to GPG")?;
+
+  if let Some(home) = &options.home {
+    let home = home
+      .to_str()
+      .ok_or_else(|| anyhow!("GnuPG home directory {} is not valid UTF-8", home.display()))?;
+    let () = gpg
+      .set_engine_home_dir(home)
+      .with_context(|| format!("failed to use GnuPG home directory {home}"))?;
+  }
+
+  let mode = match options.pinentry_mode {
+    PinentryMode::Default => GpgmePinentryMode::Default,
+    PinentryMode::Ask => GpgmePinentryMode::Ask,
+    PinentryMode::Cancel => GpgmePinentryMode::Cancel,
+    PinentryMode::Error => GpgmePinentryMode::Error,
+    PinentryMode::Loopback => GpgmePinentryMode::Loopback,
+  };
+  if mode != GpgmePinentryMode::Default {
+    let () = gpg
+      .set_pinentry_mode(mode)
+      .with_context(|| "failed to set pinentry mode")?;
+  }
+  Ok(gpg)
+}
+
+
+/// Create a command invoking the GnuPG program `program`, configured as
+/// per the provided options.
+pub fn command(program: &str, options: &GpgOptions) -> Command {
+  let mut command = Command::new(program);
+  let _ = command.arg("--quiet").arg("--batch");
+  if let Some(home) = &options.home {
+    let _ = command.arg("--homedir").arg(home);
+  }
+  command
+}
+
+
+/// A raw `gpgme` context that can be cancelled from another thread.
+#[cfg(feature = "gpgme")]
+struct RawContext(gpgme_ctx_t);
+
+// SAFETY: The context is only ever used for `gpgme_cancel_async`,
+//         which is meant to be invoked from a different thread than
+//         the one performing the operation.
+#[cfg(feature = "gpgme")]
+unsafe impl Send for RawContext {}
+
+#[cfg(feature = "gpgme")]
+impl RawContext {
+  /// Cancel the operation pending on the context.
+  fn cancel(&self) {
+    // SAFETY: The context is valid for as long as the registration
+    //         invoking us exists, which is dropped before the context.
+    let _ = unsafe { gpgme_cancel_async(self.0) };
+  }
+}
+
+
+/// A decryptor using `gpgme` and, hence, the user's `gpg-agent`.
+///
+/// Symmetrically encrypted files are decrypted using loopback pinentry,
+/// with the agent prompting for the passphrase. The same happens for
+/// passphrases of secret keys if loopback pinentry is configured.
+#[cfg(feature = "gpgme")]
+#[derive(Clone, Debug, Default)]
+pub struct Gpgme {
+  /// The options to use for GnuPG.
+  options: GpgOptions,
+}
+
+#[cfg(feature = "gpgme")]
+impl Gpgme {
+  /// Create a decryptor using the given GnuPG options.
+  pub fn new(options: GpgOptions) -> Self {
+    if options.try_all_secrets {
+      warn!("Trying all secret keys is not supported by the gpgme backend; ignoring");
+    }
+    Self { options }
+  }
+
+  /// Resolve the ID of a recipient's (sub)key to the fingerprints of
+  /// the key and its primary key, falling back to the ID itself if the
+  /// key cannot be found.
+  fn resolve_key(gpg: &mut Context, id: &str) -> Vec<String> {
+    let fingerprints = gpg
+      .get_key(id)
+      .map(|key| {
+        key
+          .subkeys()
+          .filter_map(|subkey| subkey.fingerprint().ok().map(str::to_string))
+          .collect()
+      })
+      .unwrap_or_default();
+    key_and_primary_key(id, &[fingerprints]).unwrap_or_else(|| vec![id.to_string()])
+  }
+}
+
+#[cfg(feature = "gpgme")]
+impl Decryptor for Gpgme {
+  fn decrypt(&self, file: &Path, protocol: Protocol) -> StdResult<Decrypted, DecryptError> {
+    let input = read(file).with_context(|| format!("failed to read {}", file.display()))?;
+    let mut gpg = context(protocol, &self.options)?;
+    let raw = RawContext(gpg.as_raw());
+    let _cancel = on_cancel(move || raw.cancel());
+
+    if protocol == Protocol::OpenPgp && is_symmetric(&input) {
+      let () = gpg
+        .set_pinentry_mode(GpgmePinentryMode::Loopback)
+        .with_context(|| "failed to enable loopback pinentry")?;
+
+      decrypt_symmetric(file, |passphrase| {
+        let provider = |_request: PassphraseRequest<'_>, out: &mut dyn Write| {
+          let () = out.write_all(passphrase.as_bytes())?;
+          let () = out.write_all(b"\n")?;
+          Ok(())
+        };
+
+        let mut output = Vec::new();
+        let result = gpg.with_passphrase_provider(provider, |gpg| {
+          gpg.decrypt(input.as_slice(), &mut output)
+        });
+        match result {
+          Ok(_) => Ok(Some(Decrypted::from(PemPrivateKey::from(output)))),
+          Err(err)
+            if err.code() == GpgError::BAD_PASSPHRASE.code()
+              || err.code() == GpgError::DECRYPT_FAILED.code() =>
+          {
+            Ok(None)
+          },
+          Err(err) => Err(err).with_context(|| format!("failed to decrypt {}", file.display())),
+        }
+      })
+      .map_err(DecryptError::from)
+    } else {
+      // Only used with loopback pinentry, in which case we are
+      // responsible for asking for the secret key's passphrase.
+      let provider = |request: PassphraseRequest<'_>, out: &mut dyn Write| {
+        let key = request.user_id_hint().unwrap_or("<unknown>");
+        let mut description = format!(
+          "Enter passphrase for GPG key {key} to decrypt {}",
+          file.display()
+        );
+        if request.prev_attempt_failed {
+          description = format!("Incorrect passphrase. {description}");
+        }
+
+        match prompt::passphrase(&description) {
+          Ok(Some(passphrase)) => {
+            let () = out.write_all(passphrase.as_bytes())?;
+            let () = out.write_all(b"\n")?;
+            Ok(())
+          },
+          Ok(None) => Err(GpgError::CANCELED),
+          Err(err) => {
+            warn!("Failed to prompt for passphrase: {err:#}");
+            Err(GpgError::CANCELED)
+          },
+        }
+      };
+
+      let mut output = Vec::new();
+      let result = gpg
+        .with_passphrase_provider(provider, |gpg| {
+          gpg.decrypt(input.as_slice(), &mut output)
+        })
+        .with_context(|| format!("failed to decrypt {}", file.display()))?;
+
+      // `gpgme` does not tell us which key got used, only which of the
+      // recipients we have a secret key for.
+      let candidates = result
+        .recipients()
+        .filter(|recipient| recipient.status().is_ok())
+        .filter_map(|recipient| recipient.key_id().ok().map(str::to_string))
+        .collect();
+      let keys = unambiguous_key(candidates)
+        .into_iter()
+        .flat_map(|id| Self::resolve_key(&mut gpg, &id))
+        .collect();
+      Ok(Decrypted::new(PemPrivateKey::from(output), keys))
+    }
+  }
+
+  fn can_decrypt(
+    &self,
+    file: &Path,
+    protocol: Protocol,
+  ) -> StdResult<Option<bool>, DecryptError> {
+    // The `gpgme` version we use has no way of listing the recipients
+    // of a message without decrypting it, so ask `gpg` directly.
+    GpgCli::new(self.options.clone()).can_decrypt(file, protocol)
+  }
+
+  fn check(&self) -> StdResult<(), DecryptError> {
+    let () = check_gpg_agent(&self.options)?;
+    Ok(())
+  }
+}
+
+
+/// Check whether the given `gpg --with-colons --list-secret-keys`
+/// output contains a usable secret key with the given ID, i.e., one
+/// that is not just a stub.
+fn lists_secret_key(colons: &str, id: &str) -> bool {
+  colons
+    .lines()
+    .map(|line| line.split(':').collect::<Vec<_>>())
+    .filter(|fields| matches!(fields.first(), Some(&"sec") | Some(&"ssb")))
+    .any(|fields| {
+      fields.get(4).is_some_and(|key| key_id_matches(key, id)) && fields.get(14) != Some(&"#")
+    })
+}
+
+
+/// A decryptor invoking the `gpg` command line program.
+///
+/// This backend is useful on systems where `gpgme` misbehaves, as it
+/// only relies on `gpg` being available in `PATH`. Symmetrically
+/// encrypted files are decrypted using loopback pinentry, with the
+/// agent prompting for the passphrase. The same happens for
+/// passphrases of secret keys if loopback pinentry is configured.
+#[derive(Clone, Debug, Default)]
+pub struct GpgCli {
+  /// The options to use for GnuPG.
+  options: GpgOptions,
+}
+
+impl GpgCli {
+  /// Create a decryptor using the given GnuPG options.
+  pub fn new(options: GpgOptions) -> Self {
+    Self { options }
+  }
+
+  /// Create a command for decrypting using `program`, with options
+  /// but without the actual command and its arguments.
+  fn command(&self, program: &str, mode: PinentryMode) -> Command {
+    let mut command = command(program, &self.options);
+    let _ = command
+      .arg("--status-fd")
+      .arg("2")
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped());
+    if program == GPG && self.options.try_all_secrets {
+      let _ = command.arg("--try-all-secrets");
+    }
+
+    let mode = match mode {
+      PinentryMode::Default => None,
+      PinentryMode::Ask => Some("ask"),
+      PinentryMode::Cancel => Some("cancel"),
+      PinentryMode::Error => Some("error"),
+      PinentryMode::Loopback => Some("loopback"),
+    };
+    if let Some(mode) = mode {
+      let _ = command.arg("--pinentry-mode").arg(mode);
+    }
+    command
+  }
+
+  /// Run `program` to decrypt the given file, providing the passphrase
+  /// via loopback pinentry if one is given.
+  fn run(&self, program: &str, file: &Path, passphrase: Option<&str>) -> Result<Output> {
+    let mut command = if passphrase.is_some() {
+      let mut command = self.command(program, PinentryMode::Loopback);
+      let _ = command.arg("--passphrase-fd").arg("0").stdin(Stdio::piped());
+      command
+    } else {
+      let mut command = self.command(program, self.options.pinentry_mode);
+      let _ = command.stdin(Stdio::null());
+      command
+    };
+
+    let mut child = command
+      .arg("--decrypt")
+      .arg("--")
+      .arg(file)
+      .spawn()
+      .with_context(|| format!("failed to run {program}"))?;
+    let cancel = kill_on_cancel(&child);
+
+    if let Some(passphrase) = passphrase {
+      let mut stdin = child.stdin.take().unwrap();
+      let () = stdin
+        .write_all(passphrase.as_bytes())
+        .and_then(|()| stdin.write_all(b"\n"))
+        .with_context(|| format!("failed to write passphrase to {program}"))?;
+    }
+
+    cancel
+      .wait_with_output(child)
+      .with_context(|| format!("failed to run {program}"))
+  }
+
+  /// Run `gpg` to decrypt the given file using loopback pinentry,
+  /// prompting the user for passphrases as `gpg` requests them.
+  fn run_loopback(&self, file: &Path) -> Result<Output> {
+    let mut child = self
+      .command(GPG, PinentryMode::Loopback)
+      .arg("--command-fd")
+      .arg("0")
+      .arg("--decrypt")
+      .arg("--")
+      .arg(file)
+      .stdin(Stdio::piped())
+      .spawn()
+      .with_context(|| format!("failed to run {GPG}"))?;
+    let cancel = kill_on_cancel(&child);
+    let result = Self::interact(&mut child, file, cancel);
+    if result.is_err() {
+      let _ = child.kill();
+      let _ = child.wait();
+    }
+    result
+  }
+
+  /// Interact with a `gpg` process reading commands from its standard
+  /// input, answering its passphrase requests.
+  fn interact(child: &mut Child, file: &Path, cancel: Registration) -> Result<Output> {
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    // Read the output in the background, so that `gpg` can never block
+    // on writing it while we wait for its status.
+    let reader = spawn(move || {
+      let mut output = Vec::new();
+      stdout.read_to_end(&mut output).map(|_count| output)
+    });
+
+    let mut messages = Vec::new();
+    let mut key = String::from("<unknown>");
+    let mut failed = false;
+    for line in BufReader::new(stderr).lines() {
+      let line = line.with_context(|| format!("failed to read output of {GPG}"))?;
+      if let Some(status) = line.strip_prefix(STATUS_PREFIX) {
+        if let Some(hint) = status.strip_prefix("USERID_HINT ") {
+          key = hint.to_string();
+        } else if status.contains(BAD_PASSPHRASE_STATUS) {
+          failed = true;
+        } else if status.starts_with("GET_HIDDEN passphrase.enter") {
+          let mut description = format!(
+            "Enter passphrase for GPG key {key} to decrypt {}",
+            file.display()
+          );
+          if failed {
+            description = format!("Incorrect passphrase. {description}");
+          }
+          let passphrase = prompt::passphrase(&description)?
+            .ok_or_else(|| anyhow!("passphrase entry for {} canceled", file.display()))?;
+          let () = stdin
+            .write_all(passphrase.as_bytes())
+            .and_then(|()| stdin.write_all(b"\n"))
+            .with_context(|| format!("failed to write passphrase to {GPG}"))?;
+        }
+      }
+      let () = messages.push(line);
+    }
+
+    let status = cancel
+      .wait(child)
+      .with_context(|| format!("failed to wait for {GPG}"))?;
+    let stdout = reader
+      .join()
+      .map_err(|_| anyhow!("failed to read output of {GPG}"))?
+      .with_context(|| format!("failed to read output of {GPG}"))?;
+    Ok(Output {
+      status,
+      stdout,
+      stderr: messages.join("\n").into_bytes(),
+    })
+  }
+
+  /// Extract the identifiers of the key used for decryption from the
+  /// status output of `gpg` or `gpgsm`.
+  fn decryption_keys(output: &Output) -> Vec<String> {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut recipients = Vec::new();
+
+    for status in stderr.lines().filter_map(|line| line.strip_prefix(STATUS_PREFIX)) {
+      if let Some(keys) = status.strip_prefix("DECRYPTION_KEY ") {
+        // The fingerprints of the subkey and its primary key.
+        return keys.split(' ').take(2).map(str::to_string).collect()
+      } else if let Some(recipient) = status.strip_prefix("ENC_TO ") {
+        let () = recipients.push(recipient.split(' ').next().unwrap_or_default().to_string());
+      }
+    }
+    // `gpgsm` does not report the key used for decryption, so fall back
+    // to the recipients the file is encrypted to.
+    unambiguous_key(recipients)
+  }
+
+  /// Retrieve the IDs of the keys the given file is encrypted to.
+  fn recipients(&self, file: &Path) -> Result<Vec<String>> {
+    let output = command(GPG, &self.options)
+      .arg("--status-fd")
+      .arg("1")
+      .arg("--list-only")
+      .arg("--decrypt")
+      .arg("--")
+      .arg(file)
+      .stdin(Stdio::null())
+      .output()
+      .with_context(|| format!("failed to run {GPG}"))?;
+    if !output.status.success() {
+      bail!(
+        "failed to list recipients of {}: {}",
+        file.display(),
+        Self::failure(GPG, &output)
+      )
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let recipients = stdout
+      .lines()
+      .filter_map(|line| line.strip_prefix(STATUS_PREFIX))
+      .filter_map(|status| status.strip_prefix("ENC_TO "))
+      .map(|recipient| recipient.split(' ').next().unwrap_or_default().to_string())
+      .collect();
+    Ok(recipients)
+  }
+
+  /// Resolve the ID of a recipient's (sub)key to the fingerprints of
+  /// the key and its primary key, falling back to the ID itself if the
+  /// key cannot be found.
+  fn resolve_key(&self, program: &str, id: &str) -> Vec<String> {
+    let mut command = command(program, &self.options);
+    let _ = command.arg("--with-colons");
+    if program == GPG {
+      let _ = command.arg("--with-subkey-fingerprint");
+    }
+    let output = command
+      .arg("--list-keys")
+      .arg("--")
+      .arg(id)
+      .stdin(Stdio::null())
+      .output();
+
+    match output {
+      Ok(output) if output.status.success() => {
+        let keys = listed_keys(&String::from_utf8_lossy(&output.stdout));
+        key_and_primary_key(id, &keys).unwrap_or_else(|| vec![id.to_string()])
+      },
+      _ => vec![id.to_string()],
+    }
+  }
+
+  /// Check whether a usable secret key with the given ID is available.
+  fn has_secret_key(&self, id: &str) -> Result<bool> {
+    let output = command(GPG, &self.options)
+      .arg("--with-colons")
+      .arg("--list-secret-keys")
+      .arg("--")
+      .arg(id)
+      .stdin(Stdio::null())
+      .output()
+      .with_context(|| format!("failed to run {GPG}"))?;
+    // `gpg` fails if there is no matching key.
+    Ok(output.status.success() && lists_secret_key(&String::from_utf8_lossy(&output.stdout), id))
+  }
+
+  /// Describe the failure of an invocation of `program`.
+  fn failure(program: &str, output: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // Status lines are meant for us, not for the user.
+    let messages = stderr
+      .lines()
+      .filter(|line| !line.starts_with(STATUS_PREFIX))
+      .collect::<Vec<_>>()
+      .join("\n");
+    format!("{program} exited with {}: {}", output.status, messages.trim())
+  }
+
+  /// Create an error for a failed decryption of `file`.
+  fn error(program: &str, file: &Path, output: &Output) -> Error {
+    anyhow!(
+      "failed to decrypt {}: {}",
+      file.display(),
+      Self::failure(program, output)
+    )
+  }
+}
+
+impl Decryptor for GpgCli {
+  fn decrypt(&self, file: &Path, protocol: Protocol) -> StdResult<Decrypted, DecryptError> {
+    let input = read(file).with_context(|| format!("failed to read {}", file.display()))?;
+    let program = match protocol {
+      Protocol::OpenPgp => GPG,
+      Protocol::Cms => GPGSM,
+    };
+
+    if protocol == Protocol::OpenPgp && is_symmetric(&input) {
+      decrypt_symmetric(file, |passphrase| {
+        let output = self.run(program, file, Some(passphrase))?;
+        if output.status.success() {
+          Ok(Some(Decrypted::from(PemPrivateKey::from(output.stdout))))
+        } else if String::from_utf8_lossy(&output.stderr).contains(BAD_PASSPHRASE_STATUS) {
+          Ok(None)
+        } else {
+          Err(Self::error(program, file, &output))
+        }
+      })
+      .map_err(DecryptError::from)
+    } else {
+      let output = match (protocol, self.options.pinentry_mode) {
+        (Protocol::OpenPgp, PinentryMode::Loopback) => self.run_loopback(file)?,
+        (Protocol::Cms, PinentryMode::Loopback) => {
+          return Err(DecryptError::from(anyhow!(
+            "failed to decrypt {}: loopback pinentry is not supported for CMS encrypted files by the gpg backend",
+            file.display()
+          )))
+        },
+        _ => self.run(program, file, None)?,
+      };
+      if !output.status.success() {
+        return Err(DecryptError::from(Self::error(program, file, &output)))
+      }
+      let mut keys = Self::decryption_keys(&output);
+      // Absent a `DECRYPTION_KEY` status, as with `gpgsm`, all we know
+      // is the ID of the recipient, which may be a subkey. Look up its
+      // primary key, for pinning either to work.
+      if let [id] = keys.as_slice() {
+        keys = self.resolve_key(program, id);
+      }
+      Ok(Decrypted::new(PemPrivateKey::from(output.stdout), keys))
+    }
+  }
+
+  fn can_decrypt(
+    &self,
+    file: &Path,
+    protocol: Protocol,
+  ) -> StdResult<Option<bool>, DecryptError> {
+    // `gpgsm` has no way of listing recipients without decrypting.
+    if protocol != Protocol::OpenPgp {
+      return Ok(None)
+    }
+
+    let input = read(file).with_context(|| format!("failed to read {}", file.display()))?;
+    if is_symmetric(&input) {
+      // All it takes is the passphrase.
+      return Ok(Some(true))
+    }
+
+    let recipients = self.recipients(file)?;
+    // Hidden recipients are reported with an all zero key ID.
+    if recipients.iter().any(|id| id.chars().all(|c| c == '0')) {
+      return Ok(None)
+    }
+
+    for id in recipients {
+      if self.has_secret_key(&id)? {
+        return Ok(Some(true))
+      }
+    }
+    Ok(Some(false))
+  }
+
+  fn check(&self) -> StdResult<(), DecryptError> {
+    let () = check_gpg_agent(&self.options)?;
+    Ok(())
+  }
+}
+
+
+/// Create the decryptor for the configured backend.
+pub fn decryptor(config: &Config) -> Box<dyn Decryptor> {
+  match config.backend {
+    #[cfg(feature = "gpgme")]
+    Backend::Gpgme => Box::new(Gpgme::new(config.gpg.clone())),
+    Backend::Gpg => Box::new(GpgCli::new(config.gpg.clone())),
+    #[cfg(feature = "sequoia")]
+    Backend::Sequoia => Box::new(Sequoia::new(config.keyrings.clone())),
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+
+  /// Check that we correctly detect symmetrically encrypted messages.
+  #[test]
+  fn symmetric_detection() {
+    // Old format symmetric-key encrypted session key packet, as emitted
+    // by `gpg --symmetric`.
+    assert!(is_symmetric(&[0x8c, 0x0d, 0x04, 0x09]));
+    // New format symmetric-key encrypted session key packet.
+    assert!(is_symmetric(&[0xc3, 0x0d, 0x04, 0x09]));
+    // Old and new format public-key encrypted session key packets.
+    assert!(!is_symmetric(&[0x84, 0x5e, 0x03]));
+    assert!(!is_symmetric(&[0x85, 0x01, 0x0c, 0x03]));
+    assert!(!is_symmetric(&[0xc1, 0x5e, 0x03]));
+    assert!(!is_symmetric(b"-----BEGIN PGP MESSAGE-----"));
+    assert!(!is_symmetric(&[]));
+  }
+
+  /// Check that we correctly match key IDs and fingerprints.
+  #[test]
+  fn key_id_matching() {
+    let fpr = "FB127B8E0D4D3932345258FE6B6F0910F1B905B2";
+    assert!(key_id_matches(fpr, fpr));
+    assert!(key_id_matches(fpr, "fb127b8e0d4d3932345258fe6b6f0910f1b905b2"));
+    assert!(key_id_matches(fpr, "FB12 7B8E 0D4D 3932 3452  58FE 6B6F 0910 F1B9 05B2"));
+    assert!(key_id_matches(fpr, "0x6B6F0910F1B905B2"));
+    assert!(key_id_matches("6B6F0910F1B905B2", fpr));
+    // Short key IDs are too ambiguous to be accepted.
+    assert!(!key_id_matches(fpr, "F1B905B2"));
+    assert!(!key_id_matches(fpr, "0x974F33B637ECF782"));
+    assert!(!key_id_matches(fpr, ""));
+  }
+
+  /// Check that we extract the key used for decryption from `gpg`
+  /// status output.
+  #[test]
+  fn decryption_key_extraction() {
+    let output = |stderr: &str| Output {
+      status: Default::default(),
+      stdout: Vec::new(),
+      stderr: stderr.as_bytes().to_vec(),
+    };
+
+    let keys = GpgCli::decryption_keys(&output(
+      "[GNUPG:] ENC_TO 6B6F0910F1B905B2 18 0\n\
+       [GNUPG:] DECRYPTION_KEY FB127B8E0D4D3932345258FE6B6F0910F1B905B2 78902DD34450BB171CCC0394E82615DBDD3A3915 u\n\
+       [GNUPG:] DECRYPTION_OKAY",
+    ));
+    assert_eq!(
+      keys,
+      vec![
+        "FB127B8E0D4D3932345258FE6B6F0910F1B905B2".to_string(),
+        "78902DD34450BB171CCC0394E82615DBDD3A3915".to_string(),
+      ]
+    );
+
+    let keys = GpgCli::decryption_keys(&output("[GNUPG:] ENC_TO 974F33B637ECF782 0 0"));
+    assert_eq!(keys, vec!["974F33B637ECF782".to_string()]);
+
+    let keys = GpgCli::decryption_keys(&output(
+      "[GNUPG:] ENC_TO 974F33B637ECF782 0 0\n[GNUPG:] ENC_TO 6B6F0910F1B905B2 18 0",
+    ));
+    assert!(keys.is_empty());
+
+    let keys = GpgCli::decryption_keys(&output("[GNUPG:] ENC_TO 0000000000000000 18 0"));
+    assert!(keys.is_empty());
+  }
+
+  /// Check that recipients are resolved to their primary key, so that
+  /// pinning either the subkey or the primary key matches.
+  #[test]
+  fn primary_key_resolution() {
+    let listing = "\
+pub:u:255:22:E82615DBDD3A3915:1792283963:::u:::scESC:::::ed25519:::0:
+fpr:::::::::78902DD34450BB171CCC0394E82615DBDD3A3915:
+uid:u::::1792283963::7C5F6E4E0B1CC4A4E1B3E8F7D2D3A0B2E4E6C3A1::Test <test@example.com>::::::::::0:
+sub:u:255:18:6B6F0910F1B905B2:1792283970::::::e:::::cv25519::
+fpr:::::::::FB127B8E0D4D3932345258FE6B6F0910F1B905B2:
+";
+    let keys = listed_keys(listing);
+    assert_eq!(
+      keys,
+      vec![vec![
+        "78902DD34450BB171CCC0394E82615DBDD3A3915".to_string(),
+        "FB127B8E0D4D3932345258FE6B6F0910F1B905B2".to_string(),
+      ]]
+    );
+    assert_eq!(key_and_primary_key("974F33B637ECF782", &keys), None);
+
+    let keys = key_and_primary_key("6B6F0910F1B905B2", &keys).unwrap();
+    let decrypted = Decrypted::new(PemPrivateKey::from(Vec::new()), keys);
+    assert!(decrypted.decrypted_with(&["78902DD34450BB171CCC0394E82615DBDD3A3915".to_string()]));
+    assert!(decrypted.decrypted_with(&["0xE82615DBDD3A3915".to_string()]));
+    assert!(decrypted.decrypted_with(&["0x6B6F0910F1B905B2".to_string()]));
+    assert!(!decrypted.decrypted_with(&["0x974F33B637ECF782".to_string()]));
+  }
+
+  /// Check that we correctly detect usable secret keys in `gpg`'s
+  /// colon delimited key listing.
+  #[test]
+  fn secret_key_listing() {
+    let listing = "\
+sec:u:255:22:E82615DBDD3A3915:1792283963:::u:::scESC:::+::ed25519:::0:
+fpr:::::::::78902DD34450BB171CCC0394E82615DBDD3A3915:
+ssb:u:255:18:6B6F0910F1B905B2:1792283970::::::e:::+::cv25519::
+fpr:::::::::FB127B8E0D4D3932345258FE6B6F0910F1B905B2:
+";
+    assert!(lists_secret_key(listing, "6B6F0910F1B905B2"));
+    assert!(lists_secret_key(listing, "FB127B8E0D4D3932345258FE6B6F0910F1B905B2"));
+    assert!(lists_secret_key(listing, "0xe82615dbdd3a3915"));
+    assert!(!lists_secret_key(listing, "974F33B637ECF782"));
+
+    // The secret part of the subkey is not available.
+    let stub = listing.replace("e:::+::cv25519", "e:::#::cv25519");
+    assert!(!lists_secret_key(&stub, "6B6F0910F1B905B2"));
+  }
+}
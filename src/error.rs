@@ -0,0 +1,393 @@
+// error.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Categorized errors of the agent's handling of requests.
+//!
+//! Most errors are plain `anyhow` errors. Those telling why a request
+//! got refused are [`RequestError`]s, which may be attached anywhere in
+//! an error's chain and determine the [`ErrorKind`] of the failure as a
+//! whole. Clients of the agent's own protocol extensions receive the
+//! kind along with the error message, as an [`ErrorReport`].
+//!
+//! The library's extension points and protocol functions report typed
+//! errors: [`KeyStoreError`], [`DecryptError`], [`SignError`], and
+//! [`ProtocolError`]. Each wraps an error chain as described above and
+//! exposes its kind.
+
+use std::error::Error as StdError;
+use std::fmt::Debug;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use anyhow::Error;
+use anyhow::Result;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use ssh_agent_lib::proto::from_bytes;
+use ssh_agent_lib::proto::message::Extension;
+use ssh_agent_lib::proto::message::ExtensionContents;
+use ssh_agent_lib::proto::message::Message;
+use ssh_agent_lib::proto::to_bytes;
+
+
+/// The name of the agent protocol extension the agent responds with
+/// when it failed to handle a request for one of its own extensions.
+///
+/// The response contains the serialized [`ErrorReport`].
+pub const ERROR_EXTENSION: &str = "error@ssh-gpg-agent";
+
+
+/// The category of a failure to handle a request.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum ErrorKind {
+  /// The requested identity is not known to the agent.
+  KeyNotFound,
+  /// The private key of the requested identity could not be
+  /// decrypted.
+  DecryptionFailed,
+  /// The request was refused by the configured policies or the user.
+  PolicyDenied,
+  /// The agent is locked.
+  Locked,
+  /// The request is not supported by the agent.
+  Unsupported,
+  /// The request or an operation it required did not complete in
+  /// time.
+  TimedOut,
+  /// Any other failure.
+  Other,
+}
+
+impl ErrorKind {
+  /// Determine the kind of the given error, as per the outermost
+  /// [`RequestError`] in its chain.
+  ///
+  /// Typed errors and [`ErrorReport`]s in the chain are categorized as
+  /// well, unless a request error got attached on top of them.
+  pub fn of(err: &Error) -> Self {
+    if let Some(err) = err.downcast_ref::<RequestError>() {
+      return err.kind()
+    }
+    // Downcasting does not look into the chain wrapped by a typed
+    // error, so ask it directly.
+    if let Some(err) = err.downcast_ref::<KeyStoreError>() {
+      err.kind()
+    } else if let Some(err) = err.downcast_ref::<DecryptError>() {
+      err.kind()
+    } else if let Some(err) = err.downcast_ref::<SignError>() {
+      err.kind()
+    } else if let Some(err) = err.downcast_ref::<ProtocolError>() {
+      err.kind()
+    } else if let Some(report) = err.downcast_ref::<ErrorReport>() {
+      report.kind
+    } else {
+      Self::Other
+    }
+  }
+}
+
+impl Display for ErrorKind {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    let s = match self {
+      Self::KeyNotFound => "key not found",
+      Self::DecryptionFailed => "decryption failed",
+      Self::PolicyDenied => "policy denied",
+      Self::Locked => "locked",
+      Self::Unsupported => "unsupported",
+      Self::TimedOut => "timed out",
+      Self::Other => "other",
+    };
+    f.write_str(s)
+  }
+}
+
+
+/// An error telling why the agent refused or failed to handle a
+/// request.
+#[derive(Debug)]
+pub enum RequestError {
+  /// The requested identity is not known to the agent.
+  KeyNotFound,
+  /// The private key stored in the given file could not be decrypted
+  /// (or did not match its public key).
+  DecryptionFailed(PathBuf),
+  /// The request was refused for the given reason.
+  PolicyDenied(String),
+  /// The agent is locked.
+  Locked,
+  /// The request is not supported, as described.
+  Unsupported(String),
+  /// The described operation did not complete in time.
+  TimedOut(String),
+}
+
+impl RequestError {
+  /// Retrieve the kind of the error.
+  pub fn kind(&self) -> ErrorKind {
+    match self {
+      Self::KeyNotFound => ErrorKind::KeyNotFound,
+      Self::DecryptionFailed(..) => ErrorKind::DecryptionFailed,
+      Self::PolicyDenied(..) => ErrorKind::PolicyDenied,
+      Self::Locked => ErrorKind::Locked,
+      Self::Unsupported(..) => ErrorKind::Unsupported,
+      Self::TimedOut(..) => ErrorKind::TimedOut,
+    }
+  }
+}
+
+impl Display for RequestError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    match self {
+      Self::KeyNotFound => f.write_str("identity not found"),
+      Self::DecryptionFailed(path) => {
+        write!(f, "failed to load private key from {}", path.display())
+      },
+      Self::PolicyDenied(reason) => f.write_str(reason),
+      Self::Locked => f.write_str("agent is locked"),
+      Self::Unsupported(what) => f.write_str(what),
+      Self::TimedOut(what) => f.write_str(what),
+    }
+  }
+}
+
+impl StdError for RequestError {}
+
+
+/// Define a typed error wrapping an `anyhow` error chain.
+macro_rules! typed_error {
+  ($(#[$doc:meta])* $name:ident) => {
+    $(#[$doc])*
+    pub struct $name(Error);
+
+    impl $name {
+      /// Retrieve the kind of the error.
+      pub fn kind(&self) -> ErrorKind {
+        ErrorKind::of(&self.0)
+      }
+
+      /// Convert the error into the `anyhow` error it wraps.
+      pub fn into_inner(self) -> Error {
+        self.0
+      }
+    }
+
+    impl Debug for $name {
+      fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        Debug::fmt(&self.0, f)
+      }
+    }
+
+    impl Display for $name {
+      fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        Display::fmt(&self.0, f)
+      }
+    }
+
+    impl StdError for $name {
+      fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.0.source()
+      }
+    }
+
+    impl From<Error> for $name {
+      fn from(err: Error) -> Self {
+        Self(err)
+      }
+    }
+
+    impl From<RequestError> for $name {
+      fn from(err: RequestError) -> Self {
+        Self(Error::from(err))
+      }
+    }
+  };
+}
+
+typed_error!(
+  /// An error reported by a [`KeyStore`][crate::KeyStore].
+  KeyStoreError
+);
+
+typed_error!(
+  /// An error reported by a [`Decryptor`][crate::Decryptor].
+  DecryptError
+);
+
+typed_error!(
+  /// An error reported by a [`Signer`][crate::Signer].
+  SignError
+);
+
+typed_error!(
+  /// An error talking to an agent via the agent protocol.
+  ProtocolError
+);
+
+// Key stores decrypt and sign on behalf of the agent.
+impl From<DecryptError> for KeyStoreError {
+  fn from(err: DecryptError) -> Self {
+    Self(Error::from(err))
+  }
+}
+
+impl From<SignError> for KeyStoreError {
+  fn from(err: SignError) -> Self {
+    Self(Error::from(err))
+  }
+}
+
+
+/// The report of a failure to handle a request, as sent to clients of
+/// the agent's own protocol extensions.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ErrorReport {
+  /// The kind of the failure.
+  pub kind: ErrorKind,
+  /// The error message, including its causes.
+  pub message: String,
+}
+
+impl ErrorReport {
+  /// Create a report of the given error.
+  pub fn new(err: &Error) -> Self {
+    Self {
+      kind: ErrorKind::of(err),
+      message: format!("{err:#}"),
+    }
+  }
+
+  /// Convert the report into the response to a failed request.
+  pub fn to_response(&self) -> Result<Message> {
+    let contents = to_bytes(self).with_context(|| "failed to encode error report")?;
+    let response = Message::Extension(Extension {
+      extension_type: ERROR_EXTENSION.to_string(),
+      extension_contents: ExtensionContents(contents),
+    });
+    Ok(response)
+  }
+
+  /// Extract the error report from an agent's response, if it is one.
+  pub fn from_response(response: &Message) -> Option<Self> {
+    match response {
+      Message::Extension(extension) if extension.extension_type == ERROR_EXTENSION => {
+        from_bytes(&extension.extension_contents.0).ok()
+      },
+      _ => None,
+    }
+  }
+}
+
+impl Display for ErrorReport {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    f.write_str(&self.message)
+  }
+}
+
+impl StdError for ErrorReport {}
+
+
+/// The number of failures of a certain kind.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FailureCount {
+  /// The kind of the failures.
+  pub kind: ErrorKind,
+  /// The number of failures.
+  pub count: u64,
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use anyhow::anyhow;
+
+
+  /// Check that errors are categorized by the outermost request error
+  /// in their chain.
+  #[test]
+  fn error_kind() {
+    let err = anyhow!("failed to connect");
+    assert_eq!(ErrorKind::of(&err), ErrorKind::Other);
+
+    let err = Error::from(RequestError::Locked).context("failed to handle agent request");
+    assert_eq!(ErrorKind::of(&err), ErrorKind::Locked);
+    assert_eq!(format!("{err:#}"), "failed to handle agent request: agent is locked");
+
+    let err = anyhow!("too many incorrect passphrases")
+      .context(RequestError::DecryptionFailed(PathBuf::from("id_ed25519.gpg")))
+      .context(RequestError::PolicyDenied("refusing to sign".to_string()));
+    assert_eq!(ErrorKind::of(&err), ErrorKind::PolicyDenied);
+  }
+
+  /// Check that typed errors report the kind of the chain they wrap and
+  /// preserve it when converted back into an `anyhow` error.
+  #[test]
+  fn typed_error_kind() {
+    let err = DecryptError::from(
+      anyhow!("bad passphrase")
+        .context(RequestError::DecryptionFailed(PathBuf::from("id_ed25519.gpg"))),
+    );
+    assert_eq!(err.kind(), ErrorKind::DecryptionFailed);
+    assert_eq!(
+      format!("{err:#}"),
+      "failed to load private key from id_ed25519.gpg: bad passphrase"
+    );
+
+    let err = KeyStoreError::from(err);
+    assert_eq!(err.kind(), ErrorKind::DecryptionFailed);
+
+    let err = Error::from(err).context("failed to sign request");
+    assert_eq!(ErrorKind::of(&err), ErrorKind::DecryptionFailed);
+    assert_eq!(
+      format!("{err:#}"),
+      "failed to sign request: failed to load private key from id_ed25519.gpg: bad passphrase"
+    );
+
+    let err = err.context(RequestError::PolicyDenied("refusing to sign".to_string()));
+    assert_eq!(ErrorKind::of(&err), ErrorKind::PolicyDenied);
+
+    let err = ProtocolError::from(Error::from(ErrorReport {
+      kind: ErrorKind::Locked,
+      message: "agent is locked".to_string(),
+    }));
+    assert_eq!(err.kind(), ErrorKind::Locked);
+    assert_eq!(SignError::from(anyhow!("failed")).kind(), ErrorKind::Other);
+  }
+
+  /// Check that error reports survive a round trip through the agent
+  /// protocol.
+  #[test]
+  fn error_report_round_trip() -> Result<()> {
+    let err = Error::from(RequestError::KeyNotFound).context("failed to remove identity");
+    let report = ErrorReport::new(&err);
+    assert_eq!(report.kind, ErrorKind::KeyNotFound);
+    assert_eq!(report.message, "failed to remove identity: identity not found");
+
+    let response = report.to_response()?;
+    assert_eq!(ErrorReport::from_response(&response), Some(report));
+    assert_eq!(ErrorReport::from_response(&Message::Failure), None);
+    Ok(())
+  }
+}
@@ -18,17 +18,59 @@
 // *************************************************************************
 
 use std::ffi::OsStr;
+use std::fs::canonicalize;
 use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::metadata;
+use std::fs::read;
 use std::fs::read_dir;
+use std::fs::read_to_string;
+use std::fs::remove_file;
+use std::fs::rename;
+use std::fs::symlink_metadata;
+use std::io;
+use std::io::ErrorKind;
 use std::io::Read;
+use std::io::Write as _;
+use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::fs::MetadataExt as _;
 use std::path::Path;
 use std::path::PathBuf;
+#[cfg(not(feature = "gpgme"))]
+use std::process::Stdio;
+use std::thread::sleep;
+use std::time::Duration;
 
+use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context as _;
+use anyhow::Error;
 use anyhow::Result;
 
-use gpgme::Context;
-use gpgme::Protocol;
+use libc::geteuid;
+
+use ssh_agent_lib::proto::public_key::PublicKey;
+
+use tracing::warn;
+
+use crate::config::DiscoveryConfig;
+use crate::config::GpgOptions;
+use crate::config::KeyConfig;
+use crate::config::PermissionPolicy;
+use crate::config::Protocol;
+use crate::config::SymlinkPolicy;
+#[cfg(not(feature = "gpgme"))]
+use crate::decrypt::command;
+#[cfg(feature = "gpgme")]
+use crate::decrypt::context;
+#[cfg(not(feature = "gpgme"))]
+use crate::decrypt::GPG;
+use crate::hosts::matches_wildcard;
+use crate::keys::is_passphrase_protected;
+use crate::keys::parse_private_keys;
+use crate::keys::ToPem as _;
+use crate::secret::wipe;
+use crate::secret::SecretBuffer;
 
 
 /// The extension SSH public keys in a given directory that we recognize
@@ -37,10 +79,28 @@ const PUBLIC_EXT: &str = "pub";
 /// The extension of GPG encrypted private keys in a given directory
 /// that we recognize and attempt to decrypt.
 const PRIVATE_EXT: &str = "gpg";
+/// The extension of CMS (S/MIME) encrypted private keys, as created by
+/// `gpgsm`.
+const CMS_EXT: &str = "p7m";
+/// The extension of private keys sealed to the TPM.
+const TPM_EXT: &str = "tpm";
+/// The extension of files containing per-key options.
+const CONFIG_EXT: &str = "conf";
+/// The suffix of the file name (without extension) of a certificate
+/// for a key.
+const CERT_SUFFIX: &str = "-cert";
+/// The suffix appended to key files that got removed from the agent.
+const REMOVED_SUFFIX: &str = "removed";
+/// The number of attempts made at reading key files and directories
+/// failing with a likely transient error.
+const ATTEMPTS: u32 = 3;
+/// The delay before retrying a failed read for the first time. It is
+/// doubled for each subsequent retry.
+const RETRY_DELAY: Duration = Duration::from_millis(25);
 
 
 /// A public key in PEM encoded form, as it was loaded from file.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct PemPublicKey(Vec<u8>);
 
 impl From<PemPublicKey> for Vec<u8> {
@@ -49,33 +109,389 @@ impl From<PemPublicKey> for Vec<u8> {
   }
 }
 
+impl From<Vec<u8>> for PemPublicKey {
+  fn from(data: Vec<u8>) -> Self {
+    Self(data)
+  }
+}
+
+impl AsRef<[u8]> for PemPublicKey {
+  fn as_ref(&self) -> &[u8] {
+    &self.0
+  }
+}
+
 
 /// A private key in PEM encoded form, as it was loaded from file.
+///
+/// The key data is wiped from memory once dropped.
 #[derive(Debug)]
-pub struct PemPrivateKey(Vec<u8>);
+pub struct PemPrivateKey(SecretBuffer);
 
-impl From<PemPrivateKey> for Vec<u8> {
-  fn from(key: PemPrivateKey) -> Self {
-    key.0
+impl From<Vec<u8>> for PemPrivateKey {
+  fn from(data: Vec<u8>) -> Self {
+    Self(SecretBuffer::from(data))
+  }
+}
+
+impl AsRef<[u8]> for PemPrivateKey {
+  fn as_ref(&self) -> &[u8] {
+    self.0.as_slice()
+  }
+}
+
+
+/// Infer the protocol the given private key file is encrypted with
+/// from its extension.
+pub fn protocol(gpg_path: &Path) -> Protocol {
+  if gpg_path.extension() == Some(OsStr::new(CMS_EXT)) {
+    Protocol::Cms
+  } else {
+    Protocol::OpenPgp
+  }
+}
+
+
+/// Check whether the given private key file contains a key sealed to
+/// the TPM, as opposed to a GPG encrypted one.
+pub fn is_tpm_sealed(path: &Path) -> bool {
+  path.extension() == Some(OsStr::new(TPM_EXT))
+}
+
+
+/// Create the given file, failing if it exists already, and write the
+/// provided data to it.
+fn write_new_file(file: &Path, data: &[u8]) -> Result<()> {
+  let mut f = OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .open(file)
+    .with_context(|| format!("failed to create {}", file.display()))?;
+
+  f.write_all(data)
+    .with_context(|| format!("failed to write data to {}", file.display()))
+}
+
+
+/// Check whether the given I/O error is likely transient, as is common
+/// for network file systems (e.g., a stale NFS file handle).
+fn is_transient_io(err: &io::Error) -> bool {
+  matches!(
+    err.raw_os_error(),
+    Some(libc::ESTALE | libc::EIO | libc::ETIMEDOUT)
+  )
+}
+
+
+/// Check whether the given error was caused by a likely transient I/O
+/// error.
+pub(crate) fn is_transient(err: &Error) -> bool {
+  err
+    .chain()
+    .any(|cause| cause.downcast_ref::<io::Error>().is_some_and(is_transient_io))
+}
+
+
+/// Run the given file system operation, retrying it with exponential
+/// backoff as long as it fails with a likely transient error, up to
+/// [`ATTEMPTS`] times in total.
+fn retry<T, F>(mut op: F) -> io::Result<T>
+where
+  F: FnMut() -> io::Result<T>,
+{
+  let mut delay = RETRY_DELAY;
+  for _ in 1..ATTEMPTS {
+    match op() {
+      Err(err) if is_transient_io(&err) => {
+        let () = sleep(delay);
+        delay *= 2;
+      },
+      result => return result,
+    }
   }
+  op()
 }
 
 
-/// Load a private SSH key from the given file. The file is assumed to
-/// be GPG encrypted.
-pub fn load_private_key(file: &Path) -> Result<PemPrivateKey> {
-  let mut input =
-    File::open(file).with_context(|| format!("failed to open {} for reading", file.display()))?;
+/// GPG encrypt the given data to the provided recipient.
+#[cfg(feature = "gpgme")]
+fn encrypt(recipient: &str, options: &GpgOptions, data: &[u8]) -> Result<Vec<u8>> {
+  let mut gpg = context(Protocol::OpenPgp, options)?;
 
-  let mut gpg =
-    Context::from_protocol(Protocol::OpenPgp).with_context(|| "failed to connect to GPG")?;
+  let key = gpg
+    .locate_key(recipient)
+    .with_context(|| format!("failed to find GPG key for recipient {recipient}"))?;
 
   let mut output = Vec::new();
   let _ = gpg
-    .decrypt(&mut input, &mut output)
-    .with_context(|| format!("failed to decrypt {}", file.display()))?;
+    .encrypt(Some(&key), data, &mut output)
+    .with_context(|| format!("failed to encrypt private key to {recipient}"))?;
+  Ok(output)
+}
+
+/// GPG encrypt the given data to the provided recipient.
+#[cfg(not(feature = "gpgme"))]
+fn encrypt(recipient: &str, options: &GpgOptions, data: &[u8]) -> Result<Vec<u8>> {
+  let mut child = command(GPG, options)
+    .arg("--encrypt")
+    .arg("--recipient")
+    .arg(recipient)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .with_context(|| format!("failed to run {GPG}"))?;
+
+  // Keys are small enough for the data to fit into the pipe's buffer,
+  // so we don't have to feed it concurrently to reading the output.
+  let mut stdin = child.stdin.take().unwrap();
+  let () = stdin
+    .write_all(data)
+    .with_context(|| format!("failed to write data to {GPG}"))?;
+  let () = drop(stdin);
+
+  let output = child
+    .wait_with_output()
+    .with_context(|| format!("failed to run {GPG}"))?;
+  if !output.status.success() {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    bail!(
+      "failed to encrypt private key to {recipient}: {GPG} exited with {}: {}",
+      output.status,
+      stderr.trim()
+    )
+  }
+  Ok(output.stdout)
+}
+
+
+/// Store a key pair in the given directory, using the provided base
+/// name. The private key is GPG encrypted to the given recipient.
+/// Existing files are never overwritten.
+pub fn store_key_pair(
+  dir: &Path,
+  name: &str,
+  recipient: &str,
+  options: &GpgOptions,
+  pubkey: PemPublicKey,
+  privkey: PemPrivateKey,
+) -> Result<PathBuf> {
+  let output = encrypt(recipient, options, privkey.as_ref())?;
+  let gpg_path = dir.join(format!("{name}.{PRIVATE_EXT}"));
+  let pub_path = dir.join(format!("{name}.{PUBLIC_EXT}"));
+
+  let () = write_new_file(&gpg_path, &output)?;
+  if let Err(err) = write_new_file(&pub_path, &pubkey.0) {
+    let _ = remove_file(&gpg_path);
+    return Err(err)
+  }
+  Ok(gpg_path)
+}
+
+
+/// Format the given public keys, along with their comments, the way
+/// they are stored in a `.pub` file, i.e., one per line.
+pub fn public_keys_to_pem(keys: &[(PublicKey, String)]) -> Result<PemPublicKey> {
+  let mut data = Vec::new();
+  for (key, comment) in keys {
+    let () = data.extend(key.to_pem(comment)?.0);
+  }
+  Ok(PemPublicKey(data))
+}
+
+
+/// Retrieve the path of the public key belonging to the GPG encrypted
+/// private key stored in the given file, i.e., "key.pub" for "key.gpg".
+pub fn public_key_path(gpg_path: &Path) -> PathBuf {
+  gpg_path.with_extension(OsStr::new(PUBLIC_EXT))
+}
+
+
+/// Store the given public key alongside the GPG encrypted private key
+/// it belongs to, failing if a public key file exists already.
+pub fn store_public_key(gpg_path: &Path, pubkey: &PemPublicKey) -> Result<PathBuf> {
+  let pub_path = public_key_path(gpg_path);
+  let () = write_new_file(&pub_path, &pubkey.0)?;
+  Ok(pub_path)
+}
+
+
+/// GPG encrypt the plain text private key stored in the given file to
+/// the provided recipient, storing the result alongside it, i.e., for
+/// "id_ed25519" in "id_ed25519.gpg".
+///
+/// Unless present already, the corresponding public key is derived
+/// from the private one and stored as well ("id_ed25519.pub"). The
+/// plain text file itself is left untouched. Returns the path to the
+/// encrypted private key.
+pub fn encrypt_key_file(file: &Path, recipient: &str, options: &GpgOptions) -> Result<PathBuf> {
+  let data = read(file).with_context(|| format!("failed to read {}", file.display()))?;
+  let privkey = PemPrivateKey::from(data);
+
+  let mut name = file
+    .file_name()
+    .ok_or_else(|| anyhow!("{} does not name a file", file.display()))?
+    .to_os_string();
+  name.push(".");
+  name.push(PRIVATE_EXT);
+  let gpg_path = file.with_file_name(name);
+  let pub_path = public_key_path(&gpg_path);
+
+  // Passphrase protected keys are stored as they are, but we need the
+  // passphrase for deriving the public key.
+  let pubkey = if is_passphrase_protected(&privkey) {
+    if !pub_path.exists() {
+      bail!(
+        "{} is passphrase protected; please provide the public key in {}",
+        file.display(),
+        pub_path.display()
+      )
+    }
+    None
+  } else {
+    // Make sure that we are dealing with a private key to begin with.
+    let keys =
+      parse_private_keys(&privkey).with_context(|| format!("failed to load {}", file.display()))?;
+    let pubkeys = keys
+      .into_iter()
+      .map(|(mut key, comment)| {
+        let pubkey = PublicKey::from(&key);
+        let () = wipe(&mut key);
+        (pubkey, comment)
+      })
+      .collect::<Vec<_>>();
+    (!pub_path.exists())
+      .then(|| public_keys_to_pem(&pubkeys))
+      .transpose()?
+  };
+
+  let output = encrypt(recipient, options, privkey.as_ref())?;
+  let () = write_new_file(&gpg_path, &output)?;
+  if let Some(pubkey) = pubkey {
+    if let Err(err) = write_new_file(&pub_path, &pubkey.0) {
+      let _ = remove_file(&gpg_path);
+      return Err(err)
+    }
+  }
+  Ok(gpg_path)
+}
 
-  Ok(PemPrivateKey(output))
+
+/// Overwrite the contents of the given file with zeros before removing
+/// it.
+///
+/// Note that on copy-on-write or journaling file systems, as well as
+/// on flash storage, the original data may nevertheless remain on
+/// disk.
+pub fn shred_file(file: &Path) -> Result<()> {
+  let len = metadata(file)
+    .with_context(|| format!("failed to query metadata of {}", file.display()))?
+    .len();
+  let mut f = OpenOptions::new()
+    .write(true)
+    .open(file)
+    .with_context(|| format!("failed to open {} for writing", file.display()))?;
+
+  let zeros = [0; 4096];
+  let mut remaining = len;
+  while remaining > 0 {
+    let count = remaining.min(zeros.len() as u64) as usize;
+    let () = f
+      .write_all(&zeros[..count])
+      .with_context(|| format!("failed to overwrite {}", file.display()))?;
+    remaining -= count as u64;
+  }
+  let () = f
+    .sync_all()
+    .with_context(|| format!("failed to sync {}", file.display()))?;
+  let () = drop(f);
+
+  remove_file(file).with_context(|| format!("failed to remove {}", file.display()))
+}
+
+
+/// Remove the key pair whose GPG encrypted private key is stored in the
+/// given file from disk. If `keep` is true, the files are renamed to no
+/// longer be discovered instead of being deleted.
+pub fn remove_key_pair(gpg_path: &Path, keep: bool) -> Result<()> {
+  let pub_path = public_key_path(gpg_path);
+
+  for path in [&pub_path, gpg_path] {
+    // The public key of a private key lacking a public key file was
+    // derived.
+    if path == pub_path && !pub_path.exists() {
+      continue
+    }
+
+    if keep {
+      let mut new_path = path.as_os_str().to_os_string();
+      new_path.push(".");
+      new_path.push(REMOVED_SUFFIX);
+      let () = rename(path, &new_path)
+        .with_context(|| format!("failed to rename {}", path.display()))?;
+    } else {
+      let () = remove_file(path).with_context(|| format!("failed to remove {}", path.display()))?;
+    }
+  }
+  Ok(())
+}
+
+
+/// Load the per-key options stored alongside the key pair whose GPG
+/// encrypted private key is stored in the given file. That is, for
+/// "key.gpg" we load options from "key.conf", if present.
+pub fn load_key_config(gpg_path: &Path) -> Result<Option<KeyConfig>> {
+  let mut path = gpg_path.to_path_buf();
+  let _ = path.set_extension(OsStr::new(CONFIG_EXT));
+
+  match read_to_string(&path) {
+    Ok(toml) => KeyConfig::parse(&toml)
+      .with_context(|| format!("invalid key configuration in {}", path.display()))
+      .map(Some),
+    Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+    Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+  }
+}
+
+
+/// Load the certificates stored alongside the key pair whose GPG
+/// encrypted private key is stored in the given file. That is, for
+/// "key.gpg" we load the certificates from "key-cert.pub", if present.
+pub fn load_certificates(gpg_path: &Path) -> Result<Vec<PemPublicKey>> {
+  let mut name = gpg_path.file_stem().unwrap_or_default().to_os_string();
+  name.push(CERT_SUFFIX);
+  name.push(".");
+  name.push(PUBLIC_EXT);
+  let path = gpg_path.with_file_name(name);
+
+  if path.exists() {
+    load_public_keys(&path)
+  } else {
+    Ok(Vec::new())
+  }
+}
+
+
+/// Load all public SSH keys from the given file, which lists them one
+/// per line, as done for private key files containing multiple keys.
+pub(crate) fn load_public_keys<P>(file: P) -> Result<Vec<PemPublicKey>>
+where
+  P: AsRef<Path>,
+{
+  let pem = load_public_key(file)?;
+  let keys = String::from_utf8_lossy(pem.as_ref())
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .map(|line| PemPublicKey(format!("{line}\n").into_bytes()))
+    .collect::<Vec<_>>();
+
+  if keys.is_empty() {
+    // Let parsing report why the file does not contain any keys.
+    Ok(vec![pem])
+  } else {
+    Ok(keys)
+  }
 }
 
 
@@ -85,55 +501,244 @@ where
   P: AsRef<Path>,
 {
   let file = file.as_ref();
-  let mut f =
-    File::open(file).with_context(|| format!("failed to open {} for reading", file.display()))?;
-
-  let mut data = Vec::new();
-  let _ = f
-    .read_to_end(&mut data)
-    .with_context(|| format!("failed to read data from {}", file.display()))?;
+  let data = retry(|| {
+    let mut f = File::open(file)?;
+    let mut data = Vec::new();
+    let _ = f.read_to_end(&mut data)?;
+    Ok(data)
+  })
+  .with_context(|| format!("failed to read data from {}", file.display()))?;
 
   Ok(PemPublicKey(data))
 }
 
 
+/// Check whether the given public key file is to be considered as per
+/// the provided discovery rules, based on its file name.
+fn is_discoverable(path: &Path, rules: &DiscoveryConfig) -> bool {
+  let name = path.file_name().map(OsStr::as_bytes).unwrap_or_default();
+  let matches = |pattern: &String| matches_wildcard(pattern.as_bytes(), name, false);
+  (rules.include.is_empty() || rules.include.iter().any(matches))
+    && !rules.exclude.iter().any(matches)
+}
+
+
 /// Find all public keys that have a corresponding GPG encrypted private
 /// key available as well. That is, we directly load all "key.pub" files
 /// in the given directory that also have a corresponding "key.gpg"
-/// available. The path to the encrypted secret key is returned as well.
-pub fn public_keys<P>(dir: P) -> Result<impl Iterator<Item = Result<(PemPublicKey, PathBuf)>>>
+/// available, using the provided function. The path to the encrypted
+/// secret key is returned as well. Keys are reported in the order of
+/// their file names. Only public key files selected by the given
+/// discovery rules are considered.
+pub(crate) fn public_keys_with<P, L>(
+  dir: P,
+  rules: &DiscoveryConfig,
+  mut load: L,
+) -> Result<impl Iterator<Item = Result<(PemPublicKey, PathBuf)>>>
 where
   P: Into<PathBuf>,
+  L: FnMut(&Path) -> Result<Vec<PemPublicKey>>,
 {
   let dir = dir.into();
 
-  read_dir(&dir)
-    .with_context(|| format!("failed to read contents of {}", dir.display()))
-    .map(move |x| {
-      x.filter_map(move |entry| match entry {
-        Ok(entry) => {
-          let path = entry.path();
-          if path.exists() && !path.is_dir() && path.extension() == Some(OsStr::new(PUBLIC_EXT)) {
-            let mut gpg_path = path.clone();
-            let _ = gpg_path.set_extension(OsStr::new(PRIVATE_EXT));
-
-            if gpg_path.exists() && !gpg_path.is_dir() {
-              Some(load_public_key(&path).map(|x| (x, gpg_path)))
-            } else {
-              None
-            }
-          } else {
-            None
-          }
-        }
-        Err(err) => Some(Err(err).with_context(|| {
-          format!(
-            "failed to read directory entry in {}",
-            dir.display(),
-          )
-        })),
-      })
-    })
+  let mut paths = retry(|| read_dir(&dir))
+    .with_context(|| format!("failed to read contents of {}", dir.display()))?
+    .map(|entry| entry.map(|entry| entry.path()))
+    .filter(|path| path.as_ref().map_or(true, |path| is_discoverable(path, rules)))
+    .collect::<Vec<_>>();
+  // Directory entries are reported in an unspecified order, but we
+  // want to be deterministic.
+  let () = paths.sort_by(|x, y| x.as_ref().ok().cmp(&y.as_ref().ok()));
+  let rules = rules.clone();
+
+  let keys = paths.into_iter().flat_map(move |path| match path {
+    Ok(path) => {
+      if path.extension() == Some(OsStr::new(PUBLIC_EXT)) {
+        key_pair_with(&path, &rules, &mut load)
+      } else {
+        Vec::new()
+      }
+    }
+    Err(err) => vec![Err(err).with_context(|| {
+      format!(
+        "failed to read directory entry in {}",
+        dir.display(),
+      )
+    })],
+  });
+  Ok(keys)
+}
+
+
+/// Check whether the given path refers to a key file, i.e., a file (or
+/// a symbolic link to one, if permitted by the given policy).
+///
+/// Dangling symbolic links are ignored, but loops are reported.
+fn is_key_file(path: &Path, policy: SymlinkPolicy) -> Result<bool> {
+  let metadata = match symlink_metadata(path) {
+    Ok(metadata) => metadata,
+    Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+    Err(err) => {
+      return Err(err).with_context(|| format!("failed to query metadata of {}", path.display()))
+    },
+  };
+
+  if !metadata.file_type().is_symlink() {
+    return Ok(!metadata.is_dir())
+  }
+
+  if policy == SymlinkPolicy::Never {
+    return Ok(false)
+  }
+
+  let target = match canonicalize(path) {
+    Ok(target) => target,
+    Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+    Err(err) if err.raw_os_error() == Some(libc::ELOOP) => {
+      bail!("symbolic link {} is part of a loop", path.display())
+    },
+    Err(err) => {
+      return Err(err).with_context(|| format!("failed to resolve symbolic link {}", path.display()))
+    },
+  };
+
+  if policy == SymlinkPolicy::FollowWithinDirectory {
+    let dir = match path.parent() {
+      Some(dir) if !dir.as_os_str().is_empty() => dir,
+      _ => Path::new("."),
+    };
+    let dir = canonicalize(dir)
+      .with_context(|| format!("failed to resolve directory {}", dir.display()))?;
+    if !target.starts_with(dir) {
+      return Ok(false)
+    }
+  }
+  Ok(!target.is_dir())
+}
+
+
+/// Check that the files of the key pair whose GPG encrypted private key
+/// is in `gpg_path` are neither writable by group or others nor owned
+/// by a user other than the current one (or the super user), similar
+/// to what OpenSSH's `StrictModes` setting checks.
+pub fn check_key_pair_permissions(gpg_path: &Path) -> Result<()> {
+  // SAFETY: `geteuid` is always safe to call.
+  let uid = unsafe { geteuid() };
+
+  for path in [public_key_path(gpg_path), gpg_path.to_path_buf()] {
+    let metadata =
+      metadata(&path).with_context(|| format!("failed to query metadata of {}", path.display()))?;
+    if metadata.uid() != uid && metadata.uid() != 0 {
+      bail!("{} is owned by user {}", path.display(), metadata.uid())
+    }
+    let writable = match (metadata.mode() & 0o020 != 0, metadata.mode() & 0o002 != 0) {
+      (true, true) => "group and others",
+      (true, false) => "group",
+      (false, true) => "others",
+      (false, false) => continue,
+    };
+    bail!("{} is writable by {writable}", path.display())
+  }
+  Ok(())
+}
+
+
+/// Load the public keys from the given public key file, if it exists
+/// and has a corresponding GPG encrypted private key, using the
+/// provided function. Symbolic links and key files with unsafe
+/// permissions are treated as per the given discovery rules.
+fn key_pair_with<L>(
+  path: &Path,
+  rules: &DiscoveryConfig,
+  load: &mut L,
+) -> Vec<Result<(PemPublicKey, PathBuf)>>
+where
+  L: FnMut(&Path) -> Result<Vec<PemPublicKey>>,
+{
+  let policy = rules.symlinks;
+  let result = is_key_file(path, policy).and_then(|is_key| {
+    if !is_key {
+      return Ok(None)
+    }
+
+    for gpg_path in [PRIVATE_EXT, CMS_EXT, TPM_EXT].map(|ext| path.with_extension(ext)) {
+      if is_key_file(&gpg_path, policy)? {
+        return Ok(Some(gpg_path))
+      }
+    }
+    Ok(None)
+  });
+
+  let gpg_path = match result {
+    Ok(Some(gpg_path)) => gpg_path,
+    Ok(None) => return Vec::new(),
+    Err(err) => return vec![Err(err)],
+  };
+
+  if rules.permissions != PermissionPolicy::Ignore {
+    if let Err(err) = check_key_pair_permissions(&gpg_path) {
+      if rules.permissions == PermissionPolicy::Refuse {
+        return vec![Err(err.context("refusing to use key pair with unsafe permissions"))]
+      }
+      warn!("key pair has unsafe permissions: {err:#}");
+    }
+  }
+
+  match load(path) {
+    Ok(keys) => keys.into_iter().map(|x| Ok((x, gpg_path.clone()))).collect(),
+    Err(err) => vec![Err(err)],
+  }
+}
+
+
+/// Load the public keys of the key pair referred to by the given
+/// identity file, as used by `ssh`, using the provided function.
+///
+/// That is, for `id_ed25519` the public keys are loaded from
+/// `id_ed25519.pub`, if a GPG encrypted private key (e.g.,
+/// `id_ed25519.gpg`) exists as well. Symbolic links and key files with
+/// unsafe permissions are treated as per the given discovery rules.
+pub(crate) fn identity_file_keys_with<L>(
+  file: &Path,
+  rules: &DiscoveryConfig,
+  mut load: L,
+) -> Vec<Result<(PemPublicKey, PathBuf)>>
+where
+  L: FnMut(&Path) -> Result<Vec<PemPublicKey>>,
+{
+  let mut path = file.as_os_str().to_os_string();
+  let () = path.push(".");
+  let () = path.push(PUBLIC_EXT);
+  key_pair_with(Path::new(&path), rules, &mut load)
+}
+
+
+/// Find the GPG encrypted private keys in the given directory that
+/// lack a public key file, and that are thus not picked up by
+/// [`public_keys_with`].
+pub fn orphaned_private_keys<P>(dir: P) -> Result<Vec<PathBuf>>
+where
+  P: AsRef<Path>,
+{
+  let dir = dir.as_ref();
+  let entries =
+    read_dir(dir).with_context(|| format!("failed to read contents of {}", dir.display()))?;
+  let mut paths = Vec::new();
+
+  for entry in entries {
+    let path = entry
+      .with_context(|| format!("failed to read directory entry in {}", dir.display()))?
+      .path();
+    let private = [PRIVATE_EXT, CMS_EXT, TPM_EXT]
+      .into_iter()
+      .any(|ext| path.extension() == Some(OsStr::new(ext)));
+    if private && !path.is_dir() && !public_key_path(&path).exists() {
+      let () = paths.push(path);
+    }
+  }
+
+  let () = paths.sort();
+  Ok(paths)
 }
 
 
@@ -141,11 +746,39 @@ where
 pub mod test {
   use super::*;
 
+  use crate::keys::Certificate;
+  use crate::keys::is_passphrase_protected;
+  use crate::keys::public_key_comment;
+  use crate::keys::FromEncryptedPem;
   use crate::keys::FromPem;
+  use crate::keys::ToPem;
+  use crate::openssh::IncorrectPassphrase;
+
+  use std::fs::copy;
+  use std::fs::create_dir_all;
+  use std::fs::set_permissions;
+  use std::fs::write;
+  use std::fs::Permissions;
+  use std::os::unix::fs::symlink;
+  use std::os::unix::fs::PermissionsExt as _;
 
   use ssh_agent_lib::proto::private_key::PrivateKey;
   use ssh_agent_lib::proto::public_key::PublicKey;
 
+  use tempfile::tempdir;
+
+
+  /// Find all key pairs in the given directory, as selected by the
+  /// provided discovery rules.
+  fn public_keys<P>(
+    dir: P,
+    rules: &DiscoveryConfig,
+  ) -> Result<impl Iterator<Item = Result<(PemPublicKey, PathBuf)>>>
+  where
+    P: Into<PathBuf>,
+  {
+    public_keys_with(dir, rules, |path| super::load_public_keys(path))
+  }
 
   /// Load a private key from a plain text file. This function is for
   /// testing only. Throughout the program we assume GPG encrypted
@@ -163,14 +796,14 @@ pub mod test {
       .read_to_end(&mut output)
       .with_context(|| format!("failed to read data from {}", file.display()))?;
 
-    Ok(PemPrivateKey(output))
+    Ok(PemPrivateKey::from(output))
   }
 
 
   /// Verify that we can load our test key.
   #[test]
   fn load_public_keys() -> Result<()> {
-    let mut keys = public_keys("tests/valid_keys")?;
+    let mut keys = public_keys("tests/valid_keys", &DiscoveryConfig::default())?;
     let (_, path) = keys.next().unwrap()?;
     assert_eq!(path.to_str().unwrap(), "tests/valid_keys/ed25519.gpg");
 
@@ -185,7 +818,7 @@ pub mod test {
   /// Verify that invalid keys are not loaded.
   #[test]
   fn dont_load_invalid_public_keys() -> Result<()> {
-    let keys = public_keys("tests/invalid_keys")?;
+    let keys = public_keys("tests/invalid_keys", &DiscoveryConfig::default())?;
     assert_eq!(keys.count(), 0);
     Ok(())
   }
@@ -229,4 +862,386 @@ pub mod test {
     let _ = PrivateKey::from_pem(privkey)?;
     Ok(())
   }
+
+
+  /// Test the conversion into of an ECDSA public key object loaded
+  /// from file into an ssh_agent style PublicKey.
+  #[test]
+  fn public_key_conversion_ecdsa256() -> Result<()> {
+    let pubkey = load_public_key("tests/valid_keys/ecdsa256.pub")?;
+    let _ = PublicKey::from_pem(pubkey)?;
+    Ok(())
+  }
+
+
+  /// Test the conversion into of an ECDSA private key object loaded
+  /// from file into an ssh_agent style PrivateKey.
+  #[test]
+  fn private_key_conversion_ecdsa256() -> Result<()> {
+    let privkey = load_unencrypted_private_key("tests/valid_keys/ecdsa256")?;
+    let _ = PrivateKey::from_pem(privkey)?;
+    Ok(())
+  }
+
+
+  /// Check that we can load all keys from a private key file containing
+  /// multiple, but not a single one.
+  #[test]
+  fn private_key_conversion_multi() -> Result<()> {
+    let ed25519 = load_unencrypted_private_key("tests/valid_keys/ed25519")?;
+    let ecdsa256 = load_unencrypted_private_key("tests/valid_keys/ecdsa256")?;
+    let expected = vec![PrivateKey::from_pem(ed25519)?, PrivateKey::from_pem(ecdsa256)?];
+
+    let multi = load_unencrypted_private_key("tests/valid_keys/multi")?;
+    assert_eq!(Vec::<PrivateKey>::from_pem(multi)?, expected);
+
+    let multi = load_unencrypted_private_key("tests/valid_keys/multi")?;
+    let err = PrivateKey::from_pem(multi).unwrap_err();
+    assert!(format!("{err:#}").contains("more than one key"), "{err:#}");
+    Ok(())
+  }
+
+
+  /// Test the conversion of a passphrase protected private key loaded
+  /// from file into an ssh_agent style PrivateKey.
+  #[test]
+  fn private_key_conversion_encrypted() -> Result<()> {
+    let expected = load_unencrypted_private_key("tests/valid_keys/ed25519")?;
+    let expected = PrivateKey::from_pem(expected)?;
+
+    let privkey = load_unencrypted_private_key("tests/valid_keys/ed25519.encrypted")?;
+    assert!(is_passphrase_protected(&privkey));
+    assert_eq!(PrivateKey::from_encrypted_pem(&privkey, b"passphrase")?, expected);
+
+    let err = PrivateKey::from_encrypted_pem(&privkey, b"incorrect").unwrap_err();
+    assert!(err.is::<IncorrectPassphrase>(), "{err:?}");
+    Ok(())
+  }
+
+
+  /// Check that private keys survive a round trip through the OpenSSH
+  /// format.
+  #[test]
+  fn private_key_round_trip() -> Result<()> {
+    for name in ["ecdsa256", "ed25519", "rsa2048"] {
+      let privkey = load_unencrypted_private_key(format!("tests/valid_keys/{name}"))?;
+      let privkey = PrivateKey::from_pem(privkey)?;
+      let pem = privkey.to_pem(name)?;
+      assert_eq!(PrivateKey::from_pem(pem)?, privkey);
+    }
+    Ok(())
+  }
+
+
+  /// Check that public keys survive a round trip through the OpenSSH
+  /// format.
+  #[test]
+  fn public_key_round_trip() -> Result<()> {
+    for name in ["ecdsa256", "ed25519", "rsa2048"] {
+      let pubkey = load_public_key(format!("tests/valid_keys/{name}.pub"))?;
+      let pubkey = PublicKey::from_pem(pubkey)?;
+      let pem = pubkey.to_pem(name)?;
+      assert_eq!(PublicKey::from_pem(pem)?, pubkey);
+    }
+    Ok(())
+  }
+
+
+  /// Check that we extract comments from public key files correctly.
+  #[test]
+  fn public_key_comments() -> Result<()> {
+    let pubkey = load_public_key("tests/valid_keys/ed25519.pub")?;
+    assert_eq!(public_key_comment(&pubkey)?, "ed25519 test");
+
+    let pubkey = load_public_key("tests/valid_keys/ed25519.pub")?;
+    let pubkey = PublicKey::from_pem(pubkey)?.to_pem("")?;
+    assert_eq!(public_key_comment(&pubkey)?, "");
+    Ok(())
+  }
+
+
+  /// Check that we can load a certificate and match it against the key
+  /// it certifies.
+  #[test]
+  fn load_key_certificates() -> Result<()> {
+    let mut certs = load_certificates(Path::new("tests/valid_keys/ed25519.gpg"))?;
+    assert_eq!(certs.len(), 1);
+    let cert = Certificate::from_pem(certs.remove(0))?;
+
+    let ed25519 = PublicKey::from_pem(load_public_key("tests/valid_keys/ed25519.pub")?)?;
+    let rsa2048 = PublicKey::from_pem(load_public_key("tests/valid_keys/rsa2048.pub")?)?;
+    assert!(cert.certifies(&ed25519)?);
+    assert!(!cert.certifies(&rsa2048)?);
+
+    assert!(load_certificates(Path::new("tests/valid_keys/rsa2048.gpg"))?.is_empty());
+    // A plain public key is not a certificate.
+    assert!(Certificate::from_pem(load_public_key("tests/valid_keys/ed25519.pub")?).is_err());
+    Ok(())
+  }
+
+
+  /// Check that each key listed in a public key file is reported as
+  /// belonging to the same private key file.
+  #[test]
+  fn load_multi_key_pairs() -> Result<()> {
+    let dir = tempdir()?;
+    let _ = copy("tests/valid_keys/multi.pub", dir.path().join("multi.pub"))?;
+    let _ = copy("tests/valid_keys/ed25519.gpg", dir.path().join("multi.gpg"))?;
+
+    let keys = public_keys(dir.path(), &DiscoveryConfig::default())?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys.len(), 2);
+
+    for ((pubkey, path), name) in keys.into_iter().zip(["ed25519", "ecdsa256"]) {
+      assert_eq!(path, dir.path().join("multi.gpg"));
+      let expected = load_public_key(format!("tests/valid_keys/{name}.pub"))?;
+      assert_eq!(PublicKey::from_pem(pubkey)?, PublicKey::from_pem(expected)?);
+    }
+    Ok(())
+  }
+
+
+  /// Check that removed key pairs are no longer discovered.
+  #[test]
+  fn remove_key_pairs() -> Result<()> {
+    let dir = tempdir()?;
+    for name in ["ed25519.pub", "ed25519.gpg", "rsa2048.pub", "rsa2048.gpg"] {
+      let _ = copy(Path::new("tests/valid_keys").join(name), dir.path().join(name))?;
+    }
+    assert_eq!(public_keys(dir.path(), &DiscoveryConfig::default())?.count(), 2);
+
+    let () = remove_key_pair(&dir.path().join("ed25519.gpg"), true)?;
+    assert!(dir.path().join("ed25519.gpg.removed").exists());
+    assert!(dir.path().join("ed25519.pub.removed").exists());
+    assert_eq!(public_keys(dir.path(), &DiscoveryConfig::default())?.count(), 1);
+
+    let () = remove_key_pair(&dir.path().join("rsa2048.gpg"), false)?;
+    assert!(!dir.path().join("rsa2048.pub").exists());
+    assert_eq!(public_keys(dir.path(), &DiscoveryConfig::default())?.count(), 0);
+    Ok(())
+  }
+
+  /// Check that only the public key files selected by the discovery
+  /// rules are considered.
+  #[test]
+  fn discover_key_pairs() -> Result<()> {
+    let dir = tempdir()?;
+    for (src, dst) in [
+      ("ed25519.pub", "id_ed25519.pub"),
+      ("ed25519.gpg", "id_ed25519.gpg"),
+      ("rsa2048.pub", "id_rsa.pub"),
+      ("rsa2048.gpg", "id_rsa.gpg"),
+      ("ed25519.pub", "backup.pub"),
+      ("ed25519.gpg", "backup.gpg"),
+    ] {
+      let _ = copy(Path::new("tests/valid_keys").join(src), dir.path().join(dst))?;
+    }
+
+    let discovered = |rules: &DiscoveryConfig| -> Result<Vec<PathBuf>> {
+      public_keys(dir.path(), rules)?
+        .map(|result| result.map(|(_, path)| path))
+        .collect()
+    };
+
+    let mut rules = DiscoveryConfig::default();
+    assert_eq!(discovered(&rules)?.len(), 3);
+
+    rules.include = vec!["id_*".to_string()];
+    assert_eq!(
+      discovered(&rules)?,
+      vec![dir.path().join("id_ed25519.gpg"), dir.path().join("id_rsa.gpg")]
+    );
+
+    rules.exclude = vec!["*rsa.pub".to_string()];
+    assert_eq!(discovered(&rules)?, vec![dir.path().join("id_ed25519.gpg")]);
+
+    rules.include = Vec::new();
+    assert_eq!(
+      discovered(&rules)?,
+      vec![dir.path().join("backup.gpg"), dir.path().join("id_ed25519.gpg")]
+    );
+    Ok(())
+  }
+
+  /// Check that symbolic links to key files are followed as per the
+  /// configured policy, and that loops are reported.
+  #[test]
+  fn discover_symlinked_key_pairs() -> Result<()> {
+    let root = tempdir()?;
+    let dir = root.path().join("keys");
+    let farm = root.path().join("dotfiles");
+    let () = create_dir_all(dir.join("sub"))?;
+    let () = create_dir_all(&farm)?;
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("valid_keys");
+    let _ = copy(src.join("ed25519.pub"), dir.join("sub").join("ed25519.pub"))?;
+    let _ = copy(src.join("ed25519.gpg"), dir.join("sub").join("ed25519.gpg"))?;
+    let _ = copy(src.join("rsa2048.pub"), farm.join("rsa2048.pub"))?;
+    let _ = copy(src.join("rsa2048.gpg"), farm.join("rsa2048.gpg"))?;
+
+    let () = symlink(dir.join("sub").join("ed25519.pub"), dir.join("local.pub"))?;
+    let () = symlink(dir.join("sub").join("ed25519.gpg"), dir.join("local.gpg"))?;
+    let () = symlink(farm.join("rsa2048.pub"), dir.join("farm.pub"))?;
+    let () = symlink(farm.join("rsa2048.gpg"), dir.join("farm.gpg"))?;
+    let () = symlink(dir.join("dangling.pub"), dir.join("missing.pub"))?;
+    let _ = copy(src.join("ed25519.pub"), dir.join("loop.pub"))?;
+    let () = symlink(dir.join("loop.gpg"), dir.join("loop.gpg"))?;
+
+    let discovered = |symlinks| -> Vec<Result<PathBuf>> {
+      let rules = DiscoveryConfig {
+        symlinks,
+        ..Default::default()
+      };
+      public_keys(&dir, &rules)
+        .unwrap()
+        .map(|result| result.map(|(_, path)| path))
+        .collect()
+    };
+
+    let found = discovered(SymlinkPolicy::Follow);
+    assert_eq!(found.len(), 3);
+    assert_eq!(found[0].as_ref().unwrap(), &dir.join("farm.gpg"));
+    assert_eq!(found[1].as_ref().unwrap(), &dir.join("local.gpg"));
+    let err = found[2].as_ref().unwrap_err();
+    assert!(err.to_string().contains("loop"), "{err:#}");
+
+    let found = discovered(SymlinkPolicy::FollowWithinDirectory);
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0].as_ref().unwrap(), &dir.join("local.gpg"));
+    assert!(found[1].is_err());
+
+    let found = discovered(SymlinkPolicy::Never);
+    assert!(found.is_empty());
+    Ok(())
+  }
+
+  /// Check that only likely transient errors are retried.
+  #[test]
+  fn retry_transient_errors() {
+    let mut attempts = 0;
+    let result = retry(|| {
+      attempts += 1;
+      if attempts < ATTEMPTS {
+        Err(io::Error::from_raw_os_error(libc::ESTALE))
+      } else {
+        Ok(attempts)
+      }
+    });
+    assert_eq!(result.unwrap(), ATTEMPTS);
+
+    let mut attempts = 0;
+    let result = retry(|| -> io::Result<()> {
+      attempts += 1;
+      Err(io::Error::from_raw_os_error(libc::EIO))
+    });
+    assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EIO));
+    assert_eq!(attempts, ATTEMPTS);
+
+    let mut attempts = 0;
+    let result = retry(|| -> io::Result<()> {
+      attempts += 1;
+      Err(io::Error::from(ErrorKind::NotFound))
+    });
+    assert!(!is_transient(&Error::from(result.unwrap_err())));
+    assert_eq!(attempts, 1);
+
+    let err = Error::from(io::Error::from_raw_os_error(libc::ESTALE)).context("failed to read");
+    assert!(is_transient(&err));
+  }
+
+  /// Check that key pairs with files writable by others are reported
+  /// and refused if so configured.
+  #[test]
+  fn key_pair_permissions() -> Result<()> {
+    let dir = tempdir()?;
+    for name in ["ed25519.pub", "ed25519.gpg"] {
+      let path = dir.path().join(name);
+      let _ = copy(Path::new("tests/valid_keys").join(name), &path)?;
+      let () = set_permissions(&path, Permissions::from_mode(0o600))?;
+    }
+    let gpg_path = dir.path().join("ed25519.gpg");
+    let () = check_key_pair_permissions(&gpg_path)?;
+
+    let pub_path = dir.path().join("ed25519.pub");
+    let () = set_permissions(&pub_path, Permissions::from_mode(0o664))?;
+    let err = check_key_pair_permissions(&gpg_path).unwrap_err();
+    assert_eq!(err.to_string(), format!("{} is writable by group", pub_path.display()));
+
+    let () = set_permissions(&gpg_path, Permissions::from_mode(0o606))?;
+    let () = set_permissions(&pub_path, Permissions::from_mode(0o644))?;
+    let err = check_key_pair_permissions(&gpg_path).unwrap_err();
+    assert_eq!(err.to_string(), format!("{} is writable by others", gpg_path.display()));
+
+    let mut rules = DiscoveryConfig::default();
+    assert_eq!(public_keys(dir.path(), &rules)?.collect::<Result<Vec<_>>>()?.len(), 1);
+
+    rules.permissions = PermissionPolicy::Refuse;
+    let err = public_keys(dir.path(), &rules)?.next().unwrap().unwrap_err();
+    assert_eq!(err.to_string(), "refusing to use key pair with unsafe permissions");
+    Ok(())
+  }
+
+  /// Check that we find private keys lacking a public key and can
+  /// store one for them.
+  #[test]
+  fn find_orphaned_private_keys() -> Result<()> {
+    let dir = tempdir()?;
+    for (src, dst) in [
+      ("ed25519.pub", "ed25519.pub"),
+      ("ed25519.gpg", "ed25519.gpg"),
+      ("rsa2048.gpg", "rsa2048.gpg"),
+      ("ed25519.gpg", "other.p7m"),
+    ] {
+      let _ = copy(Path::new("tests/valid_keys").join(src), dir.path().join(dst))?;
+    }
+    let orphans = orphaned_private_keys(dir.path())?;
+    assert_eq!(
+      orphans,
+      vec![dir.path().join("other.p7m"), dir.path().join("rsa2048.gpg")]
+    );
+    assert_eq!(public_keys(dir.path(), &DiscoveryConfig::default())?.count(), 1);
+
+    let pubkey = load_public_key("tests/valid_keys/rsa2048.pub")?;
+    let path = store_public_key(&orphans[1], &pubkey)?;
+    assert_eq!(path, dir.path().join("rsa2048.pub"));
+    assert_eq!(public_keys(dir.path(), &DiscoveryConfig::default())?.count(), 2);
+    assert_eq!(orphaned_private_keys(dir.path())?, vec![orphans[0].clone()]);
+
+    // Existing public keys are never overwritten.
+    assert!(store_public_key(&orphans[1], &pubkey).is_err());
+    Ok(())
+  }
+
+  /// Check that we discover CMS encrypted private keys and infer their
+  /// protocol.
+  #[test]
+  fn load_cms_key_pairs() -> Result<()> {
+    let dir = tempdir()?;
+    let _ = copy("tests/valid_keys/ed25519.pub", dir.path().join("ed25519.pub"))?;
+    let _ = copy("tests/valid_keys/ed25519.gpg", dir.path().join("ed25519.p7m"))?;
+
+    let mut keys = public_keys(dir.path(), &DiscoveryConfig::default())?;
+    let (_, path) = keys.next().unwrap()?;
+    assert_eq!(path, dir.path().join("ed25519.p7m"));
+    assert!(keys.next().is_none());
+
+    assert_eq!(protocol(&path), Protocol::Cms);
+    assert_eq!(protocol(Path::new("ed25519.gpg")), Protocol::OpenPgp);
+    Ok(())
+  }
+
+  /// Check that we discover private keys sealed to the TPM.
+  #[test]
+  fn load_tpm_key_pairs() -> Result<()> {
+    let dir = tempdir()?;
+    let _ = copy("tests/valid_keys/ed25519.pub", dir.path().join("ed25519.pub"))?;
+    let () = write(dir.path().join("ed25519.tpm"), b"")?;
+
+    let mut keys = public_keys(dir.path(), &DiscoveryConfig::default())?;
+    let (_, path) = keys.next().unwrap()?;
+    assert_eq!(path, dir.path().join("ed25519.tpm"));
+    assert!(keys.next().is_none());
+
+    assert!(is_tpm_sealed(&path));
+    assert!(!is_tpm_sealed(Path::new("ed25519.gpg")));
+    Ok(())
+  }
 }
@@ -0,0 +1,855 @@
+// filestore.rs
+
+// *************************************************************************
+// * Copyright (C) 2019-2024 Daniel Mueller (deso@posteo.net)              *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! The key store managing the GPG encrypted (or TPM sealed) key files
+//! in the agent's directories.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::metadata;
+use std::path::Path;
+use std::path::PathBuf;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use dirs::home_dir;
+
+use ssh_agent_lib::proto::key_type::KeyTypeEnum;
+use ssh_agent_lib::proto::message::AddIdentity;
+use ssh_agent_lib::proto::message::SignatureBlob;
+use ssh_agent_lib::proto::message::SignRequest;
+use ssh_agent_lib::proto::private_key::PrivateKey;
+use ssh_agent_lib::proto::public_key::PublicKey;
+use ssh_agent_lib::proto::Blob;
+
+use tracing::debug;
+use tracing::debug_span;
+use tracing::info;
+use tracing::warn;
+
+use crate::agent::PASSPHRASE_ATTEMPTS;
+use crate::cache::Cache;
+use crate::cache::CachedKey;
+use crate::config::Config;
+use crate::config::KeyConfig;
+use crate::config::OnRemove;
+use crate::decrypt::decryptor;
+use crate::decrypt::Decryptor;
+use crate::error::DecryptError;
+use crate::error::KeyStoreError;
+use crate::error::RequestError;
+use crate::files::is_tpm_sealed;
+use crate::files::load_certificates;
+use crate::files::load_key_config;
+use crate::files::load_public_keys;
+use crate::files::orphaned_private_keys;
+use crate::files::protocol as file_protocol;
+use crate::files::public_key_path;
+use crate::files::public_keys_to_pem;
+use crate::files::remove_key_pair;
+use crate::files::store_key_pair;
+use crate::files::PemPrivateKey;
+use crate::files::PemPublicKey;
+use crate::fingerprint::Fingerprint;
+use crate::fingerprint::HashAlg;
+use crate::flight::SingleFlight;
+use crate::index::KeyIndex;
+use crate::keys::is_passphrase_protected;
+use crate::keys::public_key_comment;
+use crate::keys::Certificate;
+use crate::keys::FromEncryptedPem;
+use crate::keys::FromPem;
+use crate::keys::ToPem;
+use crate::openssh::IncorrectPassphrase;
+use crate::peer::Peer;
+use crate::policy::check_access;
+use crate::prompt;
+use crate::sign::Signer;
+use crate::sshconfig::identity_files;
+use crate::store::KeyFiles;
+use crate::store::KeyStore;
+use crate::store::StoreIdentity;
+#[cfg(feature = "tpm")]
+use crate::tpm;
+
+
+trait Mapper<T, E>
+where
+  Self: Sized,
+{
+  fn map_flat<F, U>(self, f: F) -> StdResult<U, E>
+  where
+    F: FnMut(T) -> StdResult<U, E>;
+}
+
+impl<T, E> Mapper<T, E> for StdResult<T, E> {
+  fn map_flat<F, U>(self, mut f: F) -> StdResult<U, E>
+  where
+    F: FnMut(T) -> StdResult<U, E>,
+  {
+    match self {
+      Ok(val) => f(val),
+      Err(err) => Err(err),
+    }
+  }
+}
+
+
+/// The public keys derived from a GPG encrypted private key file
+/// lacking a public key file.
+#[derive(Debug)]
+struct DerivedKeys {
+  /// The modification time of the file at the time of derivation.
+  modified: Option<SystemTime>,
+  /// The derived public keys or, if derivation failed, the error
+  /// message.
+  keys: StdResult<Vec<PublicKey>, String>,
+}
+
+
+/// The key store offering the key pairs in the agent's directories,
+/// whose private keys are GPG encrypted or sealed to the TPM.
+#[derive(Debug)]
+pub struct FileStore {
+  /// The name of the store.
+  name: &'static str,
+  /// The directories in which to look for SSH key pairs, in order of
+  /// precedence.
+  dirs: Vec<PathBuf>,
+  /// The key files in these directories.
+  files: RwLock<Arc<dyn KeyFiles>>,
+  /// The decryptor used for loading GPG encrypted private keys.
+  decryptor: RwLock<Arc<dyn Decryptor>>,
+  /// The agent's configuration.
+  config: RwLock<Arc<Config>>,
+  /// The cache of decrypted private keys.
+  cache: Arc<Cache>,
+  /// The decryptions of private keys currently in progress, shared by
+  /// concurrent requests for the same key.
+  decryptions: SingleFlight<PublicKey, Arc<CachedKey>>,
+  /// The public keys derived from private key files lacking a public
+  /// key file, by path.
+  derived: Mutex<HashMap<PathBuf, DerivedKeys>>,
+  /// A lock serializing derivations of public keys, without holding up
+  /// users of the ones derived already.
+  deriving: Mutex<()>,
+}
+
+impl FileStore {
+  /// Create a store for the key pairs in the given directories (and
+  /// those referred to by the configured OpenSSH client configuration),
+  /// caching decrypted private keys in `cache`.
+  pub fn new(dirs: Vec<PathBuf>, config: Arc<Config>, cache: Arc<Cache>) -> Self {
+    let files = KeyIndex::new(
+      dirs.clone(),
+      ssh_config_identity_files(&config),
+      config.discovery.clone(),
+      config.index_file.clone(),
+    );
+    Self::with_files("files", dirs, files, config, cache)
+  }
+
+  /// Create a store for the key pairs kept in the given directory of
+  /// the `pass` password store, caching decrypted private keys in
+  /// `cache`.
+  ///
+  /// The entries of the password store are GPG encrypted files
+  /// themselves, so they are treated just like other key files.
+  pub fn pass(dir: PathBuf, config: Arc<Config>, cache: Arc<Cache>) -> Self {
+    let dirs = vec![dir];
+    let files = KeyIndex::new(dirs.clone(), Vec::new(), config.discovery.clone(), None);
+    Self::with_files("pass", dirs, files, config, cache)
+  }
+
+  /// Create a store with the given name for the provided key files.
+  fn with_files(
+    name: &'static str,
+    dirs: Vec<PathBuf>,
+    files: KeyIndex,
+    config: Arc<Config>,
+    cache: Arc<Cache>,
+  ) -> Self {
+    Self {
+      name,
+      files: RwLock::new(Arc::new(files)),
+      decryptor: RwLock::new(Arc::from(decryptor(&config))),
+      dirs,
+      config: RwLock::new(config),
+      cache,
+      decryptions: SingleFlight::new(),
+      derived: Mutex::new(HashMap::new()),
+      deriving: Mutex::new(()),
+    }
+  }
+
+  /// Use the given key files instead of those found by indexing the
+  /// store's directories.
+  pub fn set_key_files<F>(&self, files: F)
+  where
+    F: KeyFiles + 'static,
+  {
+    *self.files.write().unwrap() = Arc::new(files);
+  }
+
+  /// Use the given decryptor instead of the one for the configured
+  /// backend.
+  pub fn set_decryptor<D>(&self, decryptor: D)
+  where
+    D: Decryptor + 'static,
+  {
+    *self.decryptor.write().unwrap() = Arc::new(decryptor);
+  }
+
+  /// Retrieve the current configuration.
+  fn config(&self) -> Arc<Config> {
+    self.config.read().unwrap().clone()
+  }
+
+  /// Retrieve the decryptor currently in use.
+  fn decryptor(&self) -> Arc<dyn Decryptor> {
+    self.decryptor.read().unwrap().clone()
+  }
+
+  /// Retrieve the key files currently in use.
+  fn files(&self) -> Arc<dyn KeyFiles> {
+    self.files.read().unwrap().clone()
+  }
+
+  /// Retrieve the store's public keys, along with their comments (or
+  /// configured labels).
+  ///
+  /// Keys are reported in the order of the directories they are
+  /// contained in, followed by those derived from private key files
+  /// lacking a public key file (see [`FileStore::derive_orphans`]). A
+  /// key present in multiple directories is only reported for the first
+  /// one.
+  pub fn public_keys(&self) -> impl Iterator<Item = Result<(PublicKey, String, PathBuf)>> + '_ {
+    let mut seen = HashSet::new();
+
+    self
+      .files()
+      .keys()
+      .into_iter()
+      .map(|x| {
+        x.map_flat(|(key, path)| {
+          let (key, comment) = parse_public_key(key, self.key_label(&path)).with_context(|| {
+            format!(
+              "failed to parse public key stored in {}",
+              public_key_path(&path).display()
+            )
+          })?;
+          Ok((key, comment, path))
+        })
+      })
+      .chain(self.derived_public_keys())
+      .filter(move |x| match x {
+        Ok((key, _, path)) => {
+          let enabled = self
+            .key_config(path)
+            .map(|config| config.enabled)
+            // Report keys with broken configuration, so that errors
+            // don't go unnoticed when the key is used.
+            .unwrap_or(true);
+          enabled
+            && Fingerprint::from_key(key, HashAlg::Sha256)
+              .map(|fingerprint| seen.insert(fingerprint))
+              // Again, errors surface once the key is used.
+              .unwrap_or(true)
+        },
+        Err(_) => true,
+      })
+  }
+
+  /// Check whether the GPG encrypted private key stored in the given
+  /// file could currently be decrypted, without decrypting it.
+  ///
+  /// `None` is returned if that cannot be told.
+  pub fn can_decrypt(&self, gpg_path: &Path) -> Result<Option<bool>> {
+    // Whether unsealing succeeds depends on the state of the TPM.
+    if is_tpm_sealed(gpg_path) {
+      return Ok(None)
+    }
+
+    let config = self.key_config(gpg_path)?;
+    let protocol = config.protocol.unwrap_or_else(|| file_protocol(gpg_path));
+    self
+      .decryptor()
+      .can_decrypt(gpg_path, protocol)
+      .map_err(DecryptError::into_inner)
+  }
+
+  /// Load the private key belonging to the provided public key from the
+  /// given GPG encrypted file, which may contain multiple keys.
+  ///
+  /// Key files are associated with each other by name only. If they
+  /// got out of sync we would create signatures that no server accepts.
+  fn load_key_pair(&self, pubkey: &PublicKey, gpg_path: &Path) -> Result<CachedKey> {
+    let keys = self.load_private_keys(gpg_path)?;
+    let count = keys.len();
+    match keys.into_iter().find(|key| PublicKey::from(&**key) == *pubkey) {
+      Some(key) => Ok(key),
+      None if count == 1 => bail!(
+        "private key stored in {} does not belong to public key stored in {}",
+        gpg_path.display(),
+        public_key_path(gpg_path).display(),
+      ),
+      None => bail!(
+        "none of the private keys stored in {} belongs to public key stored in {}",
+        gpg_path.display(),
+        public_key_path(gpg_path).display(),
+      ),
+    }
+  }
+
+  /// Check that the private key stored in the given GPG encrypted file
+  /// belongs to the provided public key, by decrypting it.
+  pub fn verify_key_pair(&self, pubkey: &PublicKey, gpg_path: &Path) -> Result<()> {
+    self.load_key_pair(pubkey, gpg_path).map(|_key| ())
+  }
+
+  /// Retrieve the GPG encrypted private keys lacking a public key file,
+  /// in the order of the directories they are contained in.
+  ///
+  /// Telling the public keys of such keys requires decryption, so they
+  /// are only offered to clients once derived via
+  /// [`FileStore::derive_orphans`].
+  pub fn orphaned_private_keys(&self) -> Vec<Result<PathBuf>> {
+    self
+      .dirs
+      .iter()
+      .flat_map(|dir| match orphaned_private_keys(dir) {
+        Ok(paths) => paths.into_iter().map(Ok).collect(),
+        Err(err) => vec![Err(err)],
+      })
+      .collect()
+  }
+
+  /// Derive the public keys belonging to the private keys stored in
+  /// the given GPG encrypted file, by decrypting it.
+  pub fn derive_public_key(&self, gpg_path: &Path) -> Result<PemPublicKey> {
+    let name = gpg_path.file_stem().unwrap_or_default().to_string_lossy();
+    let pubkeys = self
+      .load_private_keys(gpg_path)?
+      .iter()
+      .map(|key| (PublicKey::from(&**key), name.to_string()))
+      .collect::<Vec<_>>();
+    public_keys_to_pem(&pubkeys)
+  }
+
+  /// Derive the public keys of the private key files lacking a public
+  /// key file whose public keys are not known yet, by decrypting them.
+  ///
+  /// The derived public keys are remembered for as long as the files
+  /// remain unchanged, and the decrypted private keys are cached as
+  /// usual. Failures are remembered as well, so that the user is not
+  /// prompted over and over again, until the store is invalidated.
+  ///
+  /// As decryption may involve prompting the user, this only happens
+  /// when explicitly asked for and never as part of listing the store's
+  /// identities.
+  pub fn derive_orphans(&self) {
+    let _guard = self.deriving.lock().unwrap();
+    for path in self.orphaned_private_keys().into_iter().flatten() {
+      let modified = metadata(&path).and_then(|meta| meta.modified()).ok();
+      let current = self
+        .derived
+        .lock()
+        .unwrap()
+        .get(&path)
+        .is_some_and(|keys| keys.modified == modified);
+      if current {
+        continue
+      }
+
+      info!("Deriving public key of {}", path.display());
+      let keys = self
+        .load_private_keys(&path)
+        .map(|keys| {
+          keys
+            .into_iter()
+            .map(|key| {
+              let pubkey = PublicKey::from(&*key);
+              let () = self.cache.insert(pubkey.clone(), Arc::new(key));
+              pubkey
+            })
+            .collect()
+        })
+        .map_err(|err| {
+          format!(
+            "failed to derive public key of {}: {err:#}",
+            path.display()
+          )
+        });
+      let _ = self
+        .derived
+        .lock()
+        .unwrap()
+        .insert(path, DerivedKeys { modified, keys });
+    }
+  }
+
+  /// Retrieve the public keys derived from private key files lacking a
+  /// public key file, along with their comments (or configured labels).
+  fn derived_public_keys(&self) -> Vec<Result<(PublicKey, String, PathBuf)>> {
+    let derived = self.derived.lock().unwrap();
+    let mut paths = derived.keys().collect::<Vec<_>>();
+    let () = paths.sort();
+
+    paths
+      .into_iter()
+      // Files that are gone (or got a public key file in the meantime)
+      // are no longer of interest.
+      .filter(|path| path.exists() && !public_key_path(path).exists())
+      .flat_map(|path| match &derived[path].keys {
+        Ok(keys) => {
+          let comment = self.key_label(path).unwrap_or_else(|| {
+            path
+              .file_stem()
+              .unwrap_or_default()
+              .to_string_lossy()
+              .into_owned()
+          });
+          keys
+            .iter()
+            .map(|key| Ok((key.clone(), comment.clone(), path.clone())))
+            .collect()
+        },
+        Err(err) => vec![Err(anyhow!("{err}"))],
+      })
+      .collect()
+  }
+
+  /// Check that the decryption backend is operational.
+  pub fn check_decryptor(&self) -> Result<()> {
+    self.decryptor().check().map_err(DecryptError::into_inner)
+  }
+
+  /// Retrieve the options of the key pair whose GPG encrypted private
+  /// key is stored in the given file.
+  ///
+  /// Options stored in a file alongside the key take precedence over
+  /// those from the agent's configuration.
+  fn key_config(&self, gpg_path: &Path) -> Result<KeyConfig> {
+    if let Some(config) = load_key_config(gpg_path)? {
+      Ok(config)
+    } else {
+      let name = gpg_path.file_stem().unwrap_or_default().to_string_lossy();
+      Ok(self.config().key(&name))
+    }
+  }
+
+  /// Retrieve the label configured for the key pair whose GPG encrypted
+  /// private key is stored in the given file, if any.
+  fn key_label(&self, gpg_path: &Path) -> Option<String> {
+    self
+      .key_config(gpg_path)
+      // A broken configuration is reported once the key is used.
+      .ok()
+      .and_then(|config| config.label)
+  }
+
+  /// Load the certificate for the given public key, stored alongside
+  /// the key pair whose GPG encrypted private key is in `gpg_path`.
+  fn certificate(&self, pubkey: &PublicKey, gpg_path: &Path) -> Result<Option<Certificate>> {
+    let certs = load_certificates(gpg_path)?;
+    if certs.is_empty() {
+      return Ok(None)
+    }
+
+    for cert in certs {
+      let cert = Certificate::from_pem(cert).with_context(|| {
+        format!("failed to load certificate for {}", gpg_path.display())
+      })?;
+      if cert.certifies(pubkey)? {
+        return Ok(Some(cert))
+      }
+    }
+
+    // Files containing multiple keys may come with certificates for
+    // only some of them.
+    ensure!(
+      load_public_keys(public_key_path(gpg_path))?.len() > 1,
+      "certificate for {} does not certify its key",
+      gpg_path.display()
+    );
+    Ok(None)
+  }
+
+  /// Unseal the private keys sealed to the TPM in the given file.
+  #[cfg(feature = "tpm")]
+  fn unseal(&self, path: &Path, config: &KeyConfig) -> Result<PemPrivateKey> {
+    info!("Unsealing {}", path.display());
+    tpm::unseal(path, &self.config().tpm, &config.pcrs)
+  }
+
+  /// Unseal the private keys sealed to the TPM in the given file.
+  #[cfg(not(feature = "tpm"))]
+  fn unseal(&self, path: &Path, _config: &KeyConfig) -> Result<PemPrivateKey> {
+    bail!(
+      "{} is sealed to the TPM, but TPM support is not compiled in",
+      path.display()
+    )
+  }
+
+  /// Load the private keys stored in the given GPG encrypted (or TPM
+  /// sealed) file, prompting the user for passphrases as necessary.
+  fn load_private_keys(&self, gpg_path: &Path) -> Result<Vec<CachedKey>> {
+    let config = self.key_config(gpg_path)?;
+    if is_tpm_sealed(gpg_path) {
+      let pem = self.unseal(gpg_path, &config)?;
+      return self.parse_private_keys(pem, gpg_path)
+    }
+
+    let protocol = config.protocol.unwrap_or_else(|| file_protocol(gpg_path));
+    let decrypted = self.decryptor().decrypt(gpg_path, protocol)?;
+    ensure!(
+      config.decryption_keys.is_empty() || decrypted.decrypted_with(&config.decryption_keys),
+      "{} was not decrypted using any of the expected GPG keys ({}); used: {}",
+      gpg_path.display(),
+      config.decryption_keys.join(", "),
+      if decrypted.keys.is_empty() {
+        "unknown".to_string()
+      } else {
+        decrypted.keys.join(", ")
+      },
+    );
+    self.parse_private_keys(decrypted.key, gpg_path)
+  }
+
+  /// Parse the decrypted private keys originating from the given file,
+  /// prompting the user for the passphrase if they are protected by
+  /// one.
+  fn parse_private_keys(&self, pem: PemPrivateKey, gpg_path: &Path) -> Result<Vec<CachedKey>> {
+    let keys = if is_passphrase_protected(&pem) {
+      self.decrypt_private_keys(&pem, gpg_path)?
+    } else {
+      Vec::<PrivateKey>::from_pem(pem)
+        .with_context(|| format!("{} contains an invalid private key", gpg_path.display()))?
+    };
+    Ok(keys.into_iter().map(CachedKey::from).collect())
+  }
+
+  /// Decrypt private keys that are protected by a passphrase (in
+  /// addition to GPG encryption), prompting the user for it.
+  fn decrypt_private_keys(&self, pem: &PemPrivateKey, gpg_path: &Path) -> Result<Vec<PrivateKey>> {
+    let name = gpg_path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut description = format!("Enter passphrase for SSH key {name}");
+
+    for _ in 0..PASSPHRASE_ATTEMPTS {
+      let passphrase = prompt::passphrase(&description)?
+        .ok_or_else(|| anyhow!("passphrase entry for {} canceled", gpg_path.display()))?;
+      match Vec::<PrivateKey>::from_encrypted_pem(pem, passphrase.as_bytes()) {
+        Err(err) if err.is::<IncorrectPassphrase>() => {
+          description = format!("Incorrect passphrase. Enter passphrase for SSH key {name}");
+        },
+        result => {
+          return result
+            .with_context(|| format!("{} contains an invalid private key", gpg_path.display()))
+        },
+      }
+    }
+    Err(anyhow!(
+      "failed to decrypt {}: too many incorrect passphrases",
+      gpg_path.display()
+    ))
+  }
+
+  /// Create the identities for the given key pair, i.e., one for the
+  /// public key and one for its certificate, if any.
+  fn key_identities(
+    &self,
+    pubkey: PublicKey,
+    comment: String,
+    gpg_path: &Path,
+  ) -> Result<Vec<StoreIdentity>> {
+    let confirm = self
+      .key_config(gpg_path)
+      .map(|config| config.confirm)
+      // A broken configuration is reported once the key is used.
+      .unwrap_or(false);
+    let cert = self.certificate(&pubkey, gpg_path)?;
+    let blob = pubkey
+      .to_blob()
+      .with_context(|| "failed to serialize public key")?;
+    let mut identities = vec![StoreIdentity {
+      blob,
+      pubkey: Some(pubkey.clone()),
+      comment,
+      confirm,
+    }];
+
+    if let Some(cert) = cert {
+      let comment = self
+        .key_label(gpg_path)
+        .unwrap_or_else(|| cert.comment().to_string());
+      let identity = StoreIdentity {
+        blob: cert.blob().to_vec(),
+        pubkey: Some(pubkey),
+        comment,
+        confirm,
+      };
+      let () = identities.push(identity);
+    }
+    Ok(identities)
+  }
+
+  /// Find the key pair corresponding to the given public key or
+  /// certificate blob.
+  ///
+  /// Public keys that fail to load are skipped, so that a single broken
+  /// key does not get in the way of finding the others. They are
+  /// reported when listing identities.
+  pub fn find_private_key(&self, blob: &[u8]) -> Option<Result<(PublicKey, String, PathBuf)>> {
+    self.public_keys().filter(Result::is_ok).find_map(|x| {
+      x.and_then(|(key, comment, path)| {
+        let key_blob = key
+          .to_blob()
+          .with_context(|| "failed to serialize public key")?;
+        let found = key_blob == blob
+          || self
+            .certificate(&key, &path)?
+            .map(|cert| cert.blob() == blob)
+            .unwrap_or(false);
+        Ok(found.then_some((key, comment, path)))
+      })
+      .transpose()
+    })
+  }
+
+  /// Find the key pair of the given identity of the store.
+  fn find_identity(&self, identity: &StoreIdentity) -> Result<(PublicKey, String, PathBuf)> {
+    self
+      .find_private_key(&identity.blob)
+      .unwrap_or_else(|| Err(anyhow!("key {:?} no longer present", identity.comment)))
+  }
+
+  /// Add the given identity to the store.
+  ///
+  /// The private key is GPG encrypted and stored in the first of the
+  /// store's directories, alongside the corresponding public key.
+  pub fn add(&self, identity: &AddIdentity) -> Result<()> {
+    let config = self.config();
+    let recipient = config
+      .recipient
+      .as_deref()
+      .ok_or_else(|| anyhow!("no GPG recipient configured"))
+      .with_context(|| "failed to add identity")?;
+
+    let pubkey = PublicKey::from(&identity.privkey);
+    let blob = pubkey
+      .to_blob()
+      .with_context(|| "failed to serialize public key")?;
+    if let Some(result) = self.find_private_key(&blob) {
+      let (_, _, path) = result?;
+      info!("Identity already present in {}", path.display());
+      return Ok(())
+    }
+
+    let name = key_file_name(&identity.comment, &pubkey.key_type());
+    let privkey = identity.privkey.to_pem(&identity.comment)?;
+    let pubkey = pubkey.to_pem(&identity.comment)?;
+    let dir = self
+      .dirs
+      .first()
+      .ok_or_else(|| anyhow!("no key directory configured"))?;
+    let path = store_key_pair(dir, &name, recipient, &config.gpg, pubkey, privkey)
+      .with_context(|| "failed to add identity")?;
+    let () = self.files().invalidate();
+
+    info!("Stored identity in {}", path.display());
+    Ok(())
+  }
+}
+
+impl KeyStore for FileStore {
+  fn name(&self) -> &str {
+    self.name
+  }
+
+  fn identities(
+    &self,
+  ) -> StdResult<Vec<StdResult<StoreIdentity, KeyStoreError>>, KeyStoreError> {
+    let identities = self
+      .public_keys()
+      .flat_map(|result| {
+        match result.and_then(|(pubkey, comment, path)| self.key_identities(pubkey, comment, &path))
+        {
+          Ok(identities) => identities.into_iter().map(Ok).collect(),
+          Err(err) => vec![Err(KeyStoreError::from(err))],
+        }
+      })
+      .collect();
+    Ok(identities)
+  }
+
+  fn check_access(
+    &self,
+    identity: &StoreIdentity,
+    peer: Option<&Peer>,
+  ) -> StdResult<(), KeyStoreError> {
+    let (_, _, file) = self.find_identity(identity)?;
+    let () = check_access(&self.key_config(&file)?, peer)?;
+    Ok(())
+  }
+
+  fn sign(
+    &self,
+    identity: &StoreIdentity,
+    request: &SignRequest,
+  ) -> StdResult<SignatureBlob, KeyStoreError> {
+    let (pubkey, comment, file) = self.find_identity(identity)?;
+    info!("Signing with key {comment:?} stored in {}", file.display());
+
+    let key = if let Some(key) = self.cache.get(&pubkey) {
+      key
+    } else {
+      // Concurrent requests for the same key share a single
+      // decryption (and passphrase prompt).
+      self
+        .decryptions
+        .run(pubkey.clone(), || {
+          let _span = debug_span!("decrypt", file = %file.display()).entered();
+          let key = Arc::new(self.load_key_pair(&pubkey, &file)?);
+          let () = self.cache.insert(pubkey.clone(), key.clone());
+          Ok(key)
+        })
+        .map_err(|err| err.context(RequestError::DecryptionFailed(file.clone())))?
+    };
+
+    let sig = debug_span!("sign")
+      .in_scope(|| key.sign(request.flags, &request.data))
+      .with_context(|| "failed to sign request data")?;
+    let blob = sig
+      .to_blob()
+      .with_context(|| "failed to serialized signature")?;
+    Ok(blob)
+  }
+
+  /// Remove the key files of the given identity, as per the configured
+  /// policy.
+  fn remove(&self, identity: &StoreIdentity) -> StdResult<(), KeyStoreError> {
+    let (_, comment, path) = self.find_identity(identity)?;
+    match self.config().on_remove {
+      OnRemove::Hide => (),
+      OnRemove::Rename => remove_key_pair(&path, true)?,
+      OnRemove::Delete => remove_key_pair(&path, false)?,
+    }
+    let () = self.files().invalidate();
+
+    info!("Removed identity {comment:?} stored in {}", path.display());
+    Ok(())
+  }
+
+  /// Rescan the store's directories, retrying the derivation of public
+  /// keys that failed before.
+  fn invalidate(&self) {
+    let () = self
+      .derived
+      .lock()
+      .unwrap()
+      .retain(|_, derived| derived.keys.is_ok());
+    self.files().invalidate()
+  }
+
+  /// Apply a changed configuration, recreating the decryptor for the
+  /// configured backend.
+  fn reconfigure(&self, config: &Arc<Config>) {
+    *self.decryptor.write().unwrap() = Arc::from(decryptor(config));
+    *self.config.write().unwrap() = config.clone();
+  }
+}
+
+
+/// Find the identity files referenced by the OpenSSH client
+/// configuration file configured as a source of keys, if any.
+///
+/// A configuration file that cannot be read merely causes a warning, as
+/// the keys in the agent's directories are usable regardless.
+fn ssh_config_identity_files(config: &Config) -> Vec<PathBuf> {
+  let (path, home) = match (&config.ssh_config, home_dir()) {
+    (Some(path), Some(home)) => (path, home),
+    (Some(_), None) => {
+      warn!("Not reading SSH configuration: no home directory found");
+      return Vec::new()
+    },
+    (None, _) => return Vec::new(),
+  };
+
+  match identity_files(path, &home) {
+    Ok(files) => {
+      let () = files
+        .iter()
+        .for_each(|file| debug!("Considering identity file {}", file.display()));
+      files
+    },
+    Err(err) => {
+      warn!("Failed to find identity files in SSH configuration: {err:#}");
+      Vec::new()
+    },
+  }
+}
+
+
+/// Parse the given public key, along with its comment or, if provided,
+/// the label to use instead.
+fn parse_public_key(key: PemPublicKey, label: Option<String>) -> Result<(PublicKey, String)> {
+  let comment = match label {
+    Some(label) => label,
+    None => public_key_comment(&key)?,
+  };
+  let key = PublicKey::from_pem(key)?;
+  Ok((key, comment))
+}
+
+
+/// Derive a file name for a key from its comment.
+///
+/// `ssh-add` sends the path of the key file as comment if the key itself
+/// does not have one, so we strip any leading directories. Characters
+/// that are unwieldy in file names are replaced.
+fn key_file_name(comment: &str, key_type: &str) -> String {
+  let name = comment
+    .rsplit('/')
+    .next()
+    .unwrap_or_default()
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() || "@:._-".contains(c) {
+        c
+      } else {
+        '_'
+      }
+    })
+    .collect::<String>();
+
+  if name.is_empty() || name.starts_with('.') {
+    key_type.to_string()
+  } else {
+    name
+  }
+}
@@ -0,0 +1,237 @@
+// fingerprint.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! OpenSSH style fingerprints of public keys.
+
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::result::Result as StdResult;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use anyhow::Error;
+use anyhow::Result;
+
+use base64::engine::general_purpose::STANDARD_NO_PAD as BASE64;
+use base64::Engine as _;
+
+use md5::Digest as _;
+use md5::Md5;
+
+use ring::digest::digest;
+use ring::digest::SHA256;
+
+use serde::Deserialize;
+use serde::Serialize;
+use serde::Serializer;
+
+use ssh_agent_lib::proto::public_key::PublicKey;
+use ssh_agent_lib::proto::Blob as _;
+
+
+/// The hash algorithm a fingerprint is calculated with.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum HashAlg {
+  /// SHA-256, as used by OpenSSH by default.
+  #[default]
+  Sha256,
+  /// MD5, as used by legacy versions of OpenSSH.
+  Md5,
+}
+
+impl FromStr for HashAlg {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "sha256" => Ok(Self::Sha256),
+      "md5" => Ok(Self::Md5),
+      _ => Err(anyhow!("unsupported fingerprint hash algorithm: {s}")),
+    }
+  }
+}
+
+
+/// The fingerprint of a public key, as reported by OpenSSH.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Fingerprint {
+  /// A SHA-256 fingerprint, e.g., `SHA256:47DEQpj8HBSa+/TI...`.
+  Sha256([u8; 32]),
+  /// An MD5 fingerprint, e.g., `MD5:d4:1d:8c:d9:8f:00:b2:04:...`.
+  Md5([u8; 16]),
+}
+
+impl Fingerprint {
+  /// Calculate the SHA-256 fingerprint of the given public key blob.
+  pub fn sha256(blob: &[u8]) -> Self {
+    let digest = digest(&SHA256, blob);
+    // SANITY: A SHA-256 digest is always 32 bytes long.
+    Self::Sha256(digest.as_ref().try_into().unwrap())
+  }
+
+  /// Calculate the MD5 fingerprint of the given public key blob.
+  pub fn md5(blob: &[u8]) -> Self {
+    Self::Md5(Md5::digest(blob).into())
+  }
+
+  /// Calculate the fingerprint of the given public key blob using the
+  /// provided hash algorithm.
+  pub fn from_blob(blob: &[u8], alg: HashAlg) -> Result<Self> {
+    match alg {
+      HashAlg::Sha256 => Ok(Self::sha256(blob)),
+      HashAlg::Md5 => Ok(Self::md5(blob)),
+    }
+  }
+
+  /// Calculate the fingerprint of the given public key using the
+  /// provided hash algorithm.
+  pub fn from_key(key: &PublicKey, alg: HashAlg) -> Result<Self> {
+    let blob = key.to_blob().context("failed to serialize public key")?;
+    Self::from_blob(&blob, alg)
+  }
+
+  /// Retrieve the hash algorithm the fingerprint was calculated with.
+  pub fn alg(&self) -> HashAlg {
+    match self {
+      Self::Sha256(..) => HashAlg::Sha256,
+      Self::Md5(..) => HashAlg::Md5,
+    }
+  }
+
+  /// Check whether this is the fingerprint of the given public key.
+  pub fn matches(&self, key: &PublicKey) -> Result<bool> {
+    Ok(Self::from_key(key, self.alg())? == *self)
+  }
+}
+
+impl Display for Fingerprint {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    match self {
+      Self::Sha256(digest) => write!(f, "SHA256:{}", BASE64.encode(digest)),
+      Self::Md5(digest) => {
+        let () = f.write_str("MD5")?;
+        digest.iter().try_for_each(|byte| write!(f, ":{byte:02x}"))
+      },
+    }
+  }
+}
+
+impl FromStr for Fingerprint {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    let invalid = || anyhow!("invalid fingerprint: {s}");
+
+    if let Some(digest) = s.strip_prefix("SHA256:") {
+      let digest = BASE64
+        .decode(digest.trim_end_matches('='))
+        .with_context(invalid)?;
+      Ok(Self::Sha256(digest.try_into().map_err(|_| invalid())?))
+    } else if let Some(digest) = s.strip_prefix("MD5:") {
+      let digest = digest
+        .split(':')
+        .map(|byte| u8::from_str_radix(byte, 16).ok().filter(|_| byte.len() == 2))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(invalid)?;
+      Ok(Self::Md5(digest.try_into().map_err(|_| invalid())?))
+    } else {
+      Err(anyhow!(
+        "invalid fingerprint: {s}; expected SHA256:<base64> or MD5:<hex>"
+      ))
+    }
+  }
+}
+
+impl Serialize for Fingerprint {
+  fn serialize<S>(&self, serializer: S) -> StdResult<S::Ok, S::Error>
+  where
+    S: Serializer,
+  {
+    serializer.collect_str(self)
+  }
+}
+
+impl TryFrom<String> for Fingerprint {
+  type Error = Error;
+
+  fn try_from(s: String) -> Result<Self> {
+    Self::from_str(&s)
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use crate::files::load_public_key;
+  use crate::keys::FromPem as _;
+
+
+  /// Check that we calculate fingerprints the way OpenSSH does.
+  #[test]
+  fn fingerprints() -> Result<()> {
+    assert_eq!(
+      Fingerprint::sha256(b"").to_string(),
+      "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU"
+    );
+    assert_eq!(
+      Fingerprint::md5(b"").to_string(),
+      "MD5:d4:1d:8c:d9:8f:00:b2:04:e9:80:09:98:ec:f8:42:7e"
+    );
+    Ok(())
+  }
+
+  /// Check that fingerprints survive a round trip through their string
+  /// representation and match the keys they were calculated for.
+  #[test]
+  fn fingerprint_round_trip() -> Result<()> {
+    let ed25519 = PublicKey::from_pem(load_public_key("tests/valid_keys/ed25519.pub")?)?;
+    let rsa = PublicKey::from_pem(load_public_key("tests/valid_keys/rsa2048.pub")?)?;
+
+    for alg in [HashAlg::Sha256, HashAlg::Md5] {
+      let fingerprint = Fingerprint::from_key(&ed25519, alg)?;
+      let parsed = fingerprint.to_string().parse::<Fingerprint>()?;
+      assert_eq!(parsed, fingerprint);
+      assert_eq!(parsed.alg(), alg);
+      assert!(parsed.matches(&ed25519)?);
+      assert!(!parsed.matches(&rsa)?);
+    }
+    Ok(())
+  }
+
+  /// Check that we reject malformed fingerprints.
+  #[test]
+  fn invalid_fingerprints() {
+    for s in [
+      "",
+      "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU",
+      "SHA256:47DEQpj8",
+      "SHA256:!!!",
+      "MD5:d4:1d:8c",
+      "MD5:d4:1d:8c:d9:8f:00:b2:04:e9:80:09:98:ec:f8:42:7",
+      "SHA1:d4:1d",
+    ] {
+      assert!(s.parse::<Fingerprint>().is_err(), "{s}");
+    }
+  }
+}
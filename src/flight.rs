@@ -0,0 +1,291 @@
+// flight.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Coalescing of concurrent operations on the same key.
+//!
+//! When many clients connect at once (e.g., because of a fan-out of
+//! SSH connections), each of them asks for a signature with the same
+//! key. Instead of decrypting the key (and prompting the user) once per
+//! request, the first request performs the decryption and all requests
+//! arriving in the meantime wait for and share its result.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+
+/// An operation in progress, the result of which is shared by all
+/// callers waiting for it.
+#[derive(Debug)]
+struct Call<V> {
+  /// The result of the operation, once it concluded. Errors are kept
+  /// in their textual representation.
+  result: Mutex<Option<Result<V, String>>>,
+  /// The condition variable signaled once the result is available.
+  done: Condvar,
+}
+
+impl<V> Call<V> {
+  /// Create a new operation that has yet to conclude.
+  fn new() -> Self {
+    Self {
+      result: Mutex::new(None),
+      done: Condvar::new(),
+    }
+  }
+
+  /// Publish the result of the operation, waking up all waiters.
+  fn finish(&self, result: Result<V, String>) {
+    *self.result.lock().unwrap() = Some(result);
+    let () = self.done.notify_all();
+  }
+}
+
+
+/// A guard concluding an operation on behalf of the caller performing
+/// it, even if it panics.
+struct Leader<'flight, K, V>
+where
+  K: Eq + Hash,
+{
+  flight: &'flight SingleFlight<K, V>,
+  key: Option<K>,
+  call: Arc<Call<V>>,
+}
+
+impl<K, V> Leader<'_, K, V>
+where
+  K: Eq + Hash,
+{
+  /// Conclude the operation with the given result.
+  fn finish(mut self, result: Result<V, String>) {
+    let () = self.remove();
+    let () = self.call.finish(result);
+  }
+
+  /// Remove the operation from the set of ones in progress, so that
+  /// subsequent callers start a new one.
+  fn remove(&mut self) {
+    if let Some(key) = self.key.take() {
+      let _ = self.flight.calls.lock().unwrap().remove(&key);
+    }
+  }
+}
+
+impl<K, V> Drop for Leader<'_, K, V>
+where
+  K: Eq + Hash,
+{
+  fn drop(&mut self) {
+    if self.key.is_some() {
+      let () = self.remove();
+      let () = self
+        .call
+        .finish(Err("concurrent operation was aborted".to_string()));
+    }
+  }
+}
+
+
+/// A mechanism for coalescing concurrent operations on the same key.
+#[derive(Debug)]
+pub struct SingleFlight<K, V> {
+  /// The operations currently in progress.
+  calls: Mutex<HashMap<K, Arc<Call<V>>>>,
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+  K: Clone + Eq + Hash,
+  V: Clone,
+{
+  /// Create a new, empty, object.
+  pub fn new() -> Self {
+    Self {
+      calls: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Run the given operation for the provided key, unless one is
+  /// already in progress, in which case its result is waited for and
+  /// returned instead.
+  ///
+  /// Errors are shared as well. Callers that did not perform the
+  /// operation themselves receive them in stringified form.
+  pub fn run<F>(&self, key: K, f: F) -> Result<V>
+  where
+    F: FnOnce() -> Result<V>,
+  {
+    let call = {
+      let mut calls = self.calls.lock().unwrap();
+      if let Some(call) = calls.get(&key) {
+        let call = call.clone();
+        drop(calls);
+        return Self::wait(&call)
+      }
+
+      let call = Arc::new(Call::new());
+      let _ = calls.insert(key.clone(), call.clone());
+      call
+    };
+
+    let leader = Leader {
+      flight: self,
+      key: Some(key),
+      call,
+    };
+    match f() {
+      Ok(value) => {
+        let () = leader.finish(Ok(value.clone()));
+        Ok(value)
+      },
+      Err(err) => {
+        let () = leader.finish(Err(format!("{err:#}")));
+        Err(err)
+      },
+    }
+  }
+
+  /// Wait for the given operation to conclude, returning its result.
+  fn wait(call: &Call<V>) -> Result<V> {
+    let mut result = call.result.lock().unwrap();
+    loop {
+      match &*result {
+        Some(Ok(value)) => return Ok(value.clone()),
+        Some(Err(err)) => return Err(anyhow!("{err}")),
+        None => result = call.done.wait(result).unwrap(),
+      }
+    }
+  }
+}
+
+impl<K, V> Default for SingleFlight<K, V>
+where
+  K: Clone + Eq + Hash,
+  V: Clone,
+{
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::sync::atomic::AtomicUsize;
+  use std::sync::atomic::Ordering;
+  use std::sync::Barrier;
+  use std::thread::scope;
+  use std::thread::sleep;
+  use std::time::Duration;
+
+
+  /// Check that concurrent operations on the same key are coalesced.
+  #[test]
+  fn coalescing() {
+    let flight = SingleFlight::<u8, usize>::new();
+    let count = AtomicUsize::new(0);
+    let barrier = Barrier::new(8);
+
+    let results = scope(|scope| {
+      let handles = (0..8)
+        .map(|_| {
+          scope.spawn(|| {
+            let _ = barrier.wait();
+            flight.run(1, || {
+              // Give the other threads a chance to join in.
+              let () = sleep(Duration::from_millis(100));
+              Ok(count.fetch_add(1, Ordering::SeqCst) + 42)
+            })
+          })
+        })
+        .collect::<Vec<_>>();
+      handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Vec<_>>()
+    });
+
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+    assert!(results.iter().all(|result| *result.as_ref().unwrap() == 42));
+
+    // Once concluded, the next operation runs anew.
+    assert_eq!(flight.run(1, || Ok(1337)).unwrap(), 1337);
+  }
+
+  /// Check that errors get shared with all waiters.
+  #[test]
+  fn error_sharing() {
+    let flight = SingleFlight::<u8, usize>::new();
+    let barrier = Barrier::new(4);
+
+    let errors = scope(|scope| {
+      let handles = (0..4)
+        .map(|_| {
+          scope.spawn(|| {
+            let _ = barrier.wait();
+            flight.run(1, || {
+              let () = sleep(Duration::from_millis(100));
+              Err(anyhow!("decryption failed"))
+            })
+          })
+        })
+        .collect::<Vec<_>>();
+      handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap().unwrap_err().to_string())
+        .collect::<Vec<_>>()
+    });
+
+    assert!(errors.iter().all(|err| err == "decryption failed"));
+  }
+
+  /// Check that waiters are released if the operation panics.
+  #[test]
+  fn panicking_leader() {
+    let flight = SingleFlight::<u8, usize>::new();
+    let result = scope(|scope| {
+      let leader = scope.spawn(|| {
+        flight.run(1, || -> Result<usize> {
+          let () = sleep(Duration::from_millis(100));
+          panic!("operation failed")
+        })
+      });
+      let () = sleep(Duration::from_millis(20));
+      let waiter = scope.spawn(|| flight.run(1, || Ok(42)));
+
+      assert!(leader.join().is_err());
+      waiter.join().unwrap()
+    });
+
+    // The waiter either shared the aborted operation or, if it came
+    // late, ran its own.
+    match result {
+      Ok(value) => assert_eq!(value, 42),
+      Err(err) => assert_eq!(err.to_string(), "concurrent operation was aborted"),
+    }
+  }
+}
@@ -0,0 +1,753 @@
+// gpgagent.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Usage of the GnuPG agent's own authentication keys.
+//!
+//! The keys listed in the agent's `sshcontrol` file are offered as
+//! identities, with signing delegated to the agent via Assuan. Their
+//! private keys never leave the GnuPG agent, which also takes care of
+//! asking for passphrases.
+
+use std::env::var_os;
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use ring::digest::digest;
+use ring::digest::Algorithm;
+use ring::digest::SHA1_FOR_LEGACY_USE_ONLY;
+use ring::digest::SHA256;
+use ring::digest::SHA384;
+use ring::digest::SHA512;
+
+use ssh_agent_lib::proto::Blob as _;
+use ssh_agent_lib::proto::key_type::KeyTypeEnum as _;
+use ssh_agent_lib::proto::message::SignatureBlob;
+use ssh_agent_lib::proto::message::SignRequest;
+use ssh_agent_lib::proto::public_key::EcDsaPublicKey;
+use ssh_agent_lib::proto::public_key::Ed25519PublicKey;
+use ssh_agent_lib::proto::public_key::PublicKey;
+use ssh_agent_lib::proto::public_key::RsaPublicKey;
+use ssh_agent_lib::proto::signature::EcDsaSignatureData;
+use ssh_agent_lib::proto::signature::Signature;
+use ssh_agent_lib::proto::signature::RSA_SHA2_256;
+use ssh_agent_lib::proto::signature::RSA_SHA2_512;
+use ssh_agent_lib::proto::to_bytes;
+
+use tracing::debug;
+use tracing::info;
+
+use crate::assuan;
+use crate::assuan::Client;
+use crate::config::Config;
+use crate::config::GpgOptions;
+use crate::error::KeyStoreError;
+use crate::openssh::to_mpint;
+use crate::sexp::Sexp;
+use crate::store::KeyStore;
+use crate::store::StoreIdentity;
+
+
+/// The name of the program for querying the GnuPG configuration.
+const GPGCONF: &str = "gpgconf";
+/// The GnuPG error code reported for unsupported algorithms.
+const GPG_ERR_UNSUPPORTED_ALGORITHM: u32 = 84;
+/// The length of an Ed25519 public key and of each half of a signature.
+const ED25519_LEN: usize = 32;
+/// The reference of the authentication key of OpenPGP cards.
+const OPENPGP_AUTH_KEY: &str = "OPENPGP.3";
+/// The application identifier prefix of OpenPGP cards.
+const OPENPGP_AID: &str = "D27600012401";
+
+
+/// An authentication key managed by the GnuPG agent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AgentKey {
+  /// The key's keygrip, identifying it to the GnuPG agent.
+  pub keygrip: String,
+  /// The public key.
+  pub pubkey: PublicKey,
+  /// The key's comment.
+  pub comment: String,
+  /// Whether the key is marked as requiring confirmation before each
+  /// usage in `sshcontrol`.
+  pub confirm: bool,
+  /// The serial number of the smartcard the key is stored on, if any.
+  pub card: Option<String>,
+}
+
+
+/// Parse a `KEYINFO` status line as reported for `KEYINFO --ssh-list`,
+/// returning the keygrip and whether the key requires confirmation.
+///
+/// `None` is returned for keys disabled in `sshcontrol`.
+fn parse_keyinfo(status: &str) -> Result<Option<(String, bool)>> {
+  let mut fields = status.split(' ');
+  ensure!(
+    fields.next() == Some("KEYINFO"),
+    "encountered unexpected status line: {status}"
+  );
+  let keygrip = fields
+    .next()
+    .filter(|keygrip| !keygrip.is_empty())
+    .ok_or_else(|| anyhow!("KEYINFO status lacks keygrip"))?;
+  // The flags are the last of the reported fields.
+  let flags = fields.nth(7).unwrap_or_default();
+  if flags.contains('D') {
+    return Ok(None)
+  }
+  Ok(Some((keygrip.to_string(), flags.contains('c'))))
+}
+
+
+/// Parse a `KEYPAIRINFO` status line as reported when learning about a
+/// smartcard, returning the keygrip if it describes an authentication
+/// key.
+fn parse_keypairinfo(status: &str) -> Option<String> {
+  let mut fields = status.strip_prefix("KEYPAIRINFO ")?.split(' ');
+  let keygrip = fields.next()?;
+  let keyref = fields.next().unwrap_or_default();
+  // Newer versions of GnuPG report the usage of the key as well.
+  let usage = fields.next().unwrap_or_default();
+  let valid = keygrip.len() == 40 && keygrip.bytes().all(|byte| byte.is_ascii_hexdigit());
+  (valid && (keyref == OPENPGP_AUTH_KEY || usage.contains('a'))).then(|| keygrip.to_string())
+}
+
+
+/// Create the comment of a key stored on the smartcard with the given
+/// serial number, in the same format GnuPG uses.
+fn card_comment(serialno: &str) -> String {
+  // For OpenPGP cards the serial number proper follows the application
+  // identifier, version, and manufacturer.
+  match serialno.get(16..28) {
+    Some(number) if serialno.starts_with(OPENPGP_AID) => format!("cardno:{number}"),
+    _ => format!("cardno:{serialno}"),
+  }
+}
+
+
+/// Convert a public key in the GnuPG agent's S-expression format into
+/// an SSH public key.
+fn public_key(sexp: &Sexp) -> Result<PublicKey> {
+  let key = sexp
+    .find("public-key")
+    .ok_or_else(|| anyhow!("S-expression does not contain a public key"))?;
+  let value = |name| {
+    key
+      .value(name)
+      .ok_or_else(|| anyhow!("public key lacks {name} parameter"))
+  };
+
+  if key.find("rsa").is_some() {
+    let key = RsaPublicKey {
+      e: to_mpint(value("e")?),
+      n: to_mpint(value("n")?),
+    };
+    Ok(PublicKey::Rsa(key))
+  } else if key.find("ecc").is_some() {
+    let curve = String::from_utf8_lossy(value("curve")?).into_owned();
+    let q = value("q")?;
+    match curve.as_str() {
+      "Ed25519" | "ed25519" | "1.3.6.1.4.1.11591.15.1" => {
+        // The public key may be prefixed with 0x40, denoting the
+        // native point format.
+        let enc_a = match q {
+          [0x40, rest @ ..] if rest.len() == ED25519_LEN => rest,
+          _ => q,
+        };
+        ensure!(enc_a.len() == ED25519_LEN, "encountered invalid Ed25519 public key");
+        Ok(PublicKey::Ed25519(Ed25519PublicKey { enc_a: enc_a.to_vec() }))
+      },
+      _ => {
+        let identifier = ecdsa_identifier(&curve)
+          .ok_or_else(|| anyhow!("elliptic curve {curve} is not supported"))?;
+        let key = EcDsaPublicKey {
+          identifier: identifier.to_string(),
+          q: q.to_vec(),
+        };
+        Ok(PublicKey::EcDsa(key))
+      },
+    }
+  } else {
+    bail!("public key algorithm is not supported")
+  }
+}
+
+
+/// Map a GnuPG curve name to the SSH identifier of the curve.
+pub fn ecdsa_identifier(curve: &str) -> Option<&'static str> {
+  match curve {
+    "NIST P-256" | "nistp256" | "prime256v1" | "secp256r1" | "1.2.840.10045.3.1.7" => {
+      Some("nistp256")
+    },
+    "NIST P-384" | "nistp384" | "secp384r1" | "1.3.132.0.34" => Some("nistp384"),
+    "NIST P-521" | "nistp521" | "secp521r1" | "1.3.132.0.35" => Some("nistp521"),
+    _ => None,
+  }
+}
+
+
+/// Left-pad an integer with zeros to the given length.
+fn pad(int: &[u8], len: usize) -> Result<Vec<u8>> {
+  let start = int.iter().position(|byte| *byte != 0).unwrap_or(int.len());
+  let int = &int[start..];
+  ensure!(int.len() <= len, "signature value exceeds {len} bytes");
+
+  let mut result = vec![0; len - int.len()];
+  result.extend_from_slice(int);
+  Ok(result)
+}
+
+
+/// Convert a signature in the GnuPG agent's S-expression format, as
+/// created with the given public key, into an SSH signature blob.
+fn signature(pubkey: &PublicKey, sexp: &Sexp) -> Result<Vec<u8>> {
+  let sig = sexp
+    .find("sig-val")
+    .ok_or_else(|| anyhow!("S-expression does not contain a signature"))?;
+  let value = |name| {
+    sig
+      .value(name)
+      .ok_or_else(|| anyhow!("signature lacks {name} parameter"))
+  };
+
+  match pubkey {
+    PublicKey::Rsa(key) => {
+      // SSH expects the signature to be as long as the modulus.
+      let len = key.n.iter().skip_while(|byte| **byte == 0).count();
+      pad(value("s")?, len)
+    },
+    PublicKey::EcDsa(..) => {
+      let sig = EcDsaSignatureData {
+        r: to_mpint(value("r")?),
+        s: to_mpint(value("s")?),
+      };
+      to_bytes(&sig).context("failed to serialize ECDSA signature")
+    },
+    PublicKey::Ed25519(..) => {
+      let mut sig = pad(value("r")?, ED25519_LEN)?;
+      let () = sig.extend(pad(value("s")?, ED25519_LEN)?);
+      Ok(sig)
+    },
+    _ => bail!("{} keys are not supported", pubkey.key_type()),
+  }
+}
+
+
+/// Escape a string for usage as a parameter of commands expecting it
+/// plus-escaped, i.e., with spaces replaced by `+`.
+fn plus_escape(s: &str) -> String {
+  assuan::escape(s).replace('+', "%2B").replace(' ', "+")
+}
+
+
+/// Encode data as hexadecimal string.
+fn to_hex(data: &[u8]) -> String {
+  data.iter().fold(String::new(), |mut hex, byte| {
+    let _ = write!(hex, "{byte:02X}");
+    hex
+  })
+}
+
+
+/// A client for the GnuPG agent, providing access to its
+/// authentication keys.
+#[derive(Debug)]
+pub struct GpgAgent {
+  /// The options to use for GnuPG.
+  options: GpgOptions,
+}
+
+impl GpgAgent {
+  /// Create a client for the GnuPG agent used with the given options.
+  pub fn new(options: GpgOptions) -> Self {
+    Self { options }
+  }
+
+  /// Create a command invoking `gpgconf` for the configured GnuPG
+  /// home directory.
+  fn gpgconf(&self) -> Command {
+    let mut command = Command::new(GPGCONF);
+    if let Some(home) = &self.options.home {
+      let _ = command.arg("--homedir").arg(home);
+    }
+    let _ = command.stdin(Stdio::null());
+    command
+  }
+
+  /// Retrieve the path to the GnuPG agent's socket.
+  fn socket(&self) -> Result<PathBuf> {
+    let output = self
+      .gpgconf()
+      .arg("--list-dirs")
+      .arg("agent-socket")
+      .output()
+      .with_context(|| format!("failed to run {GPGCONF}"))?;
+    ensure!(
+      output.status.success(),
+      "failed to determine GnuPG agent socket: {}",
+      String::from_utf8_lossy(&output.stderr).trim()
+    );
+    let socket = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    ensure!(!socket.is_empty(), "failed to determine GnuPG agent socket");
+    Ok(PathBuf::from(socket))
+  }
+
+  /// Connect to the GnuPG agent, starting it if necessary.
+  fn connect(&self) -> Result<Client> {
+    let socket = self.socket()?;
+    let mut client = match Client::connect(&socket) {
+      Ok(client) => client,
+      Err(_) => {
+        let status = self
+          .gpgconf()
+          .arg("--launch")
+          .arg("gpg-agent")
+          .status()
+          .with_context(|| format!("failed to run {GPGCONF}"))?;
+        ensure!(status.success(), "failed to start GnuPG agent");
+        Client::connect(&socket).with_context(|| "failed to connect to GnuPG agent")?
+      },
+    };
+
+    // Have passphrase prompts show up on our display, which may not be
+    // the one the agent got started on.
+    if let Some(display) = var_os("DISPLAY") {
+      let option = format!("OPTION display={}", display.to_string_lossy());
+      let _response = client.transact(&option)?;
+    }
+    Ok(client)
+  }
+
+  /// Retrieve the authentication keys enabled in the GnuPG agent's
+  /// `sshcontrol` file.
+  pub fn keys(&self) -> Result<Vec<AgentKey>> {
+    let mut client = self.connect()?;
+    let response = client
+      .transact("KEYINFO --ssh-list")
+      .with_context(|| "failed to list GnuPG agent keys")?;
+
+    let mut keys = Vec::new();
+    for status in response.status {
+      if let Some((keygrip, confirm)) = parse_keyinfo(&status)? {
+        let (pubkey, comment) = Self::read_key(&mut client, &keygrip)?;
+        let key = AgentKey {
+          comment: comment.unwrap_or_else(|| format!("gpg-agent:{keygrip}")),
+          keygrip,
+          pubkey,
+          confirm,
+          card: None,
+        };
+        let () = keys.push(key);
+      }
+    }
+    Ok(keys)
+  }
+
+  /// Retrieve the authentication keys of the OpenPGP smartcard
+  /// currently available to the GnuPG agent, if any.
+  ///
+  /// Learning about the card makes the agent create stubs for its keys,
+  /// which it uses to forward signing requests to the card.
+  pub fn card_keys(&self) -> Result<Vec<AgentKey>> {
+    let mut client = self.connect()?;
+    let serialno = match client.transact("SCD SERIALNO") {
+      Ok(response) => response
+        .status
+        .iter()
+        .find_map(|status| status.strip_prefix("SERIALNO "))
+        .and_then(|serialno| serialno.split(' ').next())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("smartcard did not report serial number"))?,
+      // Not having a card (or reader or even `scdaemon`) at hand is
+      // perfectly normal.
+      Err(err) => {
+        debug!("No smartcard available: {err:#}");
+        return Ok(Vec::new())
+      },
+    };
+
+    let response = client
+      .transact("LEARN --sendinfo")
+      .with_context(|| format!("failed to learn keys of smartcard {serialno}"))?;
+    let mut keys = Vec::new();
+    for keygrip in response.status.iter().filter_map(|status| parse_keypairinfo(status)) {
+      let (pubkey, _comment) = Self::read_key(&mut client, &keygrip)?;
+      let key = AgentKey {
+        keygrip,
+        pubkey,
+        comment: card_comment(&serialno),
+        confirm: false,
+        card: Some(serialno.clone()),
+      };
+      let () = keys.push(key);
+    }
+    Ok(keys)
+  }
+
+  /// Read the public key with the given keygrip, along with its comment,
+  /// if any.
+  fn read_key(client: &mut Client, keygrip: &str) -> Result<(PublicKey, Option<String>)> {
+    let response = client
+      .transact(&format!("READKEY {keygrip}"))
+      .with_context(|| format!("failed to read GnuPG agent key {keygrip}"))?;
+    let sexp = Sexp::parse(&response.data)
+      .with_context(|| format!("GnuPG agent key {keygrip} is invalid"))?;
+    let pubkey =
+      public_key(&sexp).with_context(|| format!("GnuPG agent key {keygrip} is unsupported"))?;
+    let comment = sexp
+      .value("comment")
+      .map(|comment| String::from_utf8_lossy(comment).into_owned());
+    Ok((pubkey, comment))
+  }
+
+  /// Sign the given data with a key of the GnuPG agent, taking into
+  /// account the signature flags of the request.
+  pub fn sign(&self, key: &AgentKey, flags: u32, data: &[u8]) -> Result<Signature> {
+    let mut client = self.connect()?;
+    let _response = client.transact(&format!("SIGKEY {}", key.keygrip))?;
+    let description = format!(
+      "Please enter the passphrase to sign with SSH key {}",
+      key.comment
+    );
+    let _response = client.transact(&format!("SETKEYDESC {}", plus_escape(&description)))?;
+
+    let (algorithm, hash): (_, Option<&Algorithm>) = match &key.pubkey {
+      PublicKey::Rsa(..) if flags & RSA_SHA2_512 != 0 => ("rsa-sha2-512", Some(&SHA512)),
+      PublicKey::Rsa(..) if flags & RSA_SHA2_256 != 0 => ("rsa-sha2-256", Some(&SHA256)),
+      PublicKey::Rsa(..) => ("ssh-rsa", Some(&SHA1_FOR_LEGACY_USE_ONLY)),
+      PublicKey::EcDsa(ecdsa) => {
+        let hash = match ecdsa.identifier.as_str() {
+          "nistp256" => &SHA256,
+          "nistp384" => &SHA384,
+          _ => &SHA512,
+        };
+        ("", Some(hash))
+      },
+      // EdDSA signs the data itself, not a hash thereof.
+      PublicKey::Ed25519(..) => ("", None),
+      pubkey => bail!("{} keys are not supported", pubkey.key_type()),
+    };
+    // Only RSA keys support different signature algorithms.
+    let algorithm = if algorithm.is_empty() {
+      key.pubkey.key_type()
+    } else {
+      algorithm.to_string()
+    };
+
+    if let Some(hash) = hash {
+      let name = match hash.output_len() {
+        20 => "sha1",
+        32 => "sha256",
+        48 => "sha384",
+        _ => "sha512",
+      };
+      let digest = digest(hash, data);
+      let command = format!("SETHASH --hash={name} {}", to_hex(digest.as_ref()));
+      let _response = client.transact(&command)?;
+    } else {
+      let result = client.transact_inquire("SETHASH --inquire", |keyword, _params| {
+        Ok((keyword == "TBSDATA").then(|| data.to_vec()))
+      });
+      match result {
+        Err(err)
+          if err
+            .downcast_ref::<assuan::Error>()
+            .map(|err| err.code() == GPG_ERR_UNSUPPORTED_ALGORITHM)
+            .unwrap_or(false) =>
+        {
+          return Err(err).context("signing with Ed25519 keys requires GnuPG 2.3 or later")
+        },
+        result => {
+          let _response = result?;
+        },
+      }
+    }
+
+    let response = client
+      .transact("PKSIGN")
+      .with_context(|| format!("GnuPG agent failed to sign with key {}", key.keygrip))?;
+    let sexp = Sexp::parse(&response.data).context("GnuPG agent sent invalid signature")?;
+    let blob = signature(&key.pubkey, &sexp)?;
+    let sig = Signature { algorithm, blob };
+    Ok(sig)
+  }
+}
+
+
+/// The key store offering the authentication keys of the GnuPG agent
+/// and of the smartcards available to it, as configured.
+#[derive(Debug)]
+pub struct GpgAgentStore {
+  /// The agent's configuration.
+  config: RwLock<Arc<Config>>,
+}
+
+impl GpgAgentStore {
+  /// Create a store for the GnuPG agent keys enabled in the given
+  /// configuration.
+  pub fn new(config: Arc<Config>) -> Self {
+    Self {
+      config: RwLock::new(config),
+    }
+  }
+
+  /// Retrieve the configured authentication keys of the GnuPG agent
+  /// and of the smartcard available to it.
+  fn keys(&self) -> Result<Vec<AgentKey>> {
+    let config = self.config.read().unwrap().clone();
+    let gpg_agent = GpgAgent::new(config.gpg.clone());
+    let mut keys = Vec::new();
+
+    if config.gpg_agent_keys {
+      keys = gpg_agent
+        .keys()
+        .context("failed to retrieve GnuPG agent keys")?;
+    }
+
+    if config.card_keys {
+      let card_keys = gpg_agent
+        .card_keys()
+        .context("failed to retrieve smartcard keys")?;
+      for card_key in card_keys {
+        // Card keys may be listed in `sshcontrol` as well.
+        if let Some(key) = keys.iter_mut().find(|key| key.keygrip == card_key.keygrip) {
+          key.card = card_key.card;
+        } else {
+          let () = keys.push(card_key);
+        }
+      }
+    }
+    Ok(keys)
+  }
+}
+
+impl KeyStore for GpgAgentStore {
+  fn name(&self) -> &str {
+    "gpg-agent"
+  }
+
+  fn identities(&self) -> StdResult<Vec<StdResult<StoreIdentity, KeyStoreError>>, KeyStoreError> {
+    let identities = self
+      .keys()?
+      .into_iter()
+      .map(|key| {
+        let blob = key
+          .pubkey
+          .to_blob()
+          .with_context(|| "failed to serialize public key")?;
+        let identity = StoreIdentity {
+          blob,
+          pubkey: Some(key.pubkey),
+          comment: key.comment,
+          confirm: key.confirm,
+        };
+        Ok(identity)
+      })
+      .collect();
+    Ok(identities)
+  }
+
+  fn sign(
+    &self,
+    identity: &StoreIdentity,
+    request: &SignRequest,
+  ) -> StdResult<SignatureBlob, KeyStoreError> {
+    let key = self
+      .keys()?
+      .into_iter()
+      .find(|key| Some(&key.pubkey) == identity.pubkey.as_ref())
+      .ok_or_else(|| anyhow!("GnuPG agent key {:?} no longer present", identity.comment))?;
+    if let Some(card) = &key.card {
+      // The GnuPG agent asks for the PIN itself, but touch
+      // confirmation required by some cards goes unannounced.
+      info!(
+        "Signing with key {:?} on smartcard {card}; touch the card if it blinks",
+        key.comment
+      );
+    } else {
+      info!("Signing with GnuPG agent key {:?}", key.comment);
+    }
+
+    let gpg = self.config.read().unwrap().gpg.clone();
+    let sig = GpgAgent::new(gpg)
+      .sign(&key, request.flags, &request.data)
+      .with_context(|| "failed to create signature")?;
+    let blob = sig
+      .to_blob()
+      .with_context(|| "failed to serialized signature")?;
+    Ok(blob)
+  }
+
+  /// Keys of the GnuPG agent can only be hidden.
+  fn remove(&self, identity: &StoreIdentity) -> StdResult<(), KeyStoreError> {
+    info!("Removed GnuPG agent identity {:?}", identity.comment);
+    Ok(())
+  }
+
+  fn reconfigure(&self, config: &Arc<Config>) {
+    *self.config.write().unwrap() = config.clone();
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+
+  /// Check that we correctly parse `KEYINFO` status lines.
+  #[test]
+  fn keyinfo_parsing() -> Result<()> {
+    let status = "KEYINFO CBD4F40C39A96C8549B70F7769C6075E8B01D773 D - - - C - - S";
+    assert_eq!(
+      parse_keyinfo(status)?,
+      Some(("CBD4F40C39A96C8549B70F7769C6075E8B01D773".to_string(), false))
+    );
+    let status = "KEYINFO 2C4D287455677C5AF4B7D1A54A4803DCDAF9AED7 D - - - C - - Sc";
+    assert_eq!(
+      parse_keyinfo(status)?,
+      Some(("2C4D287455677C5AF4B7D1A54A4803DCDAF9AED7".to_string(), true))
+    );
+    let status = "KEYINFO 44390E96EFD8F5FC89232CA000A216556E882213 D - - - C - - DS";
+    assert_eq!(parse_keyinfo(status)?, None);
+
+    assert!(parse_keyinfo("PROGRESS foo").is_err());
+    assert!(parse_keyinfo("KEYINFO").is_err());
+    Ok(())
+  }
+
+  /// Check that we can convert public keys reported by the GnuPG agent.
+  #[test]
+  fn public_key_conversion() -> Result<()> {
+    let mut sexp = b"(10:public-key(3:ecc(5:curve7:Ed25519)(5:flags5:eddsa)(1:q33:\x40".to_vec();
+    sexp.extend([0x11; 32]);
+    sexp.extend(b")))");
+    let key = public_key(&Sexp::parse(&sexp)?)?;
+    assert_eq!(key, PublicKey::Ed25519(Ed25519PublicKey { enc_a: vec![0x11; 32] }));
+
+    let mut sexp = b"(10:public-key(3:ecc(5:curve10:NIST P-256)(1:q65:".to_vec();
+    sexp.extend([0x04; 65]);
+    sexp.extend(b")))");
+    let key = public_key(&Sexp::parse(&sexp)?)?;
+    let expected = EcDsaPublicKey {
+      identifier: "nistp256".to_string(),
+      q: vec![0x04; 65],
+    };
+    assert_eq!(key, PublicKey::EcDsa(expected));
+
+    let sexp = b"(10:public-key(3:rsa(1:n3:\x00\x80\x01)(1:e3:\x01\x00\x01)))";
+    let key = public_key(&Sexp::parse(sexp)?)?;
+    let expected = RsaPublicKey {
+      e: vec![0x01, 0x00, 0x01],
+      n: vec![0x00, 0x80, 0x01],
+    };
+    assert_eq!(key, PublicKey::Rsa(expected));
+
+    let sexp = b"(10:public-key(3:ecc(5:curve11:brainpoolP1)(1:q1:\x04)))";
+    assert!(public_key(&Sexp::parse(sexp)?).is_err());
+    let sexp = b"(10:public-key(3:dsa(1:p1:\x01)))";
+    assert!(public_key(&Sexp::parse(sexp)?).is_err());
+    Ok(())
+  }
+
+  /// Check that we can convert signatures created by the GnuPG agent.
+  #[test]
+  fn signature_conversion() -> Result<()> {
+    let rsa = PublicKey::Rsa(RsaPublicKey {
+      e: vec![0x01, 0x00, 0x01],
+      n: vec![0x00, 0x80, 0x00, 0x01],
+    });
+    let sexp = Sexp::parse(b"(7:sig-val(3:rsa(1:s2:\x12\x34)))")?;
+    assert_eq!(signature(&rsa, &sexp)?, vec![0x00, 0x12, 0x34]);
+
+    let ed25519 = PublicKey::Ed25519(Ed25519PublicKey { enc_a: vec![0; 32] });
+    let mut sexp = b"(7:sig-val(5:eddsa(1:r31:".to_vec();
+    sexp.extend([0x01; 31]);
+    sexp.extend(b")(1:s32:");
+    sexp.extend([0x02; 32]);
+    sexp.extend(b")))");
+    let sig = signature(&ed25519, &Sexp::parse(&sexp)?)?;
+    assert_eq!(sig.len(), 64);
+    assert_eq!(sig[0], 0x00);
+    assert_eq!(&sig[1..32], [0x01; 31].as_slice());
+    assert_eq!(&sig[32..], [0x02; 32].as_slice());
+
+    let ecdsa = PublicKey::EcDsa(EcDsaPublicKey {
+      identifier: "nistp256".to_string(),
+      q: vec![0x04; 65],
+    });
+    let sexp = Sexp::parse(b"(7:sig-val(5:ecdsa(1:r1:\x81)(1:s1:\x01)))")?;
+    let expected = to_bytes(&EcDsaSignatureData {
+      r: vec![0x00, 0x81],
+      s: vec![0x01],
+    })?;
+    assert_eq!(signature(&ecdsa, &sexp)?, expected);
+
+    let sexp = Sexp::parse(b"(7:sig-val(5:ecdsa(1:r1:\x81)))")?;
+    assert!(signature(&ecdsa, &sexp).is_err());
+    Ok(())
+  }
+
+  /// Check that we correctly parse `KEYPAIRINFO` status lines.
+  #[test]
+  fn keypairinfo_parsing() {
+    let status = "KEYPAIRINFO 44390E96EFD8F5FC89232CA000A216556E882213 OPENPGP.3";
+    assert_eq!(
+      parse_keypairinfo(status),
+      Some("44390E96EFD8F5FC89232CA000A216556E882213".to_string())
+    );
+    let status = "KEYPAIRINFO 2C4D287455677C5AF4B7D1A54A4803DCDAF9AED7 PIV.9A sa";
+    assert_eq!(
+      parse_keypairinfo(status),
+      Some("2C4D287455677C5AF4B7D1A54A4803DCDAF9AED7".to_string())
+    );
+    let status = "KEYPAIRINFO CBD4F40C39A96C8549B70F7769C6075E8B01D773 OPENPGP.1 sc";
+    assert_eq!(parse_keypairinfo(status), None);
+    assert_eq!(parse_keypairinfo("KEYPAIRINFO X OPENPGP.3"), None);
+    assert_eq!(parse_keypairinfo("SERIALNO D2760001240102010006123456780000"), None);
+  }
+
+  /// Check that we name smartcard keys the way GnuPG does.
+  #[test]
+  fn card_comments() {
+    assert_eq!(
+      card_comment("D2760001240102010006123456780000"),
+      "cardno:000612345678"
+    );
+    assert_eq!(card_comment("FF020001008A"), "cardno:FF020001008A");
+  }
+
+  /// Check that we plus-escape strings correctly.
+  #[test]
+  fn plus_escaping() {
+    assert_eq!(plus_escape("1 + 1%\n"), "1+%2B+1%25%0A");
+    assert_eq!(to_hex(&[0x00, 0xab, 0x10]), "00AB10");
+  }
+}
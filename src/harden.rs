@@ -0,0 +1,109 @@
+// harden.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Hardening of the agent process.
+//!
+//! The agent holds decrypted private keys in memory, at least briefly.
+//! We make sure that this memory does not end up in core dumps and that
+//! other processes of the same user cannot attach to us to read it.
+
+use std::io::Error as IoError;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use libc::mlockall;
+#[cfg(target_os = "linux")]
+use libc::prctl;
+use libc::rlimit;
+use libc::setrlimit;
+use libc::MCL_CURRENT;
+use libc::MCL_FUTURE;
+#[cfg(target_os = "linux")]
+use libc::PR_SET_DUMPABLE;
+use libc::RLIMIT_CORE;
+
+use tracing::debug;
+
+use crate::config::HardeningConfig;
+
+
+/// Convert the return value of a libc function into a `Result`.
+fn check(result: i32) -> Result<(), IoError> {
+  if result == 0 {
+    Ok(())
+  } else {
+    Err(IoError::last_os_error())
+  }
+}
+
+
+/// Prevent the process from dumping core.
+fn disable_core_dumps() -> Result<()> {
+  let limit = rlimit {
+    rlim_cur: 0,
+    rlim_max: 0,
+  };
+  // SAFETY: `limit` is a valid `rlimit` object.
+  let () = check(unsafe { setrlimit(RLIMIT_CORE, &limit) })
+    .context("failed to set core file size limit")?;
+  Ok(())
+}
+
+
+/// Mark the process as not dumpable, which additionally prevents
+/// processes of the same user from attaching to it via `ptrace`.
+#[cfg(target_os = "linux")]
+fn disable_dumpable() -> Result<()> {
+  // SAFETY: `PR_SET_DUMPABLE` takes a single integer argument.
+  let () = check(unsafe { prctl(PR_SET_DUMPABLE, 0, 0, 0, 0) })
+    .context("failed to mark process as not dumpable")?;
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn disable_dumpable() -> Result<()> {
+  Ok(())
+}
+
+
+/// Lock all current and future memory of the process, so that none of
+/// it gets swapped out.
+fn lock_memory() -> Result<()> {
+  // SAFETY: `mlockall` has no memory safety related preconditions.
+  let () = check(unsafe { mlockall(MCL_CURRENT | MCL_FUTURE) })
+    .context("failed to lock process memory")?;
+  Ok(())
+}
+
+
+/// Harden the current process as per the provided configuration.
+pub fn harden(config: &HardeningConfig) -> Result<()> {
+  if !config.enabled {
+    debug!("Process hardening disabled");
+    return Ok(())
+  }
+
+  let () = disable_core_dumps()?;
+  let () = disable_dumpable()?;
+  if config.lock_memory {
+    let () = lock_memory()?;
+  }
+  Ok(())
+}
@@ -0,0 +1,314 @@
+// hosts.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Matching of host name patterns and lookup of host keys in OpenSSH
+//! `known_hosts` files.
+//!
+//! Patterns follow OpenSSH's syntax: `*` matches any number of
+//! characters, `?` exactly one, and a pattern prefixed with `!`
+//! excludes the hosts it matches. Entries of hashed `known_hosts` files
+//! can only be matched by patterns without wildcards.
+
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+
+use ring::hmac;
+
+
+/// The prefix of a hashed host name in a `known_hosts` file.
+const HASHED_PREFIX: &str = "|1|";
+
+
+/// Check whether the given string matches the provided wildcard
+/// pattern, optionally ignoring case.
+pub(crate) fn matches_wildcard(pattern: &[u8], s: &[u8], ignore_case: bool) -> bool {
+  match (pattern.split_first(), s.split_first()) {
+    (None, None) => true,
+    (Some((b'*', rest)), _) => {
+      (0..=s.len()).any(|skip| matches_wildcard(rest, &s[skip..], ignore_case))
+    },
+    (Some((b'?', rest)), Some((_, s))) => matches_wildcard(rest, s, ignore_case),
+    (Some((p, rest)), Some((c, s)))
+      if p == c || (ignore_case && p.eq_ignore_ascii_case(c)) =>
+    {
+      matches_wildcard(rest, s, ignore_case)
+    },
+    _ => false,
+  }
+}
+
+/// Check whether the given host name matches the provided wildcard
+/// pattern, ignoring case.
+fn matches_pattern(pattern: &[u8], host: &[u8]) -> bool {
+  matches_wildcard(pattern, host, true)
+}
+
+
+/// Check whether the given user name matches the provided wildcard
+/// pattern. Unlike host names, user names are case sensitive.
+pub fn matches_user(pattern: &str, user: &str) -> bool {
+  matches_wildcard(pattern.as_bytes(), user.as_bytes(), false)
+}
+
+
+/// Check whether the given host name matches the provided list of
+/// patterns, i.e., matches at least one of them and none of the negated
+/// ones.
+pub fn matches_hosts(patterns: &[String], host: &str) -> bool {
+  let mut matched = false;
+  for pattern in patterns {
+    match pattern.strip_prefix('!') {
+      Some(pattern) if matches_pattern(pattern.as_bytes(), host.as_bytes()) => return false,
+      Some(_) => (),
+      None => matched |= matches_pattern(pattern.as_bytes(), host.as_bytes()),
+    }
+  }
+  matched
+}
+
+
+/// Check whether the given hashed host name (in the form
+/// `|1|<salt>|<hash>`) is that of the provided host.
+fn matches_hashed(hashed: &str, host: &str) -> Result<bool> {
+  let (salt, hash) = hashed
+    .strip_prefix(HASHED_PREFIX)
+    .and_then(|hashed| hashed.split_once('|'))
+    .ok_or_else(|| anyhow!("hashed host name {hashed} is malformed"))?;
+  let salt = BASE64
+    .decode(salt)
+    .with_context(|| format!("hashed host name {hashed} contains invalid salt"))?;
+  let hash = BASE64
+    .decode(hash)
+    .with_context(|| format!("hashed host name {hashed} contains invalid hash"))?;
+
+  let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &salt);
+  Ok(hmac::verify(&key, host.as_bytes(), &hash).is_ok())
+}
+
+
+/// Check whether the host names of a `known_hosts` entry match the
+/// given patterns.
+fn matches_entry(patterns: &[String], hosts: &str) -> Result<bool> {
+  for host in hosts.split(',') {
+    if let Some(hashed) = host.strip_prefix(HASHED_PREFIX) {
+      let hashed = format!("{HASHED_PREFIX}{hashed}");
+      // Hashed host names can only be checked against literal names.
+      for pattern in patterns {
+        if !pattern.contains(['*', '?', '!'])
+          && matches_hosts(patterns, pattern)
+          && matches_hashed(&hashed, pattern)?
+        {
+          return Ok(true)
+        }
+      }
+    } else if !host.starts_with('!') && matches_hosts(patterns, host) {
+      return Ok(true)
+    }
+  }
+  Ok(false)
+}
+
+
+/// Parse the given `known_hosts` file contents, collecting the host
+/// keys of hosts matching the provided patterns as well as all revoked
+/// keys.
+fn parse_known_hosts(
+  patterns: &[String],
+  data: &str,
+  keys: &mut Vec<Vec<u8>>,
+  revoked: &mut Vec<Vec<u8>>,
+) -> Result<()> {
+  for (idx, line) in data.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue
+    }
+
+    let mut fields = line.split_whitespace();
+    let (marker, hosts) = match fields.next() {
+      Some(marker) if marker.starts_with('@') => (Some(marker), fields.next()),
+      hosts => (None, hosts),
+    };
+    let _algorithm = fields.next();
+    let (hosts, key) = hosts
+      .zip(fields.next())
+      .ok_or_else(|| anyhow!("line {} is malformed", idx + 1))?;
+    let key = BASE64
+      .decode(key)
+      .with_context(|| format!("line {} contains an invalid key", idx + 1))?;
+
+    match marker {
+      // Revocations apply regardless of the hosts listed.
+      Some("@revoked") => revoked.push(key),
+      // We only work with plain host keys, not certificates.
+      Some(_) => (),
+      None => {
+        if matches_entry(patterns, hosts).with_context(|| format!("line {} is invalid", idx + 1))? {
+          let () = keys.push(key);
+        }
+      },
+    }
+  }
+  Ok(())
+}
+
+
+/// Look up the host keys of all hosts matching the given patterns in
+/// the provided `known_hosts` files.
+///
+/// Files that do not exist are ignored. Revoked keys are never
+/// reported.
+pub fn host_keys(patterns: &[String], files: &[PathBuf]) -> Result<Vec<Vec<u8>>> {
+  let mut keys = Vec::new();
+  let mut revoked = Vec::new();
+
+  for file in files {
+    let data = match read_to_string(file) {
+      Ok(data) => data,
+      Err(err) if err.kind() == ErrorKind::NotFound => continue,
+      Err(err) => {
+        return Err(err).with_context(|| format!("failed to read {}", file.display()))
+      },
+    };
+    let () = parse_known_hosts(patterns, &data, &mut keys, &mut revoked)
+      .with_context(|| format!("{} is not a valid known hosts file", file.display()))?;
+  }
+
+  let () = keys.retain(|key| !revoked.contains(key));
+  Ok(keys)
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::fs::write;
+
+  use tempfile::tempdir;
+
+
+  /// The key of `server.example.com`.
+  const KEY1: &str = "AAAAC3NzaC1lZDI1NTE5AAAAICH03CAbp4gaOrLwB05PTqeDpqEFL2bqFDpk4mIlNAn4";
+  /// The key of `other.example.org`.
+  const KEY2: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIGhBEQZKovzefN1bhXg0wTlaR+9uCVdvXRrGeLM7Jg+O";
+
+
+  fn patterns(patterns: &[&str]) -> Vec<String> {
+    patterns.iter().map(ToString::to_string).collect()
+  }
+
+
+  /// Check that host name patterns match as expected.
+  #[test]
+  fn pattern_matching() {
+    assert!(matches_hosts(&patterns(&["example.com"]), "example.com"));
+    assert!(matches_hosts(&patterns(&["example.com"]), "EXAMPLE.com"));
+    assert!(!matches_hosts(&patterns(&["example.com"]), "example.co"));
+    assert!(matches_hosts(&patterns(&["*.example.com"]), "a.b.example.com"));
+    assert!(!matches_hosts(&patterns(&["*.example.com"]), "example.com"));
+    assert!(matches_hosts(&patterns(&["host?"]), "host1"));
+    assert!(!matches_hosts(&patterns(&["host?"]), "host"));
+    assert!(matches_hosts(&patterns(&["*"]), ""));
+    assert!(!matches_hosts(&patterns(&[]), "example.com"));
+
+    let list = patterns(&["*.example.com", "!bad.example.com"]);
+    assert!(matches_hosts(&list, "good.example.com"));
+    assert!(!matches_hosts(&list, "bad.example.com"));
+    assert!(!matches_hosts(&patterns(&["!bad.example.com"]), "good.example.com"));
+  }
+
+  /// Check that user name patterns match case sensitively.
+  #[test]
+  fn user_matching() {
+    assert!(matches_user("deso", "deso"));
+    assert!(!matches_user("deso", "Deso"));
+    assert!(matches_user("d*", "deso"));
+    assert!(!matches_user("d?", "deso"));
+  }
+
+  /// Check that we can match hashed host names.
+  #[test]
+  fn hashed_matching() -> Result<()> {
+    let hashed = "|1|0SNFzmegTXdU8KPI7T+DHWCYsKs=|fhFFicRrXt8wT4bZUerlkneu5lo=";
+    assert!(matches_hashed(hashed, "server.example.com")?);
+    assert!(!matches_hashed(hashed, "other.example.org")?);
+    assert!(matches_hashed("|1|salt", "server.example.com").is_err());
+    Ok(())
+  }
+
+  /// Check that we look up the expected host keys.
+  #[test]
+  fn host_key_lookup() -> Result<()> {
+    let dir = tempdir()?;
+    let hashed = dir.path().join("hashed");
+    let () = write(
+      &hashed,
+      format!(
+        "|1|0SNFzmegTXdU8KPI7T+DHWCYsKs=|fhFFicRrXt8wT4bZUerlkneu5lo= ssh-ed25519 {KEY1}\n\
+         |1|/iCdSuV8so+v56HbOBysV0KgDMM=|I/xFWFUbDYlxbSmCbc/OciyEKSM= ssh-ed25519 {KEY2}\n"
+      ),
+    )?;
+    let plain = dir.path().join("plain");
+    let () = write(
+      &plain,
+      format!(
+        "# comment\n\n\
+         server.example.com,192.0.2.1 ssh-ed25519 {KEY1}\n\
+         other.example.org ssh-ed25519 {KEY2}\n\
+         @cert-authority *.example.com ssh-ed25519 {KEY2}\n"
+      ),
+    )?;
+    let missing = dir.path().join("missing");
+    let key1 = BASE64.decode(KEY1)?;
+    let key2 = BASE64.decode(KEY2)?;
+
+    let files = [hashed.clone(), missing];
+    let keys = host_keys(&patterns(&["server.example.com"]), &files)?;
+    assert_eq!(keys, vec![key1.clone()]);
+    // Wildcards can't match hashed host names.
+    let keys = host_keys(&patterns(&["*.example.com"]), &files)?;
+    assert_eq!(keys, Vec::<Vec<u8>>::new());
+
+    let files = [plain.clone()];
+    let keys = host_keys(&patterns(&["*.example.com"]), &files)?;
+    assert_eq!(keys, vec![key1.clone()]);
+    let keys = host_keys(&patterns(&["*", "!*.example.com"]), &files)?;
+    assert_eq!(keys, vec![key1.clone(), key2.clone()]);
+    let keys = host_keys(&patterns(&["*.org"]), &files)?;
+    assert_eq!(keys, vec![key2]);
+
+    let revoked = dir.path().join("revoked");
+    let () = write(&revoked, format!("@revoked * ssh-ed25519 {KEY1}\n"))?;
+    let keys = host_keys(&patterns(&["server.example.com"]), &[plain, revoked.clone()])?;
+    assert_eq!(keys, Vec::<Vec<u8>>::new());
+
+    let () = write(&revoked, "server.example.com ssh-ed25519\n")?;
+    assert!(host_keys(&patterns(&["*"]), &[revoked]).is_err());
+    Ok(())
+  }
+}
@@ -0,0 +1,678 @@
+// index.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! An in-memory index of the key pairs in a set of directories, along
+//! with those referred to by individual identity files.
+//!
+//! Instead of reading the key directories on every request, we watch
+//! them (and the directories containing the identity files) for
+//! changes and only rescan once something changed. If the
+//! platform's native notification mechanism is unavailable, we fall
+//! back to polling and, as a last resort, to rescanning every time.
+//!
+//! The contents of public key files can additionally be persisted to a
+//! file, along with the modification time and size of each. Public key
+//! files that did not change since are not read again when rescanning,
+//! including after a restart of the agent.
+//!
+//! Key directories residing on network file systems may fail to be
+//! read temporarily (e.g., with a stale file handle). Such reads are
+//! retried a few times and, if they keep failing, the directory or file
+//! is left alone for a while, with the key pairs found in it last being
+//! served meanwhile.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::fs::create_dir_all;
+use std::fs::metadata;
+use std::fs::read_to_string;
+use std::fs::rename;
+use std::fs::write;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::UNIX_EPOCH;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use anyhow::Error;
+use anyhow::Result;
+
+use notify::recommended_watcher;
+use notify::Config as NotifyConfig;
+use notify::Event;
+use notify::PollWatcher;
+use notify::RecursiveMode;
+use notify::Result as NotifyResult;
+use notify::Watcher;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use tracing::debug;
+use tracing::warn;
+
+use crate::config::DiscoveryConfig;
+use crate::files::identity_file_keys_with;
+use crate::files::is_transient;
+use crate::files::load_public_keys;
+use crate::files::public_keys_with;
+use crate::files::PemPublicKey;
+
+
+/// The interval at which to poll directories for changes if native
+/// change notifications are unavailable.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// The time for which files and directories failing with a likely
+/// transient error are not accessed again.
+const QUARANTINE: Duration = Duration::from_secs(30);
+
+
+/// Create a watcher of the given kind for all provided directories,
+/// marking the index as dirty on every change.
+fn watch<W, F>(
+  dirs: &[PathBuf],
+  dirty: &Arc<AtomicBool>,
+  create: F,
+) -> NotifyResult<Box<dyn Watcher + Send>>
+where
+  W: Watcher + Send + 'static,
+  F: FnOnce(Box<dyn FnMut(NotifyResult<Event>) + Send>) -> NotifyResult<W>,
+{
+  let dirty = dirty.clone();
+  let handler = move |event: NotifyResult<Event>| {
+    match event {
+      Ok(event) => debug!("Key directory changed: {:?}", event.paths),
+      Err(err) => warn!("Failed to watch key directory: {err}"),
+    }
+    // Even on error, rescanning is the conservative thing to do.
+    let () = dirty.store(true, Ordering::SeqCst);
+  };
+
+  let mut watcher = create(Box::new(handler))?;
+  for dir in dirs {
+    let () = watcher.watch(dir, RecursiveMode::NonRecursive)?;
+  }
+  Ok(Box::new(watcher))
+}
+
+
+/// The state of a public key file as recorded in the persisted index.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct IndexEntry {
+  /// The seconds part of the file's modification time, relative to the
+  /// Unix epoch.
+  secs: u64,
+  /// The nanoseconds part of the file's modification time.
+  nanos: u32,
+  /// The size of the file.
+  len: u64,
+  /// The public keys contained in the file, one per line.
+  keys: Vec<String>,
+}
+
+impl IndexEntry {
+  /// Check whether the entry is still current for the file with the
+  /// given modification time and size.
+  fn is_current(&self, secs: u64, nanos: u32, len: u64) -> bool {
+    self.secs == secs && self.nanos == nanos && self.len == len
+  }
+}
+
+
+/// The contents of the file the index is persisted in.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct IndexFile {
+  /// The indexed public key files, keyed by their paths.
+  files: HashMap<PathBuf, IndexEntry>,
+}
+
+
+/// Load the persisted index from the given file, if it exists.
+fn load_index(file: &Path) -> Result<HashMap<PathBuf, IndexEntry>> {
+  let toml = match read_to_string(file) {
+    Ok(toml) => toml,
+    Err(err) if err.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+    Err(err) => {
+      return Err(err).with_context(|| format!("failed to read key index {}", file.display()))
+    },
+  };
+
+  let index = toml::from_str::<IndexFile>(&toml)
+    .with_context(|| format!("invalid key index in {}", file.display()))?;
+  Ok(index.files)
+}
+
+
+/// Persist the index to the given file.
+///
+/// The file is replaced atomically, so that concurrently starting
+/// agents never pick up a truncated index.
+fn save_index(file: &Path, files: &HashMap<PathBuf, IndexEntry>) -> Result<()> {
+  let index = IndexFile {
+    files: files.clone(),
+  };
+  let toml = toml::to_string(&index).with_context(|| "failed to serialize key index")?;
+
+  if let Some(dir) = file.parent() {
+    let () = create_dir_all(dir)
+      .with_context(|| format!("failed to create directory {}", dir.display()))?;
+  }
+
+  let mut tmp = OsString::from(file);
+  let () = tmp.push(".tmp");
+  let () = write(&tmp, toml)
+    .with_context(|| format!("failed to write key index to {}", file.display()))?;
+  rename(&tmp, file).with_context(|| format!("failed to write key index to {}", file.display()))
+}
+
+
+/// Load the public keys stored in the given file, reusing the recorded
+/// ones if the file did not change since it got indexed.
+///
+/// The entry for the file, if it can be indexed, is added to `current`.
+fn load_indexed(
+  path: &Path,
+  indexed: &HashMap<PathBuf, IndexEntry>,
+  current: &mut HashMap<PathBuf, IndexEntry>,
+) -> Result<Vec<PemPublicKey>> {
+  let stamp = metadata(path).ok().and_then(|metadata| {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((modified.as_secs(), modified.subsec_nanos(), metadata.len()))
+  });
+  let (secs, nanos, len) = match stamp {
+    Some(stamp) => stamp,
+    None => return load_public_keys(path),
+  };
+
+  if let Some(entry) = indexed.get(path) {
+    if entry.is_current(secs, nanos, len) {
+      let keys = entry
+        .keys
+        .iter()
+        .map(|key| PemPublicKey::from(key.clone().into_bytes()))
+        .collect();
+      let _ = current.insert(path.to_path_buf(), entry.clone());
+      return Ok(keys)
+    }
+  }
+
+  let keys = load_public_keys(path)?;
+  // Only files with textual paths and contents can be persisted.
+  let lines = keys
+    .iter()
+    .map(|key| String::from_utf8(key.as_ref().to_vec()).ok())
+    .collect::<Option<Vec<_>>>();
+  if let (Some(lines), Some(_)) = (lines, path.to_str()) {
+    let entry = IndexEntry {
+      secs,
+      nanos,
+      len,
+      keys: lines,
+    };
+    let _ = current.insert(path.to_path_buf(), entry);
+  }
+  Ok(keys)
+}
+
+
+/// The last known good state of key files and directories, used for
+/// riding out transient errors, e.g., of network file systems.
+#[derive(Debug, Default)]
+struct Fallback {
+  /// The public keys of the files, as last loaded successfully.
+  files: HashMap<PathBuf, Vec<PemPublicKey>>,
+  /// The key pairs in the directories, as per their last successful
+  /// scan.
+  dirs: HashMap<PathBuf, Vec<(PemPublicKey, PathBuf)>>,
+  /// The files and directories not to access until the given time,
+  /// along with the error they failed with.
+  quarantined: HashMap<PathBuf, (Instant, String)>,
+  /// Whether the last scan used any fallback.
+  degraded: bool,
+}
+
+impl Fallback {
+  /// Retrieve the error the given file or directory failed with, if it
+  /// is still quarantined.
+  fn quarantined(&mut self, path: &Path) -> Option<Error> {
+    let (until, err) = self.quarantined.get(path)?;
+    if *until <= Instant::now() {
+      let _ = self.quarantined.remove(path);
+      return None
+    }
+    Some(anyhow!("{err} (not retried for now)"))
+  }
+
+  /// Quarantine the given file or directory after it failed with the
+  /// provided (transient) error.
+  fn quarantine(&mut self, path: &Path, err: &Error) {
+    warn!("{err:#}; not accessing {} for {QUARANTINE:?}", path.display());
+    let until = Instant::now() + QUARANTINE;
+    let _ = self.quarantined.insert(path.to_path_buf(), (until, format!("{err:#}")));
+  }
+
+  /// Load the public keys from the given file using the provided
+  /// function, falling back to the last known good ones if that fails
+  /// with a transient error.
+  fn load<L>(&mut self, path: &Path, load: &mut L) -> Result<Vec<PemPublicKey>>
+  where
+    L: FnMut(&Path) -> Result<Vec<PemPublicKey>>,
+  {
+    let err = match self.quarantined(path) {
+      Some(err) => err,
+      None => match load(path) {
+        Ok(keys) => {
+          let _ = self.files.insert(path.to_path_buf(), keys.clone());
+          return Ok(keys)
+        },
+        Err(err) if is_transient(&err) => {
+          let () = self.quarantine(path, &err);
+          err
+        },
+        Err(err) => {
+          let _ = self.files.remove(path);
+          return Err(err)
+        },
+      },
+    };
+
+    self.degraded = true;
+    self.files.get(path).cloned().ok_or(err)
+  }
+
+  /// Scan the given directory using the provided function, falling back
+  /// to the key pairs found by the last successful scan if that fails
+  /// with a transient error.
+  fn scan_dir<S>(&mut self, dir: &Path, scan: S) -> Vec<Result<(PemPublicKey, PathBuf)>>
+  where
+    S: FnOnce(&mut Self) -> Result<Vec<Result<(PemPublicKey, PathBuf)>>>,
+  {
+    let err = match self.quarantined(dir) {
+      Some(err) => err,
+      None => match scan(self) {
+        Ok(found) => {
+          let good = found.iter().filter_map(|x| x.as_ref().ok().cloned()).collect();
+          let _ = self.dirs.insert(dir.to_path_buf(), good);
+          return found
+        },
+        Err(err) if is_transient(&err) => {
+          let () = self.quarantine(dir, &err);
+          err
+        },
+        Err(err) => {
+          let _ = self.dirs.remove(dir);
+          return vec![Err(err)]
+        },
+      },
+    };
+
+    self.degraded = true;
+    match self.dirs.get(dir) {
+      Some(keys) => keys.iter().cloned().map(Ok).collect(),
+      None => vec![Err(err)],
+    }
+  }
+}
+
+
+/// The persisted part of an index.
+#[derive(Debug)]
+struct Persisted {
+  /// The file the index is persisted in.
+  file: PathBuf,
+  /// The indexed public key files, as per the last scan.
+  files: Mutex<HashMap<PathBuf, IndexEntry>>,
+}
+
+
+/// An index of the key pairs contained in a set of directories.
+pub struct KeyIndex {
+  /// The directories to index, in order of precedence.
+  dirs: Vec<PathBuf>,
+  /// The identity files (as used by `ssh`) whose key pairs to index in
+  /// addition, after those in the directories.
+  identity_files: Vec<PathBuf>,
+  /// The rules selecting the public key files in the directories.
+  rules: DiscoveryConfig,
+  /// A flag indicating whether any of the directories changed since we
+  /// last scanned them.
+  dirty: Arc<AtomicBool>,
+  /// The keys as per the last successful scan.
+  keys: Mutex<Vec<(PemPublicKey, PathBuf)>>,
+  /// The watcher informing us about changes to the directories, if any.
+  /// It is only kept around to keep it alive and never accessed, but
+  /// we need it to be `Sync`.
+  watcher: Option<Mutex<Box<dyn Watcher + Send>>>,
+  /// The persisted index of public key files, if any.
+  persisted: Option<Persisted>,
+  /// The last known good state of the indexed files and directories.
+  fallback: Mutex<Fallback>,
+}
+
+impl KeyIndex {
+  /// Create an index of the key pairs in the given directories (as
+  /// selected by the provided discovery rules) and those referred to by
+  /// the provided identity files, persisting the contents of public key
+  /// files in `file`, if provided.
+  ///
+  /// Failure to load a previously persisted index is reported but
+  /// otherwise ignored, as all files are simply read again.
+  pub fn new(
+    dirs: Vec<PathBuf>,
+    identity_files: Vec<PathBuf>,
+    rules: DiscoveryConfig,
+    file: Option<PathBuf>,
+  ) -> Self {
+    let mut watched = dirs.clone();
+    for dir in identity_files.iter().filter_map(|file| file.parent()) {
+      // Directories that don't exist (yet) cannot be watched.
+      if dir.is_dir() && !watched.iter().any(|watched| watched == dir) {
+        let () = watched.push(dir.to_path_buf());
+      }
+    }
+
+    let dirty = Arc::new(AtomicBool::new(true));
+    let watcher = watch(&watched, &dirty, recommended_watcher)
+      .or_else(|err| {
+        warn!("Failed to watch key directories ({err}); falling back to polling");
+        let config = NotifyConfig::default().with_poll_interval(POLL_INTERVAL);
+        watch(&watched, &dirty, |handler| PollWatcher::new(handler, config))
+      })
+      .map_err(|err| warn!("Failed to poll key directories ({err}); always rescanning"))
+      .ok()
+      .map(Mutex::new);
+
+    let persisted = file.map(|file| {
+      let files = load_index(&file).unwrap_or_else(|err| {
+        warn!("{err:#}");
+        HashMap::new()
+      });
+      Persisted {
+        file,
+        files: Mutex::new(files),
+      }
+    });
+
+    Self {
+      dirs,
+      identity_files,
+      rules,
+      dirty,
+      keys: Mutex::new(Vec::new()),
+      watcher,
+      persisted,
+      fallback: Mutex::new(Fallback::default()),
+    }
+  }
+
+  /// Mark the index as outdated, forcing a rescan on next access.
+  ///
+  /// Changes we make ourselves should be visible immediately, without
+  /// waiting for the watcher to report them.
+  pub fn invalidate(&self) {
+    let () = self.dirty.store(true, Ordering::SeqCst);
+  }
+
+  /// Scan all directories for key pairs.
+  fn scan(&self) -> Vec<Result<(PemPublicKey, PathBuf)>> {
+    let persisted = match &self.persisted {
+      Some(persisted) => persisted,
+      None => return self.scan_with(|path| load_public_keys(path)),
+    };
+
+    let mut indexed = persisted.files.lock().unwrap();
+    let mut current = HashMap::new();
+    let keys = self.scan_with(|path| load_indexed(path, &indexed, &mut current));
+
+    if current != *indexed {
+      if let Err(err) = save_index(&persisted.file, &current) {
+        warn!("{err:#}");
+      }
+      *indexed = current;
+    }
+    keys
+  }
+
+  /// Scan all directories for key pairs, loading the contents of public
+  /// key files using the provided function.
+  ///
+  /// Directories and files failing with a likely transient error are
+  /// served from the last successful scan, if possible.
+  fn scan_with<L>(&self, mut load: L) -> Vec<Result<(PemPublicKey, PathBuf)>>
+  where
+    L: FnMut(&Path) -> Result<Vec<PemPublicKey>>,
+  {
+    let mut fallback = self.fallback.lock().unwrap();
+    let () = fallback.degraded = false;
+
+    let mut keys = Vec::new();
+    for dir in &self.dirs {
+      let () = keys.extend(fallback.scan_dir(dir, |fallback| {
+        let load = |path: &Path| fallback.load(path, &mut load);
+        public_keys_with(dir.clone(), &self.rules, load).map(Iterator::collect)
+      }));
+    }
+    for file in &self.identity_files {
+      let load = |path: &Path| fallback.load(path, &mut load);
+      let () = keys.extend(identity_file_keys_with(file, &self.rules, load));
+    }
+
+    if fallback.degraded {
+      // Make sure to pick up the actual state once the problem is gone,
+      // even if we are not informed about any change.
+      let () = self.invalidate();
+    }
+    keys
+  }
+
+  /// Retrieve the key pairs in the indexed directories, in the order of
+  /// the directories they are contained in, followed by those of the
+  /// identity files.
+  pub fn keys(&self) -> Vec<Result<(PemPublicKey, PathBuf)>> {
+    if self.watcher.is_none() {
+      return self.scan()
+    }
+
+    let mut keys = self.keys.lock().unwrap();
+    // Clear the flag before scanning, so that changes happening while
+    // we scan cause yet another rescan.
+    if self.dirty.swap(false, Ordering::SeqCst) {
+      let result = self.scan();
+      if result.iter().any(Result::is_err) {
+        // Errors are not cached, so that they are reported again (and
+        // possibly fixed) on next access.
+        let () = self.invalidate();
+        return result
+      }
+
+      *keys = result.into_iter().filter_map(Result::ok).collect();
+    }
+    keys.iter().cloned().map(Ok).collect()
+  }
+}
+
+impl Debug for KeyIndex {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    f.debug_struct("KeyIndex")
+      .field("dirs", &self.dirs)
+      .field("identity_files", &self.identity_files)
+      .field("dirty", &self.dirty)
+      .field("watching", &self.watcher.is_some())
+      .field("persisted", &self.persisted.as_ref().map(|persisted| &persisted.file))
+      .finish()
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::fs::copy;
+  use std::fs::remove_file;
+  use std::io;
+
+  use tempfile::tempdir;
+
+
+  /// Check that the index picks up changes to the indexed directory.
+  #[test]
+  fn index_updates() -> Result<()> {
+    let src = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("valid_keys");
+    let dir = tempdir()?;
+    let dirs = vec![dir.path().to_path_buf()];
+    let index = KeyIndex::new(dirs, Vec::new(), DiscoveryConfig::default(), None);
+    assert_eq!(index.keys().len(), 0);
+
+    for file in ["ed25519.pub", "ed25519.gpg"] {
+      let _ = copy(src.join(file), dir.path().join(file))?;
+    }
+    let () = index.invalidate();
+    assert_eq!(index.keys().len(), 1);
+    // A second access is served from the index.
+    assert_eq!(index.keys().len(), 1);
+
+    let () = remove_file(dir.path().join("ed25519.gpg"))?;
+    let () = index.invalidate();
+    assert_eq!(index.keys().len(), 0);
+    Ok(())
+  }
+
+  /// Check that the index includes the key pairs of identity files
+  /// outside of the indexed directories.
+  #[test]
+  fn identity_file_indexing() -> Result<()> {
+    let src = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("valid_keys");
+    let dir = tempdir()?;
+    let keys = dir.path().join("keys");
+    let other = dir.path().join("other");
+    let () = create_dir_all(&keys)?;
+    let () = create_dir_all(&other)?;
+    for file in ["ed25519.pub", "ed25519.gpg"] {
+      let _ = copy(src.join(file), other.join(file))?;
+    }
+    let _ = copy(src.join("rsa2048.pub"), other.join("rsa2048.pub"))?;
+
+    let files = vec![other.join("ed25519"), other.join("rsa2048"), other.join("missing")];
+    let index = KeyIndex::new(vec![keys], files, DiscoveryConfig::default(), None);
+    let indexed = index.keys().into_iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(indexed.len(), 1);
+    assert_eq!(indexed[0].1, other.join("ed25519.gpg"));
+    Ok(())
+  }
+
+  /// Check that unchanged public key files are served from the
+  /// persisted index.
+  #[test]
+  fn index_persistence() -> Result<()> {
+    let src = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("valid_keys");
+    let dir = tempdir()?;
+    let keys = dir.path().join("keys");
+    let () = create_dir_all(&keys)?;
+    for file in ["ed25519.pub", "ed25519.gpg"] {
+      let _ = copy(src.join(file), keys.join(file))?;
+    }
+    let file = dir.path().join("state").join("index.toml");
+    let rules = DiscoveryConfig::default();
+
+    let index = KeyIndex::new(vec![keys.clone()], Vec::new(), rules.clone(), Some(file.clone()));
+    let (key, _) = index.keys().pop().unwrap()?;
+    let mut files = load_index(&file)?;
+    assert_eq!(files.len(), 1);
+
+    // Pretend the file contained a different key when it got indexed
+    // last. As it did not change since, the recorded key is used.
+    let pub_path = keys.join("ed25519.pub");
+    let entry = files.get_mut(&pub_path).unwrap();
+    assert_eq!(entry.keys, vec![String::from_utf8(key.as_ref().to_vec())?]);
+    entry.keys = vec!["ssh-ed25519 recorded\n".to_string()];
+    let () = save_index(&file, &files)?;
+
+    let index = KeyIndex::new(vec![keys.clone()], Vec::new(), rules.clone(), Some(file.clone()));
+    let (recorded, _) = index.keys().pop().unwrap()?;
+    assert_eq!(recorded.as_ref(), b"ssh-ed25519 recorded\n");
+
+    // Once the file changes, it is read again.
+    let () = write(&pub_path, [key.as_ref(), b"\n"].concat())?;
+    let () = index.invalidate();
+    let (reread, _) = index.keys().pop().unwrap()?;
+    assert_eq!(reread.as_ref(), key.as_ref());
+    assert_ne!(load_index(&file)?, files);
+
+    // A corrupted index is ignored.
+    let () = write(&file, "files = 42")?;
+    let index = KeyIndex::new(vec![keys], Vec::new(), rules, Some(file));
+    assert_eq!(index.keys().len(), 1);
+    Ok(())
+  }
+
+  /// Check that files and directories failing with transient errors
+  /// are served from the last successful scan and quarantined.
+  #[test]
+  fn transient_error_fallback() {
+    let stale = || Error::from(io::Error::from_raw_os_error(libc::ESTALE));
+    let key = PemPublicKey::from(b"ssh-ed25519 key\n".to_vec());
+    let other = PemPublicKey::from(b"ssh-ed25519 other\n".to_vec());
+    let file = Path::new("/keys/ed25519.pub");
+    let mut fallback = Fallback::default();
+
+    let keys = fallback.load(file, &mut |_| Ok(vec![key.clone()])).unwrap();
+    assert_eq!(keys[0].as_ref(), key.as_ref());
+    assert!(!fallback.degraded);
+
+    let keys = fallback.load(file, &mut |_| Err(stale())).unwrap();
+    assert_eq!(keys[0].as_ref(), key.as_ref());
+    assert!(fallback.degraded);
+
+    // While quarantined, the file is not accessed.
+    let keys = fallback
+      .load(file, &mut |_| -> Result<Vec<PemPublicKey>> { unreachable!() })
+      .unwrap();
+    assert_eq!(keys[0].as_ref(), key.as_ref());
+
+    // Without a last known good state the error is reported.
+    let new = Path::new("/keys/new.pub");
+    assert!(fallback.load(new, &mut |_| Err(stale())).is_err());
+
+    // Other errors are never papered over.
+    let broken = Path::new("/keys/broken.pub");
+    let _ = fallback.load(broken, &mut |_| Ok(vec![other.clone()])).unwrap();
+    let err = fallback.load(broken, &mut |_| Err(anyhow!("invalid key"))).unwrap_err();
+    assert_eq!(err.to_string(), "invalid key");
+
+    let dir = Path::new("/keys");
+    let found = fallback.scan_dir(dir, |_| Ok(vec![Ok((key.clone(), file.to_path_buf()))]));
+    assert_eq!(found.len(), 1);
+    fallback.degraded = false;
+    let found = fallback.scan_dir(dir, |_| Err(stale()));
+    assert_eq!(found[0].as_ref().unwrap().1, file);
+    assert!(fallback.degraded);
+  }
+}
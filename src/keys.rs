@@ -1,7 +1,7 @@
 // keys.rs
 
 // *************************************************************************
-// * Copyright (C) 2019-2023 Daniel Mueller (deso@posteo.net)              *
+// * Copyright (C) 2019-2024 Daniel Mueller (deso@posteo.net)              *
 // *                                                                       *
 // * This program is free software: you can redistribute it and/or modify  *
 // * it under the terms of the GNU General Public License as published by  *
@@ -21,77 +21,105 @@ use std::ops::DerefMut;
 use std::str::from_utf8 as str_from_utf8;
 
 use anyhow::anyhow;
+use anyhow::ensure;
 use anyhow::Context as _;
 use anyhow::Result;
 
-use ssh_agent_lib::proto::private_key::Ed25519PrivateKey;
+use ssh_agent_lib::proto::from_bytes;
+use ssh_agent_lib::proto::key_type::KeyTypeEnum;
+use ssh_agent_lib::proto::Blob;
 use ssh_agent_lib::proto::private_key::PrivateKey;
-use ssh_agent_lib::proto::private_key::RsaPrivateKey;
-use ssh_agent_lib::proto::public_key::Ed25519PublicKey;
 use ssh_agent_lib::proto::public_key::PublicKey;
-use ssh_agent_lib::proto::public_key::RsaPublicKey;
-
-use ssh_keys::openssh::parse_private_key;
-use ssh_keys::openssh::parse_public_key;
-use ssh_keys::PrivateKey as SshPrivateKey;
-use ssh_keys::PublicKey as SshPublicKey;
 
 use crate::files::PemPrivateKey;
 use crate::files::PemPublicKey;
+use crate::openssh::format_private_key;
+use crate::openssh::format_public_key;
+use crate::openssh::is_encrypted;
+use crate::openssh::parse_private_keys as parse_openssh_private_keys;
+use crate::openssh::parse_public_key;
+use crate::openssh::read_string;
+use crate::openssh::PublicKeyLine;
+use crate::ppk::is_encrypted as is_ppk_encrypted;
+use crate::ppk::is_ppk;
+use crate::ppk::parse_private_key as parse_ppk_private_key;
+use crate::secret::wipe;
 
 
-/// Convert an ssh_keys PrivateKey into an ssh_agent PrivateKey.
-fn convert_pub(key: SshPublicKey) -> PublicKey {
-  match key {
-    SshPublicKey::Rsa { exponent, modulus } => {
-      let rsa = RsaPublicKey {
-        e: exponent,
-        n: modulus,
-      };
-      PublicKey::Rsa(rsa)
-    },
-    SshPublicKey::Ed25519(data) => {
-      let ed25519 = Ed25519PublicKey {
-        enc_a: data.to_vec(),
-      };
-      PublicKey::Ed25519(ed25519)
-    },
-  }
+/// The suffix of the key type of OpenSSH certificates.
+const CERT_TYPE_SUFFIX: &str = "-cert-v01@openssh.com";
+
+
+/// Parse a public key (or certificate) in the format used by OpenSSH's
+/// `.pub` files.
+fn parse_pem_public_key(pem_key: &PemPublicKey) -> Result<PublicKeyLine> {
+  let string =
+    str_from_utf8(pem_key.as_ref()).with_context(|| "failed to convert public key to string")?;
+  parse_public_key(string).with_context(|| "failed to parse public key")
 }
 
 
-/// Convert an ssh_keys PrivateKey into an ssh_agent PrivateKey.
-fn convert_priv(key: SshPrivateKey) -> PrivateKey {
-  match key {
-    SshPrivateKey::Rsa {
-      n,
-      e,
-      d,
-      iqmp,
-      p,
-      q,
-    } => {
-      let rsa = RsaPrivateKey {
-        n,
-        e,
-        d,
-        iqmp,
-        p,
-        q,
-      };
-      PrivateKey::Rsa(rsa)
-    },
-    SshPrivateKey::Ed25519(data) => {
-      let mut key = data.to_vec();
-      let public = key.split_off(32);
-      let seed = key;
-
-      let ed25519 = Ed25519PrivateKey {
-        enc_a: public,
-        k_enc_a: seed,
-      };
-      PrivateKey::Ed25519(ed25519)
-    },
+/// Extract the comment from a public key (or certificate) in the format
+/// used by OpenSSH's `.pub` files.
+pub fn public_key_comment(pem_key: &PemPublicKey) -> Result<String> {
+  Ok(parse_pem_public_key(pem_key)?.comment)
+}
+
+
+/// An OpenSSH certificate, as stored in a "key-cert.pub" file.
+///
+/// We treat certificates as mostly opaque blobs: all we need to know is
+/// which key they certify, so that we can sign with it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Certificate {
+  /// The certificate in SSH wire format.
+  blob: Vec<u8>,
+  /// The comment stored alongside the certificate.
+  comment: String,
+}
+
+impl Certificate {
+  /// Retrieve the certificate in SSH wire format.
+  pub fn blob(&self) -> &[u8] {
+    &self.blob
+  }
+
+  /// Retrieve the comment stored alongside the certificate.
+  pub fn comment(&self) -> &str {
+    &self.comment
+  }
+
+  /// Check whether the certificate certifies the given public key.
+  pub fn certifies(&self, key: &PublicKey) -> Result<bool> {
+    // A certificate contains the key type, followed by a nonce, followed
+    // by the type specific public key fields. A public key blob contains
+    // the key type followed by those very same fields. So compare them.
+    let mut cert = self.blob.as_slice();
+    let cert_type = read_string(&mut cert)?;
+    let _nonce = read_string(&mut cert)?;
+
+    let blob = key.to_blob().context("failed to serialize public key")?;
+    let mut fields = blob.as_slice();
+    let key_type = read_string(&mut fields)?;
+
+    let matches = cert_type.strip_suffix(CERT_TYPE_SUFFIX.as_bytes()) == Some(key_type)
+      && cert.starts_with(fields);
+    Ok(matches)
+  }
+}
+
+impl FromPem<PemPublicKey> for Certificate {
+  fn from_pem(pem_key: PemPublicKey) -> Result<Self> {
+    let PublicKeyLine {
+      key_type,
+      blob,
+      comment,
+    } = parse_pem_public_key(&pem_key)?;
+    ensure!(
+      key_type.ends_with(CERT_TYPE_SUFFIX),
+      "{key_type} is not a certificate type"
+    );
+    Ok(Self { blob, comment })
   }
 }
 
@@ -101,37 +129,137 @@ pub trait FromPem<K>
 where
   Self: Sized,
 {
+  /// Create an object from the given PEM encoded key.
   fn from_pem(pem_key: K) -> Result<Self>;
 }
 
+/// Parse all private keys contained in the given PEM encoded data,
+/// decrypting them with the given passphrase if necessary. Keys are
+/// returned along with their comments.
+fn parse_pem_private_keys(
+  pem_key: &PemPrivateKey,
+  passphrase: Option<&[u8]>,
+) -> Result<Vec<(PrivateKey, String)>> {
+  let string =
+    str_from_utf8(pem_key.as_ref()).with_context(|| "failed to convert private key to string")?;
+
+  let keys = if is_ppk(string) {
+    vec![parse_ppk_private_key(string, passphrase)?]
+  } else {
+    parse_openssh_private_keys(string, passphrase)?
+  };
+  ensure!(!keys.is_empty(), "private key file contains no keys");
+  Ok(keys)
+}
+
+
+/// Parse the single private key contained in the given PEM encoded
+/// data, decrypting it with the given passphrase if necessary. The key
+/// is returned along with its comment.
+fn parse_pem_private_key(
+  pem_key: &PemPrivateKey,
+  passphrase: Option<&[u8]>,
+) -> Result<(PrivateKey, String)> {
+  let mut keys = parse_pem_private_keys(pem_key, passphrase)?;
+
+  match keys.deref_mut() {
+    [_] => Ok(keys.swap_remove(0)),
+    _ => {
+      let () = keys.iter_mut().for_each(|(key, _comment)| wipe(key));
+      let err = Err(anyhow!(
+        "private key file contains more than one key"
+      ));
+      err.with_context(|| "failed to read PEM encoded private key")
+    },
+  }
+}
+
+
+/// Parse all keys contained in the given unencrypted PEM encoded
+/// private key file, returning them along with their comments.
+pub fn parse_private_keys(pem_key: &PemPrivateKey) -> Result<Vec<(PrivateKey, String)>> {
+  parse_pem_private_keys(pem_key, None)
+}
+
+
+/// Check whether the given PEM encoded private key is protected by a
+/// passphrase.
+pub fn is_passphrase_protected(pem_key: &PemPrivateKey) -> bool {
+  str_from_utf8(pem_key.as_ref())
+    .map(|string| is_encrypted(string) || is_ppk_encrypted(string))
+    .unwrap_or(false)
+}
+
 impl FromPem<PemPrivateKey> for PrivateKey {
   fn from_pem(pem_key: PemPrivateKey) -> Result<Self> {
-    let data = Vec::<_>::from(pem_key);
-    let string = str_from_utf8(&data).with_context(|| "failed to convert private key to string")?;
-
-    // Note that the SSH format actually supports having multiple keys
-    // inside a single file...
-    let mut keys = parse_private_key(string).with_context(|| "failed to parse private key")?;
-
-    // ... but we don't :)
-    match keys.deref_mut() {
-      [_] => Ok(convert_priv(keys.swap_remove(0))),
-      _ => {
-        let err = Err(anyhow!(
-          "private key file contains unsupported number of keys"
-        ));
-        err.with_context(|| "failed to read PEM encoded private key")
-      },
-    }
+    parse_pem_private_key(&pem_key, None).map(|(key, _comment)| key)
+  }
+}
+
+impl FromPem<PemPrivateKey> for Vec<PrivateKey> {
+  fn from_pem(pem_key: PemPrivateKey) -> Result<Self> {
+    let keys = parse_pem_private_keys(&pem_key, None)?;
+    Ok(keys.into_iter().map(|(key, _comment)| key).collect())
   }
 }
 
 impl FromPem<PemPublicKey> for PublicKey {
   fn from_pem(pem_key: PemPublicKey) -> Result<Self> {
-    let data = Vec::<_>::from(pem_key);
-    let string = str_from_utf8(&data).with_context(|| "failed to convert public key to string")?;
-    let key = parse_public_key(string).with_context(|| "failed to parse public key")?;
+    let line = parse_pem_public_key(&pem_key)?;
+    let key =
+      from_bytes::<PublicKey>(&line.blob).context("failed to deserialize public key blob")?;
+    Ok(key)
+  }
+}
+
+
+/// A trait for construction from PEM encoded data that is protected by
+/// a passphrase.
+pub trait FromEncryptedPem<K>
+where
+  Self: Sized,
+{
+  /// Create an object from the given PEM encoded key, decrypting it
+  /// using the provided passphrase.
+  fn from_encrypted_pem(pem_key: &K, passphrase: &[u8]) -> Result<Self>;
+}
+
+impl FromEncryptedPem<PemPrivateKey> for PrivateKey {
+  fn from_encrypted_pem(pem_key: &PemPrivateKey, passphrase: &[u8]) -> Result<Self> {
+    parse_pem_private_key(pem_key, Some(passphrase)).map(|(key, _comment)| key)
+  }
+}
+
+impl FromEncryptedPem<PemPrivateKey> for Vec<PrivateKey> {
+  fn from_encrypted_pem(pem_key: &PemPrivateKey, passphrase: &[u8]) -> Result<Self> {
+    let keys = parse_pem_private_keys(pem_key, Some(passphrase))?;
+    Ok(keys.into_iter().map(|(key, _comment)| key).collect())
+  }
+}
+
+
+/// A trait for conversion into PEM encoded data.
+pub trait ToPem<K> {
+  /// Convert the object into its PEM encoded form, carrying the
+  /// given comment.
+  fn to_pem(&self, comment: &str) -> Result<K>;
+}
+
+impl ToPem<PemPrivateKey> for PrivateKey {
+  /// Serialize the private key in the unencrypted `openssh-key-v1`
+  /// format.
+  fn to_pem(&self, comment: &str) -> Result<PemPrivateKey> {
+    let pem = format_private_key(self, comment)?;
+    Ok(PemPrivateKey::from(pem.into_bytes()))
+  }
+}
 
-    Ok(convert_pub(key))
+impl ToPem<PemPublicKey> for PublicKey {
+  /// Serialize the public key in the format used by OpenSSH's `.pub`
+  /// files.
+  fn to_pem(&self, comment: &str) -> Result<PemPublicKey> {
+    let blob = self.to_blob().context("failed to serialize public key")?;
+    let line = format_public_key(&self.key_type(), &blob, comment);
+    Ok(PemPublicKey::from(line.into_bytes()))
   }
 }
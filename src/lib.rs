@@ -0,0 +1,236 @@
+// lib.rs
+
+// *************************************************************************
+// * Copyright (C) 2019-2024 Daniel Mueller (deso@posteo.net)              *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+#![allow(clippy::let_unit_value)]
+#![warn(
+  bad_style,
+  broken_intra_doc_links,
+  dead_code,
+  future_incompatible,
+  improper_ctypes,
+  late_bound_lifetime_arguments,
+  missing_copy_implementations,
+  missing_debug_implementations,
+  missing_docs,
+  no_mangle_generic_items,
+  non_shorthand_field_patterns,
+  nonstandard_style,
+  overflowing_literals,
+  path_statements,
+  patterns_in_fns_without_body,
+  proc_macro_derive_resolution_fallback,
+  renamed_and_removed_lints,
+  rust_2018_compatibility,
+  rust_2018_idioms,
+  stable_features,
+  trivial_bounds,
+  trivial_numeric_casts,
+  type_alias_bounds,
+  tyvar_behind_raw_pointer,
+  unconditional_recursion,
+  unreachable_code,
+  unreachable_patterns,
+  unstable_features,
+  unstable_name_collisions,
+  unused,
+  unused_comparisons,
+  unused_import_braces,
+  unused_lifetimes,
+  unused_qualifications,
+  unused_results,
+  while_true,
+)]
+
+//! `ssh-gpg-agent` is an SSH agent that can transparently handle GPG
+//! encrypted SSH keys.
+//!
+//! This library contains the agent proper, [`GpgKeyAgent`], along with
+//! the extension points it is built on: [`KeyFiles`] provide the key
+//! pairs to manage, a [`Decryptor`] decrypts the GPG encrypted private
+//! keys, and a [`Signer`] creates signatures with them. Keys from other
+//! sources, such as hardware tokens, are offered by a [`KeyStore`]. The
+//! `ssh-gpg-agent` binary is a thin command line wrapper around it.
+
+// Self-contained builds must not link against system libraries.
+#[cfg(all(feature = "static", feature = "gpgme"))]
+compile_error!("the `static` feature requires building with `--no-default-features`");
+#[cfg(all(feature = "static", feature = "pkcs11"))]
+compile_error!("the `static` feature cannot be combined with `pkcs11`");
+#[cfg(all(feature = "static", feature = "tpm"))]
+compile_error!("the `static` feature cannot be combined with `tpm`");
+
+mod agent;
+mod batch;
+mod assuan;
+mod cache;
+mod cancel;
+mod client;
+mod composite;
+mod config;
+mod constraint;
+mod daemon;
+mod decrypt;
+mod error;
+mod files;
+mod filestore;
+mod fingerprint;
+mod gpgagent;
+mod flight;
+mod harden;
+mod hosts;
+mod index;
+mod keys;
+mod lock;
+mod logging;
+mod memstore;
+mod mock;
+mod openssh;
+mod peer;
+#[cfg(feature = "pkcs11")]
+mod pkcs11;
+mod policy;
+mod ppk;
+mod prompt;
+mod secret;
+mod sexp;
+#[cfg(feature = "sequoia")]
+mod sequoia;
+mod session;
+mod sign;
+mod sshconfig;
+mod status;
+mod store;
+#[cfg(feature = "tpm")]
+mod tpm;
+mod trace;
+mod transport;
+mod upstream;
+mod usage;
+
+pub use crate::agent::ForwardedAgent;
+pub use crate::agent::GpgKeyAgent;
+pub use crate::agent::PING_EXTENSION;
+pub use crate::agent::RELOAD_EXTENSION;
+pub use crate::agent::SocketAgent;
+pub use crate::agent::SHUTDOWN_EXTENSION;
+pub use crate::batch::sign_batch;
+pub use crate::batch::BatchSignRequest;
+pub use crate::batch::BatchSignResponse;
+pub use crate::batch::SignOutcome;
+pub use crate::batch::SIGN_BATCH_EXTENSION;
+pub use crate::cache::CacheStats;
+pub use crate::client::AgentClient;
+pub use crate::composite::CompositeStore;
+pub use crate::config::Backend;
+pub use crate::config::CacheConfig;
+pub use crate::config::Config;
+pub use crate::config::DestinationConfig;
+pub use crate::config::DiscoveryConfig;
+pub use crate::config::ForwardingConfig;
+pub use crate::config::GpgOptions;
+pub use crate::config::HardeningConfig;
+pub use crate::config::HostRule;
+pub use crate::config::KeyConfig;
+pub use crate::config::LogSink;
+pub use crate::config::OnAdd;
+pub use crate::config::OnConflict;
+pub use crate::config::OnRemove;
+pub use crate::config::PassConfig;
+pub use crate::config::PermissionPolicy;
+pub use crate::config::PinentryMode;
+pub use crate::config::Pkcs11Config;
+pub use crate::config::PromptConfig;
+pub use crate::config::PromptMethod;
+pub use crate::config::Protocol;
+pub use crate::config::RsaConfig;
+pub use crate::config::RsaSignatureAlgorithm;
+pub use crate::config::SocketConfig;
+pub use crate::config::SocketPolicy;
+pub use crate::config::StoresConfig;
+pub use crate::config::SymlinkPolicy;
+pub use crate::config::TpmConfig;
+pub use crate::daemon::activated_sockets;
+pub use crate::daemon::daemonize;
+pub use crate::daemon::read_pid_file;
+pub use crate::daemon::terminate;
+pub use crate::daemon::write_pid_file;
+pub use crate::daemon::Notifier;
+pub use crate::decrypt::Decrypted;
+pub use crate::decrypt::Decryptor;
+pub use crate::decrypt::GpgCli;
+#[cfg(feature = "gpgme")]
+pub use crate::decrypt::Gpgme;
+pub use crate::error::DecryptError;
+pub use crate::error::ErrorKind;
+pub use crate::error::ErrorReport;
+pub use crate::error::FailureCount;
+pub use crate::error::KeyStoreError;
+pub use crate::error::ProtocolError;
+pub use crate::error::RequestError;
+pub use crate::error::SignError;
+pub use crate::error::ERROR_EXTENSION;
+pub use crate::files::check_key_pair_permissions;
+pub use crate::files::encrypt_key_file;
+pub use crate::files::shred_file;
+pub use crate::files::store_public_key;
+pub use crate::files::PemPrivateKey;
+pub use crate::files::PemPublicKey;
+pub use crate::fingerprint::Fingerprint;
+pub use crate::fingerprint::HashAlg;
+pub use crate::harden::harden;
+pub use crate::index::KeyIndex;
+pub use crate::keys::FromEncryptedPem;
+pub use crate::keys::FromPem;
+pub use crate::keys::ToPem;
+pub use crate::logging::init_logging;
+pub use crate::mock::MockDecryptor;
+pub use crate::peer::Peer;
+pub use crate::prompt::set_prompt_methods;
+pub use crate::secret::SecretBuffer;
+#[cfg(feature = "sequoia")]
+pub use crate::sequoia::Sequoia;
+pub use crate::session::Binding;
+pub use crate::session::Session;
+pub use crate::session::SESSION_BIND_EXTENSION;
+pub use crate::sign::Signer;
+pub use crate::status::KeyStatus;
+pub use crate::status::Status;
+pub use crate::status::StoreStatus;
+pub use crate::status::QUERY_EXTENSION;
+pub use crate::store::KeyFiles;
+pub use crate::store::KeyStore;
+pub use crate::store::StoreIdentity;
+pub use crate::trace::init_tracing;
+pub use crate::transport::abstract_name;
+pub use crate::transport::bind_tcp;
+pub use crate::transport::bind_unix;
+pub use crate::transport::handle_bytes;
+pub use crate::transport::connect_unix;
+pub use crate::transport::load_token;
+pub use crate::transport::ping;
+pub use crate::transport::probe_unix;
+pub use crate::transport::request_shutdown;
+pub use crate::transport::serve_tcp;
+pub use crate::transport::serve_unix;
+pub use crate::transport::serve_unix_all;
+pub use crate::transport::share_unix;
+pub use crate::transport::PeerAgent;
+pub use crate::transport::RemoteAgent;
+pub use crate::transport::Shared;
+pub use crate::usage::KeyUsage;
@@ -0,0 +1,177 @@
+// lock.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use ring::digest::SHA256_OUTPUT_LEN;
+use ring::pbkdf2::derive;
+use ring::pbkdf2::verify;
+use ring::pbkdf2::PBKDF2_HMAC_SHA256;
+use ring::rand::SecureRandom as _;
+use ring::rand::SystemRandom;
+
+
+/// The number of PBKDF2 iterations used for deriving a key from a lock
+/// passphrase.
+const ITERATIONS: u32 = 100_000;
+
+
+/// The state kept while the agent is locked.
+#[derive(Debug)]
+enum Locked {
+  /// The agent got locked by a client using a passphrase.
+  ///
+  /// We never store the passphrase itself, only a salted key derived
+  /// from it.
+  Passphrase {
+    salt: [u8; 16],
+    hash: [u8; SHA256_OUTPUT_LEN],
+  },
+  /// The agent locked itself after being idle. There is no passphrase
+  /// to check, so any unlocks it.
+  Idle,
+}
+
+
+/// The lock state of the agent, as controlled by the `Lock` and
+/// `Unlock` protocol messages, as well as by inactivity.
+#[derive(Debug, Default)]
+pub struct Lock {
+  locked: Mutex<Option<Locked>>,
+}
+
+impl Lock {
+  /// Check whether the agent is currently locked.
+  pub fn is_locked(&self) -> bool {
+    self.locked.lock().unwrap().is_some()
+  }
+
+  /// Lock the agent using the given passphrase.
+  pub fn lock(&self, passphrase: &str) -> Result<()> {
+    let mut locked = self.locked.lock().unwrap();
+    if locked.is_some() {
+      return Err(anyhow!("agent is already locked"))
+    }
+
+    let mut salt = [0; 16];
+    let () = SystemRandom::new()
+      .fill(&mut salt)
+      .map_err(|_| anyhow!("failed to generate salt"))
+      .context("failed to lock agent")?;
+
+    let mut hash = [0; SHA256_OUTPUT_LEN];
+    let () = derive(
+      PBKDF2_HMAC_SHA256,
+      NonZeroU32::new(ITERATIONS).unwrap(),
+      &salt,
+      passphrase.as_bytes(),
+      &mut hash,
+    );
+
+    *locked = Some(Locked::Passphrase { salt, hash });
+    Ok(())
+  }
+
+  /// Lock the agent because it has been idle, returning whether it got
+  /// locked (as opposed to having been locked already).
+  pub fn lock_idle(&self) -> bool {
+    let mut locked = self.locked.lock().unwrap();
+    if locked.is_some() {
+      return false
+    }
+    *locked = Some(Locked::Idle);
+    true
+  }
+
+  /// Unlock the agent, provided the given passphrase matches the one
+  /// it was locked with.
+  pub fn unlock(&self, passphrase: &str) -> Result<()> {
+    let mut locked = self.locked.lock().unwrap();
+    match locked
+      .as_ref()
+      .ok_or_else(|| anyhow!("agent is not locked"))?
+    {
+      Locked::Passphrase { salt, hash } => {
+        let () = verify(
+          PBKDF2_HMAC_SHA256,
+          NonZeroU32::new(ITERATIONS).unwrap(),
+          salt,
+          passphrase.as_bytes(),
+          hash,
+        )
+        .map_err(|_| anyhow!("incorrect passphrase"))
+        .context("failed to unlock agent")?;
+      },
+      Locked::Idle => (),
+    }
+
+    *locked = None;
+    Ok(())
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+
+  /// Check that the agent can only be unlocked with the correct
+  /// passphrase.
+  #[test]
+  fn lock_unlock() -> Result<()> {
+    let lock = Lock::default();
+    assert!(!lock.is_locked());
+    assert!(lock.unlock("secret").is_err());
+
+    let () = lock.lock("secret")?;
+    assert!(lock.is_locked());
+    assert!(lock.lock("secret").is_err());
+    assert!(lock.unlock("wrong").is_err());
+    assert!(lock.is_locked());
+
+    let () = lock.unlock("secret")?;
+    assert!(!lock.is_locked());
+    Ok(())
+  }
+
+  /// Check that an agent locked due to inactivity can be unlocked with
+  /// any passphrase.
+  #[test]
+  fn lock_idle() -> Result<()> {
+    let lock = Lock::default();
+    assert!(lock.lock_idle());
+    assert!(lock.is_locked());
+    assert!(!lock.lock_idle());
+    assert!(lock.lock("secret").is_err());
+
+    let () = lock.unlock("whatever")?;
+    assert!(!lock.is_locked());
+
+    let () = lock.lock("secret")?;
+    assert!(!lock.lock_idle());
+    assert!(lock.unlock("whatever").is_err());
+    Ok(())
+  }
+}
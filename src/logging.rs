@@ -0,0 +1,292 @@
+// logging.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Logging helpers.
+//!
+//! Log messages are grouped into categories, each being a separate log
+//! target whose verbosity can be controlled individually using
+//! `env_logger` filter directives, e.g., `info,ssh_gpg_agent::requests=debug`.
+//!
+//! Agent protocol messages contain data that should not end up in log
+//! files, such as private keys, passphrases, or the data to sign. They
+//! are only ever logged in [`Redacted`] form.
+
+use std::ffi::CString;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::os::unix::net::UnixDatagram;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use env_logger::Env;
+use env_logger::Logger as Filter;
+
+use libc::openlog;
+use libc::syslog;
+use libc::LOG_AUTH;
+use libc::LOG_DEBUG;
+use libc::LOG_ERR;
+use libc::LOG_INFO;
+use libc::LOG_PID;
+use libc::LOG_WARNING;
+
+use log::set_boxed_logger;
+use log::set_max_level;
+use log::Level;
+use log::Log;
+use log::Metadata;
+use log::Record;
+
+use ssh_agent_lib::proto::message::Message;
+
+use crate::config::LogSink;
+use crate::fingerprint::Fingerprint;
+
+
+/// The log category of agent protocol requests and responses.
+pub const REQUESTS: &str = "ssh_gpg_agent::requests";
+/// The log category of client connections.
+pub const CLIENTS: &str = "ssh_gpg_agent::clients";
+/// The log category of the time spent handling requests.
+pub const TIMING: &str = "ssh_gpg_agent::timing";
+
+
+/// The identifier we use for log messages sent to the journal or the
+/// system logger.
+const IDENTIFIER: &str = "ssh-gpg-agent";
+/// The NUL terminated identifier, for usage with the system logger.
+const IDENTIFIER_C: &[u8] = b"ssh-gpg-agent\0";
+/// The path to the socket of the systemd journal.
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+
+/// Map a log level to a syslog priority, as also used by the journal.
+fn priority(level: Level) -> i32 {
+  match level {
+    Level::Error => LOG_ERR,
+    Level::Warn => LOG_WARNING,
+    Level::Info => LOG_INFO,
+    Level::Debug | Level::Trace => LOG_DEBUG,
+  }
+}
+
+
+/// Append a field to a message in the journal's native protocol.
+fn journal_field(message: &mut Vec<u8>, name: &str, value: &str) {
+  message.extend_from_slice(name.as_bytes());
+  if value.contains('\n') {
+    // Values containing newlines have to be length prefixed.
+    message.push(b'\n');
+    message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+  } else {
+    message.push(b'=');
+  }
+  message.extend_from_slice(value.as_bytes());
+  message.push(b'\n');
+}
+
+
+/// A destination for log messages other than standard error.
+#[derive(Debug)]
+enum Sink {
+  /// The systemd journal, connected to via its native protocol.
+  Journald(UnixDatagram),
+  /// The system logger.
+  Syslog,
+}
+
+impl Sink {
+  /// Send the given log record to the sink.
+  fn send(&self, record: &Record<'_>) {
+    let priority = priority(record.level());
+    match self {
+      Self::Journald(socket) => {
+        let mut message = Vec::new();
+        journal_field(&mut message, "PRIORITY", &priority.to_string());
+        journal_field(&mut message, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        journal_field(&mut message, "TARGET", record.target());
+        journal_field(&mut message, "MESSAGE", &record.args().to_string());
+        // There is nothing sensible we could do about failures.
+        let _ = socket.send(&message);
+      },
+      Self::Syslog => {
+        let message = format!("{}: {}", record.target(), record.args()).replace('\0', "");
+        // The message cannot contain NUL bytes anymore.
+        let message = CString::new(message).unwrap_or_default();
+        // SAFETY: The format string expects exactly one C string, which
+        //         we provide.
+        let () = unsafe { syslog(priority, b"%s\0".as_ptr().cast(), message.as_ptr()) };
+      },
+    }
+  }
+}
+
+
+/// A logger forwarding log messages passing a filter to a sink.
+#[derive(Debug)]
+struct SinkLogger {
+  /// The filter log messages have to pass.
+  filter: Filter,
+  /// The sink to send log messages to.
+  sink: Sink,
+}
+
+impl Log for SinkLogger {
+  fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+    self.filter.enabled(metadata)
+  }
+
+  fn log(&self, record: &Record<'_>) {
+    if self.filter.matches(record) {
+      let () = self.sink.send(record);
+    }
+  }
+
+  fn flush(&self) {}
+}
+
+
+/// Set up logging to the given sink, using the provided `env_logger`
+/// style filter unless overwritten by the environment.
+pub fn init_logging(sink: LogSink, filter: &str) -> Result<()> {
+  let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or(filter));
+  let sink = match sink {
+    LogSink::Stderr => {
+      let () = builder
+        .try_init()
+        .context("failed to initialize logging")?;
+      return Ok(())
+    },
+    LogSink::Journald => {
+      let socket = UnixDatagram::unbound().context("failed to create journal socket")?;
+      let () = socket
+        .connect(JOURNALD_SOCKET)
+        .with_context(|| format!("failed to connect to journal at {JOURNALD_SOCKET}"))?;
+      Sink::Journald(socket)
+    },
+    LogSink::Syslog => {
+      // SAFETY: The identifier is a static NUL terminated string.
+      let () = unsafe { openlog(IDENTIFIER_C.as_ptr().cast(), LOG_PID, LOG_AUTH) };
+      Sink::Syslog
+    },
+  };
+
+  let filter = builder.build();
+  let () = set_max_level(filter.filter());
+  let () = set_boxed_logger(Box::new(SinkLogger { filter, sink }))
+    .context("failed to initialize logging")?;
+  Ok(())
+}
+
+
+/// A redacted representation of an agent protocol message, suitable
+/// for logging.
+///
+/// Only the message type and non-sensitive metadata are included.
+#[derive(Debug)]
+pub struct Redacted<'msg>(pub &'msg Message);
+
+impl Display for Redacted<'_> {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    match self.0 {
+      Message::IdentitiesAnswer(identities) => {
+        write!(f, "IdentitiesAnswer({} identities)", identities.len())
+      },
+      Message::SignRequest(request) => write!(
+        f,
+        "SignRequest(key {}, {} bytes of data, flags {:#x})",
+        Fingerprint::sha256(&request.pubkey_blob),
+        request.data.len(),
+        request.flags
+      ),
+      Message::SignResponse(signature) => write!(f, "SignResponse({} bytes)", signature.len()),
+      Message::AddIdentity(identity) => write!(f, "AddIdentity({:?})", identity.comment),
+      Message::AddIdConstrained(constrained) => write!(
+        f,
+        "AddIdConstrained({:?}, {} constraints)",
+        constrained.identity.comment,
+        constrained.constraints.len()
+      ),
+      Message::RemoveIdentity(request) => {
+        write!(f, "RemoveIdentity(key {})", Fingerprint::sha256(&request.pubkey_blob))
+      },
+      Message::AddSmartcardKey(key) => write!(f, "AddSmartcardKey({:?})", key.id),
+      Message::RemoveSmartcardKey(key) => write!(f, "RemoveSmartcardKey({:?})", key.id),
+      Message::AddSmartcardKeyConstrained(constrained) => write!(
+        f,
+        "AddSmartcardKeyConstrained({:?}, {} constraints)",
+        constrained.key.id,
+        constrained.constraints.len()
+      ),
+      Message::Lock(..) => write!(f, "Lock"),
+      Message::Unlock(..) => write!(f, "Unlock"),
+      Message::Extension(extension) => write!(
+        f,
+        "Extension({:?}, {} bytes)",
+        extension.extension_type,
+        extension.extension_contents.0.len()
+      ),
+      // All remaining messages carry no data.
+      message => write!(f, "{message:?}"),
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use ssh_agent_lib::proto::message::SignRequest;
+
+
+  /// Check that we correctly encode fields in the journal's native
+  /// protocol.
+  #[test]
+  fn journal_fields() {
+    let mut message = Vec::new();
+    journal_field(&mut message, "PRIORITY", "6");
+    journal_field(&mut message, "MESSAGE", "a\nb");
+    assert_eq!(
+      message,
+      b"PRIORITY=6\nMESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n".to_vec()
+    );
+  }
+
+  /// Check that sensitive message contents are redacted.
+  #[test]
+  fn redaction() {
+    let message = Message::SignRequest(SignRequest {
+      pubkey_blob: vec![1, 2, 3],
+      data: b"secret-data".to_vec(),
+      flags: 2,
+    });
+    let redacted = Redacted(&message).to_string();
+    assert!(redacted.starts_with("SignRequest(key SHA256:"), "{redacted}");
+    assert!(redacted.ends_with("11 bytes of data, flags 0x2)"), "{redacted}");
+
+    let message = Message::Unlock("passphrase".to_string());
+    assert_eq!(Redacted(&message).to_string(), "Unlock");
+
+    let message = Message::RequestIdentities;
+    assert_eq!(Redacted(&message).to_string(), "RequestIdentities");
+  }
+}
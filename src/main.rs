@@ -23,7 +23,6 @@
   broken_intra_doc_links,
   dead_code,
   future_incompatible,
-  illegal_floating_point_literal_pattern,
   improper_ctypes,
   late_bound_lifetime_arguments,
   missing_copy_implementations,
@@ -55,235 +54,874 @@
   unused_lifetimes,
   unused_qualifications,
   unused_results,
-  where_clauses_object_safety,
   while_true,
 )]
 
 //! `ssh-gpg-agent` is an SSH agent that can transparently handle GPG
 //! encrypted SSH keys.
 
-mod files;
-mod keys;
-mod sign;
+mod args;
 
-use std::env::args_os;
+use std::env::current_dir;
 use std::env::temp_dir;
-use std::error::Error as StdError;
-use std::fmt::Display;
-use std::fmt::Formatter;
-use std::fmt::Result as FmtResult;
+use std::env::var;
+use std::env::var_os;
 use std::fs::remove_file;
+use std::io::stdin;
+use std::io::stdout;
 use std::io::Error as IoError;
 use std::io::ErrorKind;
+use std::io::Write as _;
+use std::os::unix::net::SocketAddr;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::path::PathBuf;
-use std::result::Result as StdResult;
+use std::process::exit;
+use std::process::id;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::thread::spawn;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
 use anyhow::Context as _;
 use anyhow::Result;
 
+use clap::Parser as _;
+
 use dirs::home_dir;
 
-use log::error;
-use log::info;
-
-use ssh_agent_lib::agent::Agent;
-use ssh_agent_lib::proto::Blob;
-use ssh_agent_lib::proto::from_bytes;
-use ssh_agent_lib::proto::message::Identity;
-use ssh_agent_lib::proto::message::Message;
-use ssh_agent_lib::proto::message::SignatureBlob;
-use ssh_agent_lib::proto::message::SignRequest;
-use ssh_agent_lib::proto::private_key::PrivateKey;
+use signal_hook::consts::SIGHUP;
+use signal_hook::consts::SIGINT;
+use signal_hook::consts::SIGTERM;
+use signal_hook::iterator::Signals;
+
+use ssh_agent_lib::proto::key_type::KeyTypeEnum as _;
 use ssh_agent_lib::proto::public_key::PublicKey;
 
-use crate::files::load_private_key;
-use crate::files::public_keys;
-use crate::keys::FromPem;
-use crate::sign::Signer;
+use ssh_gpg_agent::abstract_name;
+use ssh_gpg_agent::activated_sockets;
+use ssh_gpg_agent::bind_tcp;
+use ssh_gpg_agent::bind_unix;
+use ssh_gpg_agent::check_key_pair_permissions;
+use ssh_gpg_agent::connect_unix;
+use ssh_gpg_agent::daemonize;
+use ssh_gpg_agent::encrypt_key_file;
+use ssh_gpg_agent::harden;
+use ssh_gpg_agent::init_logging;
+use ssh_gpg_agent::init_tracing;
+use ssh_gpg_agent::load_token;
+use ssh_gpg_agent::ping;
+use ssh_gpg_agent::probe_unix;
+use ssh_gpg_agent::read_pid_file;
+use ssh_gpg_agent::request_shutdown;
+use ssh_gpg_agent::serve_tcp;
+use ssh_gpg_agent::serve_unix_all;
+use ssh_gpg_agent::set_prompt_methods;
+use ssh_gpg_agent::share_unix;
+use ssh_gpg_agent::shred_file;
+use ssh_gpg_agent::store_public_key;
+use ssh_gpg_agent::terminate;
+use ssh_gpg_agent::write_pid_file;
+use ssh_gpg_agent::Config;
+use ssh_gpg_agent::Fingerprint;
+use ssh_gpg_agent::GpgKeyAgent;
+use ssh_gpg_agent::GpgOptions;
+use ssh_gpg_agent::HashAlg;
+use ssh_gpg_agent::KeyUsage;
+use ssh_gpg_agent::Notifier;
+use ssh_gpg_agent::ProtocolError;
+use ssh_gpg_agent::SocketAgent;
+use ssh_gpg_agent::SocketConfig;
+use ssh_gpg_agent::SocketPolicy;
+use ssh_gpg_agent::Status;
+
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::args::Args;
+use crate::args::Command;
+use crate::args::Shell;
+
+
+/// The name (as set via `FileDescriptorName=`) of a socket passed in by
+/// systemd that is to be served with the forwarding socket's policy.
+const FORWARDING_SOCKET_NAME: &str = "forwarding";
+
+
+/// Quote the given string for usage in a shell command, if necessary.
+fn shell_quote(s: &str) -> String {
+  let safe = |c: char| c.is_ascii_alphanumeric() || "/._-+:@%,".contains(c);
+  if !s.is_empty() && s.chars().all(safe) {
+    s.to_string()
+  } else {
+    format!("'{}'", s.replace('\'', "'\\''"))
+  }
+}
+
+
+/// Create shell commands for setting up the environment for usage of
+/// the agent with the given process ID listening on the provided
+/// socket.
+fn env_commands(shell: Shell, socket: &Path, pid: u32) -> String {
+  let socket = shell_quote(&socket.to_string_lossy());
+  match shell {
+    Shell::Sh => format!(
+      "SSH_AUTH_SOCK={socket}; export SSH_AUTH_SOCK;\nSSH_AGENT_PID={pid}; export SSH_AGENT_PID;\n"
+    ),
+    Shell::Csh => format!("setenv SSH_AUTH_SOCK {socket};\nsetenv SSH_AGENT_PID {pid};\n"),
+  }
+}
+
+
+/// Create shell commands for tearing down the environment for usage of
+/// an agent.
+fn unset_env_commands(shell: Shell) -> &'static str {
+  match shell {
+    Shell::Sh => "unset SSH_AUTH_SOCK;\nunset SSH_AGENT_PID;\n",
+    Shell::Csh => "unsetenv SSH_AUTH_SOCK;\nunsetenv SSH_AGENT_PID;\n",
+  }
+}
+
+
+/// Load the configuration from the given path or the default location,
+/// applying overrides from the command line.
+fn load_config(path: Option<&Path>, allow_sha1: bool) -> Result<Config> {
+  let mut config = if let Some(path) = path {
+    Config::load(path)?
+  } else {
+    Config::load_default()?
+  };
+  config.allow_sha1 |= allow_sha1;
+  Ok(config)
+}
+
+
+/// The sockets the agent serves clients on.
+#[derive(Debug)]
+struct Sockets {
+  /// The main Unix domain socket.
+  unix: SocketConfig,
+  /// Further Unix domain sockets, such as the one intended for being
+  /// forwarded to remote hosts.
+  others: Vec<SocketConfig>,
+  /// The TCP address to listen on, along with the token clients have
+  /// to authenticate with, if any.
+  tcp: Option<(String, Vec<u8>)>,
+}
 
 
-trait Mapper<T, E>
+/// Handle signals in the background: SIGHUP causes the configuration
+/// to be reloaded and the key directories to be rescanned, while
+/// SIGINT and SIGTERM make the agent remove the given files (its
+/// socket and PID file), wipe cached keys as well as identities kept
+/// in memory, and exit.
+fn handle_signals<F>(agent: Arc<GpgKeyAgent>, files: Vec<PathBuf>, reload: F) -> Result<()>
 where
-  Self: Sized,
+  F: Fn() -> Result<Config> + Send + 'static,
 {
-  fn map_flat<F, U>(self, f: F) -> StdResult<U, E>
-  where
-    F: FnMut(T) -> StdResult<U, E>;
+  let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM])
+    .with_context(|| "failed to install signal handlers")?;
+
+  let _handle = spawn(move || {
+    for signal in signals.forever() {
+      match signal {
+        SIGHUP => {
+          match reload() {
+            Ok(config) => {
+              let () = agent.reconfigure(config);
+              info!("Reloaded configuration");
+            },
+            Err(err) => error!("failed to reload configuration: {err:#}"),
+          }
+          let () = agent.reload_keys();
+          let () = GpgKeyAgent::derive_orphans_in_background(&agent);
+        },
+        _ => {
+          info!("Shutting down");
+          for file in &files {
+            let _ = remove_file(file);
+          }
+          let () = agent.clear_cache();
+          let () = agent.wipe_ephemeral();
+          let () = log::logger().flush();
+          exit(0)
+        },
+      }
+    }
+  });
+  Ok(())
 }
 
-impl<T, E> Mapper<T, E> for StdResult<T, E> {
-  fn map_flat<F, U>(self, mut f: F) -> StdResult<U, E>
-  where
-    F: FnMut(T) -> StdResult<U, E>,
-  {
-    match self {
-      Ok(val) => f(val),
-      Err(err) => Err(err),
+
+/// Keep systemd's watchdog at bay for as long as the agent responds to
+/// pings on the given socket.
+///
+/// The agent is pinged through its socket, as opposed to directly, so
+/// that a stuck thread accepting connections is noticed as well.
+fn feed_watchdog(notifier: Notifier, interval: Duration, addr: SocketAddr) {
+  // Notify systemd at twice the rate it requires, so that a slow
+  // response does not get us killed right away.
+  let interval = interval / 2;
+  let _handle = spawn(move || loop {
+    let () = sleep(interval);
+    let result = UnixStream::connect_addr(&addr)
+      .with_context(|| "failed to connect to agent")
+      .and_then(|mut stream| ping(&mut stream, interval).map_err(ProtocolError::into_inner));
+    match result {
+      Ok(_duration) => {
+        if let Err(err) = notifier.notify("WATCHDOG=1") {
+          warn!("{err:#}");
+        }
+      },
+      Err(err) => error!("agent failed health check: {err:#}"),
     }
-  }
+  });
 }
 
 
-/// The SSH agent supporting GPG encrypted SSH keys.
+/// Run the SSH agent, serving clients connecting to the given Unix
+/// domain sockets, subject to the policy of each, as well as to a TCP
+/// address with clients authenticating via a token, if provided.
 ///
-/// Upon creation the agent will load public keys that have
-/// corresponding encrypted private keys inside its associated directory
-/// and keep those public keys in memory. It explicitly does not cache
-/// secret key material, but loads it on demand for each and every
-/// request.
-struct GpgKeyAgent {
-  /// The directory in which to look for SSH key pairs.
-  dir: PathBuf,
+/// The provided files are removed when the agent is terminated. If
+/// systemd asked to be notified, it is told once the agent is ready
+/// and, if its watchdog is enabled, periodically thereafter.
+fn run<F>(
+  agent: GpgKeyAgent,
+  listeners: Vec<(UnixListener, SocketPolicy)>,
+  files: Vec<PathBuf>,
+  tcp: Option<(String, Vec<u8>)>,
+  reload: F,
+) -> Result<()>
+where
+  F: Fn() -> Result<Config> + Send + 'static,
+{
+  let () = agent.purge_cache_periodically();
+
+  let agent = Arc::new(agent);
+  let () = GpgKeyAgent::housekeep_periodically(&agent);
+  // Deriving public keys may require user interaction, so it only
+  // happens on startup and when reloading, not when keys get listed.
+  let () = GpgKeyAgent::derive_orphans_in_background(&agent);
+  let () = handle_signals(agent.clone(), files, reload)?;
+
+  if let Some((addr, token)) = tcp {
+    let listener = bind_tcp(&addr)?;
+    let () = serve_tcp(agent.clone(), listener, token)?;
+  }
+
+  if let Some(notifier) = Notifier::from_env()? {
+    let () = notifier.notify("READY=1")?;
+    if let Some(interval) = notifier.watchdog() {
+      let (listener, _policy) = listeners
+        .first()
+        .ok_or_else(|| anyhow!("no socket to serve the agent on"))?;
+      let addr = listener
+        .local_addr()
+        .with_context(|| "failed to retrieve address of agent socket")?;
+      let () = feed_watchdog(notifier, interval, addr);
+    }
+  }
+
+  let sockets = listeners
+    .into_iter()
+    .map(|(listener, policy)| (listener, Arc::new(SocketAgent::new(agent.clone(), policy))))
+    .collect();
+  serve_unix_all(sockets).with_context(|| "failed to start agent")
 }
 
-impl GpgKeyAgent {
-  fn new<P>(dir: P) -> Self
-  where
-    P: Into<PathBuf>,
-  {
-    Self { dir: dir.into() }
-  }
-
-  /// Retrieve the agent's public keys.
-  fn public_keys(&self) -> Result<impl Iterator<Item = Result<(PublicKey, PathBuf)>>> {
-    let keys = public_keys(self.dir.clone())?
-      .map(|x| {
-        x.map_flat(|(key, path)| {
-          PublicKey::from_pem(key)
-            .map(|x| (x, path))
-        })
-      });
-    Ok(keys)
-  }
-
-  /// Handle a request for all known identities.
-  fn identities(&self) -> Result<Vec<Identity>> {
-    let mut idents = Vec::new();
-    for result in self.public_keys()? {
-      let pubkey = result?.0;
-      let blob = pubkey
-        .to_blob()
-        .with_context(|| "failed to serialize private key")?;
-      let ident = Identity {
-        pubkey_blob: blob,
-        // The ssh-keys crate currently does not support handling of
-        // comments and so we just fill in an empty string here.
-        comment: String::new(),
-      };
 
-      idents.push(ident);
+/// Bind the given Unix domain socket, adjusting its permissions as
+/// configured, and remember it for removal upon termination.
+fn bind_socket(socket: &SocketConfig, files: &mut Vec<PathBuf>) -> Result<UnixListener> {
+  let listener = bind_unix(&socket.path)?;
+  if socket.mode.is_some() || socket.group.is_some() {
+    let mode = socket
+      .mode
+      .unwrap_or(if socket.group.is_some() { 0o660 } else { 0o600 });
+    let () = share_unix(&socket.path, mode, socket.group.as_deref())?;
+  }
+  // Sockets in the abstract namespace vanish along with the agent.
+  if abstract_name(&socket.path).is_none() {
+    let () = files.push(socket.path.clone());
+  }
+  Ok(listener)
+}
+
+
+/// Start the SSH agent in the foreground or, if requested, in the
+/// background, listening on the given sockets.
+///
+/// The sockets are bound before daemonizing, so that errors are still
+/// reported to the user, while the agent itself, which spawns threads,
+/// is only created afterwards via `create`.
+///
+/// If an ssh-gpg-agent is already serving the main socket, it is
+/// reused (and only its environment is printed) or, if `replace` is
+/// set, shut down and replaced. If the agent got started via systemd's
+/// socket activation, the sockets passed in take the place of the main
+/// socket.
+fn start<C, F>(
+  create: C,
+  sockets: Sockets,
+  shell: Option<Shell>,
+  daemon: bool,
+  replace: bool,
+  pid_file: Option<PathBuf>,
+  reload: F,
+) -> Result<()>
+where
+  C: FnOnce() -> Result<GpgKeyAgent>,
+  F: Fn() -> Result<Config> + Send + 'static,
+{
+  let Sockets { unix, others, tcp } = sockets;
+  let mut listeners = Vec::new();
+  let mut files = Vec::new();
+
+  let activated = activated_sockets()?;
+  let socket = if activated.is_empty() {
+    let socket = unix.path.clone();
+    if let Some(mut stream) = probe_unix(&socket) {
+      let status = Status::query_stream(&mut stream)
+        .with_context(|| format!("{} is in use by another SSH agent", socket.display()))?;
+      if !replace {
+        return reuse(&socket, &status, shell, daemon)
+      }
+      let () = request_shutdown(stream).with_context(|| {
+        format!("failed to shut down agent listening on {}", socket.display())
+      })?;
+      info!("Replaced ssh-gpg-agent {} listening on {}", status.version, socket.display());
     }
-    Ok(idents)
-  }
-
-  /// Load the private key corresponding to the given public key.
-  fn find_private_key(&self, pubkey: &PublicKey) -> Option<Result<PathBuf>> {
-    match self.public_keys() {
-      Ok(mut keys) => keys.find_map(|x| match x {
-        Ok((key, path)) => {
-          if &key == pubkey {
-            Some(Ok(path))
-          } else {
-            None
-          }
-        }
-        Err(err) => Some(Err(err)),
-      }),
-      Err(err) => Some(Err(err)),
+
+    let () = listeners.push((bind_socket(&unix, &mut files)?, SocketPolicy::Full));
+    socket
+  } else {
+    // The sockets systemd passed in take the place of the main socket.
+    // They belong to systemd, which is why we never remove them.
+    let mut socket = None;
+    for (name, listener) in activated {
+      let policy = if name == FORWARDING_SOCKET_NAME {
+        SocketPolicy::Forwarding
+      } else {
+        SocketPolicy::Full
+      };
+      let path = listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(Path::to_path_buf));
+      if let Some(path) = &path {
+        info!("Listening on activated Unix domain socket {}", path.display());
+      }
+      if socket.is_none() && policy == SocketPolicy::Full {
+        socket = path;
+      }
+      let () = listeners.push((listener, policy));
     }
+    socket.unwrap_or(unix.path)
+  };
+
+  for other in &others {
+    let () = listeners.push((bind_socket(other, &mut files)?, other.policy));
   }
 
-  /// Handle a sign request.
-  fn sign(&self, request: &SignRequest) -> Result<SignatureBlob> {
-    let pubkey = from_bytes::<PublicKey>(&request.pubkey_blob)
-      .with_context(|| "failed to convert public key blob back to public key")?;
-
-    if let Some(file) = self.find_private_key(&pubkey) {
-      let key = PrivateKey::from_pem(load_private_key(&file?)?)?;
-      let sig = key
-        .sign(request.flags, &request.data)
-        .with_context(|| "failed to sign request data")?;
-      let blob = sig
-        .to_blob()
-        .with_context(|| "failed to serialized signature")?;
-      Ok(blob)
-    } else {
-      let err = Err(anyhow!("identity not found"));
-      err.with_context(|| "failed to create signature")
+  if daemon {
+    if let Some(pid) = daemonize()? {
+      let shell = shell.unwrap_or_else(|| Shell::infer(var("SHELL").ok().as_deref()));
+      print!("{}", env_commands(shell, &socket, pid));
+      println!("echo Agent pid {pid};");
+      return Ok(())
     }
+  } else if let Some(shell) = shell {
+    print!("{}", env_commands(shell, &socket, id()));
+    let () = stdout().flush().with_context(|| "failed to flush stdout")?;
+  }
+
+  if let Some(pid_file) = pid_file {
+    let () = write_pid_file(&pid_file)?;
+    let () = files.push(pid_file);
   }
 
-  /// Handle a message to the agent.
-  fn handle_message(&self, request: Message) -> Result<Message> {
-    info!("Request: {:?}", request);
-    let response = match request {
-      Message::RequestIdentities => {
-        Ok(Message::IdentitiesAnswer(self.identities()?))
+  let agent = create()?;
+  run(agent, listeners, files, tcp, reload)
+}
+
+
+/// Reuse the ssh-gpg-agent already listening on the given socket,
+/// printing the commands setting up the environment for using it.
+fn reuse(socket: &Path, status: &Status, shell: Option<Shell>, daemon: bool) -> Result<()> {
+  info!("ssh-gpg-agent {} is already listening on {}", status.version, socket.display());
+
+  let shell = shell.or_else(|| daemon.then(|| Shell::infer(var("SHELL").ok().as_deref())));
+  if let Some(shell) = shell {
+    print!("{}", env_commands(shell, socket, status.pid));
+    let () = stdout().flush().with_context(|| "failed to flush stdout")?;
+  } else {
+    eprintln!(
+      "note: reusing ssh-gpg-agent already listening on {}; use --replace to replace it",
+      socket.display()
+    );
+  }
+  Ok(())
+}
+
+
+/// Determine the path of the socket to use if none is configured.
+///
+/// The socket is placed in the user's runtime directory, if there is
+/// one, as opposed to the world-writable temporary directory.
+fn default_socket() -> PathBuf {
+  match var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+    Some(dir) if dir.is_absolute() => dir.join("ssh-gpg-agent").join("agent.sock"),
+    _ => temp_dir().join("ssh-gpg-agent.sock"),
+  }
+}
+
+
+/// Make the given path absolute, relative to the current working
+/// directory.
+///
+/// Paths referring to sockets in the abstract namespace are left alone.
+fn absolute(path: PathBuf) -> Result<PathBuf> {
+  if path.is_absolute() || abstract_name(&path).is_some() {
+    Ok(path)
+  } else {
+    let cwd = current_dir().with_context(|| "failed to retrieve working directory")?;
+    Ok(cwd.join(path))
+  }
+}
+
+
+/// List the keys managed by the agent, along with their fingerprints
+/// and whether they can currently be decrypted.
+fn list_keys(agent: &GpgKeyAgent, hash: HashAlg) -> Result<()> {
+  for result in agent.public_keys() {
+    // Report broken keys but keep listing the others.
+    let (pubkey, comment, path) = match result {
+      Ok(key) => key,
+      Err(err) => {
+        eprintln!("{err:#}");
+        continue
       },
-      Message::SignRequest(request) => {
-        Ok(Message::SignResponse(self.sign(&request)?))
+    };
+    let fingerprint = Fingerprint::from_key(&pubkey, hash)?;
+    let status = match agent.can_decrypt(&path) {
+      Ok(Some(true)) => "decryptable",
+      Ok(Some(false)) => "no secret key",
+      Ok(None) => "unknown",
+      Err(err) => {
+        eprintln!("{err:#}");
+        "unknown"
       },
-      _ => {
-        let err = Err(anyhow!("received unsupported message: {:?}", request));
-        err.with_context(|| "failed to handle agent request")
+    };
+    let usage = match agent.key_usage(&pubkey) {
+      Ok(usage) => describe_usage(&usage),
+      Err(err) => {
+        eprintln!("{err:#}");
+        "usage unknown".to_string()
       },
     };
-    info!("Response {:?}", response);
-    response
+    let line = format!(
+      "{} {} {} ({status}; {usage}) {comment}",
+      pubkey.key_type(),
+      fingerprint,
+      path.display(),
+    );
+    println!("{}", line.trim_end());
   }
+
+  for result in agent.orphaned_private_keys() {
+    let path = result?;
+    eprintln!("note: {} lacks a public key file; see the `derive` sub-command", path.display());
+  }
+  Ok(())
 }
 
-impl Agent for GpgKeyAgent {
-  type Error = ();
 
-  fn handle(&self, message: Message) -> StdResult<Message, ()> {
-    self.handle_message(message).or_else(|err| {
-      error!("Error handling message: {:?}", err);
-      Ok(Message::Failure)
-    })
+/// Check that the agent could bind a Unix domain socket at the given
+/// path.
+fn check_socket(socket: &Path) -> Result<()> {
+  if connect_unix(socket).is_ok() {
+    println!("note: {} is in use by a running agent", socket.display());
+    return Ok(())
   }
+
+  // A stale socket gets replaced, just like on startup.
+  let _listener = bind_unix(socket)?;
+  if abstract_name(socket).is_some() {
+    return Ok(())
+  }
+  remove_file(socket).with_context(|| format!("failed to remove {}", socket.display()))
 }
 
 
-/// A wrapper around a boxed error that allows us to use it in
-/// conjunction with `anyhow`.
-///
-/// This type is required because `Box<dyn Error>` is lacking an
-/// implementation of `std::error::Error`; for more details check
-/// https://github.com/rust-lang/rust/issues/60759
-#[derive(Debug)]
-struct E(Box<dyn StdError + Send + Sync>);
+/// Check whether the given key pair is usable and its files have safe
+/// permissions, decrypting the private key only if `decrypt` is true.
+fn check_key(agent: &GpgKeyAgent, pubkey: &PublicKey, path: &Path, decrypt: bool) -> Result<()> {
+  let () = check_key_pair_permissions(path)?;
+  if decrypt {
+    agent.verify_key_pair(pubkey, path)
+  } else {
+    match agent.can_decrypt(path)? {
+      Some(true) | None => Ok(()),
+      Some(false) => Err(anyhow!("no secret key for decrypting {} found", path.display())),
+    }
+  }
+}
+
+
+/// Diagnose problems with the agent's setup, reporting the outcome of
+/// each check.
+fn check(agent: &GpgKeyAgent, socket: &Path, decrypt: bool) -> Result<()> {
+  let mut failed = 0;
+  let mut report = |what: &str, result: Result<()>| match result {
+    Ok(()) => println!("ok: {what}"),
+    Err(err) => {
+      eprintln!("error: {what}: {err:#}");
+      failed += 1;
+    },
+  };
+
+  let () = report(
+    &format!("socket {} can be bound", socket.display()),
+    check_socket(socket),
+  );
+  let () = report("decryption backend is operational", agent.check_decryptor());
+
+  for result in agent.public_keys() {
+    match result {
+      Ok((pubkey, comment, path)) => {
+        let what = format!("key {comment:?} stored in {}", path.display());
+        let () = report(&what, check_key(agent, &pubkey, &path, decrypt));
+      },
+      Err(err) => report("key pair can be loaded", Err(err)),
+    }
+  }
 
-impl Display for E {
-  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-    write!(f, "{}", self.0)
+  for result in agent.orphaned_private_keys() {
+    match result {
+      Ok(path) => {
+        let what = format!("key stored in {}", path.display());
+        let err = anyhow!("no public key file found; see the `derive` sub-command");
+        let () = report(&what, Err(err));
+      },
+      Err(err) => report("key directory can be read", Err(err)),
+    }
+  }
+
+  if failed > 0 {
+    Err(anyhow!("{failed} check(s) failed"))
+  } else {
+    Ok(())
   }
 }
 
-impl StdError for E {}
 
+/// Format a duration given in seconds in a human readable way, e.g.,
+/// `1d 2h 3m 4s`.
+fn format_duration(secs: u64) -> String {
+  let units = [(86400, "d"), (3600, "h"), (60, "m")];
+  let mut rest = secs;
+  let mut parts = Vec::new();
+  for (unit, suffix) in units {
+    if rest >= unit || !parts.is_empty() {
+      let () = parts.push(format!("{}{suffix}", rest / unit));
+      rest %= unit;
+    }
+  }
+  let () = parts.push(format!("{rest}s"));
+  parts.join(" ")
+}
+
+
+/// Describe the usage statistics of a key in a human readable way.
+fn describe_usage(usage: &KeyUsage) -> String {
+  if usage.count == 0 {
+    return "never used".to_string()
+  }
+
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or(0);
+  format!(
+    "used {} time(s), last {} ago by {}",
+    usage.count,
+    format_duration(now.saturating_sub(usage.last_used)),
+    usage.last_client,
+  )
+}
 
-/// Run the SSH agent.
-fn main() -> Result<()> {
-  env_logger::init();
 
-  let dir = if let Some(dir) = args_os().nth(1) {
-    dir.into()
+/// Query and print the status of the agent listening on the given
+/// socket.
+fn status(socket: &Path) -> Result<()> {
+  let status = Status::query(socket)?;
+
+  println!("socket: {}", socket.display());
+  println!("version: {}", status.version);
+  println!("pid: {}", status.pid);
+  println!("uptime: {}", format_duration(status.uptime));
+  println!(
+    "state: {}",
+    if status.locked { "locked" } else { "unlocked" }
+  );
+  if status.cache.enabled {
+    println!(
+      "cache: {} key(s), {} hit(s), {} miss(es)",
+      status.cache.entries, status.cache.hits, status.cache.misses
+    );
   } else {
-    home_dir()
-      .ok_or_else(|| IoError::new(ErrorKind::NotFound, "no home directory found"))
-      .with_context(|| "failed to retrieve home directory")?
-      .join(".ssh")
+    println!("cache: disabled");
+  }
+
+  println!("keys: {}", status.keys.len());
+  for key in status.keys {
+    let usage = describe_usage(&key.usage);
+    let usage = if key.ephemeral {
+      format!("in memory; {usage}")
+    } else {
+      usage
+    };
+    let line = format!("  {} ({usage}) {}", key.fingerprint, key.comment);
+    println!("{}", line.trim_end());
+  }
+
+  println!("stores: {}", status.stores.len());
+  for store in status.stores {
+    let health = if store.error.is_empty() {
+      format!("ok, {} identity(ies)", store.identities)
+    } else {
+      format!("error: {}", store.error)
+    };
+    println!("  {} (priority {}): {health}", store.name, store.priority);
+  }
+
+  let total = status.failures.iter().map(|failure| failure.count).sum::<u64>();
+  println!("failed requests: {total}");
+  for failure in status.failures {
+    println!("  {}: {}", failure.kind, failure.count);
+  }
+  Ok(())
+}
+
+
+/// Check that the agent listening on the given socket responds within
+/// the given timeout.
+fn ping_agent(socket: &Path, timeout: Duration) -> Result<()> {
+  let mut stream = connect_unix(socket)
+    .with_context(|| format!("failed to connect to agent at {}", socket.display()))?;
+  let duration = ping(&mut stream, timeout)?;
+  println!("agent at {} responded in {duration:.3?}", socket.display());
+  Ok(())
+}
+
+
+/// Terminate the agent whose process ID is given by `SSH_AGENT_PID` or,
+/// absent that, the provided PID file, printing shell commands tearing
+/// down its environment.
+fn kill(shell: Shell, pid_file: Option<&Path>) -> Result<()> {
+  let pid = match (var("SSH_AGENT_PID"), pid_file) {
+    (Ok(pid), _) => pid
+      .parse()
+      .with_context(|| format!("SSH_AGENT_PID contains invalid process ID {pid:?}"))?,
+    (Err(_), Some(pid_file)) => read_pid_file(pid_file)?,
+    (Err(_), None) => bail!("SSH_AGENT_PID not set and no PID file provided; cannot kill agent"),
   };
+  let () = terminate(pid)?;
+
+  print!("{}", unset_env_commands(shell));
+  println!("echo Agent pid {pid} killed;");
+  Ok(())
+}
 
-  let agent = GpgKeyAgent::new(dir);
-  let socket = temp_dir().join("ssh-gpg-agent.sock");
-  let _ = remove_file(&socket);
 
-  agent
-    .run_unix(&socket)
-    .map_err(E)
-    .with_context(|| "failed to start agent")?;
+/// GPG encrypt the given plain text private key to the provided
+/// recipient, optionally shredding the original afterwards.
+fn encrypt(key: &Path, recipient: &str, options: &GpgOptions, shred: bool) -> Result<()> {
+  let path = encrypt_key_file(key, recipient, options)?;
+  println!("Stored encrypted key in {}", path.display());
+
+  if shred {
+    let () = shred_file(key)?;
+    println!("Shredded {}", key.display());
+  }
   Ok(())
 }
+
+
+/// Ask the user a yes/no question on the terminal.
+fn ask(question: &str) -> Result<bool> {
+  eprint!("{question} [y/N] ");
+  let mut answer = String::new();
+  let _ = stdin()
+    .read_line(&mut answer)
+    .with_context(|| "failed to read answer from stdin")?;
+  Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+
+/// Derive the public keys of all GPG encrypted private keys lacking a
+/// public key file, and store or print them.
+fn derive(agent: &GpgKeyAgent, yes: bool, print: bool) -> Result<()> {
+  let mut failed = 0;
+
+  for result in agent.orphaned_private_keys() {
+    let path = result?;
+    let question = format!("Decrypt {} to derive its public key?", path.display());
+    if !yes && !ask(&question)? {
+      continue
+    }
+
+    let result = agent.derive_public_key(&path).and_then(|pubkey| {
+      if print {
+        stdout()
+          .write_all(pubkey.as_ref())
+          .with_context(|| "failed to write public key to stdout")
+      } else {
+        let pub_path = store_public_key(&path, &pubkey)?;
+        println!("Stored public key in {}", pub_path.display());
+        Ok(())
+      }
+    });
+
+    if let Err(err) = result {
+      eprintln!("error: {err:#}");
+      failed += 1;
+    }
+  }
+
+  if failed > 0 {
+    Err(anyhow!("failed to derive {failed} public key(s)"))
+  } else {
+    Ok(())
+  }
+}
+
+
+/// Run the program.
+fn main() -> Result<()> {
+  let args = Args::parse();
+  let daemon = args.daemonize;
+  // A daemon changes its working directory, so paths have to be made
+  // absolute beforehand.
+  let resolve = |path: PathBuf| if daemon { absolute(path) } else { Ok(path) };
+  let config_path = args.config.clone().map(resolve).transpose()?;
+  let allow_sha1 = args.allow_sha1;
+  let config = load_config(config_path.as_deref(), allow_sha1)?;
+
+  let filter = args
+    .log_level
+    .as_deref()
+    .or(config.log_level.as_deref())
+    .unwrap_or("error");
+  let () = init_logging(config.log_sink, filter)?;
+  let () = set_prompt_methods(config.prompt.methods.clone());
+
+  let shell = args.shell(var("SHELL").ok().as_deref());
+  let dirs = if !args.directories.is_empty() {
+    args.directories
+  } else if !config.directories.is_empty() {
+    config.directories.clone()
+  } else {
+    let dir = home_dir()
+      .ok_or_else(|| IoError::new(ErrorKind::NotFound, "no home directory found"))
+      .with_context(|| "failed to retrieve home directory")?
+      .join(".ssh");
+    vec![dir]
+  };
+  let dirs = dirs.into_iter().map(resolve).collect::<Result<Vec<_>>>()?;
+
+  let socket = args
+    .socket
+    .or_else(|| config.socket.clone())
+    .unwrap_or_else(default_socket);
+  let socket = resolve(socket)?;
+  let pid_file = args.pid_file.map(resolve).transpose()?;
+  let listen_tcp = args.listen_tcp.or_else(|| config.listen_tcp.clone());
+  let tcp = if let Some(addr) = listen_tcp {
+    let token_file = args
+      .tcp_token_file
+      .or_else(|| config.tcp_token_file.clone())
+      .ok_or_else(|| anyhow!("serving over TCP requires a token file"))?;
+    Some((addr, load_token(&token_file)?))
+  } else {
+    None
+  };
+  let upstream = args
+    .upstream
+    .or_else(|| config.upstream.clone())
+    .map(resolve)
+    .transpose()?;
+  if upstream.as_ref() == Some(&socket) {
+    bail!("upstream agent socket must differ from the agent's own socket")
+  }
+  let forwarding = config.forwarding.socket.clone().map(resolve).transpose()?;
+  if let Some(forwarding) = &forwarding {
+    ensure!(
+      *forwarding != socket && Some(forwarding) != upstream.as_ref(),
+      "forwarding socket must differ from the agent's own and the upstream agent's socket"
+    );
+  }
+  let unix = SocketConfig {
+    path: socket.clone(),
+    policy: SocketPolicy::Full,
+    mode: config.socket_mode,
+    group: config.socket_group.clone(),
+  };
+  let forwarding = forwarding.map(|path| SocketConfig {
+    path,
+    policy: SocketPolicy::Forwarding,
+    ..Default::default()
+  });
+  let others = forwarding
+    .into_iter()
+    .chain(config.sockets.iter().cloned())
+    .map(|socket| {
+      let path = resolve(socket.path)?;
+      Ok(SocketConfig { path, ..socket })
+    })
+    .collect::<Result<Vec<_>>>()?;
+  let hardening = config.hardening;
+  let recipient = config.recipient.clone();
+  let gpg = config.gpg.clone();
+  let create = move || -> Result<GpgKeyAgent> {
+    let () = harden(&hardening).with_context(|| "failed to harden process")?;
+    let () = init_tracing()?;
+    Ok(GpgKeyAgent::new(dirs, upstream, config))
+  };
+
+  match args.command.unwrap_or_default() {
+    Command::Run => {
+      let reload = move || load_config(config_path.as_deref(), allow_sha1);
+      let sockets = Sockets { unix, others, tcp };
+      start(create, sockets, shell, daemon, args.replace, pid_file, reload)
+    },
+    Command::List { fingerprint_hash } => list_keys(&create()?, fingerprint_hash),
+    Command::Check { decrypt } => check(&create()?, &socket, decrypt),
+    Command::Status => status(&socket),
+    Command::Ping { timeout } => ping_agent(&socket, Duration::from_secs(timeout)),
+    Command::Kill => {
+      let shell = shell.unwrap_or_else(|| Shell::infer(var("SHELL").ok().as_deref()));
+      kill(shell, pid_file.as_deref())
+    },
+    Command::Encrypt {
+      key,
+      recipient: key_recipient,
+      shred,
+    } => {
+      let () = harden(&hardening).with_context(|| "failed to harden process")?;
+      let recipient = key_recipient
+        .or(recipient)
+        .ok_or_else(|| anyhow!("no GPG recipient configured; please provide one via --recipient"))?;
+      encrypt(&key, &recipient, &gpg, shred)
+    },
+    Command::Derive { yes, print } => derive(&create()?, yes, print),
+  }
+}
@@ -0,0 +1,220 @@
+// memstore.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+//! The key store for identities added at runtime that are kept in
+//! memory only.
+
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use ssh_agent_lib::proto::Blob;
+use ssh_agent_lib::proto::message::AddIdentity;
+use ssh_agent_lib::proto::message::SignatureBlob;
+use ssh_agent_lib::proto::message::SignRequest;
+use ssh_agent_lib::proto::public_key::PublicKey;
+
+use tracing::debug_span;
+use tracing::info;
+
+use crate::cache::CachedKey;
+use crate::error::KeyStoreError;
+use crate::sign::Signer;
+use crate::store::KeyStore;
+use crate::store::StoreIdentity;
+
+
+/// An identity kept in memory, along with its private key.
+#[derive(Debug)]
+struct Entry {
+  /// The identity.
+  identity: StoreIdentity,
+  /// The private key, which is wiped once dropped.
+  key: Arc<CachedKey>,
+}
+
+
+/// A key store keeping the private keys of identities added at runtime
+/// in memory, instead of storing them on disk.
+///
+/// The keys are wiped once removed from the store.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+  /// The identities, in the order they got added in.
+  entries: Mutex<Vec<Entry>>,
+}
+
+impl MemoryStore {
+  /// Add the given identity to the store, replacing it if already
+  /// present.
+  pub fn add(&self, identity: &AddIdentity) -> Result<()> {
+    let pubkey = PublicKey::from(&identity.privkey);
+    let blob = pubkey
+      .to_blob()
+      .with_context(|| "failed to serialize public key")?;
+    let entry = Entry {
+      identity: StoreIdentity {
+        blob,
+        pubkey: Some(pubkey),
+        comment: identity.comment.clone(),
+        confirm: false,
+      },
+      key: Arc::new(CachedKey::from(identity.privkey.clone())),
+    };
+
+    let mut entries = self.entries.lock().unwrap();
+    let () = entries.retain(|other| other.identity.blob != entry.identity.blob);
+    let () = entries.push(entry);
+
+    info!("Keeping identity {:?} in memory", identity.comment);
+    Ok(())
+  }
+
+  /// Check whether the identity with the given public key is kept in
+  /// the store.
+  pub fn contains(&self, pubkey: &PublicKey) -> bool {
+    self
+      .entries
+      .lock()
+      .unwrap()
+      .iter()
+      .any(|entry| entry.identity.pubkey.as_ref() == Some(pubkey))
+  }
+
+  /// Remove all identities from the store, wiping their private keys,
+  /// and return their public keys.
+  pub fn clear(&self) -> Vec<PublicKey> {
+    self
+      .entries
+      .lock()
+      .unwrap()
+      .drain(..)
+      .filter_map(|entry| entry.identity.pubkey)
+      .collect()
+  }
+}
+
+impl KeyStore for MemoryStore {
+  fn name(&self) -> &str {
+    "memory"
+  }
+
+  fn identities(&self) -> StdResult<Vec<StdResult<StoreIdentity, KeyStoreError>>, KeyStoreError> {
+    let identities = self
+      .entries
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|entry| Ok(entry.identity.clone()))
+      .collect();
+    Ok(identities)
+  }
+
+  fn sign(
+    &self,
+    identity: &StoreIdentity,
+    request: &SignRequest,
+  ) -> StdResult<SignatureBlob, KeyStoreError> {
+    // Don't hold the lock while signing.
+    let key = self
+      .entries
+      .lock()
+      .unwrap()
+      .iter()
+      .find(|entry| entry.identity.blob == identity.blob)
+      .map(|entry| entry.key.clone())
+      .ok_or_else(|| anyhow!("key {:?} no longer present", identity.comment))?;
+    info!("Signing with key {:?} kept in memory", identity.comment);
+
+    let sig = debug_span!("sign")
+      .in_scope(|| key.sign(request.flags, &request.data))
+      .with_context(|| "failed to sign request data")?;
+    let blob = sig
+      .to_blob()
+      .with_context(|| "failed to serialized signature")?;
+    Ok(blob)
+  }
+
+  fn remove(&self, identity: &StoreIdentity) -> StdResult<(), KeyStoreError> {
+    let () = self
+      .entries
+      .lock()
+      .unwrap()
+      .retain(|entry| entry.identity.blob != identity.blob);
+    info!("Removed identity {:?} kept in memory", identity.comment);
+    Ok(())
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use ssh_agent_lib::proto::private_key::PrivateKey;
+  use ssh_agent_lib::proto::signature::RSA_SHA2_256;
+
+  use crate::files::test::load_unencrypted_private_key;
+  use crate::keys::FromPem as _;
+
+
+  /// Check that identities can be added, used, and removed.
+  #[test]
+  fn add_sign_remove() -> Result<()> {
+    let privkey = load_unencrypted_private_key("tests/valid_keys/rsa2048")?;
+    let privkey = PrivateKey::from_pem(privkey)?;
+    let pubkey = PublicKey::from(&privkey);
+    let identity = AddIdentity {
+      privkey: privkey.clone(),
+      comment: "rsa2048".to_string(),
+    };
+
+    let store = MemoryStore::default();
+    let () = store.add(&identity)?;
+    let () = store.add(&identity)?;
+    let identities = store.identities()?;
+    assert_eq!(identities.len(), 1);
+    let identity = identities.into_iter().next().unwrap()?;
+    assert_eq!(identity.pubkey.as_ref(), Some(&pubkey));
+    assert!(store.contains(&pubkey));
+
+    let request = SignRequest {
+      pubkey_blob: identity.blob.clone(),
+      data: b"test-data".to_vec(),
+      flags: RSA_SHA2_256,
+    };
+    let blob = store.sign(&identity, &request)?;
+    assert_eq!(blob, privkey.sign(RSA_SHA2_256, b"test-data")?.to_blob()?);
+
+    let () = store.remove(&identity)?;
+    assert!(!store.contains(&pubkey));
+    assert!(store.sign(&identity, &request).is_err());
+
+    let () = store.add(&AddIdentity {
+      privkey,
+      comment: String::new(),
+    })?;
+    assert_eq!(store.clear(), vec![pubkey]);
+    assert!(store.identities()?.is_empty());
+    Ok(())
+  }
+}
@@ -0,0 +1,250 @@
+// mock.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! A mock decryption backend, for testing the agent without a GnuPG
+//! installation.
+//!
+//! Instead of decrypting anything, the [`MockDecryptor`] reads the
+//! private key belonging to an "encrypted" file from a directory of
+//! pre-decrypted fixtures. Decryption of individual keys can be made to
+//! fail and the number of decryptions is tracked, so that failure paths
+//! and caching can be exercised deterministically.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::read;
+use std::fs::write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use ring::rand::SecureRandom as _;
+use ring::rand::SystemRandom;
+use ring::signature::Ed25519KeyPair;
+use ring::signature::KeyPair as _;
+
+use ssh_agent_lib::proto::private_key::Ed25519PrivateKey;
+use ssh_agent_lib::proto::private_key::PrivateKey;
+use ssh_agent_lib::proto::public_key::PublicKey;
+
+use crate::config::Protocol;
+use crate::decrypt::Decrypted;
+use crate::decrypt::Decryptor;
+use crate::error::DecryptError;
+use crate::files::public_key_path;
+use crate::files::public_keys_to_pem;
+use crate::files::PemPrivateKey;
+use crate::keys::parse_private_keys;
+use crate::keys::ToPem as _;
+
+
+/// The contents of the "encrypted" private key files created for
+/// fixtures.
+const ENCRYPTED: &[u8] = b"mock encrypted private key\n";
+
+
+/// The state shared by all clones of a [`MockDecryptor`].
+#[derive(Debug, Default)]
+struct State {
+  /// The names of the keys failing to decrypt.
+  failing: HashSet<String>,
+  /// The number of successful decryptions, by key name.
+  decryptions: HashMap<String, usize>,
+}
+
+
+/// A decryptor serving pre-decrypted private keys.
+///
+/// The private key for an encrypted file `<name>.gpg` is read from the
+/// file `<name>` in the fixtures directory. Clones of a decryptor share
+/// their state, so that a test can keep one around for controlling and
+/// inspecting the one used by the agent.
+#[derive(Clone, Debug)]
+pub struct MockDecryptor {
+  /// The directory containing the pre-decrypted private keys.
+  fixtures: PathBuf,
+  /// The identifiers of the GPG key decryption pretends to have used.
+  keys: Vec<String>,
+  /// The shared state.
+  state: Arc<Mutex<State>>,
+}
+
+impl MockDecryptor {
+  /// Create a decryptor serving the pre-decrypted private keys in the
+  /// given directory.
+  pub fn new<P>(fixtures: P) -> Self
+  where
+    P: Into<PathBuf>,
+  {
+    Self {
+      fixtures: fixtures.into(),
+      keys: Vec::new(),
+      state: Arc::default(),
+    }
+  }
+
+  /// Pretend that private keys got decrypted using the GPG key with the
+  /// given identifiers (fingerprints or key IDs).
+  pub fn with_keys(mut self, keys: Vec<String>) -> Self {
+    self.keys = keys;
+    self
+  }
+
+  /// Retrieve the name of the key stored in the given file.
+  fn name(file: &Path) -> Result<String> {
+    let name = file
+      .file_stem()
+      .ok_or_else(|| anyhow!("{} does not name a file", file.display()))?;
+    Ok(name.to_string_lossy().into_owned())
+  }
+
+  /// Make decryption of the key with the given name fail or, if
+  /// `fail` is `false`, succeed again.
+  pub fn set_failing(&self, name: &str, fail: bool) {
+    let mut state = self.state.lock().unwrap();
+    if fail {
+      let _ = state.failing.insert(name.to_string());
+    } else {
+      let _ = state.failing.remove(name);
+    }
+  }
+
+  /// Retrieve the number of successful decryptions of the key with the
+  /// given name.
+  pub fn decryptions(&self, name: &str) -> usize {
+    let state = self.state.lock().unwrap();
+    state.decryptions.get(name).copied().unwrap_or(0)
+  }
+
+  /// Create a key pair with the given name in the provided directory,
+  /// the way the agent expects to find it, and store its private key
+  /// as fixture.
+  ///
+  /// The public key is derived from the private one and the "encrypted"
+  /// private key file merely contains a placeholder. Returns the path
+  /// to the latter.
+  pub fn add_key_pair(&self, dir: &Path, name: &str, privkey: &PemPrivateKey) -> Result<PathBuf> {
+    let keys = parse_private_keys(privkey).with_context(|| format!("key {name} is invalid"))?;
+    let pubkeys = keys
+      .iter()
+      .map(|(key, comment)| (PublicKey::from(key), comment.clone()))
+      .collect::<Vec<_>>();
+    let pubkey = public_keys_to_pem(&pubkeys)?;
+
+    let fixture = self.fixtures.join(name);
+    let () = write(&fixture, privkey)
+      .with_context(|| format!("failed to write {}", fixture.display()))?;
+    let gpg_path = dir.join(format!("{name}.gpg"));
+    let () = write(&gpg_path, ENCRYPTED)
+      .with_context(|| format!("failed to write {}", gpg_path.display()))?;
+    let pub_path = public_key_path(&gpg_path);
+    let () = write(&pub_path, pubkey)
+      .with_context(|| format!("failed to write {}", pub_path.display()))?;
+    Ok(gpg_path)
+  }
+
+  /// Generate a new Ed25519 key pair with the given name in the
+  /// provided directory, as per [`MockDecryptor::add_key_pair`].
+  ///
+  /// The name doubles as the key's comment.
+  pub fn generate_key_pair(&self, dir: &Path, name: &str) -> Result<PathBuf> {
+    let mut seed = [0; 32];
+    let () = SystemRandom::new()
+      .fill(&mut seed)
+      .map_err(|_| anyhow!("failed to generate Ed25519 seed"))?;
+    let key_pair = Ed25519KeyPair::from_seed_unchecked(&seed)
+      .map_err(|err| anyhow!("failed to create Ed25519 key pair: {err}"))?;
+    let public = key_pair.public_key().as_ref().to_vec();
+    let privkey = PrivateKey::Ed25519(Ed25519PrivateKey {
+      enc_a: public.clone(),
+      k_enc_a: [seed.as_slice(), &public].concat(),
+    });
+    let privkey = privkey.to_pem(name)?;
+    self.add_key_pair(dir, name, &privkey)
+  }
+}
+
+impl Decryptor for MockDecryptor {
+  fn decrypt(&self, file: &Path, _protocol: Protocol) -> StdResult<Decrypted, DecryptError> {
+    let name = Self::name(file)?;
+    if self.state.lock().unwrap().failing.contains(&name) {
+      return Err(anyhow!("failed to decrypt {}: no secret key", file.display()).into())
+    }
+
+    let fixture = self.fixtures.join(&name);
+    let key = read(&fixture)
+      .with_context(|| format!("failed to read fixture {}", fixture.display()))?;
+    *self
+      .state
+      .lock()
+      .unwrap()
+      .decryptions
+      .entry(name)
+      .or_default() += 1;
+    Ok(Decrypted::new(PemPrivateKey::from(key), self.keys.clone()))
+  }
+
+  fn can_decrypt(&self, file: &Path, _protocol: Protocol) -> StdResult<Option<bool>, DecryptError> {
+    let name = Self::name(file)?;
+    let failing = self.state.lock().unwrap().failing.contains(&name);
+    Ok(Some(!failing && self.fixtures.join(name).exists()))
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use tempfile::tempdir;
+
+  use crate::keys::FromPem as _;
+
+
+  /// Check that generated key pairs get "decrypted" as configured.
+  #[test]
+  fn decrypt_generated_key_pair() -> Result<()> {
+    let dir = tempdir()?;
+    let fixtures = tempdir()?;
+    let decryptor =
+      MockDecryptor::new(fixtures.path()).with_keys(vec!["0123456789ABCDEF".to_string()]);
+    let gpg_path = decryptor.generate_key_pair(dir.path(), "id_ed25519")?;
+    assert!(public_key_path(&gpg_path).exists());
+    assert_eq!(decryptor.can_decrypt(&gpg_path, Protocol::OpenPgp)?, Some(true));
+
+    let decrypted = decryptor.decrypt(&gpg_path, Protocol::OpenPgp)?;
+    assert!(decrypted.decrypted_with(&["0x0123456789ABCDEF".to_string()]));
+    let keys = Vec::<PrivateKey>::from_pem(decrypted.key)?;
+    assert_eq!(keys.len(), 1);
+    assert_eq!(decryptor.decryptions("id_ed25519"), 1);
+
+    let clone = decryptor.clone();
+    let () = clone.set_failing("id_ed25519", true);
+    assert_eq!(decryptor.can_decrypt(&gpg_path, Protocol::OpenPgp)?, Some(false));
+    assert!(decryptor.decrypt(&gpg_path, Protocol::OpenPgp).is_err());
+    assert_eq!(decryptor.decryptions("id_ed25519"), 1);
+    Ok(())
+  }
+}
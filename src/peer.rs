@@ -0,0 +1,159 @@
+// peer.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Retrieval of the credentials of processes connecting to the agent.
+
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::fs::read_link;
+use std::io::Error as IoError;
+#[cfg(target_os = "linux")]
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd as _;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+#[cfg(not(target_os = "linux"))]
+use libc::getpeereid;
+#[cfg(target_os = "linux")]
+use libc::getsockopt;
+#[cfg(target_os = "linux")]
+use libc::socklen_t;
+#[cfg(target_os = "linux")]
+use libc::ucred;
+#[cfg(target_os = "linux")]
+use libc::SOL_SOCKET;
+#[cfg(target_os = "linux")]
+use libc::SO_PEERCRED;
+
+
+/// The credentials of a process connected to the agent via its Unix
+/// domain socket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Peer {
+  /// The effective user ID of the process.
+  pub uid: u32,
+  /// The effective group ID of the process.
+  pub gid: u32,
+  /// The process ID, if known.
+  pub pid: Option<u32>,
+}
+
+impl Peer {
+  /// Retrieve the credentials of the process connected to the other end
+  /// of the given stream.
+  #[cfg(target_os = "linux")]
+  pub fn from_stream(stream: &UnixStream) -> Result<Self> {
+    let mut cred = ucred {
+      pid: 0,
+      uid: 0,
+      gid: 0,
+    };
+    let mut len = size_of::<ucred>() as socklen_t;
+    // SAFETY: `cred` and `len` are valid for writes and describe a
+    //         buffer of the size `SO_PEERCRED` expects.
+    let result = unsafe {
+      getsockopt(
+        stream.as_raw_fd(),
+        SOL_SOCKET,
+        SO_PEERCRED,
+        (&mut cred as *mut ucred).cast(),
+        &mut len,
+      )
+    };
+    if result != 0 {
+      return Err(IoError::last_os_error()).context("failed to retrieve peer credentials")
+    }
+
+    Ok(Self {
+      uid: cred.uid,
+      gid: cred.gid,
+      pid: u32::try_from(cred.pid).ok().filter(|pid| *pid != 0),
+    })
+  }
+
+  /// Retrieve the credentials of the process connected to the other end
+  /// of the given stream.
+  #[cfg(not(target_os = "linux"))]
+  pub fn from_stream(stream: &UnixStream) -> Result<Self> {
+    let mut uid = 0;
+    let mut gid = 0;
+    // SAFETY: `uid` and `gid` are valid for writes.
+    let result = unsafe { getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) };
+    if result != 0 {
+      return Err(IoError::last_os_error()).context("failed to retrieve peer credentials")
+    }
+
+    Ok(Self {
+      uid,
+      gid,
+      pid: None,
+    })
+  }
+
+  /// Retrieve the path to the executable of the process, if it can be
+  /// determined.
+  pub fn executable(&self) -> Option<PathBuf> {
+    self
+      .pid
+      .and_then(|pid| read_link(format!("/proc/{pid}/exe")).ok())
+  }
+}
+
+impl Display for Peer {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    write!(f, "uid {}", self.uid)?;
+    if let Some(pid) = self.pid {
+      write!(f, ", pid {pid}")?;
+    }
+    Ok(())
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::process::id;
+
+  use libc::getegid;
+  use libc::geteuid;
+
+
+  /// Check that we can retrieve the credentials of the other end of a
+  /// Unix domain socket connection.
+  #[test]
+  fn peer_credentials() -> Result<()> {
+    let (stream, _other) = UnixStream::pair()?;
+    let peer = Peer::from_stream(&stream)?;
+    // SAFETY: Both functions are always safe to call.
+    assert_eq!(peer.uid, unsafe { geteuid() });
+    assert_eq!(peer.gid, unsafe { getegid() });
+    if cfg!(target_os = "linux") {
+      assert_eq!(peer.pid, Some(id()));
+      assert!(peer.executable().is_some());
+    }
+    Ok(())
+  }
+}
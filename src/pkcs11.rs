@@ -0,0 +1,700 @@
+// pkcs11.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Support for keys residing on PKCS#11 tokens, such as the PIV
+//! application of a YubiKey or a hardware security module.
+
+use std::path::PathBuf;
+use std::result::Result as StdResult;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use cryptoki::context::CInitializeArgs;
+use cryptoki::context::Pkcs11 as Context;
+use cryptoki::error::Error as Pkcs11Error;
+use cryptoki::error::RvError;
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::Attribute;
+use cryptoki::object::AttributeType;
+use cryptoki::object::KeyType;
+use cryptoki::object::ObjectClass;
+use cryptoki::session::Session;
+use cryptoki::session::UserType;
+use cryptoki::slot::Slot;
+use cryptoki::types::AuthPin;
+
+use ring::digest::digest;
+use ring::digest::Algorithm;
+use ring::digest::SHA1_FOR_LEGACY_USE_ONLY;
+use ring::digest::SHA256;
+use ring::digest::SHA384;
+use ring::digest::SHA512;
+
+use ssh_agent_lib::proto::Blob as _;
+use ssh_agent_lib::proto::key_type::KeyTypeEnum as _;
+use ssh_agent_lib::proto::message::SignatureBlob;
+use ssh_agent_lib::proto::message::SignRequest;
+use ssh_agent_lib::proto::public_key::EcDsaPublicKey;
+use ssh_agent_lib::proto::public_key::Ed25519PublicKey;
+use ssh_agent_lib::proto::public_key::PublicKey;
+use ssh_agent_lib::proto::public_key::RsaPublicKey;
+use ssh_agent_lib::proto::signature::EcDsaSignatureData;
+use ssh_agent_lib::proto::signature::Signature;
+use ssh_agent_lib::proto::signature::RSA_SHA2_256;
+use ssh_agent_lib::proto::signature::RSA_SHA2_512;
+use ssh_agent_lib::proto::to_bytes;
+
+use tracing::debug;
+use tracing::info;
+
+use crate::agent::PASSPHRASE_ATTEMPTS;
+use crate::config::Pkcs11Config;
+use crate::error::KeyStoreError;
+use crate::gpgagent::ecdsa_identifier;
+use crate::openssh::to_mpint;
+use crate::prompt;
+use crate::store::KeyStore;
+use crate::store::StoreIdentity;
+
+
+/// The DER tag of an object identifier.
+const DER_OID: u8 = 0x06;
+/// The DER tag of an octet string.
+const DER_OCTET_STRING: u8 = 0x04;
+/// The DER tag of a printable string.
+const DER_PRINTABLE_STRING: u8 = 0x13;
+
+/// The length of an Ed25519 public key.
+const ED25519_LEN: usize = 32;
+
+/// The DER encoded `DigestInfo` prefixes of the hash algorithms used
+/// for RSA signatures, as required by the raw `CKM_RSA_PKCS` mechanism.
+const SHA1_DIGEST_INFO: &[u8] = &[
+  0x30, 0x21, 0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00, 0x04, 0x14,
+];
+const SHA256_DIGEST_INFO: &[u8] = &[
+  0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+  0x00, 0x04, 0x20,
+];
+const SHA512_DIGEST_INFO: &[u8] = &[
+  0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03, 0x05,
+  0x00, 0x04, 0x40,
+];
+
+
+/// A key residing on a PKCS#11 token.
+#[derive(Clone, Debug)]
+pub struct TokenKey {
+  /// The label of the token the key resides on.
+  pub token: String,
+  /// The ID shared by the public and private key objects on the token.
+  pub id: Vec<u8>,
+  /// The public key.
+  pub pubkey: PublicKey,
+  /// The comment of the key, i.e., the label of its public key object
+  /// or, lacking one, a description of the key's location.
+  pub comment: String,
+}
+
+
+/// The attributes of a public key object relevant to us.
+#[derive(Debug, Default)]
+struct KeyAttributes {
+  key_type: Option<KeyType>,
+  id: Vec<u8>,
+  label: Vec<u8>,
+  modulus: Vec<u8>,
+  exponent: Vec<u8>,
+  ec_params: Vec<u8>,
+  ec_point: Vec<u8>,
+}
+
+impl KeyAttributes {
+  /// The attribute types to retrieve.
+  const TYPES: [AttributeType; 7] = [
+    AttributeType::KeyType,
+    AttributeType::Id,
+    AttributeType::Label,
+    AttributeType::Modulus,
+    AttributeType::PublicExponent,
+    AttributeType::EcParams,
+    AttributeType::EcPoint,
+  ];
+
+  /// Collect the relevant attributes from the given ones.
+  fn new(attributes: Vec<Attribute>) -> Self {
+    let mut attrs = Self::default();
+    for attribute in attributes {
+      match attribute {
+        Attribute::KeyType(key_type) => attrs.key_type = Some(key_type),
+        Attribute::Id(id) => attrs.id = id,
+        Attribute::Label(label) => attrs.label = label,
+        Attribute::Modulus(modulus) => attrs.modulus = modulus,
+        Attribute::PublicExponent(exponent) => attrs.exponent = exponent,
+        Attribute::EcParams(params) => attrs.ec_params = params,
+        Attribute::EcPoint(point) => attrs.ec_point = point,
+        _ => (),
+      }
+    }
+    attrs
+  }
+}
+
+
+/// Retrieve the contents of the DER encoded value with the given tag.
+fn der_value(data: &[u8], tag: u8) -> Result<&[u8]> {
+  let (first, rest) = match data {
+    [found, first, rest @ ..] if *found == tag => (*first, rest),
+    _ => bail!("DER value is not of expected type {tag:#04x}"),
+  };
+  let (len, rest) = match first {
+    len @ 0..=0x7f => (usize::from(len), rest),
+    0x81 => match rest {
+      [len, rest @ ..] => (usize::from(*len), rest),
+      _ => bail!("DER value is truncated"),
+    },
+    0x82 => match rest {
+      [hi, lo, rest @ ..] => (usize::from(*hi) << 8 | usize::from(*lo), rest),
+      _ => bail!("DER value is truncated"),
+    },
+    _ => bail!("DER value has unsupported length encoding"),
+  };
+  ensure!(rest.len() == len, "DER value has invalid length");
+  Ok(rest)
+}
+
+
+/// Convert a DER encoded object identifier into its dotted string
+/// representation.
+fn oid(data: &[u8]) -> Result<String> {
+  let data = der_value(data, DER_OID)?;
+  let (first, rest) = data
+    .split_first()
+    .ok_or_else(|| anyhow!("object identifier is empty"))?;
+  let mut oid = format!("{}.{}", first / 40, first % 40);
+  let mut arc = 0u64;
+  for byte in rest {
+    ensure!(arc >> 57 == 0, "object identifier arc is too large");
+    arc = arc << 7 | u64::from(byte & 0x7f);
+    if byte & 0x80 == 0 {
+      oid += &format!(".{arc}");
+      arc = 0;
+    }
+  }
+  ensure!(
+    rest.last().map(|byte| byte & 0x80 == 0).unwrap_or(true),
+    "object identifier is truncated"
+  );
+  Ok(oid)
+}
+
+
+/// Retrieve the curve described by the given `CKA_EC_PARAMS` value,
+/// either as dotted object identifier or curve name.
+fn curve(params: &[u8]) -> Result<String> {
+  match params.first() {
+    Some(&DER_OID) => oid(params),
+    Some(&DER_PRINTABLE_STRING) => {
+      let name = der_value(params, DER_PRINTABLE_STRING)?;
+      Ok(String::from_utf8_lossy(name).into_owned())
+    },
+    _ => bail!("elliptic curve parameters are not supported"),
+  }
+}
+
+
+/// Retrieve the encoded point from the given `CKA_EC_POINT` value.
+///
+/// The value should be a DER encoded octet string, but some tokens
+/// provide the raw point instead.
+fn ec_point(point: &[u8]) -> &[u8] {
+  der_value(point, DER_OCTET_STRING).unwrap_or(point)
+}
+
+
+/// Convert the attributes of a public key object into an SSH public
+/// key.
+fn public_key(attrs: &KeyAttributes) -> Result<PublicKey> {
+  match attrs.key_type {
+    Some(KeyType::RSA) => {
+      ensure!(!attrs.modulus.is_empty(), "RSA public key lacks modulus");
+      let key = RsaPublicKey {
+        e: to_mpint(&attrs.exponent),
+        n: to_mpint(&attrs.modulus),
+      };
+      Ok(PublicKey::Rsa(key))
+    },
+    Some(KeyType::EC) => {
+      let curve = curve(&attrs.ec_params)?;
+      let identifier = ecdsa_identifier(&curve)
+        .ok_or_else(|| anyhow!("elliptic curve {curve} is not supported"))?;
+      let key = EcDsaPublicKey {
+        identifier: identifier.to_string(),
+        q: ec_point(&attrs.ec_point).to_vec(),
+      };
+      Ok(PublicKey::EcDsa(key))
+    },
+    Some(KeyType::EC_EDWARDS) => {
+      let curve = curve(&attrs.ec_params)?;
+      ensure!(
+        matches!(curve.as_str(), "1.3.101.112" | "edwards25519"),
+        "Edwards curve {curve} is not supported"
+      );
+      let enc_a = ec_point(&attrs.ec_point);
+      ensure!(enc_a.len() == ED25519_LEN, "encountered invalid Ed25519 public key");
+      Ok(PublicKey::Ed25519(Ed25519PublicKey { enc_a: enc_a.to_vec() }))
+    },
+    Some(key_type) => bail!("key type {key_type} is not supported"),
+    None => bail!("key lacks type"),
+  }
+}
+
+
+/// Create the `DigestInfo` structure to sign with an RSA key for the
+/// given data.
+fn digest_info(hash: &'static Algorithm, data: &[u8]) -> Vec<u8> {
+  let prefix = match hash.output_len() {
+    20 => SHA1_DIGEST_INFO,
+    32 => SHA256_DIGEST_INFO,
+    _ => SHA512_DIGEST_INFO,
+  };
+  let mut info = prefix.to_vec();
+  let () = info.extend_from_slice(digest(hash, data).as_ref());
+  info
+}
+
+
+/// Convert an ECDSA signature as created by a PKCS#11 token, i.e., the
+/// concatenation of `r` and `s`, into an SSH signature blob.
+fn ecdsa_signature(sig: &[u8]) -> Result<Vec<u8>> {
+  ensure!(
+    !sig.is_empty() && sig.len() % 2 == 0,
+    "PKCS#11 token created invalid ECDSA signature"
+  );
+  let (r, s) = sig.split_at(sig.len() / 2);
+  let sig = EcDsaSignatureData {
+    r: to_mpint(r),
+    s: to_mpint(s),
+  };
+  to_bytes(&sig).context("failed to serialize ECDSA signature")
+}
+
+
+/// Encode data as hexadecimal string.
+fn to_hex(data: &[u8]) -> String {
+  data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+
+/// A PKCS#11 module providing access to the keys on its tokens.
+#[derive(Debug)]
+pub struct Pkcs11 {
+  /// The path to the PKCS#11 module.
+  module: PathBuf,
+  /// The label of the token to use keys of, if not all.
+  token: Option<String>,
+  /// The loaded and initialized module, once used.
+  context: Mutex<Option<Context>>,
+}
+
+impl Pkcs11 {
+  /// Create an object for accessing the keys of the PKCS#11 module
+  /// referenced by the given configuration, if any.
+  ///
+  /// The module is only loaded once first used.
+  pub fn new(config: &Pkcs11Config) -> Option<Self> {
+    let module = config.module.clone()?;
+    let slf = Self {
+      module,
+      token: config.token.clone(),
+      context: Mutex::new(None),
+    };
+    Some(slf)
+  }
+
+  /// Retrieve the initialized PKCS#11 module, loading it if necessary.
+  fn context(&self) -> Result<Context> {
+    let mut context = self.context.lock().unwrap();
+    if let Some(context) = &*context {
+      return Ok(context.clone())
+    }
+
+    let module = Context::new(&self.module)
+      .with_context(|| format!("failed to load PKCS#11 module {}", self.module.display()))?;
+    let () = module
+      .initialize(CInitializeArgs::OsThreads)
+      .with_context(|| format!("failed to initialize PKCS#11 module {}", self.module.display()))?;
+    *context = Some(module.clone());
+    Ok(module)
+  }
+
+  /// Retrieve the slots with a token we are configured to use, along
+  /// with the tokens' labels.
+  fn slots(&self, context: &Context) -> Result<Vec<(Slot, String)>> {
+    let mut slots = Vec::new();
+    for slot in context
+      .get_slots_with_token()
+      .context("failed to list PKCS#11 slots")?
+    {
+      let info = context
+        .get_token_info(slot)
+        .context("failed to retrieve PKCS#11 token information")?;
+      let label = info.label().trim().to_string();
+      if self.token.as_ref().map(|token| *token == label).unwrap_or(true) {
+        let () = slots.push((slot, label));
+      }
+    }
+    Ok(slots)
+  }
+
+  /// Retrieve the keys on the available tokens.
+  ///
+  /// Keys of a type we do not support are skipped.
+  pub fn keys(&self) -> Result<Vec<TokenKey>> {
+    let context = self.context()?;
+    let mut keys = Vec::new();
+    for (slot, token) in self.slots(&context)? {
+      let session = context
+        .open_ro_session(slot)
+        .with_context(|| format!("failed to open session with PKCS#11 token {token}"))?;
+      let objects = session
+        .find_objects(&[Attribute::Class(ObjectClass::PUBLIC_KEY)])
+        .with_context(|| format!("failed to list keys of PKCS#11 token {token}"))?;
+
+      for object in objects {
+        let attributes = session
+          .get_attributes(object, &KeyAttributes::TYPES)
+          .with_context(|| format!("failed to read key of PKCS#11 token {token}"))?;
+        let attrs = KeyAttributes::new(attributes);
+        let comment = match String::from_utf8_lossy(&attrs.label).trim() {
+          "" => format!("pkcs11:{token}/{}", to_hex(&attrs.id)),
+          label => label.to_string(),
+        };
+
+        match public_key(&attrs) {
+          Ok(pubkey) => {
+            let key = TokenKey {
+              token: token.clone(),
+              id: attrs.id,
+              pubkey,
+              comment,
+            };
+            let () = keys.push(key);
+          },
+          Err(err) => debug!("Ignoring key {comment:?} of PKCS#11 token {token}: {err:#}"),
+        }
+      }
+    }
+    Ok(keys)
+  }
+
+  /// Log in to the token in the given slot, prompting the user for the
+  /// PIN if necessary.
+  fn login(context: &Context, slot: Slot, session: &Session, token: &str) -> Result<()> {
+    let mut description = format!("Enter PIN for PKCS#11 token {token}");
+
+    for _ in 0..PASSPHRASE_ATTEMPTS {
+      let info = context
+        .get_token_info(slot)
+        .context("failed to retrieve PKCS#11 token information")?;
+      ensure!(!info.user_pin_locked(), "PIN of PKCS#11 token {token} is locked");
+      if !info.login_required() {
+        return Ok(())
+      }
+
+      let result = if info.protected_authentication_path() {
+        info!("Enter the PIN for PKCS#11 token {token} using its PIN pad");
+        session.login(UserType::User, None)
+      } else {
+        let mut description = description.clone();
+        if info.user_pin_final_try() {
+          description += " (final attempt before the PIN gets locked)";
+        }
+        let pin = prompt::passphrase(&description)?
+          .ok_or_else(|| anyhow!("PIN entry for PKCS#11 token {token} canceled"))?;
+        session.login(UserType::User, Some(&AuthPin::new(pin.as_str().to_string())))
+      };
+
+      match result {
+        Err(Pkcs11Error::Pkcs11(RvError::PinIncorrect, _)) => {
+          description = format!("Incorrect PIN. Enter PIN for PKCS#11 token {token}");
+        },
+        // Another session may have logged in concurrently.
+        Err(Pkcs11Error::Pkcs11(RvError::UserAlreadyLoggedIn, _)) => return Ok(()),
+        result => {
+          return result.with_context(|| format!("failed to log in to PKCS#11 token {token}"))
+        },
+      }
+    }
+    bail!("failed to log in to PKCS#11 token {token}: too many incorrect PINs")
+  }
+
+  /// Sign the given data with a key residing on a token, taking into
+  /// account the signature flags of the request.
+  pub fn sign(&self, key: &TokenKey, flags: u32, data: &[u8]) -> Result<Signature> {
+    let context = self.context()?;
+    let (slot, _) = self
+      .slots(&context)?
+      .into_iter()
+      .find(|(_, token)| *token == key.token)
+      .ok_or_else(|| anyhow!("PKCS#11 token {} is not available", key.token))?;
+    let session = context
+      .open_ro_session(slot)
+      .with_context(|| format!("failed to open session with PKCS#11 token {}", key.token))?;
+    let () = Self::login(&context, slot, &session, &key.token)?;
+
+    let template = [
+      Attribute::Class(ObjectClass::PRIVATE_KEY),
+      Attribute::Id(key.id.clone()),
+    ];
+    let privkey = session
+      .find_objects(&template)
+      .with_context(|| format!("failed to list keys of PKCS#11 token {}", key.token))?
+      .into_iter()
+      .next()
+      .ok_or_else(|| anyhow!("private key {:?} not found on PKCS#11 token", key.comment))?;
+
+    let (algorithm, mechanism, input) = match &key.pubkey {
+      PublicKey::Rsa(..) => {
+        let (algorithm, hash): (_, &'static Algorithm) = if flags & RSA_SHA2_512 != 0 {
+          ("rsa-sha2-512", &SHA512)
+        } else if flags & RSA_SHA2_256 != 0 {
+          ("rsa-sha2-256", &SHA256)
+        } else {
+          ("ssh-rsa", &SHA1_FOR_LEGACY_USE_ONLY)
+        };
+        (algorithm.to_string(), Mechanism::RsaPkcs, digest_info(hash, data))
+      },
+      PublicKey::EcDsa(ecdsa) => {
+        let hash = match ecdsa.identifier.as_str() {
+          "nistp256" => &SHA256,
+          "nistp384" => &SHA384,
+          _ => &SHA512,
+        };
+        let digest = digest(hash, data).as_ref().to_vec();
+        (key.pubkey.key_type(), Mechanism::Ecdsa, digest)
+      },
+      // EdDSA signs the data itself, not a hash thereof.
+      PublicKey::Ed25519(..) => (key.pubkey.key_type(), Mechanism::Eddsa, data.to_vec()),
+      pubkey => bail!("{} keys are not supported", pubkey.key_type()),
+    };
+
+    let sig = session
+      .sign(&mechanism, privkey, &input)
+      .with_context(|| format!("PKCS#11 token failed to sign with key {:?}", key.comment))?;
+    let blob = match &key.pubkey {
+      PublicKey::EcDsa(..) => ecdsa_signature(&sig)?,
+      _ => sig,
+    };
+    let sig = Signature { algorithm, blob };
+    Ok(sig)
+  }
+}
+
+impl KeyStore for Pkcs11 {
+  fn name(&self) -> &str {
+    "pkcs11"
+  }
+
+  fn identities(&self) -> StdResult<Vec<StdResult<StoreIdentity, KeyStoreError>>, KeyStoreError> {
+    let identities = self
+      .keys()?
+      .into_iter()
+      .map(|key| {
+        let blob = key
+          .pubkey
+          .to_blob()
+          .with_context(|| "failed to serialize public key")?;
+        let identity = StoreIdentity {
+          blob,
+          pubkey: Some(key.pubkey),
+          comment: key.comment,
+          confirm: false,
+        };
+        Ok(identity)
+      })
+      .collect();
+    Ok(identities)
+  }
+
+  fn sign(
+    &self,
+    identity: &StoreIdentity,
+    request: &SignRequest,
+  ) -> StdResult<SignatureBlob, KeyStoreError> {
+    let key = self
+      .keys()?
+      .into_iter()
+      .find(|key| Some(&key.pubkey) == identity.pubkey.as_ref())
+      .ok_or_else(|| anyhow!("PKCS#11 token key {:?} no longer present", identity.comment))?;
+    info!(
+      "Signing with key {:?} on PKCS#11 token {}; touch the token if it blinks",
+      key.comment, key.token
+    );
+
+    let sig = self
+      .sign(&key, request.flags, &request.data)
+      .with_context(|| "failed to create signature")?;
+    let blob = sig
+      .to_blob()
+      .with_context(|| "failed to serialized signature")?;
+    Ok(blob)
+  }
+
+  /// Keys residing on tokens can only be hidden.
+  fn remove(&self, identity: &StoreIdentity) -> StdResult<(), KeyStoreError> {
+    info!("Removed PKCS#11 token identity {:?}", identity.comment);
+    Ok(())
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+
+  /// Check that we can decode DER encoded values.
+  #[test]
+  fn der_decoding() -> Result<()> {
+    assert_eq!(der_value(b"\x04\x02ab", DER_OCTET_STRING)?, b"ab");
+    assert_eq!(der_value(b"\x04\x00", DER_OCTET_STRING)?, b"");
+
+    let mut long = vec![0x04, 0x81, 0x85];
+    long.extend([0x42; 0x85]);
+    assert_eq!(der_value(&long, DER_OCTET_STRING)?, &long[3..]);
+
+    assert!(der_value(b"\x04\x02ab", DER_OID).is_err());
+    assert!(der_value(b"\x04\x03ab", DER_OCTET_STRING).is_err());
+    assert!(der_value(b"\x04\x81", DER_OCTET_STRING).is_err());
+    assert!(der_value(b"\x04", DER_OCTET_STRING).is_err());
+    Ok(())
+  }
+
+  /// Check that we correctly identify the curves of EC keys.
+  #[test]
+  fn curve_identification() -> Result<()> {
+    let p256 = b"\x06\x08\x2a\x86\x48\xce\x3d\x03\x01\x07";
+    assert_eq!(curve(p256)?, "1.2.840.10045.3.1.7");
+    assert_eq!(curve(b"\x06\x05\x2b\x81\x04\x00\x22")?, "1.3.132.0.34");
+    assert_eq!(curve(b"\x06\x03\x2b\x65\x70")?, "1.3.101.112");
+    assert_eq!(curve(b"\x13\x0cedwards25519")?, "edwards25519");
+    assert!(curve(b"\x06\x02\x2a\x86").is_err());
+    assert!(curve(b"\x30\x00").is_err());
+    assert!(curve(b"").is_err());
+    Ok(())
+  }
+
+  /// Check that we correctly convert public key objects into SSH public
+  /// keys.
+  #[test]
+  fn public_key_conversion() -> Result<()> {
+    let attrs = KeyAttributes {
+      key_type: Some(KeyType::RSA),
+      modulus: vec![0xc1, 0x00, 0x01],
+      exponent: vec![0x01, 0x00, 0x01],
+      ..Default::default()
+    };
+    let key = public_key(&attrs)?;
+    let expected = PublicKey::Rsa(RsaPublicKey {
+      e: vec![0x01, 0x00, 0x01],
+      n: vec![0x00, 0xc1, 0x00, 0x01],
+    });
+    assert_eq!(key, expected);
+
+    let point = [[0x04].as_slice(), &[0x11; 64]].concat();
+    let attrs = KeyAttributes {
+      key_type: Some(KeyType::EC),
+      ec_params: b"\x06\x08\x2a\x86\x48\xce\x3d\x03\x01\x07".to_vec(),
+      ec_point: [[0x04, 0x41].as_slice(), &point].concat(),
+      ..Default::default()
+    };
+    let key = public_key(&attrs)?;
+    let expected = PublicKey::EcDsa(EcDsaPublicKey {
+      identifier: "nistp256".to_string(),
+      q: point.clone(),
+    });
+    assert_eq!(key, expected);
+
+    // Some tokens report the raw point.
+    let attrs = KeyAttributes {
+      ec_point: point.clone(),
+      ..attrs
+    };
+    assert_eq!(public_key(&attrs)?, expected);
+
+    let attrs = KeyAttributes {
+      key_type: Some(KeyType::EC_EDWARDS),
+      ec_params: b"\x13\x0cedwards25519".to_vec(),
+      ec_point: [[0x04, 0x20].as_slice(), &[0x22; 32]].concat(),
+      ..Default::default()
+    };
+    let key = public_key(&attrs)?;
+    let expected = PublicKey::Ed25519(Ed25519PublicKey {
+      enc_a: vec![0x22; 32],
+    });
+    assert_eq!(key, expected);
+
+    let attrs = KeyAttributes {
+      key_type: Some(KeyType::EC),
+      ec_params: b"\x06\x03\x2b\x65\x70".to_vec(),
+      ..Default::default()
+    };
+    assert!(public_key(&attrs).is_err());
+    let attrs = KeyAttributes {
+      key_type: Some(KeyType::DSA),
+      ..Default::default()
+    };
+    assert!(public_key(&attrs).is_err());
+    Ok(())
+  }
+
+  /// Check that we create the `DigestInfo` structures expected for RSA
+  /// signatures.
+  #[test]
+  fn digest_infos() {
+    let info = digest_info(&SHA256, b"");
+    assert_eq!(info.len(), SHA256_DIGEST_INFO.len() + 32);
+    assert_eq!(&info[..SHA256_DIGEST_INFO.len()], SHA256_DIGEST_INFO);
+    assert_eq!(&info[SHA256_DIGEST_INFO.len()..], digest(&SHA256, b"").as_ref());
+
+    assert_eq!(digest_info(&SHA512, b"").len(), SHA512_DIGEST_INFO.len() + 64);
+    let info = digest_info(&SHA1_FOR_LEGACY_USE_ONLY, b"");
+    assert_eq!(info.len(), SHA1_DIGEST_INFO.len() + 20);
+  }
+
+  /// Check that we correctly convert ECDSA signatures.
+  #[test]
+  fn ecdsa_signature_conversion() -> Result<()> {
+    let sig = [[0x00, 0x01].as_slice(), &[0x80, 0x02]].concat();
+    let expected = EcDsaSignatureData {
+      r: vec![0x01],
+      s: vec![0x00, 0x80, 0x02],
+    };
+    assert_eq!(ecdsa_signature(&sig)?, to_bytes(&expected)?);
+    assert!(ecdsa_signature(&[0x01, 0x02, 0x03]).is_err());
+    assert!(ecdsa_signature(&[]).is_err());
+    Ok(())
+  }
+}
@@ -0,0 +1,192 @@
+// policy.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Evaluation of per-key access policies.
+//!
+//! A key may be restricted to clients running as certain users or to
+//! certain programs. Clients we know nothing about, such as those
+//! connecting via TCP, never pass such restrictions.
+//!
+//! Independently, RSA keys have to satisfy a minimum key size.
+
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use ssh_agent_lib::proto::public_key::PublicKey;
+
+use crate::config::Config;
+use crate::config::KeyConfig;
+use crate::peer::Peer;
+
+
+/// Check whether the given program matches the provided pattern, which
+/// is either the program's absolute path or its file name.
+fn program_matches(pattern: &str, program: &Path) -> bool {
+  if Path::new(pattern).is_absolute() {
+    program == Path::new(pattern)
+  } else {
+    program
+      .file_name()
+      .map(|name| name == pattern)
+      .unwrap_or(false)
+  }
+}
+
+
+/// Check whether a client with the given user ID, running the provided
+/// program, may use a key with the given configuration.
+fn check(config: &KeyConfig, uid: Option<u32>, program: Option<&Path>) -> Result<()> {
+  if !config.allowed_users.is_empty() {
+    match uid {
+      Some(uid) if config.allowed_users.contains(&uid) => (),
+      Some(uid) => bail!("user {uid} is not allowed to use the key"),
+      None => bail!("unknown users are not allowed to use the key"),
+    }
+  }
+
+  if !config.allowed_programs.is_empty() {
+    match program {
+      Some(program)
+        if config
+          .allowed_programs
+          .iter()
+          .any(|pattern| program_matches(pattern, program)) => {},
+      Some(program) => bail!("program {} is not allowed to use the key", program.display()),
+      None => bail!("unknown programs are not allowed to use the key"),
+    }
+  }
+  Ok(())
+}
+
+
+/// Check whether the given client may use a key with the provided
+/// configuration.
+///
+/// `peer` is `None` for clients whose credentials are unknown.
+pub fn check_access(config: &KeyConfig, peer: Option<&Peer>) -> Result<()> {
+  let program = peer.and_then(Peer::executable);
+  check(config, peer.map(|peer| peer.uid), program.as_deref())
+}
+
+
+/// Determine the length in bits of the given RSA modulus, encoded as
+/// SSH mpint.
+fn rsa_bits(n: &[u8]) -> usize {
+  match n.iter().position(|byte| *byte != 0) {
+    Some(start) => (n.len() - start) * 8 - n[start].leading_zeros() as usize,
+    None => 0,
+  }
+}
+
+
+/// Check whether the given key satisfies the configured minimum RSA
+/// key size, unless it is explicitly allowed not to.
+pub fn check_key_size(config: &Config, key: &PublicKey) -> Result<()> {
+  if let PublicKey::Rsa(rsa) = key {
+    let bits = rsa_bits(&rsa.n);
+    if bits < config.rsa.min_bits && !config.allow_weak_key(key)? {
+      bail!(
+        "RSA key has {bits} bits, less than the required minimum of {} bits",
+        config.rsa.min_bits
+      )
+    }
+  }
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use ssh_agent_lib::proto::public_key::RsaPublicKey;
+
+  use crate::fingerprint::Fingerprint;
+  use crate::fingerprint::HashAlg;
+
+
+  /// Check that unrestricted keys can be used by everybody.
+  #[test]
+  fn unrestricted() {
+    let config = KeyConfig::default();
+    assert!(check(&config, None, None).is_ok());
+    assert!(check(&config, Some(1000), Some(Path::new("/usr/bin/ssh"))).is_ok());
+  }
+
+  /// Check that user restrictions are enforced.
+  #[test]
+  fn user_restrictions() {
+    let config = KeyConfig {
+      allowed_users: vec![1000],
+      ..Default::default()
+    };
+    assert!(check(&config, Some(1000), None).is_ok());
+    assert!(check(&config, Some(1001), None).is_err());
+    assert!(check(&config, None, None).is_err());
+  }
+
+  /// Check that program restrictions are enforced.
+  #[test]
+  fn program_restrictions() {
+    let config = KeyConfig {
+      allowed_programs: vec!["ssh".to_string(), "/opt/bin/rsync".to_string()],
+      ..Default::default()
+    };
+    assert!(check(&config, None, Some(Path::new("/usr/bin/ssh"))).is_ok());
+    assert!(check(&config, None, Some(Path::new("/opt/bin/rsync"))).is_ok());
+    assert!(check(&config, None, Some(Path::new("/usr/bin/rsync"))).is_err());
+    assert!(check(&config, None, Some(Path::new("/usr/bin/ssh-keygen"))).is_err());
+    assert!(check(&config, Some(1000), None).is_err());
+  }
+
+  /// Check that we determine the length of RSA moduli correctly.
+  #[test]
+  fn modulus_bits() {
+    assert_eq!(rsa_bits(&[]), 0);
+    assert_eq!(rsa_bits(&[0x00, 0x00]), 0);
+    assert_eq!(rsa_bits(&[0x01]), 1);
+    assert_eq!(rsa_bits(&[0x00, 0x80, 0x00]), 16);
+    assert_eq!(rsa_bits(&[0x7f, 0xff]), 15);
+  }
+
+  /// Check that the minimum RSA key size is enforced.
+  #[test]
+  fn key_size() -> Result<()> {
+    let mut n = vec![0x00, 0x80];
+    let () = n.resize(1 + 1024 / 8, 0);
+    let key = PublicKey::Rsa(RsaPublicKey {
+      e: vec![0x01, 0x00, 0x01],
+      n,
+    });
+
+    let mut config = Config::default();
+    let err = check_key_size(&config, &key).unwrap_err();
+    assert!(err.to_string().contains("1024 bits"), "{err}");
+
+    config.rsa.min_bits = 1024;
+    assert!(check_key_size(&config, &key).is_ok());
+
+    config.rsa.min_bits = 4096;
+    config.rsa.allow_weak = vec![Fingerprint::from_key(&key, HashAlg::Sha256)?];
+    assert!(check_key_size(&config, &key).is_ok());
+    Ok(())
+  }
+}
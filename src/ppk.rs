@@ -0,0 +1,440 @@
+// ppk.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Parsing of private keys in PuTTY's `.ppk` file format.
+//!
+//! Versions 2 and 3 of the format are supported, both unencrypted and
+//! encrypted with a passphrase. The key data is stored in the SSH wire
+//! format, split into a public and a private part.
+
+use std::str::Lines;
+
+use aes::Aes256;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use argon2::Algorithm;
+use argon2::Argon2;
+use argon2::Params;
+use argon2::Version;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+
+use cbc::cipher::block_padding::NoPadding;
+use cbc::cipher::BlockDecryptMut as _;
+use cbc::cipher::KeyIvInit as _;
+use cbc::Decryptor;
+
+use ring::digest::digest;
+use ring::digest::SHA1_FOR_LEGACY_USE_ONLY as SHA1;
+use ring::hmac::verify;
+use ring::hmac::Key as HmacKey;
+use ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY as HMAC_SHA1;
+use ring::hmac::HMAC_SHA256;
+
+use ssh_agent_lib::proto::private_key::EcDsaPrivateKey;
+use ssh_agent_lib::proto::private_key::Ed25519PrivateKey;
+use ssh_agent_lib::proto::private_key::PrivateKey;
+use ssh_agent_lib::proto::private_key::RsaPrivateKey;
+
+use zeroize::Zeroizing;
+
+use crate::openssh::ed25519_key_pair;
+use crate::openssh::read_string;
+use crate::openssh::IncorrectPassphrase;
+
+
+/// The prefix of the first line of a PuTTY private key file, which is
+/// followed by the format version.
+const PPK_PREFIX: &str = "PuTTY-User-Key-File-";
+/// The string version 2 of the format derives its MAC key from.
+const MAC_KEY_PREFIX: &[u8] = b"putty-private-key-file-mac-key";
+/// The length of an AES-256 key.
+const AES_KEY_LEN: usize = 32;
+/// The block size of AES, which is also the length of the IV used.
+const AES_BLOCK_LEN: usize = 16;
+/// The length of the MAC key version 3 of the format derives.
+const MAC_KEY_LEN: usize = 32;
+/// The length of an Ed25519 seed.
+const ED25519_SEED_LEN: usize = 32;
+
+
+/// The parameters of the Argon2 key derivation used by version 3 of
+/// the format for encrypted keys.
+#[derive(Debug)]
+struct KeyDerivation {
+  algorithm: Algorithm,
+  memory: u32,
+  passes: u32,
+  parallelism: u32,
+  salt: Vec<u8>,
+}
+
+
+/// The contents of a PuTTY private key file.
+#[derive(Debug)]
+struct PpkFile<'s> {
+  version: u8,
+  algorithm: &'s str,
+  encryption: &'s str,
+  comment: &'s str,
+  public: Vec<u8>,
+  kdf: Option<KeyDerivation>,
+  private: Zeroizing<Vec<u8>>,
+  mac: Vec<u8>,
+}
+
+
+/// Read the value of the header with the given name from the next line.
+fn header<'s>(lines: &mut Lines<'s>, name: &str) -> Result<&'s str> {
+  lines
+    .next()
+    .and_then(|line| line.strip_prefix(name))
+    .and_then(|rest| rest.strip_prefix(':'))
+    .map(str::trim)
+    .ok_or_else(|| anyhow!("PuTTY private key is missing {name} header"))
+}
+
+
+/// Read the numeric value of the header with the given name.
+fn numeric_header(lines: &mut Lines<'_>, name: &str) -> Result<u32> {
+  let value = header(lines, name)?;
+  value
+    .parse()
+    .with_context(|| format!("PuTTY private key has invalid {name} value: {value}"))
+}
+
+
+/// Read a base64 encoded blob, preceded by a header with the given name
+/// stating the number of lines it spans.
+fn blob(lines: &mut Lines<'_>, name: &str) -> Result<Vec<u8>> {
+  let count = numeric_header(lines, name)?;
+  let base64 = lines.take(count as usize).collect::<String>();
+  BASE64
+    .decode(base64)
+    .with_context(|| format!("failed to base64 decode PuTTY {name}"))
+}
+
+
+/// Decode a hex string.
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+  ensure!(hex.len() % 2 == 0, "hex string has odd length");
+  (0..hex.len())
+    .step_by(2)
+    .map(|i| {
+      hex
+        .get(i..i + 2)
+        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        .ok_or_else(|| anyhow!("invalid hex string: {hex}"))
+    })
+    .collect()
+}
+
+
+/// Split a PuTTY private key file into its parts.
+fn parse_file(string: &str) -> Result<PpkFile<'_>> {
+  let mut lines = string.trim_start().lines();
+  let (version, algorithm) = lines
+    .next()
+    .and_then(|line| line.strip_prefix(PPK_PREFIX))
+    .and_then(|rest| rest.split_once(':'))
+    .ok_or_else(|| anyhow!("private key is not in PuTTY format"))?;
+  let version = match version {
+    "2" => 2,
+    "3" => 3,
+    _ => bail!("PuTTY private key format version {version} is not supported"),
+  };
+
+  let encryption = header(&mut lines, "Encryption")?;
+  let comment = header(&mut lines, "Comment")?;
+  let public = blob(&mut lines, "Public-Lines")?;
+
+  let kdf = if version == 3 && encryption != "none" {
+    let algorithm = match header(&mut lines, "Key-Derivation")? {
+      "Argon2d" => Algorithm::Argon2d,
+      "Argon2i" => Algorithm::Argon2i,
+      "Argon2id" => Algorithm::Argon2id,
+      kdf => bail!("PuTTY private key derivation function {kdf} is not supported"),
+    };
+    let kdf = KeyDerivation {
+      algorithm,
+      memory: numeric_header(&mut lines, "Argon2-Memory")?,
+      passes: numeric_header(&mut lines, "Argon2-Passes")?,
+      parallelism: numeric_header(&mut lines, "Argon2-Parallelism")?,
+      salt: from_hex(header(&mut lines, "Argon2-Salt")?)?,
+    };
+    Some(kdf)
+  } else {
+    None
+  };
+
+  let private = Zeroizing::new(blob(&mut lines, "Private-Lines")?);
+  let mac = from_hex(header(&mut lines, "Private-MAC")?)?;
+
+  let file = PpkFile {
+    version,
+    algorithm: algorithm.trim(),
+    encryption,
+    comment,
+    public,
+    kdf,
+    private,
+    mac,
+  };
+  Ok(file)
+}
+
+
+/// Decrypt the private part of a PuTTY private key in place, returning
+/// the key to verify its MAC with.
+fn decrypt(file: &mut PpkFile<'_>, passphrase: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+  let encrypted = match file.encryption {
+    "none" => false,
+    "aes256-cbc" => true,
+    cipher => bail!("PuTTY private key cipher {cipher} is not supported"),
+  };
+  ensure!(
+    !encrypted || file.private.len() % AES_BLOCK_LEN == 0,
+    "PuTTY private key data is malformed"
+  );
+
+  let mut cipher_key = Zeroizing::new(Vec::with_capacity(AES_KEY_LEN + AES_BLOCK_LEN));
+  let mac_key = if file.version == 2 {
+    if encrypted {
+      for counter in [0u32, 1] {
+        let mut input = Zeroizing::new(counter.to_be_bytes().to_vec());
+        let () = input.extend_from_slice(passphrase);
+        let () = cipher_key.extend_from_slice(digest(&SHA1, &input).as_ref());
+      }
+      // The key is made up of the first 32 bytes and the IV is all
+      // zeros.
+      let () = cipher_key.truncate(AES_KEY_LEN);
+      let () = cipher_key.resize(AES_KEY_LEN + AES_BLOCK_LEN, 0);
+    }
+
+    let mut input = Zeroizing::new(MAC_KEY_PREFIX.to_vec());
+    let () = input.extend_from_slice(passphrase);
+    Zeroizing::new(digest(&SHA1, &input).as_ref().to_vec())
+  } else if let Some(kdf) = &file.kdf {
+    let params = Params::new(
+      kdf.memory,
+      kdf.passes,
+      kdf.parallelism,
+      Some(AES_KEY_LEN + AES_BLOCK_LEN + MAC_KEY_LEN),
+    )
+    .map_err(|err| anyhow!("PuTTY private key derivation parameters are invalid: {err}"))?;
+    let argon2 = Argon2::new(kdf.algorithm, Version::V0x13, params);
+
+    let mut output = Zeroizing::new(vec![0; AES_KEY_LEN + AES_BLOCK_LEN + MAC_KEY_LEN]);
+    let () = argon2
+      .hash_password_into(passphrase, &kdf.salt, &mut output)
+      .map_err(|err| anyhow!("failed to derive PuTTY private key decryption key: {err}"))?;
+    let (key_iv, mac_key) = output.split_at(AES_KEY_LEN + AES_BLOCK_LEN);
+    let () = cipher_key.extend_from_slice(key_iv);
+    Zeroizing::new(mac_key.to_vec())
+  } else {
+    ensure!(!encrypted, "PuTTY private key lacks key derivation parameters");
+    // Unencrypted keys use an empty MAC key.
+    Zeroizing::new(Vec::new())
+  };
+
+  if encrypted {
+    let (key, iv) = cipher_key.split_at(AES_KEY_LEN);
+    let _ = Decryptor::<Aes256>::new(key.into(), iv.into())
+      .decrypt_padded_mut::<NoPadding>(&mut file.private)
+      .map_err(|err| anyhow!("failed to decrypt PuTTY private key: {err}"))?;
+  }
+  Ok(mac_key)
+}
+
+
+/// Verify the MAC of a (decrypted) PuTTY private key.
+fn verify_mac(file: &PpkFile<'_>, mac_key: &[u8]) -> Result<()> {
+  let mut data = Zeroizing::new(Vec::new());
+  for field in [
+    file.algorithm.as_bytes(),
+    file.encryption.as_bytes(),
+    file.comment.as_bytes(),
+    &file.public,
+    &file.private,
+  ] {
+    let () = data.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    let () = data.extend_from_slice(field);
+  }
+
+  let alg = if file.version == 2 {
+    HMAC_SHA1
+  } else {
+    HMAC_SHA256
+  };
+  match verify(&HmacKey::new(alg, mac_key), &data, &file.mac) {
+    Ok(()) => Ok(()),
+    // For encrypted keys, a mismatch is the sign of a wrong passphrase.
+    Err(_) if file.encryption != "none" => Err(IncorrectPassphrase.into()),
+    Err(_) => bail!("PuTTY private key MAC does not match"),
+  }
+}
+
+
+/// Assemble a private key from the public and the private part of a
+/// PuTTY private key.
+fn assemble_key(algorithm: &str, mut public: &[u8], mut private: &[u8]) -> Result<PrivateKey> {
+  ensure!(
+    read_string(&mut public)? == algorithm.as_bytes(),
+    "PuTTY public key does not match key type {algorithm}"
+  );
+
+  let key = match algorithm {
+    "ssh-rsa" => {
+      let e = read_string(&mut public)?.to_vec();
+      let n = read_string(&mut public)?.to_vec();
+      let rsa = RsaPrivateKey {
+        n,
+        e,
+        d: read_string(&mut private)?.to_vec(),
+        p: read_string(&mut private)?.to_vec(),
+        q: read_string(&mut private)?.to_vec(),
+        iqmp: read_string(&mut private)?.to_vec(),
+      };
+      PrivateKey::Rsa(rsa)
+    },
+    "ssh-ed25519" => {
+      let enc_a = read_string(&mut public)?.to_vec();
+      // PuTTY stores the seed as a little endian integer, stripping
+      // what it considers leading zeros.
+      let seed = read_string(&mut private)?;
+      ensure!(
+        seed.len() <= ED25519_SEED_LEN,
+        "Ed25519 private key has invalid length"
+      );
+      let mut k_enc_a = Vec::with_capacity(ED25519_SEED_LEN);
+      let () = k_enc_a.extend_from_slice(seed);
+      let () = k_enc_a.resize(ED25519_SEED_LEN, 0);
+      let ed25519 = Ed25519PrivateKey { enc_a, k_enc_a };
+      let _ = ed25519_key_pair(&ed25519)?;
+      PrivateKey::Ed25519(ed25519)
+    },
+    "ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384" | "ecdsa-sha2-nistp521" => {
+      let identifier = read_string(&mut public)?;
+      let ecdsa = EcDsaPrivateKey {
+        identifier: String::from_utf8_lossy(identifier).into_owned(),
+        q: read_string(&mut public)?.to_vec(),
+        d: read_string(&mut private)?.to_vec(),
+      };
+      PrivateKey::EcDsa(ecdsa)
+    },
+    _ => bail!("PuTTY private key type {algorithm} is not supported"),
+  };
+  Ok(key)
+}
+
+
+/// Check whether the given string contains a PuTTY private key.
+pub fn is_ppk(string: &str) -> bool {
+  string.trim_start().starts_with(PPK_PREFIX)
+}
+
+
+/// Check whether the given string contains a PuTTY private key that is
+/// protected by a passphrase.
+pub fn is_encrypted(string: &str) -> bool {
+  parse_file(string)
+    .map(|file| file.encryption != "none")
+    .unwrap_or(false)
+}
+
+
+/// Parse the PuTTY private key contained in the given string, along
+/// with its comment, decrypting it with the given passphrase if
+/// necessary.
+pub fn parse_private_key(
+  string: &str,
+  passphrase: Option<&[u8]>,
+) -> Result<(PrivateKey, String)> {
+  let mut file = parse_file(string)?;
+  let passphrase = if file.encryption != "none" {
+    passphrase.ok_or_else(|| anyhow!("private key is protected by a passphrase"))?
+  } else {
+    b""
+  };
+
+  let mac_key = decrypt(&mut file, passphrase)?;
+  let () = verify_mac(&file, &mac_key)?;
+  let key = assemble_key(file.algorithm, &file.public, &file.private)?;
+  Ok((key, file.comment.to_string()))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::fs::read_to_string;
+
+  use crate::openssh::parse_private_keys;
+
+
+  /// Check that we can parse unencrypted and encrypted PuTTY private
+  /// keys of both format versions.
+  #[test]
+  fn parse_ppk_keys() -> Result<()> {
+    for (name, file) in [
+      ("ecdsa256", "ecdsa256.v3.ppk"),
+      ("ed25519", "ed25519.v3.ppk"),
+      ("ed25519", "ed25519.v3.encrypted.ppk"),
+      ("rsa2048", "rsa2048.v2.ppk"),
+      ("rsa2048", "rsa2048.v2.encrypted.ppk"),
+    ] {
+      let openssh = read_to_string(format!("tests/valid_keys/{name}"))?;
+      let ppk = read_to_string(format!("tests/valid_keys/{file}"))?;
+      assert!(is_ppk(&ppk));
+      assert_eq!(is_encrypted(&ppk), file.contains("encrypted"));
+
+      let (expected, expected_comment) = parse_private_keys(&openssh, None)?.remove(0);
+      let (key, comment) = parse_private_key(&ppk, Some(b"passphrase")).context(file)?;
+      assert_eq!(key, expected, "{file}");
+      assert_eq!(comment, expected_comment);
+    }
+    Ok(())
+  }
+
+  /// Check that we detect incorrect passphrases and tampered keys.
+  #[test]
+  fn reject_invalid_ppk_keys() -> Result<()> {
+    for file in ["ed25519.v3.encrypted.ppk", "rsa2048.v2.encrypted.ppk"] {
+      let ppk = read_to_string(format!("tests/valid_keys/{file}"))?;
+      let err = parse_private_key(&ppk, Some(b"incorrect")).unwrap_err();
+      assert!(err.is::<IncorrectPassphrase>(), "{err:?}");
+
+      let err = parse_private_key(&ppk, None).unwrap_err();
+      assert!(err.to_string().contains("passphrase"), "{err}");
+    }
+
+    let ppk = read_to_string("tests/valid_keys/ed25519.v3.ppk")?;
+    let tampered = ppk.replace("Comment: ed25519 test", "Comment: tampered");
+    let err = parse_private_key(&tampered, None).unwrap_err();
+    assert!(err.to_string().contains("MAC"), "{err}");
+    Ok(())
+  }
+}
@@ -0,0 +1,626 @@
+// prompt.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Prompting of the user, e.g., for confirmation of a key usage or for
+//! a passphrase.
+//!
+//! Prompts are shown using the first available of the configured
+//! methods, which range from pinentry programs, driven via the Assuan
+//! protocol, over `zenity` to a plain prompt on the agent's controlling
+//! terminal.
+
+use std::env::var_os;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead as _;
+use std::io::BufReader;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read as _;
+use std::io::Write as _;
+use std::mem::MaybeUninit;
+use std::os::unix::io::AsRawFd as _;
+use std::process::Child;
+use std::process::ChildStdin;
+use std::process::ChildStdout;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::OnceLock;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use libc::poll;
+use libc::pollfd;
+use libc::tcgetattr;
+use libc::tcsetattr;
+use libc::termios;
+use libc::ECHO;
+use libc::POLLIN;
+use libc::TCSANOW;
+
+use tracing::debug_span;
+
+use zeroize::Zeroizing;
+
+use crate::assuan::escape;
+use crate::assuan::unescape;
+use crate::cancel::kill_on_cancel;
+use crate::cancel::on_cancel;
+use crate::cancel::Registration;
+use crate::config::PromptConfig;
+use crate::config::PromptMethod;
+
+
+/// The system's default pinentry program.
+const PINENTRY: &str = "pinentry";
+/// The graphical pinentry programs to try, in order.
+const PINENTRY_GUI: [&str; 4] = [
+  "pinentry-gnome3",
+  "pinentry-qt",
+  "pinentry-gtk-2",
+  "pinentry-fltk",
+];
+/// The pinentry program working on a terminal.
+const PINENTRY_CURSES: &str = "pinentry-curses";
+/// The program for showing graphical dialogs.
+const ZENITY: &str = "zenity";
+/// The path of the controlling terminal.
+const TTY: &str = "/dev/tty";
+
+
+/// The methods to use for prompting, as configured at startup.
+static METHODS: OnceLock<Vec<PromptMethod>> = OnceLock::new();
+
+
+/// Set the methods to try for prompting the user, in order.
+///
+/// This function should be called once, before any prompting happens.
+/// If it is not, the default methods are used.
+pub fn set_prompt_methods(methods: Vec<PromptMethod>) {
+  let _ = METHODS.set(methods);
+}
+
+
+/// Retrieve the methods to try for prompting the user.
+fn methods() -> &'static [PromptMethod] {
+  METHODS.get_or_init(|| PromptConfig::default().methods)
+}
+
+
+/// Retrieve a human readable name of the given prompt method.
+fn method_name(method: PromptMethod) -> &'static str {
+  match method {
+    PromptMethod::Pinentry => PINENTRY,
+    PromptMethod::PinentryGui => "graphical pinentry",
+    PromptMethod::PinentryCurses => PINENTRY_CURSES,
+    PromptMethod::Zenity => ZENITY,
+    PromptMethod::Tty => "terminal",
+  }
+}
+
+
+/// Check whether a graphical session is available to show dialogs in.
+fn has_display() -> bool {
+  var_os("DISPLAY").is_some() || var_os("WAYLAND_DISPLAY").is_some()
+}
+
+
+/// Check whether the agent has a controlling terminal.
+fn has_tty() -> bool {
+  OpenOptions::new().read(true).write(true).open(TTY).is_ok()
+}
+
+
+/// A session with a running pinentry process.
+struct Pinentry {
+  /// The name of the pinentry program.
+  program: String,
+  /// The pinentry process.
+  child: Child,
+  /// The process' standard input, which we send commands to.
+  stdin: ChildStdin,
+  /// The process' standard output, which we read responses from.
+  stdout: BufReader<ChildStdout>,
+  /// The registration terminating the process when the request on
+  /// whose behalf it runs gets cancelled, until the process exited.
+  cancel: Option<Registration>,
+}
+
+impl Pinentry {
+  /// Start the given pinentry program.
+  ///
+  /// Returns `None` if the program is not available.
+  fn spawn(program: &str) -> Result<Option<Self>> {
+    let child = Command::new(program)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::null())
+      .spawn();
+
+    let mut child = match child {
+      Ok(child) => child,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+      Err(err) => return Err(err).with_context(|| format!("failed to run {program}")),
+    };
+
+    // Both handles are present, because we requested pipes.
+    let stdin = child.stdin.take().unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let cancel = kill_on_cancel(&child);
+    let mut pinentry = Self {
+      program: program.to_string(),
+      child,
+      stdin,
+      stdout,
+      cancel: Some(cancel),
+    };
+
+    let (greeting, _) = pinentry.read_response()?;
+    if !greeting.starts_with("OK") {
+      return Err(anyhow!("{program} reported an error: {greeting}"))
+    }
+    Ok(Some(pinentry))
+  }
+
+  /// Read the response to a command, returning the final status line
+  /// as well as any data sent along.
+  fn read_response(&mut self) -> Result<(String, Zeroizing<String>)> {
+    let mut data = Zeroizing::new(String::new());
+    let mut line = Zeroizing::new(String::new());
+    loop {
+      line.clear();
+      let count = self
+        .stdout
+        .read_line(&mut line)
+        .with_context(|| format!("failed to read from {}", self.program))?;
+      if count == 0 {
+        return Err(anyhow!("{} terminated unexpectedly", self.program))
+      }
+
+      if let Some(line) = line.strip_prefix("D ") {
+        let line = Zeroizing::new(unescape(line.trim_end_matches(['\r', '\n']).as_bytes()));
+        data.push_str(&String::from_utf8_lossy(&line));
+      } else if line.starts_with("OK") || line.starts_with("ERR") {
+        return Ok((line.trim_end().to_string(), data))
+      }
+      // Skip over status and comment lines.
+    }
+  }
+
+  /// Send a command, returning whether it succeeded as well as any data
+  /// sent along with the response.
+  fn command(&mut self, command: &str) -> Result<(bool, Zeroizing<String>)> {
+    let () = self
+      .stdin
+      .write_all(format!("{command}\n").as_bytes())
+      .with_context(|| format!("failed to write to {}", self.program))?;
+    let (status, data) = self.read_response()?;
+    Ok((status.starts_with("OK"), data))
+  }
+}
+
+impl Drop for Pinentry {
+  fn drop(&mut self) {
+    let _ = self.stdin.write_all(b"BYE\n");
+    let _ = match self.cancel.take() {
+      Some(cancel) => cancel.wait(&mut self.child),
+      None => self.child.wait(),
+    };
+  }
+}
+
+
+/// Start a pinentry program as per the given prompt method.
+///
+/// Returns `None` if none is available.
+fn start_pinentry(method: PromptMethod) -> Result<Option<Pinentry>> {
+  match method {
+    PromptMethod::Pinentry => Pinentry::spawn(PINENTRY),
+    PromptMethod::PinentryGui => {
+      if !has_display() {
+        return Ok(None)
+      }
+      for program in PINENTRY_GUI {
+        if let Some(pinentry) = Pinentry::spawn(program)? {
+          return Ok(Some(pinentry))
+        }
+      }
+      Ok(None)
+    },
+    PromptMethod::PinentryCurses => {
+      let term = match var_os("TERM") {
+        Some(term) if has_tty() => term,
+        _ => return Ok(None),
+      };
+      let mut pinentry = match Pinentry::spawn(PINENTRY_CURSES)? {
+        Some(pinentry) => pinentry,
+        None => return Ok(None),
+      };
+      let term = term.to_string_lossy();
+      let _ = pinentry.command(&format!("OPTION ttyname={TTY}"))?;
+      let _ = pinentry.command(&format!("OPTION ttytype={}", escape(&term)))?;
+      Ok(Some(pinentry))
+    },
+    PromptMethod::Zenity | PromptMethod::Tty => Ok(None),
+  }
+}
+
+
+/// Ask the user for confirmation using the given pinentry session.
+fn confirm_pinentry(mut pinentry: Pinentry, description: &str) -> Result<bool> {
+
+  let _ = pinentry.command(&format!("SETDESC {}", escape(description)))?;
+  let _ = pinentry.command("SETPROMPT Confirm")?;
+  let (confirmed, _) = pinentry.command("CONFIRM")?;
+  Ok(confirmed)
+}
+
+
+/// Ask the user for a passphrase using the given pinentry session.
+///
+/// Returns `None` if the user canceled the prompt.
+fn passphrase_pinentry(
+  mut pinentry: Pinentry,
+  description: &str,
+) -> Result<Option<Zeroizing<String>>> {
+
+  let _ = pinentry.command(&format!("SETDESC {}", escape(description)))?;
+  let _ = pinentry.command("SETPROMPT Passphrase:")?;
+  let (entered, passphrase) = pinentry.command("GETPIN")?;
+  Ok(entered.then_some(passphrase))
+}
+
+
+/// Ask the user for confirmation using zenity.
+///
+/// Returns `None` if zenity is not available.
+fn confirm_zenity(description: &str) -> Result<Option<bool>> {
+  let result = Command::new(ZENITY)
+    .arg("--question")
+    .arg("--text")
+    .arg(description)
+    .stdin(Stdio::null())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn()
+    .and_then(|mut child| kill_on_cancel(&child).wait(&mut child));
+
+  match result {
+    Ok(status) => Ok(Some(status.success())),
+    Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+    Err(err) => Err(err).with_context(|| format!("failed to run {ZENITY}")),
+  }
+}
+
+
+/// Ask the user for a passphrase using zenity.
+///
+/// Returns `None` if zenity is not available and `Some(None)` if the
+/// user canceled the prompt.
+fn passphrase_zenity(description: &str) -> Result<Option<Option<Zeroizing<String>>>> {
+  let result = Command::new(ZENITY)
+    .arg("--password")
+    .arg("--title")
+    .arg(description)
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn()
+    .and_then(|child| kill_on_cancel(&child).wait_with_output(child));
+
+  match result {
+    Ok(output) if output.status.success() => {
+      let passphrase = Zeroizing::new(output.stdout);
+      let passphrase = String::from_utf8_lossy(&passphrase);
+      let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+      Ok(Some(Some(Zeroizing::new(passphrase.to_string()))))
+    },
+    Ok(_) => Ok(Some(None)),
+    Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+    Err(err) => Err(err).with_context(|| format!("failed to run {ZENITY}")),
+  }
+}
+
+
+/// The agent's controlling terminal, used for prompting directly.
+struct Tty {
+  /// The terminal device.
+  file: File,
+  /// Whether the request on whose behalf we prompt got cancelled.
+  cancelled: Arc<AtomicBool>,
+  /// The registration flagging cancellation of the request.
+  _cancel: Registration,
+}
+
+impl Tty {
+  /// Open the controlling terminal.
+  ///
+  /// Returns `None` if the agent does not have one.
+  fn open() -> Option<Self> {
+    let file = OpenOptions::new().read(true).write(true).open(TTY).ok()?;
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&cancelled);
+    let cancel = on_cancel(move || flag.store(true, Ordering::Relaxed));
+    let tty = Self {
+      file,
+      cancelled,
+      _cancel: cancel,
+    };
+    Some(tty)
+  }
+
+  /// Write the given text to the terminal.
+  fn write(&mut self, text: &str) -> Result<()> {
+    let () = self
+      .file
+      .write_all(text.as_bytes())
+      .and_then(|()| self.file.flush())
+      .context("failed to write to terminal")?;
+    Ok(())
+  }
+
+  /// Wait for input to become available, checking for cancellation
+  /// periodically.
+  fn wait(&self) -> Result<()> {
+    loop {
+      if self.cancelled.load(Ordering::Relaxed) {
+        bail!("prompt got cancelled")
+      }
+
+      let mut fd = pollfd {
+        fd: self.file.as_raw_fd(),
+        events: POLLIN,
+        revents: 0,
+      };
+      // SAFETY: `fd` is a valid `pollfd` object and we pass in a count
+      //         of one.
+      let rc = unsafe { poll(&mut fd, 1, 100) };
+      match rc {
+        0 => continue,
+        rc if rc > 0 => return Ok(()),
+        _ => {
+          let err = Error::last_os_error();
+          if err.kind() != ErrorKind::Interrupted {
+            return Err(err).context("failed to wait for terminal input")
+          }
+        },
+      }
+    }
+  }
+
+  /// Read a line of input, optionally without echoing it.
+  ///
+  /// Returns `None` if the end of input was reached before a line got
+  /// completed.
+  fn read_line(&mut self, echo: bool) -> Result<Option<Zeroizing<String>>> {
+    let _guard = if echo {
+      None
+    } else {
+      Some(EchoGuard::disable(&self.file)?)
+    };
+
+    let mut line = Zeroizing::new(Vec::new());
+    loop {
+      let () = self.wait()?;
+      let mut byte = [0];
+      let count = self
+        .file
+        .read(&mut byte)
+        .context("failed to read from terminal")?;
+      match (count, byte[0]) {
+        (0, _) => return Ok(None),
+        (_, b'\n') => break,
+        (_, byte) => line.push(byte),
+      }
+    }
+
+    let line = String::from_utf8_lossy(&line);
+    Ok(Some(Zeroizing::new(line.trim_end_matches('\r').to_string())))
+  }
+}
+
+
+/// A guard disabling the echoing of input on a terminal while alive.
+struct EchoGuard {
+  /// The file descriptor of the terminal.
+  fd: i32,
+  /// The original terminal attributes.
+  attrs: termios,
+}
+
+impl EchoGuard {
+  /// Disable echoing on the given terminal.
+  fn disable(file: &File) -> Result<Self> {
+    let fd = file.as_raw_fd();
+    let mut attrs = MaybeUninit::<termios>::uninit();
+    // SAFETY: `attrs` is valid for writes of a `termios` object.
+    if unsafe { tcgetattr(fd, attrs.as_mut_ptr()) } != 0 {
+      return Err(Error::last_os_error()).context("failed to retrieve terminal attributes")
+    }
+    // SAFETY: `tcgetattr` succeeded and initialized the object.
+    let attrs = unsafe { attrs.assume_init() };
+    let mut silent = attrs;
+    silent.c_lflag &= !ECHO;
+    // SAFETY: `silent` is a valid `termios` object.
+    if unsafe { tcsetattr(fd, TCSANOW, &silent) } != 0 {
+      return Err(Error::last_os_error()).context("failed to disable terminal echo")
+    }
+    Ok(Self { fd, attrs })
+  }
+}
+
+impl Drop for EchoGuard {
+  fn drop(&mut self) {
+    // SAFETY: `attrs` is a valid `termios` object.
+    let _ = unsafe { tcsetattr(self.fd, TCSANOW, &self.attrs) };
+  }
+}
+
+
+/// Ask the user for confirmation on the agent's controlling terminal.
+///
+/// Returns `None` if the agent does not have one.
+fn confirm_tty(description: &str) -> Result<Option<bool>> {
+  let mut tty = match Tty::open() {
+    Some(tty) => tty,
+    None => return Ok(None),
+  };
+
+  let () = tty.write(&format!("{description}\nConfirm? [y/N] "))?;
+  let answer = tty.read_line(true)?;
+  let confirmed = answer
+    .map(|answer| {
+      let answer = answer.trim();
+      answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+    })
+    .unwrap_or(false);
+  Ok(Some(confirmed))
+}
+
+
+/// Ask the user for a passphrase on the agent's controlling terminal.
+///
+/// Returns `None` if the agent does not have one and `Some(None)` if
+/// the user canceled the prompt.
+fn passphrase_tty(description: &str) -> Result<Option<Option<Zeroizing<String>>>> {
+  let mut tty = match Tty::open() {
+    Some(tty) => tty,
+    None => return Ok(None),
+  };
+
+  let () = tty.write(&format!("{description}\nPassphrase: "))?;
+  let passphrase = tty.read_line(false);
+  let () = tty.write("\n")?;
+  Ok(Some(passphrase?))
+}
+
+
+/// Create the error reported if none of the prompt methods is
+/// available.
+fn unavailable() -> anyhow::Error {
+  let tried = methods()
+    .iter()
+    .map(|method| method_name(*method))
+    .collect::<Vec<_>>()
+    .join(", ");
+  anyhow!("no means of prompting the user is available (tried {tried})")
+}
+
+
+/// Ask the user to confirm an action with the given description.
+///
+/// The configured prompt methods are tried in order.
+pub fn confirm(description: &str) -> Result<bool> {
+  for method in methods() {
+    let _span = debug_span!("prompt", method = method_name(*method)).entered();
+    let confirmed = match method {
+      PromptMethod::Zenity => confirm_zenity(description)?,
+      PromptMethod::Tty => confirm_tty(description)?,
+      _ => match start_pinentry(*method)? {
+        Some(pinentry) => Some(confirm_pinentry(pinentry, description)?),
+        None => None,
+      },
+    };
+
+    if let Some(confirmed) = confirmed {
+      return Ok(confirmed)
+    }
+  }
+  Err(unavailable())
+}
+
+
+/// Ask the user for a passphrase, showing the given description.
+///
+/// The configured prompt methods are tried in order. `None` is returned
+/// if the user canceled the prompt.
+pub fn passphrase(description: &str) -> Result<Option<Zeroizing<String>>> {
+  for method in methods() {
+    let _span = debug_span!("prompt", method = method_name(*method)).entered();
+    let passphrase = match method {
+      PromptMethod::Zenity => passphrase_zenity(description)?,
+      PromptMethod::Tty => passphrase_tty(description)?,
+      _ => match start_pinentry(*method)? {
+        Some(pinentry) => Some(passphrase_pinentry(pinentry, description)?),
+        None => None,
+      },
+    };
+
+    if let Some(passphrase) = passphrase {
+      return Ok(passphrase)
+    }
+  }
+  Err(unavailable())
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::fs::set_permissions;
+  use std::fs::write;
+  use std::fs::Permissions;
+  use std::os::unix::fs::PermissionsExt as _;
+
+  use tempfile::tempdir;
+
+
+  /// A pinentry replacement entering a fixed passphrase and declining
+  /// confirmations.
+  const FAKE_PINENTRY: &str = r##"#!/bin/sh
+echo "OK Pleased to meet you"
+while read -r command rest; do
+  case "$command" in
+    GETPIN) echo "# a comment"; echo "D pass%25word"; echo "OK";;
+    CONFIRM) echo "ERR 83886179 Operation cancelled";;
+    BYE) echo "OK closing connection"; exit 0;;
+    *) echo "OK";;
+  esac
+done
+"##;
+
+
+  /// Check that we can interact with a pinentry program.
+  #[test]
+  fn pinentry_interaction() -> Result<()> {
+    let dir = tempdir()?;
+    let program = dir.path().join("pinentry");
+    let () = write(&program, FAKE_PINENTRY)?;
+    let () = set_permissions(&program, Permissions::from_mode(0o700))?;
+    let program = program.to_str().unwrap();
+
+    let pinentry = Pinentry::spawn(program)?.unwrap();
+    let passphrase = passphrase_pinentry(pinentry, "Enter passphrase")?;
+    assert_eq!(passphrase.as_deref().map(String::as_str), Some("pass%word"));
+
+    let pinentry = Pinentry::spawn(program)?.unwrap();
+    assert!(!confirm_pinentry(pinentry, "Use key?")?);
+
+    let missing = dir.path().join("missing");
+    assert!(Pinentry::spawn(missing.to_str().unwrap())?.is_none());
+    Ok(())
+  }
+}
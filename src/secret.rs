@@ -0,0 +1,136 @@
+// secret.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Handling of secret data in memory.
+
+use std::fmt::Debug;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::ops::Deref;
+
+use libc::c_void;
+use libc::mlock;
+use libc::munlock;
+
+use ssh_agent_lib::proto::private_key::PrivateKey;
+
+use zeroize::Zeroize as _;
+
+
+/// Overwrite all secret material of the given private key.
+pub fn wipe(key: &mut PrivateKey) {
+  match key {
+    PrivateKey::Dss(key) => key.x.zeroize(),
+    PrivateKey::Ed25519(key) => key.k_enc_a.zeroize(),
+    PrivateKey::SkEd25519(key) => key.key_handle.zeroize(),
+    PrivateKey::Rsa(key) => {
+      key.d.zeroize();
+      key.iqmp.zeroize();
+      key.p.zeroize();
+      key.q.zeroize();
+    },
+    PrivateKey::EcDsa(key) => key.d.zeroize(),
+    PrivateKey::SkEcDsa(key) => key.key_handle.zeroize(),
+  }
+}
+
+
+/// A buffer holding secret data, such as a decrypted private key.
+///
+/// The buffer's memory is locked, so that it does not get swapped out,
+/// and wiped once the buffer is dropped. Locking is best effort only, as
+/// it may fail due to resource limits.
+pub struct SecretBuffer {
+  /// The secret data.
+  data: Vec<u8>,
+  /// Whether we managed to lock the data's memory.
+  locked: bool,
+}
+
+impl SecretBuffer {
+  /// Retrieve the secret data.
+  pub fn as_slice(&self) -> &[u8] {
+    &self.data
+  }
+}
+
+impl From<Vec<u8>> for SecretBuffer {
+  fn from(data: Vec<u8>) -> Self {
+    let locked = data.capacity() != 0
+      // SAFETY: The pointer and capacity describe the vector's valid
+      //         allocation, which lives as long as we do and never gets
+      //         reallocated, as we don't hand out mutable access.
+      && unsafe { mlock(data.as_ptr().cast::<c_void>(), data.capacity()) } == 0;
+    Self { data, locked }
+  }
+}
+
+impl Deref for SecretBuffer {
+  type Target = [u8];
+
+  fn deref(&self) -> &Self::Target {
+    &self.data
+  }
+}
+
+impl AsRef<[u8]> for SecretBuffer {
+  fn as_ref(&self) -> &[u8] {
+    &self.data
+  }
+}
+
+impl Debug for SecretBuffer {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    // Never print the secret itself.
+    f.debug_struct("SecretBuffer")
+      .field("len", &self.data.len())
+      .finish_non_exhaustive()
+  }
+}
+
+impl Drop for SecretBuffer {
+  fn drop(&mut self) {
+    // Wiping covers the vector's entire capacity, not just its length.
+    let () = self.data.zeroize();
+    if self.locked {
+      // SAFETY: Wiping does not reallocate, so we unlock exactly the
+      //         allocation we locked earlier.
+      let _ = unsafe { munlock(self.data.as_ptr().cast::<c_void>(), self.data.capacity()) };
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+
+  /// Check that secret data is accessible but not printed.
+  #[test]
+  fn secret_buffer() {
+    let buffer = SecretBuffer::from(b"secret".to_vec());
+    assert_eq!(buffer.as_slice(), b"secret");
+    assert_eq!(&*buffer, b"secret");
+
+    let debug = format!("{buffer:?}");
+    assert!(!debug.contains("secret"), "{debug}");
+    assert!(debug.contains("len: 6"), "{debug}");
+  }
+}
@@ -0,0 +1,439 @@
+// session.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Tracking of the SSH sessions agent connections are bound to.
+//!
+//! Starting with version 8.9, OpenSSH's `ssh` binds every connection to
+//! the agent to the SSH session it uses it for, by means of the
+//! `session-bind@openssh.com` extension. When the connection is
+//! forwarded to a remote host, `ssh` running there in turn binds it to
+//! its own session, resulting in a chain of bindings that describes the
+//! path a request took.
+//!
+//! Each binding is signed by the host the session is with, so that it
+//! cannot be forged by a process merely relaying the connection.
+
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Result as FmtResult;
+use std::str::from_utf8;
+use std::sync::Mutex;
+
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use ssh_agent_lib::proto::from_bytes;
+use ssh_agent_lib::proto::public_key::PublicKey;
+use ssh_agent_lib::proto::signature::Signature;
+
+use crate::fingerprint::Fingerprint;
+use crate::openssh::read_string;
+use crate::sign::verify;
+
+
+/// The name of the agent protocol extension binding a connection to an
+/// SSH session.
+pub const SESSION_BIND_EXTENSION: &str = "session-bind@openssh.com";
+
+/// The maximum number of bindings per connection, in line with what
+/// OpenSSH's agent accepts.
+const MAX_BINDINGS: usize = 16;
+
+/// The type of the message requesting user authentication.
+const SSH_MSG_USERAUTH_REQUEST: u8 = 50;
+/// The name of the public key user authentication method.
+const PUBLICKEY_METHOD: &[u8] = b"publickey";
+/// The name of the public key user authentication method that includes
+/// the server's host key in the data to sign.
+const PUBLICKEY_HOSTBOUND_METHOD: &[u8] = b"publickey-hostbound-v00@openssh.com";
+
+
+/// The binding of an agent connection to an SSH session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Binding {
+  /// The public key blob of the host the session is with.
+  pub host_key: Vec<u8>,
+  /// The identifier of the session.
+  pub session_id: Vec<u8>,
+  /// The signature of the session identifier made by the host key.
+  pub signature: Vec<u8>,
+  /// Whether the connection is getting forwarded over the session, as
+  /// opposed to being used for authenticating it.
+  pub forwarding: bool,
+}
+
+impl Binding {
+  /// Parse the fields of a `session-bind@openssh.com` extension
+  /// request.
+  fn parse_fields(mut data: &[u8]) -> Result<Self> {
+    let host_key = read_string(&mut data)?.to_vec();
+    let session_id = read_string(&mut data)?.to_vec();
+    let signature = read_string(&mut data)?.to_vec();
+    let (forwarding, rest) = data
+      .split_first()
+      .with_context(|| "forwarding flag is missing")?;
+    ensure!(rest.is_empty(), "request is followed by trailing data");
+
+    let binding = Self {
+      host_key,
+      session_id,
+      signature,
+      forwarding: *forwarding != 0,
+    };
+    Ok(binding)
+  }
+
+  /// Parse the contents of a `session-bind@openssh.com` extension
+  /// request.
+  fn parse(data: &[u8]) -> Result<Self> {
+    Self::parse_fields(data).with_context(|| "session binding request is malformed")
+  }
+
+  /// Verify that the session identifier got signed by the host key.
+  fn verify(&self) -> Result<()> {
+    let key = from_bytes::<PublicKey>(&self.host_key)
+      .with_context(|| format!("failed to parse host key {}", self.host_fingerprint()))?;
+    let signature =
+      from_bytes::<Signature>(&self.signature).with_context(|| "failed to parse signature")?;
+    verify(&key, &signature, &self.session_id).with_context(|| {
+      format!(
+        "failed to verify session signature of host {}",
+        self.host_fingerprint()
+      )
+    })
+  }
+
+  /// Retrieve the fingerprint of the host key.
+  pub fn host_fingerprint(&self) -> Fingerprint {
+    Fingerprint::sha256(&self.host_key)
+  }
+}
+
+impl Display for Binding {
+  fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+    let fingerprint = self.host_fingerprint();
+    if self.forwarding {
+      write!(f, "{fingerprint} (forwarding)")
+    } else {
+      write!(f, "{fingerprint}")
+    }
+  }
+}
+
+
+/// The bindings of a connection.
+#[derive(Debug, Default)]
+struct Bindings {
+  /// The bindings, in the order they got established.
+  bindings: Vec<Binding>,
+  /// Whether an attempt to bind the connection failed.
+  failed: bool,
+}
+
+impl Bindings {
+  /// Add the given binding, enforcing the same rules as OpenSSH's
+  /// agent.
+  fn add(&mut self, binding: Binding) -> Result<bool> {
+    if let Some(existing) = self
+      .bindings
+      .iter()
+      .find(|existing| existing.session_id == binding.session_id)
+    {
+      // Rebinding the same session is harmless.
+      ensure!(
+        existing.host_key == binding.host_key && existing.forwarding == binding.forwarding,
+        "session is already bound differently"
+      );
+      return Ok(false)
+    }
+
+    // A connection used for authenticating a session can't be handed
+    // on to another one.
+    if let Some(last) = self.bindings.last() {
+      if !last.forwarding {
+        bail!("connection is already bound to a session for authentication")
+      }
+    }
+    ensure!(
+      self.bindings.len() < MAX_BINDINGS,
+      "connection is bound to too many sessions"
+    );
+
+    let () = binding.verify()?;
+    let () = self.bindings.push(binding);
+    Ok(true)
+  }
+}
+
+
+/// The state of a single client connection, in terms of the SSH
+/// sessions it is bound to.
+#[derive(Debug, Default)]
+pub struct Session {
+  /// The connection's bindings.
+  bindings: Mutex<Bindings>,
+}
+
+impl Session {
+  /// Bind the connection to another SSH session, as requested by the
+  /// given `session-bind@openssh.com` extension request contents.
+  ///
+  /// The newly established binding is returned, unless the connection
+  /// already was bound to the session.
+  pub fn bind(&self, data: &[u8]) -> Result<Option<Binding>> {
+    let mut bindings = self.bindings.lock().unwrap();
+    let result = Binding::parse(data).and_then(|binding| {
+      let added = bindings.add(binding.clone())?;
+      Ok(added.then_some(binding))
+    });
+    if result.is_err() {
+      bindings.failed = true;
+    }
+    result
+  }
+
+  /// Retrieve the connection's bindings, in the order they got
+  /// established.
+  ///
+  /// Once an attempt to bind the connection failed, its bindings can no
+  /// longer be relied upon and an error is reported.
+  pub fn bindings(&self) -> Result<Vec<Binding>> {
+    let bindings = self.bindings.lock().unwrap();
+    ensure!(
+      !bindings.failed,
+      "binding the connection to a session failed earlier"
+    );
+    Ok(bindings.bindings.clone())
+  }
+}
+
+
+/// A public key user authentication request, as signed by a client
+/// authenticating to an SSH server.
+#[derive(Debug, PartialEq)]
+pub struct UserAuthRequest<'data> {
+  /// The identifier of the session the request is made in.
+  pub session_id: &'data [u8],
+  /// The name of the user to authenticate as.
+  pub user: &'data str,
+  /// The server's host key, if the client included it.
+  pub host_key: Option<&'data [u8]>,
+}
+
+impl<'data> UserAuthRequest<'data> {
+  /// Parse the given data to sign as a request to authenticate with the
+  /// key with the provided blob, in the same way OpenSSH's agent does.
+  pub fn parse(mut data: &'data [u8], key_blob: &[u8]) -> Result<Self> {
+    let session_id = read_string(&mut data)?;
+    let (&message_type, rest) = data
+      .split_first()
+      .with_context(|| "message type is missing")?;
+    ensure!(
+      message_type == SSH_MSG_USERAUTH_REQUEST,
+      "data is not a user authentication request"
+    );
+    data = rest;
+
+    let user = from_utf8(read_string(&mut data)?).context("user name is not valid UTF-8")?;
+    let service = read_string(&mut data)?;
+    ensure!(service == b"ssh-connection", "request is for unexpected service");
+    let hostbound = match read_string(&mut data)? {
+      PUBLICKEY_METHOD => false,
+      PUBLICKEY_HOSTBOUND_METHOD => true,
+      _ => bail!("request is for unexpected authentication method"),
+    };
+    let (&has_signature, rest) = data
+      .split_first()
+      .with_context(|| "signature flag is missing")?;
+    ensure!(has_signature != 0, "request does not carry a signature");
+    data = rest;
+
+    let _algorithm = read_string(&mut data)?;
+    let blob = read_string(&mut data)?;
+    ensure!(blob == key_blob, "request is for a different key");
+    let host_key = if hostbound {
+      Some(read_string(&mut data)?)
+    } else {
+      None
+    };
+    ensure!(data.is_empty(), "request is followed by trailing data");
+
+    let request = Self {
+      session_id,
+      user,
+      host_key,
+    };
+    Ok(request)
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use ring::signature::Ed25519KeyPair;
+  use ring::signature::KeyPair as _;
+
+  use ssh_agent_lib::proto::public_key::Ed25519PublicKey;
+  use ssh_agent_lib::proto::to_bytes;
+
+
+  /// Create the contents of a `session-bind@openssh.com` request for
+  /// the given session, signed by the host key with the provided seed.
+  fn bind_request(seed: u8, session_id: &[u8], forwarding: bool) -> Vec<u8> {
+    let key_pair = Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap();
+    let host_key = PublicKey::Ed25519(Ed25519PublicKey {
+      enc_a: key_pair.public_key().as_ref().to_vec(),
+    });
+    let signature = Signature {
+      algorithm: "ssh-ed25519".to_string(),
+      blob: key_pair.sign(session_id).as_ref().to_vec(),
+    };
+
+    let mut data = Vec::new();
+    for string in [
+      to_bytes(&host_key).unwrap(),
+      session_id.to_vec(),
+      to_bytes(&signature).unwrap(),
+    ] {
+      let () = data.extend((string.len() as u32).to_be_bytes());
+      let () = data.extend(string);
+    }
+    let () = data.push(u8::from(forwarding));
+    data
+  }
+
+
+  /// Check that we can parse session binding requests.
+  #[test]
+  fn binding_parsing() -> Result<()> {
+    let data = b"\x00\x00\x00\x03key\x00\x00\x00\x02id\x00\x00\x00\x03sig\x01";
+    let binding = Binding::parse(data)?;
+    let expected = Binding {
+      host_key: b"key".to_vec(),
+      session_id: b"id".to_vec(),
+      signature: b"sig".to_vec(),
+      forwarding: true,
+    };
+    assert_eq!(binding, expected);
+
+    assert!(Binding::parse(&data[..data.len() - 1]).is_err());
+    assert!(Binding::parse(&[data.as_slice(), b"\x00"].concat()).is_err());
+    assert!(Binding::parse(b"\x00\x00\x00\x03ke").is_err());
+    Ok(())
+  }
+
+  /// Check that connections get bound to sessions as expected.
+  #[test]
+  fn binding() -> Result<()> {
+    let session = Session::default();
+    let binding = session.bind(&bind_request(1, b"session1", true))?.unwrap();
+    assert!(binding.forwarding);
+    assert_eq!(binding.session_id, b"session1");
+    // Binding the same session again has no effect.
+    assert_eq!(session.bind(&bind_request(1, b"session1", true))?, None);
+
+    let _binding = session.bind(&bind_request(2, b"session2", false))?.unwrap();
+    let bindings = session.bindings()?;
+    assert_eq!(bindings.len(), 2);
+    assert!(!bindings[1].forwarding);
+
+    // The connection got used for authentication and can't be bound
+    // any further.
+    assert!(session.bind(&bind_request(3, b"session3", false)).is_err());
+    assert!(session.bindings().is_err());
+    Ok(())
+  }
+
+  /// Check that bindings with invalid signatures are rejected.
+  #[test]
+  fn binding_forged() {
+    let session = Session::default();
+    let mut data = bind_request(1, b"session1", false);
+    let len = data.len();
+    data[len - 2] ^= 1;
+    assert!(session.bind(&data).is_err());
+    assert!(session.bindings().is_err());
+  }
+
+  /// Check that a session can't be rebound to a different host.
+  #[test]
+  fn binding_conflict() -> Result<()> {
+    let session = Session::default();
+    let _binding = session.bind(&bind_request(1, b"session1", true))?;
+    assert!(session.bind(&bind_request(2, b"session1", true)).is_err());
+    Ok(())
+  }
+
+  /// Check that the number of bindings of a connection is limited.
+  #[test]
+  fn binding_limit() -> Result<()> {
+    let session = Session::default();
+    for idx in 0..MAX_BINDINGS {
+      let _binding = session.bind(&bind_request(1, &idx.to_be_bytes(), true))?;
+    }
+    assert!(session.bind(&bind_request(1, b"session", true)).is_err());
+    Ok(())
+  }
+
+  /// Check that we can parse user authentication requests.
+  #[test]
+  fn userauth_request_parsing() -> Result<()> {
+    let string = |s: &[u8]| [&(s.len() as u32).to_be_bytes(), s].concat();
+    let request = |method: &[u8], host_key: &[u8]| {
+      [
+        string(b"session1"),
+        vec![SSH_MSG_USERAUTH_REQUEST],
+        string(b"deso"),
+        string(b"ssh-connection"),
+        string(method),
+        vec![1],
+        string(b"ssh-ed25519"),
+        string(b"key"),
+        host_key.to_vec(),
+      ]
+      .concat()
+    };
+
+    let data = request(PUBLICKEY_METHOD, b"");
+    let parsed = UserAuthRequest::parse(&data, b"key")?;
+    let expected = UserAuthRequest {
+      session_id: b"session1",
+      user: "deso",
+      host_key: None,
+    };
+    assert_eq!(parsed, expected);
+    assert!(UserAuthRequest::parse(&data, b"other").is_err());
+    assert!(UserAuthRequest::parse(&data[..data.len() - 1], b"key").is_err());
+
+    let data = request(PUBLICKEY_HOSTBOUND_METHOD, &string(b"host"));
+    let parsed = UserAuthRequest::parse(&data, b"key")?;
+    assert_eq!(parsed.host_key, Some(b"host".as_slice()));
+
+    let data = request(b"password", b"");
+    assert!(UserAuthRequest::parse(&data, b"key").is_err());
+    // SSHSIG signatures, as created by `ssh-keygen -Y sign`, are not
+    // authentication requests.
+    assert!(UserAuthRequest::parse(b"SSHSIG\x00\x00\x00\x04file", b"key").is_err());
+    Ok(())
+  }
+}
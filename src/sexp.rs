@@ -0,0 +1,179 @@
+// sexp.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Parsing of canonical S-expressions, as used by `gpg-agent` for
+//! exchanging keys and signatures.
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Result;
+
+
+/// A canonical S-expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sexp {
+  /// An atom, i.e., a string of bytes.
+  Atom(Vec<u8>),
+  /// A list of S-expressions.
+  List(Vec<Sexp>),
+}
+
+impl Sexp {
+  /// Parse a canonical S-expression.
+  pub fn parse(data: &[u8]) -> Result<Self> {
+    let (sexp, rest) = Self::parse_one(data)?;
+    ensure!(rest.is_empty(), "S-expression is followed by trailing data");
+    Ok(sexp)
+  }
+
+  /// Parse an S-expression from the start of the given data, returning
+  /// it along with the remaining data.
+  fn parse_one(data: &[u8]) -> Result<(Self, &[u8])> {
+    match data.first() {
+      Some(b'(') => {
+        let mut items = Vec::new();
+        let mut rest = &data[1..];
+        loop {
+          match rest.first() {
+            Some(b')') => return Ok((Self::List(items), &rest[1..])),
+            Some(_) => {
+              let (item, remainder) = Self::parse_one(rest)?;
+              let () = items.push(item);
+              rest = remainder;
+            },
+            None => bail!("S-expression list is not terminated"),
+          }
+        }
+      },
+      // A display hint preceding an atom; we have no use for it.
+      Some(b'[') => {
+        let (_hint, rest) = Self::parse_atom(&data[1..])?;
+        let rest = rest
+          .strip_prefix(b"]")
+          .ok_or_else(|| anyhow!("S-expression display hint is not terminated"))?;
+        Self::parse_one(rest)
+      },
+      Some(_) => {
+        let (atom, rest) = Self::parse_atom(data)?;
+        Ok((Self::Atom(atom.to_vec()), rest))
+      },
+      None => bail!("S-expression is truncated"),
+    }
+  }
+
+  /// Parse a length prefixed atom from the start of the given data.
+  fn parse_atom(data: &[u8]) -> Result<(&[u8], &[u8])> {
+    let colon = data
+      .iter()
+      .position(|byte| *byte == b':')
+      .ok_or_else(|| anyhow!("S-expression atom lacks length prefix"))?;
+    let len = std::str::from_utf8(&data[..colon])
+      .ok()
+      .filter(|len| !len.is_empty() && len.bytes().all(|byte| byte.is_ascii_digit()))
+      .and_then(|len| len.parse::<usize>().ok())
+      .ok_or_else(|| anyhow!("S-expression atom has invalid length prefix"))?;
+    let rest = &data[colon + 1..];
+    ensure!(rest.len() >= len, "S-expression atom is truncated");
+    Ok(rest.split_at(len))
+  }
+
+  /// Retrieve the contents of the atom, if this is one.
+  pub fn as_atom(&self) -> Option<&[u8]> {
+    match self {
+      Self::Atom(atom) => Some(atom),
+      Self::List(..) => None,
+    }
+  }
+
+  /// Retrieve the name of the list, i.e., its first element, if this is
+  /// a list starting with an atom.
+  pub fn name(&self) -> Option<&[u8]> {
+    match self {
+      Self::List(items) => items.first().and_then(Self::as_atom),
+      Self::Atom(..) => None,
+    }
+  }
+
+  /// Find the sub-list with the given name, searching recursively.
+  pub fn find(&self, name: &str) -> Option<&Self> {
+    match self {
+      Self::List(items) => {
+        if self.name() == Some(name.as_bytes()) {
+          Some(self)
+        } else {
+          items.iter().find_map(|item| item.find(name))
+        }
+      },
+      Self::Atom(..) => None,
+    }
+  }
+
+  /// Retrieve the value of the sub-list with the given name, i.e., the
+  /// atom following its name.
+  pub fn value(&self, name: &str) -> Option<&[u8]> {
+    match self.find(name)? {
+      Self::List(items) => items.get(1).and_then(Self::as_atom),
+      Self::Atom(..) => None,
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+
+  /// Check that we can parse canonical S-expressions.
+  #[test]
+  fn parse() -> Result<()> {
+    let sexp = Sexp::parse(b"(10:public-key(3:ecc(5:curve7:Ed25519)(1:q3:\x40())))")?;
+    assert_eq!(sexp.name(), Some(b"public-key".as_slice()));
+    assert_eq!(sexp.find("ecc").and_then(Sexp::name), Some(b"ecc".as_slice()));
+    assert_eq!(sexp.value("curve"), Some(b"Ed25519".as_slice()));
+    assert_eq!(sexp.value("q"), Some(b"\x40()".as_slice()));
+    assert_eq!(sexp.value("n"), None);
+
+    let sexp = Sexp::parse(b"(4:data[10:text/plain]5:hello)")?;
+    assert_eq!(sexp.value("data"), Some(b"hello".as_slice()));
+    assert_eq!(Sexp::parse(b"()")?, Sexp::List(Vec::new()));
+    assert_eq!(Sexp::parse(b"0:")?, Sexp::Atom(Vec::new()));
+    Ok(())
+  }
+
+  /// Check that we reject malformed S-expressions.
+  #[test]
+  fn parse_invalid() {
+    let invalid = [
+      b"".as_slice(),
+      b"(",
+      b"(3:abc",
+      b"(4:abc)",
+      b"(abc)",
+      b"(:abc)",
+      b"(+3:abc)",
+      b"([4:text3:abc)",
+      b"(3:abc))",
+    ];
+    for sexp in invalid {
+      assert!(Sexp::parse(sexp).is_err(), "{sexp:?}");
+    }
+  }
+}
@@ -17,128 +17,562 @@
 // * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
 // *************************************************************************
 
+use std::result::Result as StdResult;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
 use anyhow::Context as _;
 use anyhow::Result;
 
-use openssl::bn::BigNum;
-
+#[cfg(feature = "dss")]
+use dsa::signature::DigestSigner as _;
+#[cfg(feature = "dss")]
+use dsa::signature::DigestVerifier as _;
+#[cfg(feature = "dss")]
+use dsa::Components as DsaComponents;
+#[cfg(feature = "dss")]
+use dsa::Signature as DsaSignature;
+#[cfg(feature = "dss")]
+use dsa::SigningKey as DsaSigningKey;
+#[cfg(feature = "dss")]
+use dsa::VerifyingKey as DsaVerifyingKey;
+
+use p521::ecdsa::signature::Signer as _;
+use p521::ecdsa::signature::Verifier as _;
+use p521::ecdsa::Signature as P521Signature;
+use p521::ecdsa::SigningKey as P521SigningKey;
+use p521::ecdsa::VerifyingKey as P521VerifyingKey;
+
+use rand_core::OsRng;
+
+use rsa::sha2::Sha256;
+use rsa::sha2::Sha512;
+use rsa::traits::PrivateKeyParts as _;
+use rsa::BigUint;
+use rsa::Pkcs1v15Sign;
+use rsa::RsaPrivateKey as RsaKey;
+use rsa::RsaPublicKey as RsaPubKey;
+
+use sha1::digest::const_oid::AssociatedOid;
+use sha1::Digest;
+use sha1::Sha1;
+
+use ssh_agent_lib::proto::from_bytes;
 use ssh_agent_lib::proto::key_type::KeyTypeEnum;
+#[cfg(feature = "dss")]
+use ssh_agent_lib::proto::private_key::DssPrivateKey;
+use ssh_agent_lib::proto::private_key::EcDsaPrivateKey;
 use ssh_agent_lib::proto::private_key::Ed25519PrivateKey;
 use ssh_agent_lib::proto::private_key::PrivateKey;
 use ssh_agent_lib::proto::private_key::RsaPrivateKey;
+use ssh_agent_lib::proto::public_key::PublicKey;
+use ssh_agent_lib::proto::signature::EcDsaSignatureData;
 use ssh_agent_lib::proto::signature::Signature;
 use ssh_agent_lib::proto::signature::RSA_SHA2_256;
 use ssh_agent_lib::proto::signature::RSA_SHA2_512;
+use ssh_agent_lib::proto::to_bytes;
 
+use ring::error::Unspecified;
 use ring::rand::SystemRandom;
 use ring::rsa::KeyPairComponents as RsaKeyPairComponents;
-use ring::signature::Ed25519KeyPair;
+use ring::signature::EcdsaKeyPair;
+use ring::signature::EcdsaSigningAlgorithm;
+use ring::signature::RsaEncoding;
 use ring::signature::RsaKeyPair;
+use ring::signature::RsaParameters;
 use ring::signature::RsaPublicKeyComponents;
+use ring::signature::UnparsedPublicKey;
+use ring::signature::VerificationAlgorithm;
+use ring::signature::ECDSA_P256_SHA256_FIXED;
+use ring::signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+use ring::signature::ECDSA_P384_SHA384_FIXED;
+use ring::signature::ECDSA_P384_SHA384_FIXED_SIGNING;
+use ring::signature::ED25519;
+use ring::signature::RSA_PKCS1_2048_8192_SHA1_FOR_LEGACY_USE_ONLY;
+use ring::signature::RSA_PKCS1_2048_8192_SHA256;
+use ring::signature::RSA_PKCS1_2048_8192_SHA512;
 use ring::signature::RSA_PKCS1_SHA256;
 use ring::signature::RSA_PKCS1_SHA512;
 
+#[cfg(feature = "dss")]
+use tracing::warn;
 
-/// Sign a given blob of data with the given ed25519 private key.
-fn sign_ed25519(key: &Ed25519PrivateKey, data: &[u8]) -> Result<Vec<u8>> {
-  let public = &key.enc_a;
-  let seed = &key.k_enc_a;
+use zeroize::Zeroizing;
+
+use crate::error::RequestError;
+use crate::error::SignError;
+use crate::openssh::ed25519_key_pair;
+use crate::openssh::to_mpint;
+
+
+/// The padding of an RSA signature algorithm supported by `ring`,
+/// along with the parameters for verifying signatures using it.
+type RsaAlgorithm = (&'static dyn RsaEncoding, &'static RsaParameters);
+
+/// The error reported for an RSA signature that failed verification
+/// right after we created it.
+const RSA_FAULT: &str = "created RSA signature is invalid, possibly due to a fault while signing";
+
+/// The length in bytes of the shortest RSA modulus `ring` works with,
+/// i.e., of a 2048 bit one.
+const RING_RSA_MIN_LEN: usize = 256;
+
+/// The length of each of the two integers making up an `ssh-dss`
+/// signature.
+#[cfg(feature = "dss")]
+const DSA_SIGNATURE_INT_LEN: usize = 20;
+
+/// The length of a scalar on the NIST P-521 curve.
+const P521_SCALAR_LEN: usize = 66;
 
-  let key_pair = Ed25519KeyPair::from_seed_and_public_key(seed, public)
-    .with_context(|| "failed to create ed25519 key pair")?;
+/// Convert an SSH mpint into a big endian integer of the given fixed
+/// length.
+fn from_mpint(mpint: &[u8], len: usize) -> Result<Vec<u8>> {
+  let start = mpint.iter().position(|byte| *byte != 0).unwrap_or(mpint.len());
+  let int = &mpint[start..];
+  ensure!(int.len() <= len, "integer exceeds {len} bytes");
 
+  let mut result = vec![0; len - int.len()];
+  result.extend_from_slice(int);
+  Ok(result)
+}
+
+
+/// Convert the given RSA private key into the representation used by
+/// the `rsa` crate, validating it in the process.
+///
+/// The result is wiped from memory once dropped.
+fn rsa_private_key(key: &RsaPrivateKey) -> Result<RsaKey> {
+  let int = BigUint::from_bytes_be;
+  RsaKey::from_components(
+    int(&key.n),
+    int(&key.e),
+    int(&key.d),
+    vec![int(&key.p), int(&key.q)],
+  )
+  .context("encountered invalid RSA private key")
+}
+
+
+/// Sign a given blob of data with the given ed25519 private key.
+fn sign_ed25519(key: &Ed25519PrivateKey, data: &[u8]) -> Result<Vec<u8>> {
+  let key_pair = ed25519_key_pair(key)?;
   let sig = key_pair.sign(data).as_ref().to_vec();
   Ok(sig)
 }
 
-/// Sign a given blob of data with the given RSA private key.
-fn sign_rsa(key: &RsaPrivateKey, flags: u32, data: &[u8]) -> Result<Vec<u8>> {
-  let padding_alg = if flags & RSA_SHA2_512 != 0 {
-    &RSA_PKCS1_SHA512
-  } else if flags & RSA_SHA2_256 != 0 {
-    &RSA_PKCS1_SHA256
+/// Sign a given blob of data with the given NIST P-521 private key,
+/// which `ring` does not support, returning the fixed-length signature.
+fn sign_ecdsa_p521(private: &[u8], public: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+  let key =
+    P521SigningKey::from_slice(private).map_err(|_| anyhow!("encountered invalid ECDSA key"))?;
+  ensure!(
+    P521VerifyingKey::from(&key).to_encoded_point(false).as_bytes() == public,
+    "ECDSA public key does not match private key"
+  );
+
+  let sig: P521Signature = key
+    .try_sign(data)
+    .map_err(|_| anyhow!("failed to sign data"))?;
+  Ok(sig.to_bytes().to_vec())
+}
+
+/// Verify a fixed-length signature made with a NIST P-521 key.
+fn verify_ecdsa_p521(public: &[u8], data: &[u8], sig: &[u8]) -> StdResult<(), Unspecified> {
+  let key = P521VerifyingKey::from_sec1_bytes(public).map_err(|_| Unspecified)?;
+  let sig = P521Signature::from_slice(sig).map_err(|_| Unspecified)?;
+  key.verify(data, &sig).map_err(|_| Unspecified)
+}
+
+/// Sign a given blob of data with the given ECDSA private key.
+fn sign_ecdsa(key: &EcDsaPrivateKey, data: &[u8]) -> Result<Vec<u8>> {
+  let (alg, len): (Option<&'static EcdsaSigningAlgorithm>, _) = match key.identifier.as_str() {
+    "nistp256" => (Some(&ECDSA_P256_SHA256_FIXED_SIGNING), 32),
+    "nistp384" => (Some(&ECDSA_P384_SHA384_FIXED_SIGNING), 48),
+    "nistp521" => (None, P521_SCALAR_LEN),
+    curve => return Err(anyhow!("ECDSA curve {curve} is not supported")),
+  };
+
+  let private =
+    Zeroizing::new(from_mpint(&key.d, len).context("encountered invalid ECDSA private key")?);
+  let sig = if let Some(alg) = alg {
+    let rng = SystemRandom::new();
+    let key_pair = EcdsaKeyPair::from_private_key_and_public_key(alg, &private, &key.q, &rng)
+      .context("failed to create ECDSA key pair")?;
+    let sig = key_pair.sign(&rng, data).context("failed to sign data")?;
+    sig.as_ref().to_vec()
   } else {
-    unimplemented!()
+    sign_ecdsa_p521(&private, &key.q, data)?
   };
 
-  let RsaPrivateKey {
-    n,
-    e,
-    d,
-    iqmp,
-    p,
-    q,
-  } = key;
-
-  let one = BigNum::from_u32(1)?;
-  let n = BigNum::from_slice(n)?;
-  let e = BigNum::from_slice(e)?;
-  let d = BigNum::from_slice(d)?;
-  let p = BigNum::from_slice(p)?;
-  let q = BigNum::from_slice(q)?;
-  let q_inv = BigNum::from_slice(iqmp)?;
-  let d_p = &d % &(&p - &one);
-  let d_q = &d % &(&q - &one);
+  // The fixed-length signature is the concatenation of r and s, but
+  // SSH wants to see them as two separate mpints.
+  let (r, s) = sig.split_at(len);
+  let sig = EcDsaSignatureData {
+    r: to_mpint(r),
+    s: to_mpint(s),
+  };
+  let sig = to_bytes(&sig).context("failed to serialize ECDSA signature")?;
+  Ok(sig)
+}
 
+/// Create the DSA public key with the given parameters.
+#[cfg(feature = "dss")]
+fn dsa_verifying_key(p: &[u8], q: &[u8], g: &[u8], y: &[u8]) -> Result<DsaVerifyingKey> {
+  let int = BigUint::from_bytes_be;
+  let components = DsaComponents::from_components(int(p), int(q), int(g))
+    .map_err(|_| anyhow!("encountered invalid DSA parameters"))?;
+  DsaVerifyingKey::from_components(components, int(y))
+    .map_err(|_| anyhow!("encountered invalid DSA public key"))
+}
+
+/// Sign a given blob of data with the given DSA private key.
+///
+/// The per-signature secret is derived deterministically as per RFC
+/// 6979, so that a weak random number generator cannot leak the key.
+/// The signature is verified before being handed out, which also
+/// catches private keys not matching their public parts.
+#[cfg(feature = "dss")]
+fn sign_dss(key: &DssPrivateKey, data: &[u8]) -> Result<Vec<u8>> {
+  let public = dsa_verifying_key(&key.p, &key.q, &key.g, &key.y)?;
+  let private = DsaSigningKey::from_components(public, BigUint::from_bytes_be(&key.x))
+    .map_err(|_| anyhow!("encountered invalid DSA private key"))?;
+
+  let sig: DsaSignature = private
+    .try_sign_digest(Sha1::new_with_prefix(data))
+    .map_err(|_| anyhow!("failed to sign data"))?;
+  let () = private
+    .verifying_key()
+    .verify_digest(Sha1::new_with_prefix(data), &sig)
+    .map_err(|_| anyhow!("created DSA signature is invalid"))?;
+
+  // The signature is the concatenation of r and s, each of fixed
+  // length.
+  let mut blob = from_mpint(&sig.r().to_bytes_be(), DSA_SIGNATURE_INT_LEN)
+    .context("encountered DSA key with unsupported parameters")?;
+  let s = from_mpint(&sig.s().to_bytes_be(), DSA_SIGNATURE_INT_LEN)
+    .context("encountered DSA key with unsupported parameters")?;
+  let () = blob.extend(s);
+  Ok(blob)
+}
+
+/// Determine the name of the RSA signature algorithm requested by the
+/// given signature flags, along with the padding to use for it and the
+/// parameters for verifying the resulting signature.
+///
+/// Absent any of the RSA SHA-2 signature flags, the legacy SHA-1 based
+/// `ssh-rsa` algorithm is selected, which `ring` does not support.
+fn rsa_algorithm(flags: u32) -> (&'static str, Option<RsaAlgorithm>) {
+  if flags & RSA_SHA2_512 != 0 {
+    ("rsa-sha2-512", Some((&RSA_PKCS1_SHA512, &RSA_PKCS1_2048_8192_SHA512)))
+  } else if flags & RSA_SHA2_256 != 0 {
+    ("rsa-sha2-256", Some((&RSA_PKCS1_SHA256, &RSA_PKCS1_2048_8192_SHA256)))
+  } else {
+    ("ssh-rsa", None)
+  }
+}
+
+/// Create the `ring` key pair for the given RSA private key.
+///
+/// Creating the key pair involves computing the CRT exponents and
+/// validating the key, so it is best done once per key.
+pub fn rsa_key_pair(key: &RsaPrivateKey) -> Result<RsaKeyPair> {
+  // The `rsa` crate computes the CRT exponents for us.
+  let rsa = rsa_private_key(key)?;
+  let (d_p, d_q) = match (rsa.dp(), rsa.dq()) {
+    (Some(d_p), Some(d_q)) => (
+      Zeroizing::new(d_p.to_bytes_be()),
+      Zeroizing::new(d_q.to_bytes_be()),
+    ),
+    _ => bail!("failed to compute RSA CRT exponents"),
+  };
   let input = RsaKeyPairComponents {
     public_key: RsaPublicKeyComponents {
-      n: n.to_vec(),
-      e: e.to_vec(),
+      n: unsigned(&key.n),
+      e: unsigned(&key.e),
     },
-    d: d.to_vec(),
-    p: p.to_vec(),
-    q: q.to_vec(),
-    dP: d_p.to_vec(),
-    dQ: d_q.to_vec(),
-    qInv: q_inv.to_vec(),
+    d: unsigned(&key.d),
+    p: unsigned(&key.p),
+    q: unsigned(&key.q),
+    dP: d_p.as_slice(),
+    dQ: d_q.as_slice(),
+    qInv: unsigned(&key.iqmp),
   };
 
-  let key_pair = RsaKeyPair::from_components(&input).context("failed to create RSA key pair")?;
-  let mut sig = vec![0u8; key_pair.public().modulus_len()];
+  RsaKeyPair::from_components(&input).context("failed to create RSA key pair")
+}
 
+/// Strip the leading zero bytes of an SSH mpint, yielding the big
+/// endian unsigned integer `ring` expects.
+fn unsigned(mpint: &[u8]) -> &[u8] {
+  let start = mpint.iter().position(|byte| *byte != 0).unwrap_or(mpint.len());
+  &mpint[start..]
+}
+
+/// Check whether `ring` supports RSA keys with the given modulus,
+/// encoded as SSH mpint.
+///
+/// `ring` refuses keys shorter than 2048 bits, which only get used if
+/// explicitly allowed to (see [`Config::allow_weak_key`]).
+///
+/// [`Config::allow_weak_key`]: crate::Config::allow_weak_key
+pub fn is_ring_rsa_modulus(n: &[u8]) -> bool {
+  unsigned(n).len() >= RING_RSA_MIN_LEN
+}
+
+/// Sign a given blob of data with the given RSA key pair, using the
+/// provided algorithm.
+///
+/// `ring` signs using the Chinese Remainder Theorem, and a fault in the
+/// computation (be it a glitch or one induced by an attacker) results
+/// in a signature revealing a factor of the modulus. Verifying the
+/// signature using the public exponent is cheap in comparison, so we
+/// never hand out one without doing so.
+fn sign_rsa_sha2(key_pair: &RsaKeyPair, algorithm: RsaAlgorithm, data: &[u8]) -> Result<Vec<u8>> {
+  let (padding_alg, verify_alg) = algorithm;
+  let mut sig = vec![0u8; key_pair.public().modulus_len()];
   let rng = SystemRandom::new();
   let () = key_pair
     .sign(padding_alg, &rng, data, &mut sig)
     .context("failed to sign data")?;
+  let () = UnparsedPublicKey::new(verify_alg, key_pair.public())
+    .verify(data, &sig)
+    .map_err(|_| anyhow!(RSA_FAULT))?;
   Ok(sig)
 }
 
+/// Create a PKCS#1 v1.5 signature of the given blob of data with the
+/// given RSA private key, using the digest `D`.
+///
+/// `ring` supports neither creating legacy SHA-1 based `ssh-rsa`
+/// signatures nor signing with keys shorter than 2048 bits, so we have
+/// to fall back to the `rsa` crate for those. Signing is blinded and,
+/// just like with `ring`, the signature is verified before being handed
+/// out.
+fn sign_rsa_pkcs1<D>(key: &RsaPrivateKey, data: &[u8]) -> Result<Vec<u8>>
+where
+  D: Digest + AssociatedOid,
+{
+  let key = rsa_private_key(key)?;
+  let digest = D::digest(data);
+  let sig = key
+    .sign_with_rng(&mut OsRng, Pkcs1v15Sign::new::<D>(), &digest)
+    .context("failed to sign data")?;
+  let () = key
+    .to_public_key()
+    .verify(Pkcs1v15Sign::new::<D>(), &digest, &sig)
+    .map_err(|_| anyhow!(RSA_FAULT))?;
+  Ok(sig)
+}
 
-pub trait Signer {
-  fn sign(&self, flags: u32, data: &[u8]) -> Result<Signature>;
+/// Verify the given PKCS#1 v1.5 signature of the provided data, made
+/// using the digest `D` with the RSA key with the given modulus and
+/// public exponent, encoded as SSH mpints.
+fn verify_rsa_pkcs1<D>(n: &[u8], e: &[u8], data: &[u8], sig: &[u8]) -> StdResult<(), Unspecified>
+where
+  D: Digest + AssociatedOid,
+{
+  let int = BigUint::from_bytes_be;
+  RsaPubKey::new(int(n), int(e))
+    .and_then(|key| key.verify(Pkcs1v15Sign::new::<D>(), &D::digest(data), sig))
+    .map_err(|_| Unspecified)
 }
 
-impl Signer for PrivateKey {
-  /// Sign data using a private key.
-  fn sign(&self, flags: u32, data: &[u8]) -> Result<Signature> {
-    // We use the ring crate for signing. In order to sign something we
-    // first need to convert our private key into a key pair that the
-    // crate can work with.
-    let (alg, sig) = match self {
-      PrivateKey::Dss { .. }
-      | PrivateKey::EcDsa { .. }
-      | PrivateKey::SkEcDsa { .. }
-      | PrivateKey::SkEd25519 { .. } => unimplemented!(),
-      PrivateKey::Rsa(key) => {
-        let algorithm = if flags & RSA_SHA2_512 != 0 {
-          "rsa-sha2-512"
-        } else if flags & RSA_SHA2_256 != 0 {
-          "rsa-sha2-256"
-        } else {
-          "ssh-rsa"
+/// Sign a given blob of data with the given RSA private key.
+///
+/// `key_pair` is the key's precomputed `ring` key pair, if available.
+/// It is created on the fly otherwise.
+fn sign_rsa(
+  key: &RsaPrivateKey,
+  key_pair: Option<&RsaKeyPair>,
+  flags: u32,
+  data: &[u8],
+) -> Result<(&'static str, Vec<u8>)> {
+  let (algorithm, ring_alg) = rsa_algorithm(flags);
+  let sig = match (ring_alg, key_pair) {
+    (Some(ring_alg), Some(key_pair)) => sign_rsa_sha2(key_pair, ring_alg, data)?,
+    (Some(ring_alg), None) if is_ring_rsa_modulus(&key.n) => {
+      sign_rsa_sha2(&rsa_key_pair(key)?, ring_alg, data)?
+    },
+    _ => match algorithm {
+      "rsa-sha2-512" => sign_rsa_pkcs1::<Sha512>(key, data)?,
+      "rsa-sha2-256" => sign_rsa_pkcs1::<Sha256>(key, data)?,
+      _ => sign_rsa_pkcs1::<Sha1>(key, data)?,
+    },
+  };
+  Ok((algorithm, sig))
+}
+
+
+/// Sign a given blob of data with the given private key, taking into
+/// account the signature flags of the request.
+///
+/// `rsa_key_pair` is the precomputed `ring` key pair of an RSA private
+/// key, if available.
+pub fn sign_key(
+  key: &PrivateKey,
+  rsa_key_pair: Option<&RsaKeyPair>,
+  flags: u32,
+  data: &[u8],
+) -> Result<Signature> {
+  // We use the ring crate for signing. In order to sign something we
+  // first need to convert our private key into a key pair that the
+  // crate can work with.
+  let (alg, sig) = match key {
+    // DSA is deprecated (and disabled by default) since OpenSSH 7.0.
+    #[cfg(feature = "dss")]
+    PrivateKey::Dss(dss) => {
+      warn!("Signing with deprecated DSA key; DSA is insecure and the key should be replaced");
+      (
+        key.key_type(),
+        sign_dss(dss, data).context("failed to sign request using DSA")?,
+      )
+    },
+    #[cfg(not(feature = "dss"))]
+    PrivateKey::Dss { .. } => bail!(RequestError::Unsupported(format!(
+      "{} keys are not supported without the `dss` feature",
+      key.key_type()
+    ))),
+    // Signing with a FIDO security key backed key requires talking to
+    // the authenticator itself, which we have no means of doing. The
+    // key handle we could decrypt is useless on its own.
+    PrivateKey::SkEcDsa { .. } | PrivateKey::SkEd25519 { .. } => {
+      bail!(RequestError::Unsupported(format!(
+        "{} keys are backed by a FIDO security key and are not supported",
+        key.key_type()
+      )))
+    },
+    PrivateKey::Rsa(rsa) => {
+      let (algorithm, signature) = sign_rsa(rsa, rsa_key_pair, flags, data)
+        .context("failed to sign request using RSA")?;
+      (algorithm.to_string(), signature)
+    },
+    PrivateKey::Ed25519(ed25519) => (key.key_type(), sign_ed25519(ed25519, data)?),
+    PrivateKey::EcDsa(ecdsa) => (
+      key.key_type(),
+      sign_ecdsa(ecdsa, data).context("failed to sign request using ECDSA")?,
+    ),
+  };
+
+  let sig = Signature {
+    algorithm: alg,
+    blob: sig,
+  };
+  Ok(sig)
+}
+
+
+/// Verify the given signature of the provided data, made with the
+/// private key corresponding to the given public key.
+pub fn verify(key: &PublicKey, signature: &Signature, data: &[u8]) -> Result<()> {
+  let result = match key {
+    PublicKey::Ed25519(ed25519) => {
+      ensure!(
+        signature.algorithm == key.key_type(),
+        "signature algorithm {} does not match key",
+        signature.algorithm
+      );
+      UnparsedPublicKey::new(&ED25519, &ed25519.enc_a).verify(data, &signature.blob)
+    },
+    PublicKey::EcDsa(ecdsa) => {
+      let (alg, len): (Option<&'static dyn VerificationAlgorithm>, _) =
+        match ecdsa.identifier.as_str() {
+          "nistp256" => (Some(&ECDSA_P256_SHA256_FIXED), 32),
+          "nistp384" => (Some(&ECDSA_P384_SHA384_FIXED), 48),
+          "nistp521" => (None, P521_SCALAR_LEN),
+          curve => bail!("ECDSA curve {curve} is not supported"),
         };
-        let signature = sign_rsa(key, flags, data).context("failed to sign request using RSA")?;
+      ensure!(
+        signature.algorithm == key.key_type(),
+        "signature algorithm {} does not match key",
+        signature.algorithm
+      );
+      let sig = from_bytes::<EcDsaSignatureData>(&signature.blob)
+        .context("encountered malformed ECDSA signature")?;
+      let mut fixed = from_mpint(&sig.r, len).context("encountered invalid ECDSA signature")?;
+      let s = from_mpint(&sig.s, len).context("encountered invalid ECDSA signature")?;
+      let () = fixed.extend(s);
+      match alg {
+        Some(alg) => UnparsedPublicKey::new(alg, &ecdsa.q).verify(data, &fixed),
+        None => verify_ecdsa_p521(&ecdsa.q, data, &fixed),
+      }
+    },
+    PublicKey::Rsa(rsa) if !is_ring_rsa_modulus(&rsa.n) => {
+      let (n, e, sig) = (&rsa.n, &rsa.e, &signature.blob);
+      match signature.algorithm.as_str() {
+        "rsa-sha2-512" => verify_rsa_pkcs1::<Sha512>(n, e, data, sig),
+        "rsa-sha2-256" => verify_rsa_pkcs1::<Sha256>(n, e, data, sig),
+        "ssh-rsa" => verify_rsa_pkcs1::<Sha1>(n, e, data, sig),
+        other => bail!("signature algorithm {other} does not match key"),
+      }
+    },
+    PublicKey::Rsa(rsa) => {
+      let alg: &'static RsaParameters = match signature.algorithm.as_str() {
+        "rsa-sha2-512" => &RSA_PKCS1_2048_8192_SHA512,
+        "rsa-sha2-256" => &RSA_PKCS1_2048_8192_SHA256,
+        "ssh-rsa" => &RSA_PKCS1_2048_8192_SHA1_FOR_LEGACY_USE_ONLY,
+        other => bail!("signature algorithm {other} does not match key"),
+      };
+      let public = RsaPublicKeyComponents {
+        n: unsigned(&rsa.n),
+        e: unsigned(&rsa.e),
+      };
+      public.verify(alg, data, &signature.blob)
+    },
+    #[cfg(feature = "dss")]
+    PublicKey::Dss(dss) => {
+      ensure!(
+        signature.algorithm == key.key_type(),
+        "signature algorithm {} does not match key",
+        signature.algorithm
+      );
+      ensure!(
+        signature.blob.len() == 2 * DSA_SIGNATURE_INT_LEN,
+        "encountered malformed DSA signature"
+      );
+      let (r, s) = signature.blob.split_at(DSA_SIGNATURE_INT_LEN);
+      let sig = DsaSignature::from_components(BigUint::from_bytes_be(r), BigUint::from_bytes_be(s))
+        .map_err(|_| anyhow!("encountered invalid DSA signature"))?;
+      dsa_verifying_key(&dss.p, &dss.q, &dss.g, &dss.y)?
+        .verify_digest(Sha1::new_with_prefix(data), &sig)
+        .map_err(|_| Unspecified)
+    },
+    _ => bail!("verification of {} signatures is not supported", key.key_type()),
+  };
+  result.map_err(|_| anyhow!("signature is invalid"))
+}
 
-        (algorithm.to_string(), signature)
-      },
-      PrivateKey::Ed25519(key) => (self.key_type(), sign_ed25519(key, data)?),
-    };
 
-    let sig = Signature {
-      algorithm: alg,
-      blob: sig,
-    };
-    Ok(sig)
+/// Check whether signatures made with the private key corresponding to
+/// the given public key can be verified.
+pub fn is_verifiable(key: &PublicKey) -> bool {
+  matches!(key, PublicKey::Ed25519(..) | PublicKey::EcDsa(..) | PublicKey::Rsa(..))
+    || (cfg!(feature = "dss") && matches!(key, PublicKey::Dss(..)))
+}
+
+
+/// Verify the given signature blob, as sent to clients, of the provided
+/// data, made with the private key corresponding to the given public
+/// key.
+pub fn verify_blob(key: &PublicKey, blob: &[u8], data: &[u8]) -> Result<()> {
+  let signature = from_bytes::<Signature>(blob).context("encountered malformed signature")?;
+  verify(key, &signature, data)
+}
+
+
+/// A trait for objects that can sign data.
+pub trait Signer {
+  /// Sign the given data, taking into account the signature flags of
+  /// the request.
+  fn sign(&self, flags: u32, data: &[u8]) -> StdResult<Signature, SignError>;
+}
+
+impl Signer for PrivateKey {
+  /// Sign data using a private key.
+  fn sign(&self, flags: u32, data: &[u8]) -> StdResult<Signature, SignError> {
+    let signature = sign_key(self, None, flags, data)?;
+    Ok(signature)
   }
 }
 
@@ -147,10 +581,11 @@ impl Signer for PrivateKey {
 mod test {
   use super::*;
 
-  use ssh_agent_lib::proto::PublicKey;
-
-  use ring::signature::RSA_PKCS1_2048_8192_SHA256;
+  #[cfg(not(feature = "dss"))]
+  use ssh_agent_lib::proto::private_key::DssPrivateKey;
+  use ssh_agent_lib::proto::private_key::SkEd25519PrivateKey;
 
+  use crate::error::ErrorKind;
   use crate::files::load_public_key;
   use crate::files::test::load_unencrypted_private_key;
   use crate::keys::FromPem;
@@ -168,14 +603,112 @@ mod test {
   }
 
 
-  /// Test the signing of data with an RSA private key.
+  /// Check that we report an Ed25519 key whose public key does not
+  /// match its seed as corrupted.
   #[test]
-  fn sign_rsa() -> Result<()> {
+  fn sign_corrupted_ed25519() -> Result<()> {
+    let privkey = load_unencrypted_private_key("tests/valid_keys/ed25519")?;
+    let mut privkey = PrivateKey::from_pem(privkey)?;
+    if let PrivateKey::Ed25519(ed25519) = &mut privkey {
+      ed25519.enc_a[0] ^= 0xff;
+    }
+
+    let err = privkey.sign(0, b"test-data").unwrap_err();
+    assert!(err.to_string().contains("corrupted"), "{err}");
+    Ok(())
+  }
+
+
+  /// Check that signing with a FIDO security key backed key fails
+  /// gracefully.
+  #[test]
+  fn sign_sk_ed25519() {
+    let privkey = PrivateKey::SkEd25519(SkEd25519PrivateKey {
+      enc_a: vec![0; 32],
+      application: "ssh:".to_string(),
+      flags: 0,
+      key_handle: vec![1, 2, 3],
+      reserved: Vec::new(),
+    });
+
+    let err = privkey.sign(0, b"test-data").unwrap_err();
+    assert!(err.to_string().contains("FIDO"), "{err}");
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+  }
+
+  /// Check that signing with a DSA key fails gracefully when support
+  /// for it is not compiled in.
+  #[cfg(not(feature = "dss"))]
+  #[test]
+  fn sign_dss() {
+    let privkey = PrivateKey::Dss(DssPrivateKey {
+      p: vec![1; 128],
+      q: vec![2; 20],
+      g: vec![3; 128],
+      y: vec![4; 128],
+      x: vec![5; 20],
+    });
+
+    let err = privkey.sign(0, b"test-data").unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "ssh-dss keys are not supported without the `dss` feature"
+    );
+    assert_eq!(err.kind(), ErrorKind::Unsupported);
+  }
+
+
+  /// Test the signing of data with a DSA private key.
+  #[cfg(feature = "dss")]
+  #[test]
+  fn sign_dss() -> Result<()> {
+    let privkey = load_unencrypted_private_key("tests/valid_keys/dss")?;
+    let privkey = PrivateKey::from_pem(privkey)?;
+    let pubkey = load_public_key("tests/valid_keys/dss.pub")?;
+    let pubkey = PublicKey::from_pem(pubkey)?;
+    assert_eq!(PublicKey::from(&privkey), pubkey);
+
+    let sig = privkey.sign(0, b"test-data")?;
+    assert_eq!(sig.algorithm, "ssh-dss");
+    assert_eq!(sig.blob.len(), 40);
+    // Signatures are deterministic.
+    assert_eq!(privkey.sign(0, b"test-data")?, sig);
+
+    let () = verify(&pubkey, &sig, b"test-data")?;
+    assert!(verify(&pubkey, &sig, b"other-data").is_err());
+    Ok(())
+  }
+
+  /// Check that we refuse to sign with a DSA private key not matching
+  /// its public key.
+  #[cfg(feature = "dss")]
+  #[test]
+  fn sign_mismatched_dss() -> Result<()> {
+    let privkey = load_unencrypted_private_key("tests/valid_keys/dss")?;
+    let mut privkey = PrivateKey::from_pem(privkey)?;
+    if let PrivateKey::Dss(dss) = &mut privkey {
+      *dss.x.last_mut().unwrap() ^= 0x01;
+    }
+
+    let err = privkey.sign(0, b"test-data").unwrap_err().into_inner();
+    assert_eq!(format!("{:#}", err.root_cause()), "created DSA signature is invalid");
+    Ok(())
+  }
+
+
+  /// Sign data with the RSA key stored in the given file using the
+  /// provided flags and verify the resulting signature.
+  fn sign_verify_rsa(
+    flags: u32,
+    alg: &'static RsaParameters,
+    algorithm: &str,
+  ) -> Result<()> {
     let privkey = load_unencrypted_private_key("tests/valid_keys/rsa2048")?;
     let privkey = PrivateKey::from_pem(privkey)?;
 
     let data = "test-data";
-    let sig = privkey.sign(RSA_SHA2_256, data.as_bytes())?;
+    let sig = privkey.sign(flags, data.as_bytes())?;
+    assert_eq!(sig.algorithm, algorithm);
 
     let pubkey = load_public_key("tests/valid_keys/rsa2048.pub")?;
     let pubkey = PublicKey::from_pem(pubkey)?;
@@ -187,8 +720,139 @@ mod test {
       },
       _ => unreachable!(),
     };
-    let () = pubkey.verify(&RSA_PKCS1_2048_8192_SHA256, data.as_bytes(), &sig.blob)?;
+    let () = pubkey.verify(alg, data.as_bytes(), &sig.blob)?;
+
+    Ok(())
+  }
+
+  /// Test the signing of data with an RSA private key.
+  #[test]
+  fn sign_rsa() -> Result<()> {
+    sign_verify_rsa(RSA_SHA2_256, &RSA_PKCS1_2048_8192_SHA256, "rsa-sha2-256")
+  }
+
+  /// Test the creation of legacy SHA-1 based RSA signatures.
+  #[test]
+  fn sign_rsa_sha1() -> Result<()> {
+    sign_verify_rsa(
+      0,
+      &RSA_PKCS1_2048_8192_SHA1_FOR_LEGACY_USE_ONLY,
+      "ssh-rsa",
+    )
+  }
+
+  /// Check that we can sign with RSA keys too short for `ring` to
+  /// handle, using the requested algorithm.
+  #[test]
+  fn sign_rsa1024() -> Result<()> {
+    let privkey = load_unencrypted_private_key("tests/valid_keys/rsa1024")?;
+    let privkey = PrivateKey::from_pem(privkey)?;
+    let pubkey = load_public_key("tests/valid_keys/rsa1024.pub")?;
+    let pubkey = PublicKey::from_pem(pubkey)?;
+    match &pubkey {
+      PublicKey::Rsa(rsa) => assert!(!is_ring_rsa_modulus(&rsa.n)),
+      _ => unreachable!(),
+    }
+
+    let data = b"test-data";
+    for (flags, algorithm) in [
+      (RSA_SHA2_512, "rsa-sha2-512"),
+      (RSA_SHA2_256, "rsa-sha2-256"),
+      (0, "ssh-rsa"),
+    ] {
+      let sig = privkey.sign(flags, data)?;
+      assert_eq!(sig.algorithm, algorithm);
+      assert_eq!(sig.blob.len(), 128);
+      let () = verify(&pubkey, &sig, data)?;
+    }
+    Ok(())
+  }
+
+  /// Check that RSA signatures failing verification are not handed
+  /// out.
+  #[test]
+  fn sign_invalid_rsa() -> Result<()> {
+    let privkey = load_unencrypted_private_key("tests/valid_keys/rsa2048")?;
+    let privkey = PrivateKey::from_pem(privkey)?;
+    let key_pair = match &privkey {
+      PrivateKey::Rsa(rsa) => rsa_key_pair(rsa)?,
+      _ => unreachable!(),
+    };
+
+    // Verifying a SHA-256 based signature as SHA-512 based one fails,
+    // just as verifying one corrupted by a fault would.
+    let algorithm = (&RSA_PKCS1_SHA256 as _, &RSA_PKCS1_2048_8192_SHA512);
+    let err = sign_rsa_sha2(&key_pair, algorithm, b"test-data").unwrap_err();
+    assert_eq!(err.to_string(), RSA_FAULT);
+    Ok(())
+  }
+
+  /// Sign data with the ECDSA key stored in the given file and verify
+  /// the resulting signature.
+  fn sign_verify_ecdsa(name: &str, len: usize) -> Result<()> {
+    let privkey = load_unencrypted_private_key(format!("tests/valid_keys/{name}"))?;
+    let privkey = PrivateKey::from_pem(privkey)?;
+
+    let data = "test-data";
+    let sig = privkey.sign(0, data.as_bytes())?;
+
+    let pubkey = load_public_key(format!("tests/valid_keys/{name}.pub"))?;
+    let pubkey = PublicKey::from_pem(pubkey)?;
+    assert_eq!(PublicKey::from(&privkey), pubkey);
+
+    let point = match &pubkey {
+      PublicKey::EcDsa(pubkey) => pubkey.q.clone(),
+      _ => unreachable!(),
+    };
+    let sig = from_bytes::<EcDsaSignatureData>(&sig.blob)?;
+    let mut fixed = from_mpint(&sig.r, len)?;
+    fixed.extend(from_mpint(&sig.s, len)?);
+
+    let data = data.as_bytes();
+    let () = match len {
+      32 => UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point).verify(data, &fixed)?,
+      48 => UnparsedPublicKey::new(&ECDSA_P384_SHA384_FIXED, point).verify(data, &fixed)?,
+      _ => verify_ecdsa_p521(&point, data, &fixed)?,
+    };
+    Ok(())
+  }
 
+  /// Test the signing of data with an ECDSA P-256 private key.
+  #[test]
+  fn sign_ecdsa256() -> Result<()> {
+    sign_verify_ecdsa("ecdsa256", 32)
+  }
+
+  /// Test the signing of data with an ECDSA P-384 private key.
+  #[test]
+  fn sign_ecdsa384() -> Result<()> {
+    sign_verify_ecdsa("ecdsa384", 48)
+  }
+
+  /// Test the signing of data with an ECDSA P-521 private key.
+  #[test]
+  fn sign_ecdsa521() -> Result<()> {
+    sign_verify_ecdsa("ecdsa521", P521_SCALAR_LEN)
+  }
+
+  /// Check that we can verify signatures created with keys of all
+  /// supported types.
+  #[test]
+  fn signature_verification() -> Result<()> {
+    for name in ["ed25519", "ecdsa256", "ecdsa384", "ecdsa521", "rsa1024", "rsa2048"] {
+      let privkey = load_unencrypted_private_key(format!("tests/valid_keys/{name}"))?;
+      let privkey = PrivateKey::from_pem(privkey)?;
+      let pubkey = PublicKey::from(&privkey);
+
+      for flags in [0, RSA_SHA2_256, RSA_SHA2_512] {
+        let mut sig = privkey.sign(flags, b"test-data")?;
+        let () = verify(&pubkey, &sig, b"test-data")?;
+        assert!(verify(&pubkey, &sig, b"other-data").is_err());
+
+        sig.algorithm = "ssh-dss".to_string();
+        assert!(verify(&pubkey, &sig, b"test-data").is_err());
+      }
+    }
     Ok(())
   }
 }
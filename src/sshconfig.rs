@@ -0,0 +1,273 @@
+// sshconfig.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+//! Discovery of the identity files referenced by an OpenSSH client
+//! configuration file.
+//!
+//! Only the `IdentityFile` and `Include` directives are considered, and
+//! they are considered regardless of the `Host` and `Match` blocks they
+//! are part of.
+
+use std::env::var;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use tracing::debug;
+
+
+/// The maximum depth of nested `Include` directives, as enforced by
+/// OpenSSH.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+
+/// Split the arguments of a directive into words, honoring double
+/// quotes.
+fn split_args(args: &str) -> Result<Vec<String>> {
+  let mut words = Vec::new();
+  let mut rest = args.trim_start();
+  while !rest.is_empty() {
+    let (word, remainder) = if let Some(quoted) = rest.strip_prefix('"') {
+      match quoted.split_once('"') {
+        Some(split) => split,
+        None => bail!("unterminated quote in {args:?}"),
+      }
+    } else {
+      rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len()))
+    };
+    let () = words.push(word.to_string());
+    rest = remainder.trim_start();
+  }
+  Ok(words)
+}
+
+
+/// Parse a line of a configuration file into its keyword, in lower
+/// case, and arguments.
+fn parse_line(line: &str) -> Result<Option<(String, Vec<String>)>> {
+  let line = line.trim();
+  if line.is_empty() || line.starts_with('#') {
+    return Ok(None)
+  }
+
+  let end = line
+    .find(|c: char| c.is_whitespace() || c == '=')
+    .unwrap_or(line.len());
+  let (keyword, args) = line.split_at(end);
+  let args = args.trim_start();
+  let args = args.strip_prefix('=').unwrap_or(args);
+  Ok(Some((keyword.to_ascii_lowercase(), split_args(args)?)))
+}
+
+
+/// Expand the tokens and environment variables in the given path, as
+/// `ssh` does for identity files.
+///
+/// Tokens other than `%d` (the home directory), `%u` (the local user),
+/// and `%%` depend on the host being connected to, so `None` is
+/// returned for paths containing them.
+fn expand(path: &str, home: &Path) -> Option<PathBuf> {
+  let home = home.to_str()?;
+  let path = match path.strip_prefix('~') {
+    Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("{home}{rest}"),
+    _ => path.to_string(),
+  };
+
+  let mut expanded = String::new();
+  let mut chars = path.chars();
+  while let Some(c) = chars.next() {
+    match c {
+      '%' => match chars.next()? {
+        '%' => expanded.push('%'),
+        'd' => expanded.push_str(home),
+        'u' => expanded.push_str(&var("USER").ok()?),
+        _ => return None,
+      },
+      '$' if chars.as_str().starts_with('{') => {
+        let (name, rest) = chars.as_str()[1..].split_once('}')?;
+        let () = expanded.push_str(&var(name).ok()?);
+        chars = rest.chars();
+      },
+      c => expanded.push(c),
+    }
+  }
+  Some(PathBuf::from(expanded))
+}
+
+
+/// Collect the identity files referenced by the configuration file at
+/// the given path, and the files it includes, into `files`.
+fn collect(path: &Path, home: &Path, depth: usize, files: &mut Vec<PathBuf>) -> Result<()> {
+  if depth > MAX_INCLUDE_DEPTH {
+    bail!("too many nested includes")
+  }
+
+  let content = read_to_string(path)
+    .with_context(|| format!("failed to read SSH configuration file {}", path.display()))?;
+  for (idx, line) in content.lines().enumerate() {
+    let (keyword, args) = match parse_line(line)
+      .with_context(|| format!("{}:{}: invalid line", path.display(), idx + 1))?
+    {
+      Some(parsed) => parsed,
+      None => continue,
+    };
+
+    match keyword.as_str() {
+      "identityfile" => {
+        let arg = match args.first() {
+          Some(arg) if !arg.eq_ignore_ascii_case("none") => arg,
+          _ => continue,
+        };
+        match expand(arg, home) {
+          Some(file) if file.is_absolute() => {
+            if !files.contains(&file) {
+              let () = files.push(file);
+            }
+          },
+          _ => debug!("Ignoring host specific or relative identity file {arg}"),
+        }
+      },
+      "include" => {
+        for arg in args {
+          if arg.contains(['*', '?', '[']) {
+            debug!("Ignoring include of {arg}, as patterns are not supported");
+            continue
+          }
+          let include = match expand(&arg, home) {
+            Some(include) if include.is_absolute() => include,
+            // Relative includes of user configuration files are
+            // relative to `~/.ssh`.
+            Some(include) => home.join(".ssh").join(include),
+            None => continue,
+          };
+          let () = collect(&include, home, depth + 1, files)
+            .with_context(|| format!("failed to process include of {arg}"))?;
+        }
+      },
+      _ => (),
+    }
+  }
+  Ok(())
+}
+
+
+/// Find the identity files referenced by the OpenSSH client
+/// configuration file at the given path, resolving paths relative to
+/// the provided home directory.
+///
+/// Identity files depending on the host connected to (e.g., via the
+/// `%h` token) as well as includes using wildcards are ignored.
+pub fn identity_files(path: &Path, home: &Path) -> Result<Vec<PathBuf>> {
+  let mut files = Vec::new();
+  let () = collect(path, home, 0, &mut files)?;
+  Ok(files)
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::fs::create_dir_all;
+  use std::fs::write;
+
+  use tempfile::tempdir;
+
+
+  /// Check that we can parse lines of a configuration file.
+  #[test]
+  fn line_parsing() {
+    let parsed = parse_line("  IdentityFile ~/.ssh/id_ed25519").unwrap();
+    assert_eq!(
+      parsed,
+      Some(("identityfile".to_string(), vec!["~/.ssh/id_ed25519".to_string()]))
+    );
+    let parsed = parse_line("identityfile=\"/keys/my key\"").unwrap();
+    assert_eq!(
+      parsed,
+      Some(("identityfile".to_string(), vec!["/keys/my key".to_string()]))
+    );
+    let parsed = parse_line("Include a  \"b c\"").unwrap();
+    assert_eq!(
+      parsed,
+      Some((
+        "include".to_string(),
+        vec!["a".to_string(), "b c".to_string()]
+      ))
+    );
+    assert_eq!(parse_line("  # IdentityFile foo").unwrap(), None);
+    assert_eq!(parse_line("").unwrap(), None);
+    assert!(parse_line("IdentityFile \"foo").is_err());
+  }
+
+  /// Check that paths in identity files are expanded as by `ssh`.
+  #[test]
+  fn path_expansion() {
+    let home = Path::new("/home/user");
+    assert_eq!(
+      expand("~/.ssh/id_rsa", home),
+      Some(PathBuf::from("/home/user/.ssh/id_rsa"))
+    );
+    assert_eq!(expand("%d/keys/100%%", home), Some(PathBuf::from("/home/user/keys/100%")));
+    assert_eq!(expand("~other/id_rsa", home), Some(PathBuf::from("~other/id_rsa")));
+    assert_eq!(expand("~/.ssh/%h", home), None);
+    assert_eq!(expand("${SSH_GPG_AGENT_UNSET_VARIABLE}/id_rsa", home), None);
+  }
+
+  /// Check that we find the identity files referenced by a
+  /// configuration file and the files it includes.
+  #[test]
+  fn identity_file_discovery() -> Result<()> {
+    let home = tempdir()?;
+    let home = home.path();
+    let ssh = home.join(".ssh");
+    let () = create_dir_all(ssh.join("config.d"))?;
+    let () = write(
+      ssh.join("config"),
+      r#"
+        IdentityFile ~/.ssh/id_ed25519
+        Include config.d/work config.d/*
+
+        Host github.com
+          IdentityFile "~/keys/github key"
+          IdentityFile ~/.ssh/%h
+          IdentityFile none
+      "#,
+    )?;
+    let () = write(
+      ssh.join("config.d").join("work"),
+      "Host work\n  IdentityFile=/srv/keys/work\n  IdentityFile ~/.ssh/id_ed25519\n",
+    )?;
+
+    let files = identity_files(&ssh.join("config"), home)?;
+    let expected = vec![
+      ssh.join("id_ed25519"),
+      PathBuf::from("/srv/keys/work"),
+      home.join("keys").join("github key"),
+    ];
+    assert_eq!(files, expected);
+
+    let () = write(ssh.join("config.d").join("work"), "Include work\n")?;
+    assert!(identity_files(&ssh.join("config"), home).is_err());
+    Ok(())
+  }
+}
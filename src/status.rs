@@ -0,0 +1,242 @@
+// status.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! The status of a running agent, as reported via the
+//! `query@ssh-gpg-agent` agent protocol extension.
+
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::result::Result as StdResult;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use ssh_agent_lib::proto::from_bytes;
+use ssh_agent_lib::proto::message::Extension;
+use ssh_agent_lib::proto::message::ExtensionContents;
+use ssh_agent_lib::proto::message::Message;
+use ssh_agent_lib::proto::to_bytes;
+
+use crate::cache::CacheStats;
+use crate::error::ErrorReport;
+use crate::error::FailureCount;
+use crate::error::ProtocolError;
+use crate::transport::connect_unix;
+use crate::transport::read_message;
+use crate::transport::write_message;
+use crate::usage::KeyUsage;
+
+
+/// The name of the agent protocol extension for querying the agent's
+/// status.
+///
+/// The agent responds to the request with an extension message of the
+/// same name, containing the serialized [`Status`].
+pub const QUERY_EXTENSION: &str = "query@ssh-gpg-agent";
+
+
+/// The status of a key managed by a running agent.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct KeyStatus {
+  /// The SHA-256 fingerprint of the key.
+  pub fingerprint: String,
+  /// The key's comment.
+  pub comment: String,
+  /// Whether the key is kept in memory only.
+  pub ephemeral: bool,
+  /// The key's usage statistics.
+  pub usage: KeyUsage,
+}
+
+
+/// The status of a key store of a running agent.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct StoreStatus {
+  /// The name of the store.
+  pub name: String,
+  /// The store's priority.
+  pub priority: i32,
+  /// The number of identities the store offers.
+  pub identities: u64,
+  /// The error encountered retrieving the store's identities, if any;
+  /// empty if the store is healthy.
+  pub error: String,
+}
+
+
+/// The status of a running agent.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Status {
+  /// The version of the agent.
+  pub version: String,
+  /// The process ID of the agent.
+  pub pid: u32,
+  /// The number of seconds the agent has been running for.
+  pub uptime: u64,
+  /// Whether the agent is locked.
+  pub locked: bool,
+  /// The keys the agent manages.
+  pub keys: Vec<KeyStatus>,
+  /// Statistics about the agent's cache of decrypted keys.
+  pub cache: CacheStats,
+  /// The agent's key stores, in order of priority.
+  pub stores: Vec<StoreStatus>,
+  /// The number of requests that failed, by kind of failure.
+  pub failures: Vec<FailureCount>,
+}
+
+impl Status {
+  /// Create the request message for querying an agent's status.
+  pub fn request() -> Message {
+    Message::Extension(Extension {
+      extension_type: QUERY_EXTENSION.to_string(),
+      extension_contents: ExtensionContents(Vec::new()),
+    })
+  }
+
+  /// Convert the status into the response to a query request.
+  pub fn to_response(&self) -> Result<Message> {
+    let contents = to_bytes(self).with_context(|| "failed to encode agent status")?;
+    let response = Message::Extension(Extension {
+      extension_type: QUERY_EXTENSION.to_string(),
+      extension_contents: ExtensionContents(contents),
+    });
+    Ok(response)
+  }
+
+  /// Extract the status from an agent's response to a query request.
+  pub fn from_response(response: &Message) -> Result<Self> {
+    if let Some(report) = ErrorReport::from_response(response) {
+      return Err(report).context("agent failed to report its status")
+    }
+
+    match response {
+      Message::Extension(extension) if extension.extension_type == QUERY_EXTENSION => {
+        from_bytes(&extension.extension_contents.0)
+          .with_context(|| "failed to decode agent status")
+      },
+      Message::Failure | Message::ExtensionFailure => {
+        bail!("agent does not support {QUERY_EXTENSION} extension")
+      },
+      _ => bail!("agent sent unexpected response to status query"),
+    }
+  }
+
+  /// Query the status of the agent listening on the given Unix domain
+  /// socket.
+  pub fn query(socket: &Path) -> StdResult<Self, ProtocolError> {
+    let mut stream = connect_unix(socket)
+      .with_context(|| format!("failed to connect to agent at {}", socket.display()))?;
+    Self::query_stream(&mut stream)
+  }
+
+  /// Query the status of the agent at the other end of the given
+  /// connection.
+  pub fn query_stream(stream: &mut UnixStream) -> StdResult<Self, ProtocolError> {
+    let () = write_message(stream, &Self::request())?;
+    let response = read_message(stream)?.ok_or_else(|| anyhow!("agent closed connection"))?;
+    let status = Self::from_response(&response)?;
+    Ok(status)
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::io::Cursor;
+
+  use crate::error::ErrorKind;
+
+
+  /// Check that the agent status survives a round trip through the
+  /// agent protocol.
+  #[test]
+  fn status_round_trip() -> Result<()> {
+    let status = Status {
+      version: "1.2.3".to_string(),
+      pid: 42,
+      uptime: 3600,
+      locked: true,
+      keys: vec![
+        KeyStatus {
+          fingerprint: "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU".to_string(),
+          comment: "me@example.com".to_string(),
+          ephemeral: false,
+          usage: KeyUsage {
+            count: 3,
+            last_used: 1700000000,
+            last_client: "/usr/bin/ssh (uid 1000, pid 42)".to_string(),
+          },
+        },
+        KeyStatus {
+          fingerprint: "SHA256:S8IIhv/nLGuze20JjY2bB06iU51F60A9tTWDKOp2aMA".to_string(),
+          comment: String::new(),
+          ephemeral: true,
+          usage: KeyUsage::default(),
+        },
+      ],
+      cache: CacheStats {
+        enabled: true,
+        entries: 2,
+        hits: 42,
+        misses: 7,
+      },
+      stores: vec![
+        StoreStatus {
+          name: "files".to_string(),
+          priority: 40,
+          identities: 2,
+          error: String::new(),
+        },
+        StoreStatus {
+          name: "upstream".to_string(),
+          priority: 10,
+          identities: 0,
+          error: "failed to connect to upstream agent".to_string(),
+        },
+      ],
+      failures: vec![
+        FailureCount {
+          kind: ErrorKind::KeyNotFound,
+          count: 1,
+        },
+        FailureCount {
+          kind: ErrorKind::Locked,
+          count: 5,
+        },
+      ],
+    };
+
+    let mut buffer = Vec::new();
+    let () = write_message(&mut buffer, &status.to_response()?)?;
+    let response = read_message(&mut Cursor::new(buffer))?.unwrap();
+    assert_eq!(Status::from_response(&response)?, status);
+
+    assert!(Status::from_response(&Message::Failure).is_err());
+    assert!(Status::from_response(&Status::request()).is_err());
+    Ok(())
+  }
+}
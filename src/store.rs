@@ -0,0 +1,140 @@
+// store.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! The abstractions over where the agent's keys come from.
+
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use ssh_agent_lib::proto::message::SignatureBlob;
+use ssh_agent_lib::proto::message::SignRequest;
+use ssh_agent_lib::proto::public_key::PublicKey;
+
+use crate::config::Config;
+use crate::error::KeyStoreError;
+use crate::files::PemPublicKey;
+use crate::index::KeyIndex;
+use crate::peer::Peer;
+
+
+/// A trait for objects providing the key files the agent manages.
+///
+/// Each key pair is reported as its public key along with the path of
+/// the file containing the GPG encrypted private key.
+pub trait KeyFiles
+where
+  Self: Debug + Send + Sync,
+{
+  /// Retrieve all key pairs, in order of precedence.
+  fn keys(&self) -> Vec<Result<(PemPublicKey, PathBuf)>>;
+
+  /// Inform the store that the underlying key files were changed by
+  /// the agent itself.
+  fn invalidate(&self);
+}
+
+impl KeyFiles for KeyIndex {
+  fn keys(&self) -> Vec<Result<(PemPublicKey, PathBuf)>> {
+    KeyIndex::keys(self)
+  }
+
+  fn invalidate(&self) {
+    KeyIndex::invalidate(self)
+  }
+}
+
+
+/// An identity offered by a key store.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoreIdentity {
+  /// The blob the identity is advertised with, i.e., the serialized
+  /// public key or a certificate for it.
+  pub blob: Vec<u8>,
+  /// The identity's public key.
+  ///
+  /// Identities merely relayed from elsewhere, such as from an upstream
+  /// agent, lack one. They are not subject to the agent's key usage
+  /// policies and cannot be hidden.
+  pub pubkey: Option<PublicKey>,
+  /// The identity's comment.
+  pub comment: String,
+  /// Whether the store requires confirmation before each usage of the
+  /// key.
+  pub confirm: bool,
+}
+
+
+/// A trait for sources of keys the agent offers to clients, such as
+/// the GPG encrypted key files in its directories, the GnuPG agent, or
+/// hardware tokens.
+pub trait KeyStore
+where
+  Self: Debug + Send + Sync,
+{
+  /// The name of the store, for usage in messages.
+  fn name(&self) -> &str;
+
+  /// Retrieve the identities the store offers, in order of precedence.
+  ///
+  /// An error is reported if the store as a whole is unavailable, while
+  /// identities that merely fail to load are reported individually.
+  fn identities(
+    &self,
+  ) -> StdResult<Vec<StdResult<StoreIdentity, KeyStoreError>>, KeyStoreError>;
+
+  /// Check that the given client, if known, is permitted to use the
+  /// provided identity of the store.
+  fn check_access(
+    &self,
+    identity: &StoreIdentity,
+    peer: Option<&Peer>,
+  ) -> StdResult<(), KeyStoreError> {
+    let _ = (identity, peer);
+    Ok(())
+  }
+
+  /// Sign the data of the given request with the private key of the
+  /// provided identity of the store.
+  fn sign(
+    &self,
+    identity: &StoreIdentity,
+    request: &SignRequest,
+  ) -> StdResult<SignatureBlob, KeyStoreError>;
+
+  /// Remove the given identity of the store.
+  ///
+  /// By default, nothing happens. Identities with a public key are
+  /// hidden by the agent regardless.
+  fn remove(&self, identity: &StoreIdentity) -> StdResult<(), KeyStoreError> {
+    let _ = identity;
+    Ok(())
+  }
+
+  /// Inform the store that its keys may have changed.
+  fn invalidate(&self) {}
+
+  /// Apply a changed configuration of the agent.
+  fn reconfigure(&self, config: &Arc<Config>) {
+    let _ = config;
+  }
+}
@@ -0,0 +1,224 @@
+// tpm.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Support for private keys sealed to the TPM.
+//!
+//! A sealed key file contains a sealed data object, as created by
+//! `tpm2_create` under the configured parent key, in the form of its
+//! marshaled `TPM2B_PUBLIC` structure followed by its marshaled
+//! `TPM2B_PRIVATE` one. The sealed data is the private key in OpenSSH
+//! format.
+
+use std::fs::read;
+use std::path::Path;
+use std::str::FromStr as _;
+
+use anyhow::anyhow;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Result;
+
+use tss_esapi::constants::SessionType;
+use tss_esapi::handles::KeyHandle;
+use tss_esapi::handles::ObjectHandle;
+use tss_esapi::handles::PersistentTpmHandle;
+use tss_esapi::handles::SessionHandle;
+use tss_esapi::handles::TpmHandle;
+use tss_esapi::interface_types::algorithm::HashingAlgorithm;
+use tss_esapi::interface_types::session_handles::PolicySession;
+use tss_esapi::structures::Digest;
+use tss_esapi::structures::PcrSelectionList;
+use tss_esapi::structures::PcrSlot;
+use tss_esapi::structures::Private;
+use tss_esapi::structures::Public;
+use tss_esapi::structures::SensitiveData;
+use tss_esapi::structures::SymmetricDefinition;
+use tss_esapi::traits::UnMarshall as _;
+use tss_esapi::Context;
+use tss_esapi::TctiNameConf;
+
+use crate::config::TpmConfig;
+use crate::files::PemPrivateKey;
+
+
+/// The TCTI to use if none is configured, neither explicitly nor via
+/// the environment.
+const DEFAULT_TCTI: &str = "device:/dev/tpmrm0";
+
+
+/// Split the given data into the contents of the size prefixed
+/// `TPM2B` structure at its start and the remaining data.
+fn split_tpm2b(data: &[u8]) -> Result<(&[u8], &[u8])> {
+  let (size, rest) = match data {
+    [hi, lo, rest @ ..] => (usize::from(*hi) << 8 | usize::from(*lo), rest),
+    _ => return Err(anyhow!("sealed key is truncated")),
+  };
+  ensure!(rest.len() >= size, "sealed key is truncated");
+  Ok(rest.split_at(size))
+}
+
+
+/// Split a sealed key into its public and private parts.
+fn parse_sealed(data: &[u8]) -> Result<(&[u8], &[u8])> {
+  let (public, rest) = split_tpm2b(data)?;
+  let (private, rest) = split_tpm2b(rest)?;
+  ensure!(rest.is_empty(), "sealed key is followed by trailing data");
+  ensure!(!public.is_empty() && !private.is_empty(), "sealed key is empty");
+  Ok((public, private))
+}
+
+
+/// Select the given PCRs of the SHA-256 bank.
+fn pcr_selection(pcrs: &[u8]) -> Result<PcrSelectionList> {
+  let slots = pcrs
+    .iter()
+    .map(|pcr| {
+      1u32
+        .checked_shl(u32::from(*pcr))
+        .and_then(|slot| PcrSlot::try_from(slot).ok())
+        .ok_or_else(|| anyhow!("PCR {pcr} is invalid"))
+    })
+    .collect::<Result<Vec<_>>>()?;
+  PcrSelectionList::builder()
+    .with_selection(HashingAlgorithm::Sha256, &slots)
+    .build()
+    .context("failed to create PCR selection")
+}
+
+
+/// Connect to the TPM.
+fn connect(config: &TpmConfig) -> Result<Context> {
+  let tcti = match &config.tcti {
+    Some(tcti) => TctiNameConf::from_str(tcti),
+    None => TctiNameConf::from_environment_variable()
+      .or_else(|_| TctiNameConf::from_str(DEFAULT_TCTI)),
+  }
+  .context("invalid TCTI configured")?;
+  Context::new(tcti).context("failed to connect to TPM")
+}
+
+
+/// Unseal the given loaded sealed data object, satisfying its PCR
+/// policy, if any.
+fn unseal_object(
+  context: &mut Context,
+  object: ObjectHandle,
+  selection: Option<PcrSelectionList>,
+) -> Result<SensitiveData> {
+  let selection = match selection {
+    Some(selection) => selection,
+    None => {
+      return context
+        .execute_with_nullauth_session(|context| context.unseal(object))
+        .context("failed to unseal data")
+    },
+  };
+
+  let session = context
+    .start_auth_session(
+      None,
+      None,
+      None,
+      SessionType::Policy,
+      SymmetricDefinition::AES_128_CFB,
+      HashingAlgorithm::Sha256,
+    )
+    .context("failed to start TPM policy session")?
+    .ok_or_else(|| anyhow!("TPM failed to start policy session"))?;
+  let result = PolicySession::try_from(session)
+    .and_then(|policy| context.policy_pcr(policy, Digest::default(), selection))
+    .context("PCR policy is not satisfied")
+    .and_then(|()| {
+      context
+        .execute_with_session(Some(session), |context| context.unseal(object))
+        .context("failed to unseal data")
+    });
+  let _ = context.flush_context(ObjectHandle::from(SessionHandle::from(session)));
+  result
+}
+
+
+/// Unseal the private key sealed to the TPM in the given file.
+///
+/// If the key is sealed with a PCR policy, the PCRs it is bound to have
+/// to be provided.
+pub fn unseal(file: &Path, config: &TpmConfig, pcrs: &[u8]) -> Result<PemPrivateKey> {
+  let data = read(file).with_context(|| format!("failed to read {}", file.display()))?;
+  let (public, private) =
+    parse_sealed(&data).with_context(|| format!("{} is not a valid sealed key", file.display()))?;
+  let public = Public::unmarshall(public).context("failed to parse public part of sealed key")?;
+  let private =
+    Private::try_from(private.to_vec()).context("failed to parse private part of sealed key")?;
+
+  let mut context = connect(config)?;
+  let parent = PersistentTpmHandle::new(config.parent)
+    .map(TpmHandle::Persistent)
+    .and_then(|handle| context.tr_from_tpm_public(handle))
+    .with_context(|| format!("failed to access TPM parent key {:#x}", config.parent))?;
+  let key = context
+    .execute_with_nullauth_session(|context| {
+      context.load(KeyHandle::from(parent), private, public)
+    })
+    .with_context(|| format!("failed to load {} into TPM", file.display()))?;
+
+  let selection = (!pcrs.is_empty()).then(|| pcr_selection(pcrs)).transpose()?;
+  let result = unseal_object(&mut context, ObjectHandle::from(key), selection);
+  let _ = context.flush_context(ObjectHandle::from(key));
+
+  let sealed = result.with_context(|| format!("failed to unseal {}", file.display()))?;
+  Ok(PemPrivateKey::from(sealed.value().to_vec()))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+
+  /// Check that we can split up sealed keys.
+  #[test]
+  fn sealed_key_parsing() -> Result<()> {
+    let (public, private) = parse_sealed(b"\x00\x02ab\x00\x03cde")?;
+    assert_eq!(public, b"ab");
+    assert_eq!(private, b"cde");
+
+    assert!(parse_sealed(b"").is_err());
+    assert!(parse_sealed(b"\x00\x02ab").is_err());
+    assert!(parse_sealed(b"\x00\x02ab\x00\x04cde").is_err());
+    assert!(parse_sealed(b"\x00\x02ab\x00\x03cdef").is_err());
+    assert!(parse_sealed(b"\x00\x00\x00\x00").is_err());
+    Ok(())
+  }
+
+  /// Check that we select the expected PCRs.
+  #[test]
+  fn pcr_selections() -> Result<()> {
+    let selection = pcr_selection(&[0, 7])?;
+    let slots = selection
+      .get_selections()
+      .iter()
+      .flat_map(|selection| selection.selected())
+      .collect::<Vec<_>>();
+    assert_eq!(slots, vec![PcrSlot::Slot0, PcrSlot::Slot7]);
+
+    assert!(pcr_selection(&[32]).is_err());
+    assert!(pcr_selection(&[255]).is_err());
+    Ok(())
+  }
+}
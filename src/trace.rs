@@ -0,0 +1,189 @@
+// trace.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Tracing of the agent's handling of requests.
+//!
+//! The handling of each request is broken down into spans for its
+//! stages, such as the lookup of the key to use, its decryption,
+//! prompting the user, and the creation of the signature. The time
+//! spent in each stage is logged in the [`TIMING`] category at the
+//! "debug" level, making it possible to tell where latency stems from.
+
+use std::fmt::Debug;
+use std::fmt::Write as _;
+use std::time::Instant;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use log::debug;
+use log::Record;
+
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::span::Attributes;
+use tracing::span::Id;
+use tracing::subscriber::set_global_default;
+use tracing::Event;
+use tracing::Level;
+use tracing::Subscriber;
+
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::layer::SubscriberExt as _;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+use tracing_subscriber::Registry;
+
+use crate::logging::TIMING;
+
+
+/// The state we keep for each span.
+#[derive(Debug)]
+struct Timed {
+  /// The point in time the span got created.
+  start: Instant,
+  /// The span's fields, formatted for logging.
+  fields: String,
+}
+
+impl Visit for Timed {
+  fn record_str(&mut self, field: &Field, value: &str) {
+    let _ = write!(self.fields, " {}={value}", field.name());
+  }
+
+  fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+    let _ = write!(self.fields, " {}={value:?}", field.name());
+  }
+}
+
+
+/// The message and further fields of an event, formatted for logging.
+#[derive(Debug, Default)]
+struct Formatted(String);
+
+impl Visit for Formatted {
+  fn record_str(&mut self, field: &Field, value: &str) {
+    if field.name() == "message" {
+      let () = self.0.insert_str(0, value);
+    } else {
+      let _ = write!(self.0, " {}={value}", field.name());
+    }
+  }
+
+  fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+    if field.name() == "message" {
+      let () = self.0.insert_str(0, &format!("{value:?}"));
+    } else {
+      let _ = write!(self.0, " {}={value:?}", field.name());
+    }
+  }
+}
+
+
+/// A layer forwarding events to the logger.
+#[derive(Debug)]
+struct Forward;
+
+impl<S> Layer<S> for Forward
+where
+  S: Subscriber,
+{
+  fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+    let metadata = event.metadata();
+    let level = match *metadata.level() {
+      Level::ERROR => log::Level::Error,
+      Level::WARN => log::Level::Warn,
+      Level::INFO => log::Level::Info,
+      Level::DEBUG => log::Level::Debug,
+      Level::TRACE => log::Level::Trace,
+    };
+    let log_metadata = log::Metadata::builder()
+      .level(level)
+      .target(metadata.target())
+      .build();
+    let logger = log::logger();
+    if level > log::max_level() || !logger.enabled(&log_metadata) {
+      return
+    }
+
+    let mut formatted = Formatted::default();
+    let () = event.record(&mut formatted);
+    let () = logger.log(
+      &Record::builder()
+        .metadata(log_metadata)
+        .args(format_args!("{}", formatted.0))
+        .module_path(metadata.module_path())
+        .file(metadata.file())
+        .line(metadata.line())
+        .build(),
+    );
+  }
+}
+
+
+/// A layer logging the time spent in each span once it is closed.
+#[derive(Debug)]
+struct Timing;
+
+impl<S> Layer<S> for Timing
+where
+  S: Subscriber + for<'span> LookupSpan<'span>,
+{
+  fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+    if let Some(span) = ctx.span(id) {
+      let mut timed = Timed {
+        start: Instant::now(),
+        fields: String::new(),
+      };
+      let () = attrs.record(&mut timed);
+      let () = span.extensions_mut().insert(timed);
+    }
+  }
+
+  fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+    if let Some(span) = ctx.span(&id) {
+      if let Some(timed) = span.extensions().get::<Timed>() {
+        let path = span
+          .scope()
+          .from_root()
+          .map(|span| span.name())
+          .collect::<Vec<_>>()
+          .join("/");
+        // We log directly, as opposed to emitting a tracing event, lest
+        // we re-enter the subscriber while it is closing the span.
+        debug!(
+          target: TIMING,
+          "{path}{} took {:.3?}",
+          timed.fields,
+          timed.start.elapsed()
+        );
+      }
+    }
+  }
+}
+
+
+/// Set up tracing of request handling.
+///
+/// Tracing events continue to be forwarded to the logger set up via
+/// [`init_logging`][crate::init_logging].
+pub fn init_tracing() -> Result<()> {
+  let subscriber = Registry::default().with(Forward).with(Timing);
+  set_global_default(subscriber).context("failed to initialize tracing")
+}
@@ -0,0 +1,1054 @@
+// transport.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Serving of the agent protocol over Unix domain and TCP sockets.
+//!
+//! Clients connecting via the Unix domain socket are identified by
+//! their credentials, which the agent may take into account. It also
+//! gets to keep track of the SSH sessions each such connection is bound
+//! to.
+//!
+//! Each connection is served by a thread of its own. Hence, a request
+//! that takes long to handle (e.g., because the user is prompted for a
+//! passphrase) only holds up the client that issued it, while others
+//! continue to be served.
+//!
+//! Unlike a Unix domain socket, a TCP socket is not protected by file
+//! system permissions. Hence, every client has to authenticate itself
+//! before it gets to talk to the agent: the first line it sends has to
+//! contain a pre-shared token. Only after that does the regular agent
+//! protocol commence. As nothing is known about the processes behind
+//! TCP connections, the agent may treat them as remote clients (see
+//! [`RemoteAgent`]).
+
+use std::ffi::CString;
+use std::fs::read_to_string;
+use std::fs::remove_file;
+use std::fs::set_permissions;
+use std::fs::DirBuilder;
+use std::fs::Permissions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Error as IoError;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Result as IoResult;
+use std::io::Write;
+use std::mem::MaybeUninit;
+use std::net::TcpListener;
+use std::net::TcpStream;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt as _;
+use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::fs::DirBuilderExt as _;
+use std::os::unix::fs::PermissionsExt as _;
+use std::os::unix::io::AsRawFd as _;
+use std::os::unix::net::SocketAddr;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::ptr::null_mut;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::thread::spawn;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context as _;
+use anyhow::Error;
+use anyhow::Result;
+
+use libc::chown;
+use libc::geteuid;
+use libc::getgrnam_r;
+use libc::gid_t;
+use libc::nfds_t;
+use libc::poll;
+use libc::pollfd;
+use libc::uid_t;
+use libc::ERANGE;
+use libc::POLLIN;
+
+use ssh_agent_lib::agent::Agent;
+use ssh_agent_lib::proto::from_bytes;
+use ssh_agent_lib::proto::message::Extension;
+use ssh_agent_lib::proto::message::ExtensionContents;
+use ssh_agent_lib::proto::message::Message;
+use ssh_agent_lib::proto::to_bytes;
+
+use tracing::debug;
+use tracing::debug_span;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::agent::PING_EXTENSION;
+use crate::agent::SHUTDOWN_EXTENSION;
+use crate::constraint::decode_add_id_constrained;
+use crate::constraint::SSH_AGENTC_ADD_ID_CONSTRAINED;
+use crate::error::ErrorReport;
+use crate::error::ProtocolError;
+use crate::logging::CLIENTS;
+use crate::peer::Peer;
+use crate::session::Session;
+
+
+/// The maximum length of the authentication line we accept.
+const MAX_TOKEN_LEN: u64 = 4096;
+/// The maximum length of a single agent protocol message, in line with
+/// what OpenSSH's agent accepts.
+const MAX_MESSAGE_LEN: u32 = 256 * 1024;
+/// The message number of the agent's generic failure response.
+const SSH_AGENT_FAILURE: u8 = 5;
+/// The time a client has to authenticate itself.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// The time to wait for an agent already listening on a socket to
+/// answer a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+
+/// An agent that can be shared between multiple transports.
+#[derive(Debug)]
+pub struct Shared<A>(pub Arc<A>);
+
+impl<A> Agent for Shared<A>
+where
+  A: Agent,
+{
+  type Error = A::Error;
+
+  fn handle(&self, message: Message) -> Result<Message, Self::Error> {
+    self.0.handle(message)
+  }
+}
+
+
+/// An agent that takes into account the credentials of the processes
+/// connecting to it via a Unix domain socket.
+pub trait PeerAgent: Agent {
+  /// Check whether to accept a connection from the given peer.
+  fn accept(&self, peer: &Peer) -> Result<()>;
+
+  /// Handle a message sent by the given peer over the connection with
+  /// the provided session state.
+  fn handle_peer(
+    &self,
+    peer: &Peer,
+    session: &Session,
+    message: Message,
+  ) -> Result<Message, Self::Error>;
+}
+
+impl<A> PeerAgent for Shared<A>
+where
+  A: PeerAgent,
+{
+  fn accept(&self, peer: &Peer) -> Result<()> {
+    self.0.accept(peer)
+  }
+
+  fn handle_peer(
+    &self,
+    peer: &Peer,
+    session: &Session,
+    message: Message,
+  ) -> Result<Message, Self::Error> {
+    self.0.handle_peer(peer, session, message)
+  }
+}
+
+
+/// An agent that tells apart clients connecting remotely, via TCP.
+pub trait RemoteAgent: Agent {
+  /// Handle a message sent by a remote client over the connection with
+  /// the provided session state.
+  fn handle_remote(&self, session: &Session, message: Message) -> Result<Message, Self::Error>;
+}
+
+impl<A> RemoteAgent for Shared<A>
+where
+  A: RemoteAgent,
+{
+  fn handle_remote(&self, session: &Session, message: Message) -> Result<Message, Self::Error> {
+    self.0.handle_remote(session, message)
+  }
+}
+
+
+/// Load the authentication token from the given file.
+pub fn load_token(path: &Path) -> Result<Vec<u8>> {
+  let token = read_to_string(path)
+    .with_context(|| format!("failed to read TCP token file {}", path.display()))?;
+  let token = token.trim();
+  ensure!(
+    !token.is_empty(),
+    "TCP token file {} is empty",
+    path.display()
+  );
+  Ok(token.as_bytes().to_vec())
+}
+
+
+/// Compare two tokens in constant time (with respect to their
+/// contents).
+fn tokens_equal(lhs: &[u8], rhs: &[u8]) -> bool {
+  lhs.len() == rhs.len() && lhs.iter().zip(rhs).fold(0, |acc, (l, r)| acc | (l ^ r)) == 0
+}
+
+
+/// Read the authentication line from the given client and check it
+/// against the expected token.
+fn authenticate<R>(reader: &mut R, token: &[u8]) -> Result<()>
+where
+  R: BufRead,
+{
+  let mut line = Vec::new();
+  let _count = reader
+    .take(MAX_TOKEN_LEN)
+    .read_until(b'\n', &mut line)
+    .with_context(|| "failed to read authentication token")?;
+  if line.last() != Some(&b'\n') {
+    bail!("authentication token not terminated by newline")
+  }
+  let _newline = line.pop();
+  if line.last() == Some(&b'\r') {
+    let _cr = line.pop();
+  }
+
+  ensure!(tokens_equal(&line, token), "invalid authentication token");
+  Ok(())
+}
+
+
+/// Read a single length prefixed agent protocol message, returning
+/// `None` once the client closed the connection.
+pub fn read_message<R>(reader: &mut R) -> Result<Option<Message>>
+where
+  R: Read,
+{
+  let mut len = [0; 4];
+  match reader.read_exact(&mut len) {
+    Ok(()) => (),
+    Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+    Err(err) => return Err(err).with_context(|| "failed to read message length"),
+  }
+
+  let len = u32::from_be_bytes(len);
+  ensure!(
+    len <= MAX_MESSAGE_LEN,
+    "message of {len} bytes exceeds maximum size"
+  );
+
+  let mut buffer = vec![0; len as usize];
+  let () = reader
+    .read_exact(&mut buffer)
+    .with_context(|| "failed to read message")?;
+  let message = debug_span!("parse")
+    .in_scope(|| decode_message(&buffer))
+    .with_context(|| "failed to decode message")?;
+  Ok(Some(message))
+}
+
+
+/// Decode a single agent protocol message (without length prefix).
+fn decode_message(buffer: &[u8]) -> Result<Message> {
+  match buffer.split_first() {
+    Some((&SSH_AGENTC_ADD_ID_CONSTRAINED, data)) => {
+      let constrained = decode_add_id_constrained(data)?;
+      Ok(Message::AddIdConstrained(constrained))
+    },
+    _ => Ok(from_bytes::<Message>(buffer)?),
+  }
+}
+
+
+/// Handle a single encoded agent protocol message (without length
+/// prefix), returning the encoded response (again without prefix).
+///
+/// Messages that fail to decode are answered with a failure, just as
+/// requests the agent fails to handle. As it neither depends on a
+/// connection nor on the framing of messages, this function serves as
+/// entry point for fuzzing the agent.
+pub fn handle_bytes<A>(agent: &A, request: &[u8]) -> Vec<u8>
+where
+  A: Agent,
+{
+  let response = match decode_message(request) {
+    Ok(message) => agent.handle(message).unwrap_or(Message::Failure),
+    Err(err) => {
+      debug!("Failed to decode message: {err:#}");
+      Message::Failure
+    },
+  };
+  to_bytes(&response).unwrap_or_else(|err| {
+    warn!("Failed to encode response: {err:#}");
+    vec![SSH_AGENT_FAILURE]
+  })
+}
+
+
+/// Write a single agent protocol message, including its length prefix.
+pub fn write_message<W>(writer: &mut W, message: &Message) -> Result<()>
+where
+  W: Write,
+{
+  let bytes = to_bytes(message).with_context(|| "failed to encode message")?;
+  let bytes = to_bytes(&bytes).with_context(|| "failed to encode message")?;
+  let () = writer
+    .write_all(&bytes)
+    .with_context(|| "failed to write message")?;
+  Ok(())
+}
+
+
+/// Handle a single TCP client.
+fn handle_client<A>(agent: &A, stream: TcpStream, token: &[u8]) -> Result<()>
+where
+  A: RemoteAgent,
+{
+  let mut writer = stream
+    .try_clone()
+    .with_context(|| "failed to clone TCP stream")?;
+  let mut reader = BufReader::new(stream);
+
+  let () = reader
+    .get_ref()
+    .set_read_timeout(Some(AUTH_TIMEOUT))
+    .with_context(|| "failed to set read timeout")?;
+  let () = authenticate(&mut reader, token)?;
+  let () = reader
+    .get_ref()
+    .set_read_timeout(None)
+    .with_context(|| "failed to clear read timeout")?;
+
+  let session = Session::default();
+  while let Some(message) = read_message(&mut reader)? {
+    let response = agent
+      .handle_remote(&session, message)
+      .unwrap_or(Message::Failure);
+    let () = write_message(&mut writer, &response)?;
+  }
+  Ok(())
+}
+
+
+/// Handle a single client connected via a Unix domain socket.
+fn handle_unix_client<A>(agent: &A, stream: UnixStream) -> Result<()>
+where
+  A: PeerAgent,
+{
+  let peer = Peer::from_stream(&stream)?;
+  debug!(target: CLIENTS, "Accepted connection from {peer}");
+  // Sockets in the abstract namespace lack permissions, so we have to
+  // keep other users out ourselves.
+  if stream.local_addr().is_ok_and(|addr| is_abstract(&addr)) {
+    // SAFETY: `geteuid` is always safe to call.
+    let uid = unsafe { geteuid() };
+    ensure!(
+      peer.uid == uid || peer.uid == 0,
+      "rejecting connection from {peer}: process belongs to another user"
+    );
+  }
+  let () = agent.accept(&peer)?;
+
+  let mut writer = stream
+    .try_clone()
+    .with_context(|| "failed to clone Unix stream")?;
+  let mut reader = BufReader::new(stream);
+  let session = Session::default();
+
+  while let Some(message) = read_message(&mut reader)? {
+    let response = agent
+      .handle_peer(&peer, &session, message)
+      .unwrap_or(Message::Failure);
+    let () = write_message(&mut writer, &response)?;
+  }
+  Ok(())
+}
+
+
+/// Retrieve the name of the socket in the abstract namespace the given
+/// path refers to, if any.
+///
+/// Such sockets are denoted by a leading `@`, which takes the place of
+/// the NUL byte their names start with on the system level.
+pub fn abstract_name(path: &Path) -> Option<&[u8]> {
+  path.as_os_str().as_bytes().strip_prefix(b"@")
+}
+
+
+/// Create the address of the socket with the given name in the
+/// abstract namespace.
+#[cfg(target_os = "linux")]
+pub(crate) fn abstract_addr(name: &[u8]) -> IoResult<SocketAddr> {
+  SocketAddr::from_abstract_name(name)
+}
+
+/// Create the address of the socket with the given name in the
+/// abstract namespace.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn abstract_addr(_name: &[u8]) -> IoResult<SocketAddr> {
+  Err(IoError::new(
+    ErrorKind::Unsupported,
+    "abstract Unix domain sockets are only supported on Linux",
+  ))
+}
+
+
+/// Check whether the given socket address is in the abstract namespace.
+#[cfg(target_os = "linux")]
+fn is_abstract(addr: &SocketAddr) -> bool {
+  addr.as_abstract_name().is_some()
+}
+
+/// Check whether the given socket address is in the abstract namespace.
+#[cfg(not(target_os = "linux"))]
+fn is_abstract(_addr: &SocketAddr) -> bool {
+  false
+}
+
+
+/// Connect to the Unix domain socket at the given path, which may refer
+/// to a socket in the abstract namespace.
+pub fn connect_unix(path: &Path) -> IoResult<UnixStream> {
+  match abstract_name(path) {
+    Some(name) => UnixStream::connect_addr(&abstract_addr(name)?),
+    None => UnixStream::connect(path),
+  }
+}
+
+
+/// Bind a Unix domain socket at the given path, for serving the agent
+/// protocol on it via [`serve_unix`].
+///
+/// The directory containing the socket is created, accessible only to
+/// the user, if it does not exist yet. A stale socket left behind by an
+/// agent that is no longer running is replaced, but a socket some agent
+/// is still listening on is not touched. The socket itself is made
+/// accessible only to the user.
+///
+/// A socket in the abstract namespace (see [`abstract_name`]) has no
+/// file system presence and, hence, neither needs cleaning up nor has
+/// permissions; connections from other users are always rejected.
+pub fn bind_unix(path: &Path) -> Result<UnixListener> {
+  if let Some(name) = abstract_name(path) {
+    let listener = abstract_addr(name)
+      .and_then(|addr| UnixListener::bind_addr(&addr))
+      .with_context(|| format!("failed to bind abstract Unix domain socket {}", path.display()))?;
+    info!("Listening on abstract Unix domain socket {}", path.display());
+    return Ok(listener)
+  }
+
+  if let Some(dir) = path.parent() {
+    if !dir.as_os_str().is_empty() && !dir.exists() {
+      let () = DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("failed to create directory {}", dir.display()))?;
+    }
+  }
+
+  if connect_unix(path).is_ok() {
+    bail!("{} is in use by a running agent", path.display())
+  }
+  match remove_file(path) {
+    Ok(()) => debug!("Removed stale socket {}", path.display()),
+    Err(err) if err.kind() == ErrorKind::NotFound => (),
+    Err(err) => {
+      return Err(err).with_context(|| format!("failed to remove stale socket {}", path.display()))
+    },
+  }
+
+  let listener = UnixListener::bind(path)
+    .with_context(|| format!("failed to bind Unix domain socket to {}", path.display()))?;
+  let () = set_permissions(path, Permissions::from_mode(0o600))
+    .with_context(|| format!("failed to set permissions of {}", path.display()))?;
+  info!("Listening on Unix domain socket {}", path.display());
+  Ok(listener)
+}
+
+
+/// Look up the ID of the group with the given name or, if numeric, ID.
+fn lookup_group(group: &str) -> Result<gid_t> {
+  if let Ok(gid) = group.parse() {
+    return Ok(gid)
+  }
+
+  let name = CString::new(group).with_context(|| format!("invalid group name {group:?}"))?;
+  let mut buffer = vec![0; 1024];
+  loop {
+    let mut entry = MaybeUninit::<libc::group>::uninit();
+    let mut result = null_mut();
+    // SAFETY: All pointers are valid for the duration of the call and
+    //         the buffer length matches the buffer's actual size.
+    let rc = unsafe {
+      getgrnam_r(
+        name.as_ptr(),
+        entry.as_mut_ptr(),
+        buffer.as_mut_ptr(),
+        buffer.len(),
+        &mut result,
+      )
+    };
+    match rc {
+      0 if result.is_null() => bail!("group {group} does not exist"),
+      // SAFETY: The entry got initialized, as reported by `result`.
+      0 => return Ok(unsafe { entry.assume_init() }.gr_gid),
+      ERANGE if buffer.len() < 1024 * 1024 => buffer.resize(buffer.len() * 2, 0),
+      _ => {
+        return Err(IoError::from_raw_os_error(rc))
+          .with_context(|| format!("failed to look up group {group}"))
+      },
+    }
+  }
+}
+
+
+/// Adjust the permissions of the Unix domain socket at the given path,
+/// as created by [`bind_unix`], to the given mode and, optionally,
+/// share it with the given group.
+///
+/// The mode has to grant the user read and write access, but must not
+/// grant any access to other users, so that the socket can never be
+/// world accessible.
+pub fn share_unix(path: &Path, mode: u32, group: Option<&str>) -> Result<()> {
+  ensure!(
+    abstract_name(path).is_none(),
+    "abstract Unix domain socket {} has no permissions to adjust",
+    path.display()
+  );
+  ensure!(
+    mode & !0o770 == 0,
+    "socket mode {mode:#o} must not grant access to other users"
+  );
+  ensure!(
+    mode & 0o600 == 0o600,
+    "socket mode {mode:#o} must grant read and write access to the user"
+  );
+
+  if let Some(group) = group {
+    let gid = lookup_group(group)?;
+    let c_path = CString::new(path.as_os_str().as_bytes())
+      .with_context(|| format!("invalid socket path {}", path.display()))?;
+    // SAFETY: `c_path` is a valid NUL terminated string; an owner of -1
+    //         leaves the owning user unchanged.
+    let rc = unsafe { chown(c_path.as_ptr(), uid_t::MAX, gid) };
+    if rc != 0 {
+      return Err(IoError::last_os_error())
+        .with_context(|| format!("failed to change group of {} to {group}", path.display()))
+    }
+
+    if let Some(dir) = path.parent().and_then(|dir| dir.metadata().ok()) {
+      if dir.permissions().mode() & 0o010 == 0 {
+        warn!(
+          "{} is not accessible to group {group}, because the directory containing it is not",
+          path.display()
+        );
+      }
+    }
+  }
+
+  let () = set_permissions(path, Permissions::from_mode(mode))
+    .with_context(|| format!("failed to set permissions of {}", path.display()))?;
+  Ok(())
+}
+
+
+/// Check whether an SSH agent is serving the Unix domain socket at the
+/// given path, by asking it for its identities.
+///
+/// The connection to the agent is returned if it answered.
+pub fn probe_unix(path: &Path) -> Option<UnixStream> {
+  let mut stream = connect_unix(path).ok()?;
+  let () = stream.set_read_timeout(Some(PROBE_TIMEOUT)).ok()?;
+  let () = stream.set_write_timeout(Some(PROBE_TIMEOUT)).ok()?;
+  let () = write_message(&mut stream, &Message::RequestIdentities).ok()?;
+  match read_message(&mut stream) {
+    Ok(Some(Message::IdentitiesAnswer(..))) => Some(stream),
+    _ => None,
+  }
+}
+
+
+/// Ask the agent at the other end of the given connection to shut
+/// down, waiting for it to do so.
+pub fn request_shutdown(mut stream: UnixStream) -> StdResult<(), ProtocolError> {
+  let request = Message::Extension(Extension {
+    extension_type: SHUTDOWN_EXTENSION.to_string(),
+    extension_contents: ExtensionContents(Vec::new()),
+  });
+  let () = write_message(&mut stream, &request)?;
+  match read_message(&mut stream)? {
+    // The agent removes its socket before exiting, at which point the
+    // connection gets closed.
+    Some(Message::Success) => {
+      let _ = read_message(&mut stream);
+      Ok(())
+    },
+    None => Ok(()),
+    Some(response) => match ErrorReport::from_response(&response) {
+      Some(report) => Err(Error::from(report).context("agent refused to shut down").into()),
+      None => Err(anyhow!("agent refused to shut down").into()),
+    },
+  }
+}
+
+
+/// Check that the agent at the other end of the given connection is
+/// alive and responds within the given timeout.
+///
+/// The time it took the agent to respond is returned.
+pub fn ping(stream: &mut UnixStream, timeout: Duration) -> StdResult<Duration, ProtocolError> {
+  let () = stream
+    .set_read_timeout(Some(timeout))
+    .with_context(|| "failed to set read timeout")?;
+  let () = stream
+    .set_write_timeout(Some(timeout))
+    .with_context(|| "failed to set write timeout")?;
+
+  let request = Message::Extension(Extension {
+    extension_type: PING_EXTENSION.to_string(),
+    extension_contents: ExtensionContents(Vec::new()),
+  });
+  let start = Instant::now();
+  let response = write_message(stream, &request)
+    .and_then(|()| read_message(stream))
+    .with_context(|| format!("agent did not respond within {timeout:?}"))?;
+  if let Some(report) = response.as_ref().and_then(ErrorReport::from_response) {
+    return Err(Error::from(report).context("agent failed to respond to ping").into())
+  }
+
+  match response {
+    Some(Message::Success) => Ok(start.elapsed()),
+    Some(Message::Failure | Message::ExtensionFailure) => {
+      Err(anyhow!("agent does not support {PING_EXTENSION} extension").into())
+    },
+    Some(_) => Err(anyhow!("agent sent unexpected response to ping").into()),
+    None => Err(anyhow!("agent closed connection").into()),
+  }
+}
+
+
+/// Serve the agent protocol to clients connecting to the given Unix
+/// domain socket.
+///
+/// This function never returns under normal circumstances.
+pub fn serve_unix<A>(agent: Arc<A>, listener: UnixListener) -> Result<()>
+where
+  A: PeerAgent,
+{
+  serve_unix_all(vec![(listener, agent)])
+}
+
+
+/// Serve the agent protocol to clients connecting to any of the given
+/// Unix domain sockets, each with the agent provided along with it.
+///
+/// Connections are accepted by a single thread waiting for any of the
+/// sockets to become ready, while each client is served on a thread of
+/// its own.
+///
+/// This function never returns under normal circumstances.
+pub fn serve_unix_all<A>(sockets: Vec<(UnixListener, Arc<A>)>) -> Result<()>
+where
+  A: PeerAgent,
+{
+  let mut fds = Vec::with_capacity(sockets.len());
+  for (listener, _agent) in &sockets {
+    let () = listener
+      .set_nonblocking(true)
+      .with_context(|| "failed to make Unix domain socket non-blocking")?;
+    let () = fds.push(pollfd {
+      fd: listener.as_raw_fd(),
+      events: POLLIN,
+      revents: 0,
+    });
+  }
+
+  loop {
+    // SAFETY: `fds` is an array of valid `pollfd` objects of the
+    //         provided length.
+    let rc = unsafe { poll(fds.as_mut_ptr(), fds.len() as nfds_t, -1) };
+    if rc < 0 {
+      let err = IoError::last_os_error();
+      if err.kind() == ErrorKind::Interrupted {
+        continue
+      }
+      return Err(err).with_context(|| "failed to wait for Unix domain socket connections")
+    }
+
+    for (fd, (listener, agent)) in fds.iter_mut().zip(&sockets) {
+      if fd.revents != 0 {
+        fd.revents = 0;
+        let () = accept_unix_clients(listener, agent);
+      }
+    }
+  }
+}
+
+
+/// Accept all pending connections to the given Unix domain socket,
+/// serving each client on a thread of its own.
+fn accept_unix_clients<A>(listener: &UnixListener, agent: &Arc<A>)
+where
+  A: PeerAgent,
+{
+  loop {
+    match listener.accept() {
+      Ok((stream, _addr)) => {
+        // Depending on the system, the connection may have inherited
+        // the listener's non-blocking mode.
+        if let Err(err) = stream.set_nonblocking(false) {
+          error!("Failed to set up Unix domain socket connection: {err}");
+          continue
+        }
+        let agent = agent.clone();
+        let _handle = spawn(move || {
+          if let Err(err) = handle_unix_client(&*agent, stream) {
+            warn!(target: CLIENTS, "Unix domain socket connection failed: {err:#}");
+          }
+        });
+      },
+      Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+      Err(err) => {
+        error!("Failed to accept Unix domain socket connection: {err}");
+        break
+      },
+    }
+  }
+}
+
+
+/// Bind a TCP socket at the given address, for serving the agent
+/// protocol on it via [`serve_tcp`].
+pub fn bind_tcp(addr: &str) -> Result<TcpListener> {
+  let listener =
+    TcpListener::bind(addr).with_context(|| format!("failed to bind TCP socket to {addr}"))?;
+  info!("Listening on TCP socket {addr}");
+  Ok(listener)
+}
+
+
+/// Serve the agent protocol to authenticated clients connecting to the
+/// given TCP socket, in the background.
+pub fn serve_tcp<A>(agent: Arc<A>, listener: TcpListener, token: Vec<u8>) -> Result<()>
+where
+  A: RemoteAgent,
+{
+  let token = Arc::new(token);
+  let _handle = spawn(move || {
+    for stream in listener.incoming() {
+      match stream {
+        Ok(stream) => {
+          let agent = agent.clone();
+          let token = token.clone();
+          let _handle = spawn(move || {
+            let peer = stream.peer_addr().ok();
+            debug!(target: CLIENTS, "Accepted TCP connection from {peer:?}");
+            if let Err(err) = handle_client(&*agent, stream, &token) {
+              warn!(target: CLIENTS, "TCP connection from {peer:?} failed: {err:#}");
+            }
+          });
+        },
+        Err(err) => error!("Failed to accept TCP connection: {err}"),
+      }
+    }
+  });
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::io::Cursor;
+  use std::os::unix::fs::MetadataExt as _;
+  use std::path::PathBuf;
+  use std::process::id;
+  use std::sync::mpsc::channel;
+  use std::sync::mpsc::Receiver;
+  use std::sync::Mutex;
+
+  use libc::getegid;
+
+  use ssh_agent_lib::proto::message::SignRequest;
+
+  use tempfile::tempdir;
+
+
+  /// An agent blocking sign requests until told to continue.
+  #[derive(Debug)]
+  struct BlockingAgent(Mutex<Receiver<()>>);
+
+  impl Agent for BlockingAgent {
+    type Error = ();
+
+    fn handle(&self, message: Message) -> Result<Message, ()> {
+      match message {
+        Message::SignRequest(..) => {
+          let () = self.0.lock().unwrap().recv().unwrap();
+          Ok(Message::SignResponse(Vec::new()))
+        },
+        Message::Extension(..) => Ok(Message::Success),
+        _ => Ok(Message::IdentitiesAnswer(Vec::new())),
+      }
+    }
+  }
+
+  impl PeerAgent for BlockingAgent {
+    fn accept(&self, _peer: &Peer) -> Result<()> {
+      Ok(())
+    }
+
+    fn handle_peer(
+      &self,
+      _peer: &Peer,
+      _session: &Session,
+      message: Message,
+    ) -> Result<Message, ()> {
+      self.handle(message)
+    }
+  }
+
+
+  /// Check that client authentication works as expected.
+  #[test]
+  fn authentication() {
+    let token = b"s3cr3t";
+    assert!(authenticate(&mut Cursor::new(b"s3cr3t\n"), token).is_ok());
+    assert!(authenticate(&mut Cursor::new(b"s3cr3t\r\n"), token).is_ok());
+    assert!(authenticate(&mut Cursor::new(b"s3cr3t"), token).is_err());
+    assert!(authenticate(&mut Cursor::new(b"s3cr3\n"), token).is_err());
+    assert!(authenticate(&mut Cursor::new(b"s3cr3tt\n"), token).is_err());
+    assert!(authenticate(&mut Cursor::new(b"\n"), token).is_err());
+  }
+
+  /// Check that sockets get set up with restrictive permissions and
+  /// that live sockets are left alone.
+  #[test]
+  fn socket_setup() -> Result<()> {
+    let dir = tempdir()?;
+    let socket = dir.path().join("runtime").join("agent.sock");
+    let listener = bind_unix(&socket)?;
+    let mode = |path: &Path| path.metadata().unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(socket.parent().unwrap()), 0o700);
+    assert_eq!(mode(&socket), 0o600);
+
+    // The socket is in use, so it must not be replaced.
+    assert!(bind_unix(&socket).is_err());
+
+    // Once nobody is listening anymore, the socket is stale.
+    drop(listener);
+    let _listener = bind_unix(&socket)?;
+    Ok(())
+  }
+
+  /// Check that sockets can be shared with a group, but never with
+  /// everybody.
+  #[test]
+  fn socket_sharing() -> Result<()> {
+    let dir = tempdir()?;
+    let socket = dir.path().join("agent.sock");
+    let _listener = bind_unix(&socket)?;
+    // SAFETY: `getegid` is always safe to call.
+    let gid = unsafe { getegid() };
+    let () = share_unix(&socket, 0o660, Some(&gid.to_string()))?;
+    let metadata = socket.metadata()?;
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+    assert_eq!(metadata.gid(), gid);
+
+    assert!(share_unix(&socket, 0o666, None).is_err());
+    assert!(share_unix(&socket, 0o460, None).is_err());
+    assert!(share_unix(&socket, 0o600, Some("no-such-group-hopefully")).is_err());
+    Ok(())
+  }
+
+  /// Check that probing a socket detects agents serving it.
+  #[test]
+  fn socket_probing() -> Result<()> {
+    let dir = tempdir()?;
+    let socket = dir.path().join("agent.sock");
+    assert!(probe_unix(&socket).is_none());
+
+    // Something listening on the socket is not necessarily an agent.
+    let listener = bind_unix(&socket)?;
+    let handle = spawn(move || {
+      let _ = listener.accept();
+    });
+    assert!(probe_unix(&socket).is_none());
+    let () = handle.join().unwrap();
+
+    let listener = bind_unix(&socket)?;
+    let (_sender, receiver) = channel();
+    let agent = Arc::new(BlockingAgent(Mutex::new(receiver)));
+    let _handle = spawn(move || serve_unix(agent, listener));
+    assert!(probe_unix(&socket).is_some());
+    Ok(())
+  }
+
+  /// Check that pinging an agent succeeds only if it responds in time.
+  #[test]
+  fn pinging() -> Result<()> {
+    let dir = tempdir()?;
+    let socket = dir.path().join("agent.sock");
+    let timeout = Duration::from_millis(100);
+
+    let listener = bind_unix(&socket)?;
+    let handle = spawn(move || listener.accept());
+    let mut stream = connect_unix(&socket)?;
+    let err = ping(&mut stream, timeout).unwrap_err();
+    assert!(err.to_string().contains("did not respond"), "{err:#}");
+    let _ = handle.join().unwrap();
+    let () = remove_file(&socket)?;
+
+    let listener = bind_unix(&socket)?;
+    let (_sender, receiver) = channel();
+    let agent = Arc::new(BlockingAgent(Mutex::new(receiver)));
+    let _handle = spawn(move || serve_unix(agent, listener));
+    let mut stream = connect_unix(&socket)?;
+    let _duration = ping(&mut stream, timeout)?;
+    Ok(())
+  }
+
+  /// Check that we can serve the agent on a socket in the abstract
+  /// namespace.
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn abstract_socket() -> Result<()> {
+    let name = format!("ssh-gpg-agent-test-{}", id());
+    let socket = PathBuf::from(format!("@{name}"));
+    assert_eq!(abstract_name(&socket), Some(name.as_bytes()));
+
+    let listener = bind_unix(&socket)?;
+    assert!(bind_unix(&socket).is_err());
+
+    let (_sender, receiver) = channel();
+    let agent = Arc::new(BlockingAgent(Mutex::new(receiver)));
+    let _handle = spawn(move || serve_unix(agent, listener));
+    assert!(probe_unix(&socket).is_some());
+    Ok(())
+  }
+
+  /// Check that clients of all sockets get served.
+  #[test]
+  fn multiple_sockets() -> Result<()> {
+    let dir = tempdir()?;
+    let sockets = (0..3)
+      .map(|i| dir.path().join(format!("agent{i}.sock")))
+      .collect::<Vec<_>>();
+    let (_sender, receiver) = channel();
+    let agent = Arc::new(BlockingAgent(Mutex::new(receiver)));
+    let listeners = sockets
+      .iter()
+      .map(|socket| Ok((bind_unix(socket)?, agent.clone())))
+      .collect::<Result<Vec<_>>>()?;
+    let _handle = spawn(move || serve_unix_all(listeners));
+
+    for socket in sockets.iter().rev().chain(&sockets) {
+      let mut stream = UnixStream::connect(socket)?;
+      let () = write_message(&mut stream, &Message::RequestIdentities)?;
+      assert_eq!(
+        read_message(&mut stream)?,
+        Some(Message::IdentitiesAnswer(Vec::new()))
+      );
+    }
+    Ok(())
+  }
+
+  /// Check that a pending request does not prevent other clients from
+  /// being served.
+  #[test]
+  fn concurrent_clients() -> Result<()> {
+    let dir = tempdir()?;
+    let socket = dir.path().join("agent.sock");
+    let listener = bind_unix(&socket)?;
+    let (sender, receiver) = channel();
+    let agent = Arc::new(BlockingAgent(Mutex::new(receiver)));
+    let _handle = spawn(move || serve_unix(agent, listener));
+
+    let request = SignRequest {
+      pubkey_blob: Vec::new(),
+      data: Vec::new(),
+      flags: 0,
+    };
+    let mut blocked = UnixStream::connect(&socket)?;
+    let () = write_message(&mut blocked, &Message::SignRequest(request))?;
+
+    let mut other = UnixStream::connect(&socket)?;
+    let () = write_message(&mut other, &Message::RequestIdentities)?;
+    assert_eq!(
+      read_message(&mut other)?,
+      Some(Message::IdentitiesAnswer(Vec::new()))
+    );
+
+    let () = sender.send(()).unwrap();
+    assert_eq!(
+      read_message(&mut blocked)?,
+      Some(Message::SignResponse(Vec::new()))
+    );
+    Ok(())
+  }
+
+  /// Check that we can round trip messages through our framing.
+  #[test]
+  fn message_framing() -> Result<()> {
+    let mut buffer = Vec::new();
+    let () = write_message(&mut buffer, &Message::RequestIdentities)?;
+    let () = write_message(&mut buffer, &Message::Success)?;
+
+    let mut reader = Cursor::new(buffer);
+    assert_eq!(
+      read_message(&mut reader)?,
+      Some(Message::RequestIdentities)
+    );
+    assert_eq!(read_message(&mut reader)?, Some(Message::Success));
+    assert_eq!(read_message(&mut reader)?, None);
+    Ok(())
+  }
+
+  /// Check that malformed messages are answered with a failure.
+  #[test]
+  fn malformed_messages() -> Result<()> {
+    let (_sender, receiver) = channel();
+    let agent = BlockingAgent(Mutex::new(receiver));
+    let failure = to_bytes(&Message::Failure)?;
+    assert_eq!(failure, vec![SSH_AGENT_FAILURE]);
+
+    let request = to_bytes(&Message::RequestIdentities)?;
+    let response = handle_bytes(&agent, &request);
+    assert_eq!(from_bytes::<Message>(&response)?, Message::IdentitiesAnswer(Vec::new()));
+
+    let request = to_bytes(&Message::SignRequest(SignRequest {
+      pubkey_blob: vec![1, 2, 3],
+      data: b"test-data".to_vec(),
+      flags: 0,
+    }))?;
+    for len in 0..request.len() {
+      assert_eq!(handle_bytes(&agent, &request[..len]), failure, "{len}");
+    }
+    assert_eq!(handle_bytes(&agent, &[0xff; 16]), failure);
+    assert_eq!(handle_bytes(&agent, &[SSH_AGENTC_ADD_ID_CONSTRAINED, 0]), failure);
+    Ok(())
+  }
+}
@@ -0,0 +1,211 @@
+// upstream.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! A client for another SSH agent, to which we forward requests for
+//! identities we do not manage ourselves.
+
+use std::io::Error as IoError;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::result::Result as StdResult;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Context as _;
+use anyhow::Error;
+use anyhow::Result;
+
+use ssh_agent_lib::proto::message::Message;
+use ssh_agent_lib::proto::message::RemoveIdentity;
+use ssh_agent_lib::proto::message::SignatureBlob;
+use ssh_agent_lib::proto::message::SignRequest;
+
+use crate::error::KeyStoreError;
+use crate::error::RequestError;
+use crate::logging::Redacted;
+use crate::store::KeyStore;
+use crate::store::StoreIdentity;
+use crate::transport::connect_unix;
+use crate::transport::read_message;
+use crate::transport::write_message;
+
+
+/// An upstream SSH agent listening on a Unix domain socket.
+#[derive(Debug)]
+pub struct Upstream {
+  /// The path to the agent's socket.
+  socket: PathBuf,
+  /// The time to wait for the agent to accept a request and to respond
+  /// to it, if limited.
+  timeout: Option<Duration>,
+}
+
+impl Upstream {
+  /// Create a client for the agent listening on the given socket.
+  pub fn new(socket: PathBuf, timeout: Option<Duration>) -> Self {
+    Self { socket, timeout }
+  }
+
+  /// Send a request to the agent, returning its response.
+  ///
+  /// If the agent does not respond in time, the request fails with a
+  /// [`RequestError::TimedOut`] error.
+  fn request(&self, request: &Message) -> Result<Message> {
+    let mut stream = connect_unix(&self.socket).with_context(|| {
+      format!(
+        "failed to connect to upstream agent at {}",
+        self.socket.display()
+      )
+    })?;
+    let () = stream
+      .set_read_timeout(self.timeout)
+      .with_context(|| "failed to set read timeout")?;
+    let () = stream
+      .set_write_timeout(self.timeout)
+      .with_context(|| "failed to set write timeout")?;
+
+    let result = write_message(&mut stream, request).and_then(|()| read_message(&mut stream));
+    match result {
+      Ok(response) => response.ok_or_else(|| anyhow!("upstream agent closed connection")),
+      Err(err) if is_timeout(&err) => {
+        let timeout = self.timeout.unwrap_or_default();
+        Err(err).context(RequestError::TimedOut(format!(
+          "upstream agent did not respond within {timeout:?}"
+        )))
+      },
+      Err(err) => Err(err),
+    }
+  }
+}
+
+
+/// Check whether the given error was caused by an I/O operation timing
+/// out.
+fn is_timeout(err: &Error) -> bool {
+  // Depending on the platform, timed out socket operations report
+  // either of the two.
+  err
+    .downcast_ref::<IoError>()
+    .map(|err| matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+    .unwrap_or(false)
+}
+
+impl KeyStore for Upstream {
+  fn name(&self) -> &str {
+    "upstream"
+  }
+
+  fn identities(
+    &self,
+  ) -> StdResult<Vec<StdResult<StoreIdentity, KeyStoreError>>, KeyStoreError> {
+    let identities = match self.request(&Message::RequestIdentities)? {
+      Message::IdentitiesAnswer(identities) => identities,
+      response => {
+        return Err(
+          anyhow!(
+            "upstream agent sent unexpected response: {}",
+            Redacted(&response)
+          )
+          .into(),
+        )
+      },
+    };
+
+    let identities = identities
+      .into_iter()
+      .map(|identity| {
+        let identity = StoreIdentity {
+          blob: identity.pubkey_blob,
+          pubkey: None,
+          comment: identity.comment,
+          confirm: false,
+        };
+        Ok(identity)
+      })
+      .collect();
+    Ok(identities)
+  }
+
+  fn sign(
+    &self,
+    _identity: &StoreIdentity,
+    request: &SignRequest,
+  ) -> StdResult<SignatureBlob, KeyStoreError> {
+    match self.request(&Message::SignRequest(request.clone()))? {
+      Message::SignResponse(signature) => Ok(signature),
+      Message::Failure => Err(anyhow!("upstream agent failed to create signature").into()),
+      response => Err(
+        anyhow!(
+          "upstream agent sent unexpected response: {}",
+          Redacted(&response)
+        )
+        .into(),
+      ),
+    }
+  }
+
+  /// Ask the agent to remove the identity.
+  fn remove(&self, identity: &StoreIdentity) -> StdResult<(), KeyStoreError> {
+    let request = RemoveIdentity {
+      pubkey_blob: identity.blob.clone(),
+    };
+    match self.request(&Message::RemoveIdentity(request))? {
+      Message::Success => Ok(()),
+      Message::Failure => Err(anyhow!("upstream agent failed to remove identity").into()),
+      response => Err(
+        anyhow!(
+          "upstream agent sent unexpected response: {}",
+          Redacted(&response)
+        )
+        .into(),
+      ),
+    }
+  }
+
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use std::os::unix::net::UnixListener;
+
+  use tempfile::tempdir;
+
+  use crate::error::ErrorKind as RequestErrorKind;
+
+
+  /// Check that requests to an upstream agent not responding to them
+  /// time out.
+  #[test]
+  fn unresponsive_agent() -> Result<()> {
+    let dir = tempdir()?;
+    let socket = dir.path().join("agent.sock");
+    // The listener never accepts the connection, but the kernel
+    // establishes it on its behalf nevertheless.
+    let _listener = UnixListener::bind(&socket)?;
+
+    let upstream = Upstream::new(socket, Some(Duration::from_millis(100)));
+    let err = upstream.identities().unwrap_err();
+    assert_eq!(err.kind(), RequestErrorKind::TimedOut);
+    assert!(err.to_string().contains("did not respond"), "{err}");
+    Ok(())
+  }
+}
@@ -0,0 +1,227 @@
+// usage.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! Tracking of per-key usage statistics.
+//!
+//! Statistics are kept in memory and, if configured, persisted to a
+//! TOML file after each change, so that they survive restarts of the
+//! agent and can be inspected by other instances of the program.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::create_dir_all;
+use std::fs::read_to_string;
+use std::fs::rename;
+use std::fs::write;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context as _;
+use anyhow::Result;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use ssh_agent_lib::proto::public_key::PublicKey;
+
+use tracing::warn;
+
+use crate::fingerprint::Fingerprint;
+use crate::fingerprint::HashAlg;
+
+
+/// Usage statistics of a single key.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct KeyUsage {
+  /// The number of signatures created with the key.
+  pub count: u64,
+  /// The time the key was last used for signing, in seconds since the
+  /// Unix epoch, or zero if it has not been used yet.
+  pub last_used: u64,
+  /// A description of the client that last used the key.
+  pub last_client: String,
+}
+
+
+/// The contents of the file usage statistics are persisted in.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct UsageFile {
+  /// The statistics of each key, indexed by its SHA-256 fingerprint.
+  keys: HashMap<Fingerprint, KeyUsage>,
+}
+
+
+/// A table of per-key usage statistics, optionally persisted to a
+/// file.
+#[derive(Debug)]
+pub struct Usage {
+  /// The file to persist statistics in.
+  file: Option<PathBuf>,
+  /// The statistics of each key, indexed by its SHA-256 fingerprint.
+  keys: Mutex<HashMap<Fingerprint, KeyUsage>>,
+}
+
+impl Usage {
+  /// Create a usage table, loading previously persisted statistics
+  /// from the given file, if any.
+  ///
+  /// Failure to load the file is reported but otherwise ignored, as
+  /// statistics are not essential to the agent's operation.
+  pub fn new(file: Option<PathBuf>) -> Self {
+    let keys = file
+      .as_deref()
+      .map(|file| {
+        load(file).unwrap_or_else(|err| {
+          warn!("{err:#}");
+          HashMap::new()
+        })
+      })
+      .unwrap_or_default();
+
+    Self {
+      file,
+      keys: Mutex::new(keys),
+    }
+  }
+
+  /// Retrieve the usage statistics of the given key.
+  pub fn get(&self, key: &PublicKey) -> Result<KeyUsage> {
+    let fingerprint = Fingerprint::from_key(key, HashAlg::Sha256)?;
+    let usage = self
+      .keys
+      .lock()
+      .unwrap()
+      .get(&fingerprint)
+      .cloned()
+      .unwrap_or_default();
+    Ok(usage)
+  }
+
+  /// Record a usage of the given key by the described client.
+  pub fn record(&self, key: &PublicKey, client: String) -> Result<()> {
+    let fingerprint = Fingerprint::from_key(key, HashAlg::Sha256)?;
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|duration| duration.as_secs())
+      .unwrap_or(0);
+
+    let mut keys = self.keys.lock().unwrap();
+    let usage = keys.entry(fingerprint).or_default();
+    usage.count += 1;
+    usage.last_used = now;
+    usage.last_client = client;
+
+    if let Some(file) = &self.file {
+      let () = save(file, &keys)?;
+    }
+    Ok(())
+  }
+}
+
+
+/// Load usage statistics from the given file, if it exists.
+fn load(file: &Path) -> Result<HashMap<Fingerprint, KeyUsage>> {
+  let toml = match read_to_string(file) {
+    Ok(toml) => toml,
+    Err(err) if err.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+    Err(err) => {
+      return Err(err)
+        .with_context(|| format!("failed to read usage statistics from {}", file.display()))
+    },
+  };
+
+  let usage = toml::from_str::<UsageFile>(&toml)
+    .with_context(|| format!("invalid usage statistics in {}", file.display()))?;
+  Ok(usage.keys)
+}
+
+
+/// Persist usage statistics to the given file.
+///
+/// The statistics are written to a temporary file first, which then
+/// replaces the original one, so that readers never see a partially
+/// written file.
+fn save(file: &Path, keys: &HashMap<Fingerprint, KeyUsage>) -> Result<()> {
+  let usage = UsageFile { keys: keys.clone() };
+  let toml = toml::to_string(&usage).with_context(|| "failed to serialize usage statistics")?;
+
+  if let Some(dir) = file.parent() {
+    let () = create_dir_all(dir)
+      .with_context(|| format!("failed to create directory {}", dir.display()))?;
+  }
+
+  let mut tmp = OsString::from(file);
+  let () = tmp.push(".tmp");
+  let () = write(&tmp, toml)
+    .with_context(|| format!("failed to write usage statistics to {}", file.display()))?;
+  rename(&tmp, file)
+    .with_context(|| format!("failed to write usage statistics to {}", file.display()))
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  use tempfile::tempdir;
+
+  use crate::files::load_public_key;
+  use crate::keys::FromPem as _;
+
+
+  /// Check that we track usage statistics and persist them if
+  /// requested.
+  #[test]
+  fn usage_tracking() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("state").join("usage.toml");
+    let ed25519 = PublicKey::from_pem(load_public_key("tests/valid_keys/ed25519.pub")?)?;
+    let rsa = PublicKey::from_pem(load_public_key("tests/valid_keys/rsa2048.pub")?)?;
+
+    let usage = Usage::new(Some(file.clone()));
+    assert_eq!(usage.get(&ed25519)?, KeyUsage::default());
+
+    let () = usage.record(&ed25519, "ssh".to_string())?;
+    let () = usage.record(&ed25519, "git".to_string())?;
+    let () = usage.record(&rsa, "ssh".to_string())?;
+
+    let ed25519_usage = usage.get(&ed25519)?;
+    assert_eq!(ed25519_usage.count, 2);
+    assert_ne!(ed25519_usage.last_used, 0);
+    assert_eq!(ed25519_usage.last_client, "git");
+    assert_eq!(usage.get(&rsa)?.count, 1);
+
+    // A new table picks up the persisted statistics.
+    let usage = Usage::new(Some(file));
+    assert_eq!(usage.get(&ed25519)?, ed25519_usage);
+    assert_eq!(usage.get(&rsa)?.count, 1);
+
+    // Without a file, nothing gets persisted.
+    let usage = Usage::new(None);
+    let () = usage.record(&rsa, "ssh".to_string())?;
+    assert_eq!(usage.get(&rsa)?.count, 1);
+    Ok(())
+  }
+}
@@ -0,0 +1,586 @@
+// agent.rs
+
+// *************************************************************************
+// * Copyright (C) 2024 Daniel Mueller (deso@posteo.net)                   *
+// *                                                                       *
+// * This program is free software: you can redistribute it and/or modify  *
+// * it under the terms of the GNU General Public License as published by  *
+// * the Free Software Foundation, either version 3 of the License, or     *
+// * (at your option) any later version.                                   *
+// *                                                                       *
+// * This program is distributed in the hope that it will be useful,       *
+// * but WITHOUT ANY WARRANTY; without even the implied warranty of        *
+// * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the         *
+// * GNU General Public License for more details.                          *
+// *                                                                       *
+// * You should have received a copy of the GNU General Public License     *
+// * along with this program.  If not, see <http://www.gnu.org/licenses/>. *
+// *************************************************************************
+
+//! End-to-end tests of the agent, served on a temporary socket and
+//! driven via the agent protocol.
+//!
+//! Private keys are "decrypted" by a [`MockDecryptor`] serving the
+//! unencrypted test keys, so that the full path of a request is covered
+//! without requiring a GnuPG setup.
+
+use std::fs::read;
+use std::fs::remove_file;
+use std::fs::write;
+use std::io::Read as _;
+use std::io::Write as _;
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::spawn;
+
+use anyhow::Result;
+
+use ring::signature::UnparsedPublicKey;
+use ring::signature::ED25519;
+
+use ssh_agent_lib::proto::from_bytes;
+use ssh_agent_lib::proto::message::AddIdentity;
+use ssh_agent_lib::proto::message::Extension;
+use ssh_agent_lib::proto::message::ExtensionContents;
+use ssh_agent_lib::proto::message::Message;
+use ssh_agent_lib::proto::message::RemoveIdentity;
+use ssh_agent_lib::proto::message::SignRequest;
+use ssh_agent_lib::proto::private_key::PrivateKey;
+use ssh_agent_lib::proto::private_key::SkEd25519PrivateKey;
+use ssh_agent_lib::proto::public_key::PublicKey;
+use ssh_agent_lib::proto::signature::RSA_SHA2_256;
+use ssh_agent_lib::proto::signature::RSA_SHA2_512;
+use ssh_agent_lib::proto::to_bytes;
+use ssh_agent_lib::proto::Blob as _;
+
+use ssh_gpg_agent::bind_tcp;
+use ssh_gpg_agent::bind_unix;
+use ssh_gpg_agent::handle_bytes;
+use ssh_gpg_agent::serve_tcp;
+use ssh_gpg_agent::serve_unix;
+use ssh_gpg_agent::sign_batch;
+use ssh_gpg_agent::AgentClient;
+use ssh_gpg_agent::Config;
+use ssh_gpg_agent::ErrorKind;
+use ssh_gpg_agent::ErrorReport;
+use ssh_gpg_agent::Fingerprint;
+use ssh_gpg_agent::FromPem as _;
+use ssh_gpg_agent::GpgKeyAgent;
+use ssh_gpg_agent::MockDecryptor;
+use ssh_gpg_agent::OnAdd;
+use ssh_gpg_agent::RsaSignatureAlgorithm;
+use ssh_gpg_agent::PING_EXTENSION;
+use ssh_gpg_agent::SHUTDOWN_EXTENSION;
+use ssh_gpg_agent::SIGN_BATCH_EXTENSION;
+
+use tempfile::tempdir;
+use tempfile::TempDir;
+
+
+/// The directory containing the test keys.
+fn keys_dir() -> PathBuf {
+  Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("valid_keys")
+}
+
+
+/// An agent served on a socket in a temporary directory.
+struct TestAgent {
+  /// The directory containing the key pairs and the socket.
+  dir: TempDir,
+  /// The directory containing the pre-decrypted private keys.
+  _fixtures: TempDir,
+  /// The decryptor used by the agent.
+  decryptor: MockDecryptor,
+  /// The agent being served.
+  agent: Arc<GpgKeyAgent>,
+}
+
+impl TestAgent {
+  /// Start an agent offering the test keys with the given names.
+  fn start(keys: &[&str]) -> Result<Self> {
+    Self::with_config(keys, Config::default())
+  }
+
+  /// Start an agent offering the test keys with the given names, using
+  /// the provided configuration.
+  fn with_config(keys: &[&str], config: Config) -> Result<Self> {
+    let dir = tempdir()?;
+    let fixtures = tempdir()?;
+    let decryptor = MockDecryptor::new(fixtures.path());
+    for key in keys {
+      let privkey = read(keys_dir().join(key))?;
+      let _path = decryptor.add_key_pair(dir.path(), key, &privkey.into())?;
+    }
+
+    let agent = GpgKeyAgent::new(vec![dir.path().to_path_buf()], None, config)
+      .with_decryptor(decryptor.clone());
+    let agent = Arc::new(agent);
+    let listener = bind_unix(&dir.path().join("agent.sock"))?;
+    // The server thread is torn down along with the test process.
+    let _handle = spawn({
+      let agent = agent.clone();
+      move || serve_unix(agent, listener)
+    });
+    Ok(Self {
+      dir,
+      _fixtures: fixtures,
+      decryptor,
+      agent,
+    })
+  }
+
+  /// Retrieve the path of the agent's socket.
+  fn socket(&self) -> PathBuf {
+    self.dir.path().join("agent.sock")
+  }
+
+  /// Connect a new client to the agent.
+  fn client(&self) -> Result<AgentClient> {
+    let client = AgentClient::connect(&self.socket())?;
+    Ok(client)
+  }
+
+  /// Serve the agent over TCP as well and connect an authenticated
+  /// client to it.
+  fn tcp_client(&self) -> Result<TcpClient> {
+    let listener = bind_tcp("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let () = serve_tcp(self.agent.clone(), listener, b"s3cr3t".to_vec())?;
+    let mut stream = TcpStream::connect(addr)?;
+    let () = stream.write_all(b"s3cr3t\n")?;
+    Ok(TcpClient(stream))
+  }
+}
+
+
+/// A client connected to the agent via TCP.
+struct TcpClient(TcpStream);
+
+impl TcpClient {
+  /// Send the given request to the agent and wait for its response.
+  fn request(&mut self, request: &Message) -> Result<Message> {
+    let () = self.0.write_all(&to_bytes(&to_bytes(request)?)?)?;
+    let mut len = [0; 4];
+    let () = self.0.read_exact(&mut len)?;
+    let mut response = vec![0; u32::from_be_bytes(len) as usize];
+    let () = self.0.read_exact(&mut response)?;
+    Ok(from_bytes(&response)?)
+  }
+}
+
+
+/// Retrieve the failures the agent reports, by kind.
+fn failures(client: &mut AgentClient) -> Result<Vec<(ErrorKind, u64)>> {
+  let failures = client
+    .status()?
+    .failures
+    .iter()
+    .map(|failure| (failure.kind, failure.count))
+    .collect();
+  Ok(failures)
+}
+
+
+/// Check that the agent offers the keys in its directory and signs
+/// with them.
+#[test]
+fn list_and_sign() -> Result<()> {
+  let agent = TestAgent::start(&["ed25519", "rsa2048"])?;
+  let mut client = agent.client()?;
+
+  let mut identities = client.identities()?;
+  let () = identities.sort_by(|lhs, rhs| lhs.comment.cmp(&rhs.comment));
+  let comments = identities
+    .iter()
+    .map(|identity| identity.comment.as_str())
+    .collect::<Vec<_>>();
+  assert_eq!(comments, vec!["ed25519 test", "rsa2048 test"]);
+
+  let ed25519 = &identities[0].pubkey_blob;
+  let signature = client.sign(ed25519, b"test-data", 0)?;
+  assert_eq!(signature.algorithm, "ssh-ed25519");
+  let pubkey = match from_bytes::<PublicKey>(ed25519)? {
+    PublicKey::Ed25519(pubkey) => pubkey,
+    pubkey => panic!("unexpected public key: {pubkey:?}"),
+  };
+  let pubkey = UnparsedPublicKey::new(&ED25519, &pubkey.enc_a);
+  assert!(pubkey.verify(b"test-data", &signature.blob).is_ok());
+
+  let rsa = &identities[1].pubkey_blob;
+  let signature = client.sign(rsa, b"test-data", RSA_SHA2_256)?;
+  assert_eq!(signature.algorithm, "rsa-sha2-256");
+  Ok(())
+}
+
+
+/// Check that keys lacking a public key file are offered once their
+/// public key got derived, which happens only once and never merely
+/// because a client lists identities.
+#[test]
+fn orphaned_key() -> Result<()> {
+  let agent = TestAgent::start(&["ed25519"])?;
+  let () = remove_file(agent.dir.path().join("ed25519.pub"))?;
+  let mut client = agent.client()?;
+
+  assert!(client.identities()?.is_empty());
+  assert_eq!(agent.decryptor.decryptions("ed25519"), 0);
+
+  let () = agent.agent.derive_orphans();
+  let () = agent.agent.derive_orphans();
+  let identities = client.identities()?;
+  assert_eq!(identities.len(), 1);
+  assert_eq!(identities[0].comment, "ed25519");
+  let _identities = client.identities()?;
+  assert_eq!(agent.decryptor.decryptions("ed25519"), 1);
+
+  let signature = client.sign(&identities[0].pubkey_blob, b"test-data", 0)?;
+  assert_eq!(signature.algorithm, "ssh-ed25519");
+  Ok(())
+}
+
+
+/// Check that sign requests for unknown keys fail, without affecting
+/// the connection.
+#[test]
+fn sign_unknown_key() -> Result<()> {
+  let agent = TestAgent::start(&["ed25519"])?;
+  let mut client = agent.client()?;
+
+  assert!(client.sign(b"unknown", b"test-data", 0).is_err());
+  assert_eq!(client.identities()?.len(), 1);
+
+  assert_eq!(failures(&mut client)?, vec![(ErrorKind::KeyNotFound, 1)]);
+  Ok(())
+}
+
+
+/// Check that failing to decrypt a private key fails the request, but
+/// not subsequent ones.
+#[test]
+fn decryption_failure() -> Result<()> {
+  let agent = TestAgent::start(&["ed25519"])?;
+  let mut client = agent.client()?;
+  let blob = client.identities()?.remove(0).pubkey_blob;
+
+  let () = agent.decryptor.set_failing("ed25519", true);
+  assert!(client.sign(&blob, b"test-data", 0).is_err());
+  assert_eq!(failures(&mut client)?, vec![(ErrorKind::DecryptionFailed, 1)]);
+  assert_eq!(agent.decryptor.decryptions("ed25519"), 0);
+
+  let () = agent.decryptor.set_failing("ed25519", false);
+  let _signature = client.sign(&blob, b"test-data", 0)?;
+  assert_eq!(agent.decryptor.decryptions("ed25519"), 1);
+  Ok(())
+}
+
+
+/// Check that decrypted keys are cached only if configured.
+#[test]
+fn key_caching() -> Result<()> {
+  let agent = TestAgent::start(&["ed25519"])?;
+  let mut client = agent.client()?;
+  let blob = client.identities()?.remove(0).pubkey_blob;
+  let _signature = client.sign(&blob, b"test-data", 0)?;
+  let _signature = client.sign(&blob, b"test-data", 0)?;
+  assert_eq!(agent.decryptor.decryptions("ed25519"), 2);
+
+  let mut config = Config::default();
+  config.cache.ttl = 60;
+  let agent = TestAgent::with_config(&["ed25519"], config)?;
+  let mut client = agent.client()?;
+  let blob = client.identities()?.remove(0).pubkey_blob;
+  let _signature = client.sign(&blob, b"test-data", 0)?;
+  let _signature = client.sign(&blob, b"test-data", 0)?;
+  assert_eq!(agent.decryptor.decryptions("ed25519"), 1);
+  assert_eq!(client.status()?.cache.hits, 1);
+  Ok(())
+}
+
+
+/// Check that keys refused by the configured policies are not used.
+#[test]
+fn policy_denial() -> Result<()> {
+  let mut config = Config::default();
+  config.rsa.min_bits = 3072;
+  let agent = TestAgent::with_config(&["rsa2048"], config)?;
+  let mut client = agent.client()?;
+
+  // Keys refused by policy are not offered to begin with, but clients
+  // may still know about them.
+  assert!(client.identities()?.is_empty());
+  let pubkey = read(keys_dir().join("rsa2048.pub"))?;
+  let blob = PublicKey::from_pem(pubkey.into())?.to_blob()?;
+  assert!(client.sign(&blob, b"test-data", RSA_SHA2_256).is_err());
+  assert_eq!(failures(&mut client)?, vec![(ErrorKind::PolicyDenied, 1)]);
+  assert_eq!(agent.decryptor.decryptions("rsa2048"), 0);
+  Ok(())
+}
+
+
+/// Check that RSA keys shorter than `min_bits` can be used for signing
+/// once explicitly allowed.
+#[test]
+fn weak_rsa_key() -> Result<()> {
+  let pubkey = read(keys_dir().join("rsa1024.pub"))?;
+  let blob = PublicKey::from_pem(pubkey.into())?.to_blob()?;
+
+  let agent = TestAgent::start(&["rsa1024"])?;
+  let mut client = agent.client()?;
+  assert!(client.identities()?.is_empty());
+  assert!(client.sign(&blob, b"test-data", RSA_SHA2_256).is_err());
+
+  let mut config = Config::default();
+  config.rsa.allow_weak = vec![Fingerprint::sha256(&blob)];
+  let agent = TestAgent::with_config(&["rsa1024"], config)?;
+  let mut client = agent.client()?;
+  assert_eq!(client.identities()?.len(), 1);
+
+  for (flags, algorithm) in [(RSA_SHA2_512, "rsa-sha2-512"), (RSA_SHA2_256, "rsa-sha2-256")] {
+    let signature = client.sign(&blob, b"test-data", flags)?;
+    assert_eq!(signature.algorithm, algorithm);
+    assert_eq!(signature.blob.len(), 128);
+  }
+  Ok(())
+}
+
+
+/// Check that only the configured RSA signature algorithms are used.
+#[test]
+fn rsa_algorithms() -> Result<()> {
+  let mut config = Config {
+    allow_sha1: true,
+    ..Default::default()
+  };
+  config.rsa.algorithms = vec![RsaSignatureAlgorithm::RsaSha2_256];
+  let agent = TestAgent::with_config(&["rsa2048"], config)?;
+  let mut client = agent.client()?;
+  let blob = client.identities()?.remove(0).pubkey_blob;
+
+  let signature = client.sign(&blob, b"test-data", RSA_SHA2_256)?;
+  assert_eq!(signature.algorithm, "rsa-sha2-256");
+  // Of multiple requested algorithms, the permitted one is used.
+  let signature = client.sign(&blob, b"test-data", RSA_SHA2_256 | RSA_SHA2_512)?;
+  assert_eq!(signature.algorithm, "rsa-sha2-256");
+
+  assert!(client.sign(&blob, b"test-data", RSA_SHA2_512).is_err());
+  assert!(client.sign(&blob, b"test-data", 0).is_err());
+  assert_eq!(failures(&mut client)?, vec![(ErrorKind::PolicyDenied, 2)]);
+  Ok(())
+}
+
+
+/// Check that of multiple requested RSA signature algorithms the first
+/// configured one is used.
+#[test]
+fn rsa_algorithm_order() -> Result<()> {
+  let mut config = Config::default();
+  config.rsa.algorithms = vec![
+    RsaSignatureAlgorithm::RsaSha2_256,
+    RsaSignatureAlgorithm::RsaSha2_512,
+  ];
+  let agent = TestAgent::with_config(&["rsa2048"], config)?;
+  let mut client = agent.client()?;
+  let blob = client.identities()?.remove(0).pubkey_blob;
+
+  let signature = client.sign(&blob, b"test-data", RSA_SHA2_256 | RSA_SHA2_512)?;
+  assert_eq!(signature.algorithm, "rsa-sha2-256");
+  let signature = client.sign(&blob, b"test-data", RSA_SHA2_512)?;
+  assert_eq!(signature.algorithm, "rsa-sha2-512");
+  Ok(())
+}
+
+
+/// Check that a locked agent neither offers nor uses keys.
+#[test]
+fn lock_unlock() -> Result<()> {
+  let agent = TestAgent::start(&["ed25519"])?;
+  let mut client = agent.client()?;
+  let blob = client.identities()?.remove(0).pubkey_blob;
+
+  let response = client.request(&Message::Lock("secret".to_string()))?;
+  assert_eq!(response, Message::Success);
+  assert!(client.identities()?.is_empty());
+  assert!(client.sign(&blob, b"test-data", 0).is_err());
+  assert!(client.status()?.locked);
+
+  let response = client.request(&Message::Unlock("wrong".to_string()))?;
+  assert_eq!(response, Message::Failure);
+  let response = client.request(&Message::Unlock("secret".to_string()))?;
+  assert_eq!(response, Message::Success);
+  let _signature = client.sign(&blob, b"test-data", 0)?;
+  Ok(())
+}
+
+
+/// Check that removing all identities is not prevented by a broken
+/// key.
+#[test]
+fn remove_all_with_broken_key() -> Result<()> {
+  let agent = TestAgent::start(&["ed25519", "rsa2048"])?;
+  let () = write(agent.dir.path().join("broken.pub"), "garbage")?;
+  let () = write(agent.dir.path().join("broken.gpg"), "garbage")?;
+  let mut client = agent.client()?;
+  assert_eq!(client.identities()?.len(), 2);
+
+  let response = client.request(&Message::RemoveAllIdentities)?;
+  assert_eq!(response, Message::Success);
+  assert!(client.identities()?.is_empty());
+  Ok(())
+}
+
+
+/// Check that clients connecting via TCP may use keys but not manage
+/// them or shut down the agent.
+#[test]
+fn tcp_restrictions() -> Result<()> {
+  let agent = TestAgent::start(&["ed25519"])?;
+  let mut client = agent.tcp_client()?;
+
+  let blob = match client.request(&Message::RequestIdentities)? {
+    Message::IdentitiesAnswer(mut identities) => identities.remove(0).pubkey_blob,
+    response => panic!("unexpected response: {response:?}"),
+  };
+  let request = Message::SignRequest(SignRequest {
+    pubkey_blob: blob.clone(),
+    data: b"test-data".to_vec(),
+    flags: 0,
+  });
+  assert!(matches!(client.request(&request)?, Message::SignResponse(..)));
+  let request = Message::Extension(Extension {
+    extension_type: PING_EXTENSION.to_string(),
+    extension_contents: ExtensionContents(Vec::new()),
+  });
+  assert_eq!(client.request(&request)?, Message::Success);
+
+  let privkey = PrivateKey::SkEd25519(SkEd25519PrivateKey {
+    enc_a: vec![0; 32],
+    application: "ssh:".to_string(),
+    flags: 0,
+    key_handle: vec![1, 2, 3],
+    reserved: Vec::new(),
+  });
+  let requests = [
+    Message::AddIdentity(AddIdentity {
+      privkey,
+      comment: "sk-ed25519".to_string(),
+    }),
+    Message::RemoveIdentity(RemoveIdentity {
+      pubkey_blob: blob.clone(),
+    }),
+    Message::RemoveAllIdentities,
+    Message::Lock("secret".to_string()),
+    Message::Extension(Extension {
+      extension_type: SHUTDOWN_EXTENSION.to_string(),
+      extension_contents: ExtensionContents(Vec::new()),
+    }),
+  ];
+  for request in &requests {
+    assert_ne!(client.request(request)?, Message::Success, "{request:?}");
+  }
+
+  let mut client = agent.client()?;
+  assert_eq!(client.identities()?.len(), 1);
+  assert_eq!(failures(&mut client)?, vec![(ErrorKind::PolicyDenied, 5)]);
+  Ok(())
+}
+
+
+/// Check that the agent refuses to shut down on behalf of clients not
+/// known to belong to its user.
+#[test]
+fn shutdown_refused() -> Result<()> {
+  let agent = TestAgent::start(&["ed25519"])?;
+  let request = Message::Extension(Extension {
+    extension_type: SHUTDOWN_EXTENSION.to_string(),
+    extension_contents: ExtensionContents(Vec::new()),
+  });
+  // Messages handled without a connection lack any peer credentials.
+  let response = handle_bytes(&*agent.agent, &to_bytes(&request)?);
+  let response = from_bytes::<Message>(&response)?;
+  let report = ErrorReport::from_response(&response).unwrap();
+  assert_eq!(report.kind, ErrorKind::PolicyDenied);
+
+  let mut client = agent.client()?;
+  assert_eq!(failures(&mut client)?, vec![(ErrorKind::PolicyDenied, 1)]);
+  Ok(())
+}
+
+
+/// Check that the agent's own extensions are served and failures to
+/// handle them reported.
+#[test]
+fn extensions() -> Result<()> {
+  let agent = TestAgent::start(&["ed25519"])?;
+  let mut client = agent.client()?;
+
+  assert_eq!(client.extension(PING_EXTENSION, Vec::new())?, Message::Success);
+
+  let status = client.status()?;
+  assert!(!status.locked);
+  assert_eq!(status.keys.len(), 1);
+  assert_eq!(status.keys[0].comment, "ed25519 test");
+
+  // Clients of unknown extensions merely learn that their request
+  // failed, while those of the agent's own ones get a report.
+  let response = client.extension("bogus@example.com", Vec::new())?;
+  assert_eq!(response, Message::Failure);
+  let err = client.extension(SIGN_BATCH_EXTENSION, vec![0xff]).unwrap_err();
+  assert_eq!(err.kind(), ErrorKind::Other);
+  let err = err.into_inner();
+  let report = err.downcast_ref::<ErrorReport>().unwrap();
+  assert_eq!(report.kind, ErrorKind::Other);
+
+  let blob = client.identities()?.remove(0).pubkey_blob;
+  let requests = vec![
+    SignRequest {
+      pubkey_blob: blob,
+      data: b"test-data".to_vec(),
+      flags: 0,
+    },
+    SignRequest {
+      pubkey_blob: b"unknown".to_vec(),
+      data: b"test-data".to_vec(),
+      flags: 0,
+    },
+  ];
+  let mut stream = UnixStream::connect(agent.socket())?;
+  let outcomes = sign_batch(&mut stream, requests)?;
+  assert!(outcomes[0].is_ok());
+  assert_eq!(outcomes[1].as_ref().unwrap_err().kind, ErrorKind::KeyNotFound);
+  Ok(())
+}
+
+
+/// Check that requests involving unsupported keys fail without taking
+/// down the agent.
+#[test]
+fn unsupported_key() -> Result<()> {
+  let config = Config {
+    on_add: OnAdd::Memory,
+    ..Default::default()
+  };
+  let agent = TestAgent::with_config(&[], config)?;
+  let mut client = agent.client()?;
+
+  let privkey = PrivateKey::SkEd25519(SkEd25519PrivateKey {
+    enc_a: vec![0; 32],
+    application: "ssh:".to_string(),
+    flags: 0,
+    key_handle: vec![1, 2, 3],
+    reserved: Vec::new(),
+  });
+  let blob = PublicKey::from(&privkey).to_blob()?;
+  let request = Message::AddIdentity(AddIdentity {
+    privkey,
+    comment: "sk-ed25519".to_string(),
+  });
+  assert_eq!(client.request(&request)?, Message::Success);
+
+  assert!(client.sign(&blob, b"test-data", 0).is_err());
+  assert!(client.sign(&blob, b"test-data", 0).is_err());
+  assert_eq!(failures(&mut client)?, vec![(ErrorKind::Unsupported, 2)]);
+  Ok(())
+}